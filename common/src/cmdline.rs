@@ -0,0 +1,248 @@
+// Parse the kernel command line like the kernel does in lib/cmdline.c: the parameters are
+// separated by whitespace, the double quotes allow spaces in the values and are removed.
+// A parameter can be given more than once, the last one wins unless all the values are
+// needed, like for console=
+
+use anyhow::{anyhow, Context, Result};
+
+use std::fmt::Display;
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::str::FromStr;
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Cmdline {
+    args: Vec<String>,
+}
+
+/// Split a parameter in its key and its value, e.g. root=/dev/sda1 or quiet
+fn split_arg(arg: &str) -> (&str, Option<&str>) {
+    match arg.split_once('=') {
+        Some((key, value)) => (key, Some(value)),
+        None => (arg, None),
+    }
+}
+
+impl Cmdline {
+    pub fn parse(cmdline: &str) -> Cmdline {
+        let mut args = Vec::new();
+        let mut arg = String::new();
+        let mut in_quote = false;
+        // Tracks the empty quoted values, e.g. rd.shell=""
+        let mut has_arg = false;
+        for c in cmdline.chars() {
+            match c {
+                '"' => {
+                    in_quote = !in_quote;
+                    has_arg = true;
+                }
+                c if c.is_whitespace() && !in_quote => {
+                    if has_arg {
+                        args.push(std::mem::take(&mut arg));
+                        has_arg = false;
+                    }
+                }
+                // The device tree bootargs are NUL terminated
+                '\0' => {}
+                c => {
+                    arg.push(c);
+                    has_arg = true;
+                }
+            }
+        }
+        if has_arg {
+            args.push(arg);
+        }
+
+        Cmdline { args }
+    }
+
+    /// Get the value of the last key=value parameter
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.args.iter().rev().find_map(|arg| match split_arg(arg) {
+            (k, Some(value)) if k == key => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Get the values of every key=value parameter, in the order they have been given
+    pub fn get_all<'a>(&'a self, key: &'a str) -> impl DoubleEndedIterator<Item = &'a str> {
+        self.args
+            .iter()
+            .filter_map(move |arg| match split_arg(arg) {
+                (k, Some(value)) if k == key => Some(value),
+                _ => None,
+            })
+    }
+
+    /// Get the values of the parameters whose key starts with prefix, as (rest of the key,
+    /// value), e.g. ("root", "60") for rd.timeout.root=60 with prefix rd.timeout.
+    pub fn get_prefixed<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = (&'a str, &'a str)> {
+        self.args
+            .iter()
+            .filter_map(move |arg| match split_arg(arg) {
+                (key, Some(value)) => key.strip_prefix(prefix).map(|key| (key, value)),
+                _ => None,
+            })
+    }
+
+    /// Whether the key is given, either as a flag or with a value
+    pub fn contains(&self, key: &str) -> bool {
+        self.args.iter().any(|arg| split_arg(arg).0 == key)
+    }
+
+    /// Whether the key is given as a flag, e.g. rd.break but not rd.break=pre-mount
+    pub fn has_flag(&self, key: &str) -> bool {
+        self.args.iter().any(|arg| arg == key)
+    }
+
+    /// Parse the value of the last key=value parameter
+    pub fn get_parsed<T>(&self, key: &str) -> Result<Option<T>>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        self.get(key)
+            .map(|value| {
+                value
+                    .parse::<T>()
+                    .map_err(|err| anyhow!("{}", err))
+                    .with_context(|| format!("invalid value for {}={}", key, value))
+            })
+            .transpose()
+    }
+
+    /// Get a boolean parameter, accepting the values used by systemd and dracut. The flag
+    /// alone is true, invalid values are None
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.args
+            .iter()
+            .rev()
+            .find_map(|arg| match split_arg(arg) {
+                (k, value) if k == key => Some(value),
+                _ => None,
+            })
+            .and_then(|value| match value {
+                None | Some("1") | Some("yes") | Some("true") | Some("on") => Some(true),
+                Some("0") | Some("no") | Some("false") | Some("off") => Some(false),
+                Some(_) => None,
+            })
+    }
+
+    /// Add the parameters, they override the existing ones
+    pub fn extend<I: IntoIterator<Item = String>>(&mut self, args: I) {
+        self.args.extend(args);
+    }
+
+    /// Add the parameters whose key is not given already
+    pub fn add_missing(&mut self, other: Cmdline) {
+        for arg in other.args {
+            if !self.contains(split_arg(&arg).0) {
+                self.args.push(arg);
+            }
+        }
+    }
+
+    pub fn into_vec(self) -> Vec<String> {
+        self.args
+    }
+}
+
+impl Deref for Cmdline {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.args
+    }
+}
+
+impl From<Vec<String>> for Cmdline {
+    fn from(args: Vec<String>) -> Cmdline {
+        Cmdline { args }
+    }
+}
+
+impl FromIterator<String> for Cmdline {
+    fn from_iter<I: IntoIterator<Item = String>>(iter: I) -> Cmdline {
+        Cmdline {
+            args: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl<'a> FromIterator<&'a str> for Cmdline {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Cmdline {
+        iter.into_iter().map(String::from).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            *Cmdline::parse(
+                "BOOT_IMAGE=/vmlinuz root=LABEL=root  rd.shell=\"/bin/sh -l\" \"quoted arg\" x=\"\"\n"
+            ),
+            [
+                "BOOT_IMAGE=/vmlinuz",
+                "root=LABEL=root",
+                "rd.shell=/bin/sh -l",
+                "quoted arg",
+                "x=",
+            ]
+        );
+        assert_eq!(
+            *Cmdline::parse("console=ttyS2,1500000 rw\0"),
+            ["console=ttyS2,1500000", "rw"]
+        );
+        assert!(Cmdline::parse(" \n").is_empty());
+    }
+
+    #[test]
+    fn get_test() {
+        let cmdline = Cmdline::parse(
+            "console=tty0 root=LABEL=root console=ttyS0 rd.break quiet=0 rd.timeout.root=60",
+        );
+        assert_eq!(cmdline.get("root"), Some("LABEL=root"));
+        assert_eq!(cmdline.get("console"), Some("ttyS0"));
+        assert_eq!(
+            cmdline.get_all("console").collect::<Vec<_>>(),
+            vec!["tty0", "ttyS0"]
+        );
+        assert_eq!(cmdline.get("rd.break"), None);
+        assert_eq!(cmdline.get("rd"), None);
+        assert!(cmdline.contains("rd.break"));
+        assert!(cmdline.contains("quiet"));
+        assert!(!cmdline.has_flag("quiet"));
+        assert!(cmdline.has_flag("rd.break"));
+        assert_eq!(
+            cmdline.get_prefixed("rd.timeout.").collect::<Vec<_>>(),
+            vec![("root", "60")]
+        );
+    }
+
+    #[test]
+    fn get_typed_test() {
+        let cmdline = Cmdline::parse("rd.ssh.port=22 rd.ssh.port=2222 a=b rd.ssh rd.verify=no");
+        assert_eq!(
+            cmdline.get_parsed::<u16>("rd.ssh.port").unwrap(),
+            Some(2222)
+        );
+        assert!(cmdline.get_parsed::<u16>("a").is_err());
+        assert_eq!(cmdline.get_parsed::<u16>("missing").unwrap(), None);
+        assert_eq!(cmdline.get_bool("rd.ssh"), Some(true));
+        assert_eq!(cmdline.get_bool("rd.verify"), Some(false));
+        assert_eq!(cmdline.get_bool("a"), None);
+        assert_eq!(cmdline.get_bool("missing"), None);
+    }
+
+    #[test]
+    fn add_missing_test() {
+        let mut cmdline = Cmdline::parse("quiet console=tty0");
+        cmdline.add_missing(Cmdline::parse("console=ttyS0 root=/dev/vda quiet"));
+        assert_eq!(*cmdline, ["quiet", "console=tty0", "root=/dev/vda"]);
+    }
+}
@@ -0,0 +1,306 @@
+// Parse the crypttab files. The crypttab.initramfs shipped by mkinitrz has the encryption
+// type as third field: <name> <device> <luks|plain> <keyfile|none> [<options>], while the
+// crypttab of the new root follows crypttab(5): <name> <device> [<keyfile>] [<options>]
+
+use anyhow::{bail, Context, Result};
+
+use std::convert::TryFrom;
+use std::fmt;
+
+pub const CRYPTTAB_INITRAMFS: &str = "/etc/crypttab.initramfs";
+// Filesystem created by the tmp option when none is given, as in crypttab(5)
+pub const DEFAULT_TMP_FILESYSTEM: &str = "ext4";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CrypttabFormat {
+    /// crypttab.initramfs, with the encryption type
+    Initramfs,
+    /// crypttab(5)
+    System,
+}
+
+/// The encrypted device, given by path, UUID=<uuid> or LABEL=<label>
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSpec {
+    Path(String),
+    Uuid(String),
+    Label(String),
+}
+
+impl From<&str> for DeviceSpec {
+    fn from(device: &str) -> DeviceSpec {
+        if let Some(uuid) = device.strip_prefix("UUID=") {
+            DeviceSpec::Uuid(uuid.to_string())
+        } else if let Some(label) = device.strip_prefix("LABEL=") {
+            DeviceSpec::Label(label.to_string())
+        } else {
+            DeviceSpec::Path(device.to_string())
+        }
+    }
+}
+
+impl fmt::Display for DeviceSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceSpec::Path(path) => write!(f, "{}", path),
+            DeviceSpec::Uuid(uuid) => write!(f, "UUID={}", uuid),
+            DeviceSpec::Label(label) => write!(f, "LABEL={}", label),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptionType {
+    Luks,
+    /// Plain dm-crypt, only supported with a random key
+    Plain,
+}
+
+impl TryFrom<&str> for EncryptionType {
+    type Error = anyhow::Error;
+
+    fn try_from(encryption: &str) -> Result<EncryptionType> {
+        Ok(match encryption {
+            "luks" => EncryptionType::Luks,
+            "plain" => EncryptionType::Plain,
+            _ => bail!("{} is not a supported encryption type", encryption),
+        })
+    }
+}
+
+/// The key field of the entry
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UnlockType {
+    AskPassphrase,
+    Key(String),
+    /// A new key on every boot, for devices formatted on every boot like swap
+    Random,
+}
+
+impl From<&str> for UnlockType {
+    fn from(unlock_type: &str) -> UnlockType {
+        match unlock_type {
+            // crypttab uses "none" and "-" as placeholders for an empty field
+            "none" | "-" => UnlockType::AskPassphrase,
+            "/dev/urandom" | "/dev/random" => UnlockType::Random,
+            _ => UnlockType::Key(unlock_type.into()),
+        }
+    }
+}
+
+/// Split the comma separated options in (key, value), in the order given
+pub fn parse_options(options: &str) -> Vec<(String, Option<String>)> {
+    if options == "none" || options == "-" {
+        return Vec::new();
+    }
+    options
+        .split(',')
+        .filter(|option| !option.is_empty())
+        .map(|option| match option.split_once('=') {
+            Some((key, value)) => (key.to_string(), Some(value.to_string())),
+            None => (option.to_string(), None),
+        })
+        .collect()
+}
+
+/// Check the values of the options understood by initrz, the other ones are ignored
+fn validate_option(key: &str, value: Option<&str>) -> Result<()> {
+    match (key, value) {
+        ("tries", Some(value)) | ("size", Some(value)) => {
+            value
+                .parse::<u32>()
+                .with_context(|| format!("invalid value for {}: {}", key, value))?;
+        }
+        ("timeout", Some(value)) => {
+            value
+                .parse::<u64>()
+                .with_context(|| format!("invalid value for {}: {}", key, value))?;
+        }
+        ("tries", None) | ("size", None) | ("timeout", None) | ("cipher", None) => {
+            bail!("option {} requires a value", key)
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CrypttabEntry {
+    /// Name of the mapping in /dev/mapper
+    pub name: String,
+    pub device: DeviceSpec,
+    pub encryption_type: EncryptionType,
+    pub unlock: UnlockType,
+    pub options: Vec<(String, Option<String>)>,
+}
+
+impl CrypttabEntry {
+    pub fn parse(line: &str, format: CrypttabFormat) -> Result<CrypttabEntry> {
+        let mut fields = line.split_whitespace();
+        let name = fields.next().context("missing name")?.to_string();
+        let device = fields.next().context("missing device")?.into();
+        let (encryption_type, unlock) = match format {
+            CrypttabFormat::Initramfs => {
+                let encryption_type =
+                    EncryptionType::try_from(fields.next().context("missing encryption type")?)?;
+                (
+                    encryption_type,
+                    UnlockType::from(fields.next().context("missing keyfile")?),
+                )
+            }
+            CrypttabFormat::System => {
+                let unlock = fields
+                    .next()
+                    .map_or(UnlockType::AskPassphrase, UnlockType::from);
+                // A random key can only be used with plain dm-crypt
+                let encryption_type = match unlock {
+                    UnlockType::Random => EncryptionType::Plain,
+                    _ => EncryptionType::Luks,
+                };
+                (encryption_type, unlock)
+            }
+        };
+        let options = fields.next().map(parse_options).unwrap_or_default();
+        if let Some(field) = fields.next() {
+            bail!("unexpected field {}", field);
+        }
+        for (key, value) in &options {
+            validate_option(key, value.as_deref())?;
+        }
+
+        Ok(CrypttabEntry {
+            name,
+            device,
+            encryption_type,
+            unlock,
+            options,
+        })
+    }
+
+    /// Get the value of the option, the last one wins. Some(None) for an option without
+    /// value
+    pub fn get_option(&self, key: &str) -> Option<Option<&str>> {
+        self.options
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.as_deref())
+    }
+}
+
+/// Parse every entry of the crypttab, the errors report the line they have been found in
+pub fn parse_crypttab(contents: &str, format: CrypttabFormat) -> Vec<Result<CrypttabEntry>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'))
+        .map(|(index, line)| {
+            CrypttabEntry::parse(line, format)
+                .with_context(|| format!("invalid crypttab entry at line {}", index + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initramfs_entry_test() {
+        let entry = CrypttabEntry::parse(
+            "root UUID=1234 luks /etc/root.key discard,tries=2",
+            CrypttabFormat::Initramfs,
+        )
+        .unwrap();
+        assert_eq!(
+            entry,
+            CrypttabEntry {
+                name: "root".to_string(),
+                device: DeviceSpec::Uuid("1234".to_string()),
+                encryption_type: EncryptionType::Luks,
+                unlock: UnlockType::Key("/etc/root.key".to_string()),
+                options: vec![
+                    ("discard".to_string(), None),
+                    ("tries".to_string(), Some("2".to_string())),
+                ],
+            }
+        );
+        assert_eq!(entry.get_option("discard"), Some(None));
+        assert_eq!(entry.get_option("tries"), Some(Some("2")));
+        assert_eq!(entry.get_option("swap"), None);
+
+        let entry = CrypttabEntry::parse(
+            "swap /dev/sda2 plain /dev/urandom swap",
+            CrypttabFormat::Initramfs,
+        )
+        .unwrap();
+        assert_eq!(entry.encryption_type, EncryptionType::Plain);
+        assert_eq!(entry.unlock, UnlockType::Random);
+
+        // The keyfile is required
+        assert!(CrypttabEntry::parse("root UUID=1234 luks", CrypttabFormat::Initramfs).is_err());
+        assert!(
+            CrypttabEntry::parse("root UUID=1234 aes none", CrypttabFormat::Initramfs).is_err()
+        );
+    }
+
+    #[test]
+    fn system_entry_test() {
+        let entry =
+            CrypttabEntry::parse("var LABEL=var - discard", CrypttabFormat::System).unwrap();
+        assert_eq!(entry.device, DeviceSpec::Label("var".to_string()));
+        assert_eq!(entry.unlock, UnlockType::AskPassphrase);
+        assert_eq!(entry.encryption_type, EncryptionType::Luks);
+
+        let entry = CrypttabEntry::parse("home /dev/sda3", CrypttabFormat::System).unwrap();
+        assert_eq!(entry.unlock, UnlockType::AskPassphrase);
+        assert!(entry.options.is_empty());
+
+        let entry =
+            CrypttabEntry::parse("swap /dev/sda2 /dev/urandom swap", CrypttabFormat::System)
+                .unwrap();
+        assert_eq!(entry.encryption_type, EncryptionType::Plain);
+        assert!(CrypttabEntry::parse("swap", CrypttabFormat::System).is_err());
+    }
+
+    #[test]
+    fn options_test() {
+        assert!(parse_options("none").is_empty());
+        assert_eq!(
+            parse_options("tmp=xfs,,nofail"),
+            vec![
+                ("tmp".to_string(), Some("xfs".to_string())),
+                ("nofail".to_string(), None),
+            ]
+        );
+        for options in &["tries=abc", "timeout=-1", "size", "cipher"] {
+            assert!(CrypttabEntry::parse(
+                &format!("root /dev/sda1 none {}", options),
+                CrypttabFormat::System
+            )
+            .is_err());
+        }
+        assert!(
+            CrypttabEntry::parse("root /dev/sda1 none discard extra", CrypttabFormat::System)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn parse_crypttab_test() {
+        let entries = parse_crypttab(
+            "# <name> <device> <type> <keyfile> <options>
+root UUID=1234 luks none
+
+swap /dev/sda2 plain
+",
+            CrypttabFormat::Initramfs,
+        );
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].as_ref().unwrap().name, "root");
+        let err = entries[1].as_ref().unwrap_err();
+        assert_eq!(err.to_string(), "invalid crypttab entry at line 4");
+        assert_eq!(err.root_cause().to_string(), "missing keyfile");
+    }
+}
@@ -0,0 +1,7 @@
+// Code shared by initrz and mkinitrz
+
+pub mod cmdline;
+pub mod crypttab;
+pub mod modules;
+pub mod uevent;
+pub mod verity;
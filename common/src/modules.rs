@@ -0,0 +1,332 @@
+// Parse the module indexes generated by depmod(8) in /lib/modules/<version>
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use thiserror::Error;
+
+pub const MODULES_DEP: &str = "modules.dep";
+pub const MODULES_ALIAS: &str = "modules.alias";
+pub const MODULES_SOFTDEP: &str = "modules.softdep";
+pub const MODULES_BUILTIN: &str = "modules.builtin";
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum ModuleError {
+    #[error("{0} is not a valid module name")]
+    InvalidName(String),
+}
+
+/// A problem found in modules.dep by check_modules_dep
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DependencyError {
+    #[error("module {module} depends on {dependency}, which is not in modules.dep")]
+    Missing { module: String, dependency: String },
+    #[error("dependency cycle {}, ignoring the dependency of {} on {}", .0.join(" -> "), .0[.0.len() - 2], .0[.0.len() - 1])]
+    Cycle(Vec<String>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Module {
+    /// Path relative to the kernel modules directory
+    pub filename: String,
+    /// Names of the dependencies, in the order they have to be loaded
+    pub deps: Vec<String>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ModAlias {
+    /// Glob pattern matched against the modalias of the devices
+    pub pattern: String,
+    pub module: String,
+}
+
+/// Modules that are not needed by the symbols, but are used at runtime, e.g. the crc32c
+/// implementation needed by ext4
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct SoftDep {
+    /// Loaded before the module
+    pub pre: Vec<String>,
+    /// Loaded after the module
+    pub post: Vec<String>,
+}
+
+/// The kernel does not distinguish dashes from underscores in the module names, use the
+/// underscores like /proc/modules does
+pub fn normalize_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+/// Get the module name from its file, e.g. kernel/fs/ext4/ext4.ko.zst is ext4
+pub fn get_module_name(filename: &str) -> Result<String, ModuleError> {
+    match Path::new(filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.split_once(".ko"))
+    {
+        Some((name, _)) if !name.is_empty() => Ok(normalize_name(name)),
+        _ => Err(ModuleError::InvalidName(filename.to_string())),
+    }
+}
+
+/// Parse modules.dep, the lines with an invalid module are skipped
+pub fn parse_modules_dep(contents: &str) -> HashMap<String, Module> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (filename, deps) = line.split_once(':')?;
+            let module = get_module_name(filename).ok()?;
+            // The dependencies are listed from the closest to the farthest
+            let mut deps: Vec<String> = deps
+                .split_whitespace()
+                .filter_map(|dep| get_module_name(dep).ok())
+                .collect();
+            deps.reverse();
+            Some((
+                module,
+                Module {
+                    filename: filename.to_string(),
+                    deps,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Check the dependencies of a malformed or hand-edited modules.dep, returning a problem
+/// for each missing dependency and cycle. The cycles are broken by removing the dependency
+/// closing them, visiting the modules by name so that the same one is always removed
+pub fn check_modules_dep(modules: &mut HashMap<String, Module>) -> Vec<DependencyError> {
+    let mut problems = Vec::new();
+    let mut names: Vec<String> = modules.keys().cloned().collect();
+    names.sort();
+    for name in &names {
+        for dependency in &modules[name].deps {
+            if !modules.contains_key(dependency) {
+                problems.push(DependencyError::Missing {
+                    module: name.clone(),
+                    dependency: dependency.clone(),
+                });
+            }
+        }
+    }
+    let mut visited = HashSet::new();
+    for name in &names {
+        let mut stack = Vec::new();
+        break_cycles(name, modules, &mut visited, &mut stack, &mut problems);
+    }
+
+    problems
+}
+
+/// Depth-first visit of the dependencies, the stack holds the modules being visited
+fn break_cycles(
+    name: &str,
+    modules: &mut HashMap<String, Module>,
+    visited: &mut HashSet<String>,
+    stack: &mut Vec<String>,
+    problems: &mut Vec<DependencyError>,
+) {
+    if !visited.insert(name.to_string()) {
+        return;
+    }
+    stack.push(name.to_string());
+    let deps = modules[name].deps.clone();
+    for dependency in deps {
+        if let Some(start) = stack.iter().position(|module| *module == dependency) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(dependency.clone());
+            problems.push(DependencyError::Cycle(cycle));
+            if let Some(module) = modules.get_mut(name) {
+                module.deps.retain(|dep| *dep != dependency);
+            }
+        } else if modules.contains_key(&dependency) {
+            break_cycles(&dependency, modules, visited, stack, problems);
+        }
+    }
+    stack.pop();
+}
+
+/// Parse modules.alias, made of lines like "alias <pattern> <module>"
+pub fn parse_modules_alias(contents: &str) -> Vec<ModAlias> {
+    contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("alias "))
+        .filter_map(|line| line.split_once(' '))
+        .map(|(pattern, module)| ModAlias {
+            pattern: pattern.to_string(),
+            module: normalize_name(module.trim()),
+        })
+        .collect()
+}
+
+/// Parse modules.softdep, made of lines like "softdep <module> pre: <modules> post: <modules>"
+pub fn parse_modules_softdep(contents: &str) -> HashMap<String, SoftDep> {
+    let mut softdeps: HashMap<String, SoftDep> = HashMap::new();
+    for line in contents
+        .lines()
+        .filter_map(|line| line.strip_prefix("softdep "))
+    {
+        let mut words = line.split_whitespace();
+        let module = match words.next() {
+            Some(module) => normalize_name(module),
+            None => continue,
+        };
+        let softdep = softdeps.entry(module).or_default();
+        let mut post = false;
+        for word in words {
+            match word {
+                "pre:" => post = false,
+                "post:" => post = true,
+                dep if post => softdep.post.push(normalize_name(dep)),
+                dep => softdep.pre.push(normalize_name(dep)),
+            }
+        }
+    }
+
+    softdeps
+}
+
+/// Parse modules.builtin, the list of the modules built into the kernel
+pub fn parse_modules_builtin(contents: &str) -> HashSet<String> {
+    contents
+        .lines()
+        .filter_map(|line| get_module_name(line.trim()).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_module_name_test() {
+        assert_eq!(
+            get_module_name("kernel/fs/ext4/ext4.ko.zst").unwrap(),
+            "ext4"
+        );
+        assert_eq!(
+            get_module_name("kernel/drivers/md/dm-crypt.ko").unwrap(),
+            "dm_crypt"
+        );
+        assert!(get_module_name("kernel/fs/ext4/").is_err());
+        assert_eq!(
+            get_module_name("modules.dep"),
+            Err(ModuleError::InvalidName("modules.dep".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_modules_dep_test() {
+        let modules = parse_modules_dep(include_str!("../test/modules.dep"));
+        assert_eq!(modules.len(), 3);
+        assert_eq!(
+            modules["qrtr_mhi"],
+            Module {
+                filename: "kernel/net/qrtr/qrtr-mhi.ko.xz".to_string(),
+                deps: vec!["mhi".to_string(), "ns".to_string(), "qrtr".to_string()],
+            }
+        );
+        assert_eq!(modules["nvidia_uvm"].deps, vec!["nvidia"]);
+        assert!(modules["nvidia"].deps.is_empty());
+    }
+
+    #[test]
+    fn check_modules_dep_test() {
+        let mut modules = parse_modules_dep(include_str!("../test/modules.dep"));
+        assert_eq!(
+            check_modules_dep(&mut modules)[0].to_string(),
+            "module qrtr_mhi depends on mhi, which is not in modules.dep"
+        );
+
+        let mut modules = parse_modules_dep(
+            "kernel/a.ko: kernel/b.ko
+kernel/b.ko: kernel/c.ko
+kernel/c.ko: kernel/a.ko
+kernel/d.ko: kernel/d.ko kernel/a.ko
+",
+        );
+        let problems = check_modules_dep(&mut modules);
+        assert_eq!(
+            problems,
+            vec![
+                DependencyError::Cycle(vec![
+                    "a".to_string(),
+                    "b".to_string(),
+                    "c".to_string(),
+                    "a".to_string()
+                ]),
+                DependencyError::Cycle(vec!["d".to_string(), "d".to_string()]),
+            ]
+        );
+        assert_eq!(
+            problems[0].to_string(),
+            "dependency cycle a -> b -> c -> a, ignoring the dependency of c on a"
+        );
+        assert!(modules["c"].deps.is_empty());
+        assert_eq!(modules["d"].deps, vec!["a"]);
+        assert!(check_modules_dep(&mut modules).is_empty());
+    }
+
+    #[test]
+    fn parse_modules_alias_test() {
+        assert_eq!(
+            parse_modules_alias(
+                "# Aliases extracted from modules themselves.
+alias pci:v000010DEd*sv*sd*bc03sc*i* nvidia
+alias fs-ext4 ext4
+alias usb:v0BDAp8153* r8152-cfgselector
+alias
+"
+            ),
+            vec![
+                ModAlias {
+                    pattern: "pci:v000010DEd*sv*sd*bc03sc*i*".to_string(),
+                    module: "nvidia".to_string(),
+                },
+                ModAlias {
+                    pattern: "fs-ext4".to_string(),
+                    module: "ext4".to_string(),
+                },
+                ModAlias {
+                    pattern: "usb:v0BDAp8153*".to_string(),
+                    module: "r8152_cfgselector".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_modules_softdep_test() {
+        let softdeps = parse_modules_softdep(
+            "# Soft dependencies extracted from modules themselves.
+softdep ext4 pre: crc32c
+softdep snd-hda-intel post: snd-hda-codec pre: snd_intel_dspcfg
+",
+        );
+        assert_eq!(
+            softdeps["ext4"],
+            SoftDep {
+                pre: vec!["crc32c".to_string()],
+                post: Vec::new(),
+            }
+        );
+        assert_eq!(
+            softdeps["snd_hda_intel"],
+            SoftDep {
+                pre: vec!["snd_intel_dspcfg".to_string()],
+                post: vec!["snd_hda_codec".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn parse_modules_builtin_test() {
+        let builtin = parse_modules_builtin(
+            "kernel/fs/ext4/ext4.ko
+kernel/drivers/md/dm-mod.ko
+",
+        );
+        assert!(builtin.contains("ext4"));
+        assert!(builtin.contains("dm_mod"));
+        assert_eq!(builtin.len(), 2);
+    }
+}
@@ -0,0 +1,157 @@
+// Parse the messages received on the NETLINK_KOBJECT_UEVENT socket. The kernel sends
+// "<action>@<devpath>" followed by the KEY=VALUE properties, each one NUL terminated.
+// udevd and the other libudev users broadcast the processed events with a binary header
+// starting with "libudev\0" followed by the same properties
+
+use anyhow::{bail, Context, Result};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+const LIBUDEV_PREFIX: &[u8] = b"libudev\0";
+// Sent in network byte order by libudev
+const LIBUDEV_MAGIC: u32 = 0xfeed_cafe;
+// prefix, magic, header_size, properties_off, properties_len and the filter fields
+const LIBUDEV_HEADER_LEN: usize = 8 + 8 * 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UeventSource {
+    Kernel,
+    /// Broadcast by udevd or another libudev user, after the kernel one
+    Udev,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Uevent {
+    pub source: UeventSource,
+    pub vars: HashMap<String, String>,
+}
+
+impl Uevent {
+    pub fn parse(buf: &[u8]) -> Result<Uevent> {
+        if buf.starts_with(LIBUDEV_PREFIX) {
+            return Ok(Uevent {
+                source: UeventSource::Udev,
+                vars: parse_properties(get_libudev_properties(buf)?)?,
+            });
+        }
+
+        let (header, properties) = match buf.iter().position(|c| *c == 0) {
+            Some(index) => (&buf[..index], &buf[index + 1..]),
+            None => (buf, &[][..]),
+        };
+        if !header.contains(&b'@') {
+            bail!(
+                "invalid uevent header '{}'",
+                String::from_utf8_lossy(header)
+            );
+        }
+
+        Ok(Uevent {
+            source: UeventSource::Kernel,
+            vars: parse_properties(properties)?,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.vars.get(key).map(String::as_str)
+    }
+}
+
+/// Get the properties of a libudev message, whose offset and length are given by the
+/// header in host byte order
+fn get_libudev_properties(buf: &[u8]) -> Result<&[u8]> {
+    if buf.len() < LIBUDEV_HEADER_LEN {
+        bail!("truncated libudev header");
+    }
+    let field = |index: usize| -> [u8; 4] {
+        let start = LIBUDEV_PREFIX.len() + index * 4;
+        buf[start..start + 4].try_into().unwrap()
+    };
+    if u32::from_be_bytes(field(0)) != LIBUDEV_MAGIC {
+        bail!("invalid libudev magic");
+    }
+    let offset = u32::from_ne_bytes(field(2)) as usize;
+    let len = u32::from_ne_bytes(field(3)) as usize;
+    offset
+        .checked_add(len)
+        .and_then(|end| buf.get(offset..end))
+        .context("libudev properties out of the message")
+}
+
+fn parse_properties(buf: &[u8]) -> Result<HashMap<String, String>> {
+    buf.split(|c| *c == 0)
+        .filter(|line| !line.is_empty())
+        .map(parse_line)
+        .collect()
+}
+
+fn parse_line(line: &[u8]) -> Result<(String, String)> {
+    let line = String::from_utf8_lossy(line);
+    let (key, value) = line
+        .split_once('=')
+        .with_context(|| format!("unable to locate '=' in line '{}'", line))?;
+    Ok((key.to_string(), value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KERNEL_UEVENT: &[u8] =
+        b"add@/devices/virtual/block/loop0\0ACTION=add\0DEVPATH=/devices/virtual/block/loop0\0SUBSYSTEM=block\0MAJOR=7\0MINOR=0\0DEVNAME=loop0\0DEVTYPE=disk\0SEQNUM=1234\0";
+
+    fn libudev_message(properties: &[u8]) -> Vec<u8> {
+        let mut buf = LIBUDEV_PREFIX.to_vec();
+        buf.extend(LIBUDEV_MAGIC.to_be_bytes());
+        buf.extend((LIBUDEV_HEADER_LEN as u32).to_ne_bytes());
+        buf.extend((LIBUDEV_HEADER_LEN as u32).to_ne_bytes());
+        buf.extend((properties.len() as u32).to_ne_bytes());
+        buf.extend([0; 16]);
+        buf.extend(properties);
+        buf
+    }
+
+    #[test]
+    fn kernel_uevent_test() {
+        let uevent = Uevent::parse(KERNEL_UEVENT).unwrap();
+        assert_eq!(uevent.source, UeventSource::Kernel);
+        assert_eq!(uevent.get("ACTION"), Some("add"));
+        assert_eq!(uevent.get("DEVNAME"), Some("loop0"));
+        assert_eq!(uevent.vars.len(), 8);
+
+        // The trailing NUL byte is optional
+        let uevent = Uevent::parse(&KERNEL_UEVENT[..KERNEL_UEVENT.len() - 1]).unwrap();
+        assert_eq!(uevent.get("SEQNUM"), Some("1234"));
+
+        assert!(Uevent::parse(b"add\0ACTION=add\0").is_err());
+        assert!(Uevent::parse(b"add@/devices\0ACTION\0").is_err());
+    }
+
+    #[test]
+    fn libudev_uevent_test() {
+        let properties = &KERNEL_UEVENT[KERNEL_UEVENT.iter().position(|c| *c == 0).unwrap() + 1..];
+        let message = libudev_message(properties);
+        let uevent = Uevent::parse(&message).unwrap();
+        assert_eq!(uevent.source, UeventSource::Udev);
+        assert_eq!(uevent.vars, Uevent::parse(KERNEL_UEVENT).unwrap().vars);
+
+        let mut message = libudev_message(b"ACTION=add\0");
+        message[8] = 0;
+        assert!(Uevent::parse(&message).is_err());
+    }
+
+    #[test]
+    fn truncated_test() {
+        // Every prefix of a valid message is either parsed or rejected, never a panic
+        let message = libudev_message(b"ACTION=add\0DEVPATH=/devices/virtual/block/loop0\0");
+        for buf in [KERNEL_UEVENT, &message[..]] {
+            for len in 0..buf.len() {
+                let _ = Uevent::parse(&buf[..len]);
+            }
+        }
+        let mut message = libudev_message(b"");
+        message[16..24].copy_from_slice(&[0xff; 8]);
+        assert!(Uevent::parse(&message).is_err());
+    }
+}
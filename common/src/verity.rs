@@ -0,0 +1,5 @@
+// Paths of the dm-verity root shared by initrz and mkinitrz
+
+// Embedded by mkinitrz when verity_certificate is set in its configuration, it verifies the
+// roothashsig= signature
+pub const VERITY_CERTIFICATE: &str = "/etc/initrz/verity.crt";
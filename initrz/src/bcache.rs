@@ -0,0 +1,52 @@
+use anyhow::{bail, Context, Result};
+
+use std::fs;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const BCACHE_REGISTER: &str = "/sys/fs/bcache/register";
+pub const BCACHE_MODULE: &str = "bcache";
+pub const BCACHE_TAG_VALUE: &str = "bcache";
+pub const BCACHE_DEVICE_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Register a backing or cache device, the bcache device is created once both of
+/// them have been registered
+pub fn register_device(path: &str) -> Result<()> {
+    fs::write(BCACHE_REGISTER, path)
+        .with_context(|| format!("unable to register bcache device {}", path))
+}
+
+/// Return true if the path points to a bcacheN device
+pub fn is_bcache_device(path: &str) -> bool {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.starts_with("bcache"))
+        .unwrap_or(false)
+}
+
+/// The bcache device is created asynchronously after the registration, wait for it
+pub fn wait_for_device(path: &str, timeout: Duration) -> Result<()> {
+    let start = Instant::now();
+    while !Path::new(path).exists() {
+        if start.elapsed() >= timeout {
+            bail!("timed out waiting for bcache device {}", path);
+        }
+        sleep(POLL_INTERVAL);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_bcache_device_test() {
+        assert!(is_bcache_device("/dev/bcache0"));
+        assert!(!is_bcache_device("/dev/sda1"));
+    }
+}
@@ -0,0 +1,78 @@
+// Register btrfs member devices with the kernel, like `btrfs device scan` does
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/btrfs.h
+
+use anyhow::{bail, Context, Result};
+use nix::ioctl_write_ptr;
+use tracing::warn;
+
+use std::fs::OpenOptions;
+use std::os::unix::io::AsRawFd;
+
+use crate::device_registry::DeviceRegistry;
+
+const BTRFS_CONTROL: &str = "/dev/btrfs-control";
+const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+const BTRFS_SCAN_DEV_CMD: u8 = 4;
+const BTRFS_PATH_NAME_MAX: usize = 4087;
+const BTRFS_TAG_VALUE: &str = "btrfs";
+
+#[repr(C)]
+struct BtrfsIoctlVolArgs {
+    fd: i64,
+    name: [u8; BTRFS_PATH_NAME_MAX + 1],
+}
+
+ioctl_write_ptr!(
+    btrfs_scan_dev,
+    BTRFS_IOCTL_MAGIC,
+    BTRFS_SCAN_DEV_CMD,
+    BtrfsIoctlVolArgs
+);
+
+/// Register a single device as a member of a btrfs filesystem
+pub fn scan_device(path: &str) -> Result<()> {
+    if path.len() > BTRFS_PATH_NAME_MAX {
+        bail!("device path {} is too long", path);
+    }
+    let mut args = BtrfsIoctlVolArgs {
+        fd: 0,
+        name: [0; BTRFS_PATH_NAME_MAX + 1],
+    };
+    args.name[..path.len()].copy_from_slice(path.as_bytes());
+
+    let control = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(BTRFS_CONTROL)
+        .with_context(|| format!("unable to open {}", BTRFS_CONTROL))?;
+    unsafe { btrfs_scan_dev(control.as_raw_fd(), &args) }
+        .with_context(|| format!("unable to scan btrfs device {}", path))?;
+
+    Ok(())
+}
+
+/// Register every probed device having a btrfs signature, so that multi-device
+/// filesystems can be mounted. The btrfs module must already be loaded
+pub fn scan_devices(device_registry: &DeviceRegistry) -> Result<()> {
+    for (devname, superblock) in device_registry.scan() {
+        if superblock.is_some_and(|superblock| superblock.fs_type == BTRFS_TAG_VALUE) {
+            // A missing member will be reported by the mount itself
+            if let Err(err) = scan_device(&devname) {
+                warn!("{:?}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn vol_args_layout_test() {
+        assert_eq!(size_of::<BtrfsIoctlVolArgs>(), 4096);
+    }
+}
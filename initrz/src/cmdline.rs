@@ -0,0 +1,53 @@
+// Fallback sources of kernel parameters, for the boards and VMs that do not pass them in
+// /proc/cmdline
+
+use common::cmdline::Cmdline;
+use tracing::info;
+
+use std::fs;
+
+const DEVICE_TREE_BOOTARGS: &str = "/proc/device-tree/chosen/bootargs";
+// Embedded by mkinitrz --cmdline, for the VMs booted without any kernel parameter
+const EMBEDDED_CMDLINE: &str = "/etc/initrz/cmdline";
+
+/// The bootargs property is a NUL terminated string
+fn parse_bootargs(bootargs: &[u8]) -> Cmdline {
+    Cmdline::parse(&String::from_utf8_lossy(bootargs))
+}
+
+/// Complete a cmdline lacking root= with the device tree bootargs and the cmdline embedded
+/// in the image
+pub fn add_fallback(mut cmdline: Cmdline) -> Cmdline {
+    if cmdline.contains("root") {
+        return cmdline;
+    }
+    if let Ok(bootargs) = fs::read(DEVICE_TREE_BOOTARGS) {
+        info!(
+            "reading the kernel parameters from {}",
+            DEVICE_TREE_BOOTARGS
+        );
+        cmdline.add_missing(parse_bootargs(&bootargs));
+    }
+    if cmdline.contains("root") {
+        return cmdline;
+    }
+    if let Ok(embedded) = fs::read_to_string(EMBEDDED_CMDLINE) {
+        info!("reading the kernel parameters from {}", EMBEDDED_CMDLINE);
+        cmdline.add_missing(Cmdline::parse(&embedded));
+    }
+
+    cmdline
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bootargs_test() {
+        assert_eq!(
+            *parse_bootargs(b"console=ttyS2,1500000 root=/dev/mmcblk0p2 rw\0"),
+            ["console=ttyS2,1500000", "root=/dev/mmcblk0p2", "rw"]
+        );
+    }
+}
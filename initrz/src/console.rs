@@ -0,0 +1,273 @@
+// Mirror the output of initrz to every console given with console= and to the kernel log
+// when rd.log=kmsg is given
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use nix::fcntl::OFlag;
+use nix::unistd::{dup2, isatty};
+use tracing::field::{Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{debug, span, warn, Event, Level, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context as LayerContext, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write as _};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+const CONSOLE_PARAM: &str = "console";
+const RING_RECORDS: usize = 1024;
+const KMSG: &str = "/dev/kmsg";
+
+// Every console except the interactive one, which is already /dev/console
+static MIRRORS: RwLock<Vec<File>> = RwLock::new(Vec::new());
+// Last records of every level, so that quiet boots still leave a log behind on failure
+static RING: Mutex<VecDeque<(Level, String)>> = Mutex::new(VecDeque::new());
+// The records above this level are only kept in the ring
+static CONSOLE_LEVEL: RwLock<LevelFilter> = RwLock::new(LevelFilter::TRACE);
+// rd.log=kmsg moves the records from the terminal to the kernel log
+static TERMINAL: AtomicBool = AtomicBool::new(true);
+static KMSG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Collect the message and the fields of an event
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for EventVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Time a span has been created, to log the duration of the boot phases
+struct SpanStart(Instant);
+
+/// Keep every record in the ring and copy the ones in the console level to the mirrored
+/// consoles and to the kernel log
+pub struct ConsoleLayer;
+
+impl<S> Layer<S> for ConsoleLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _attrs: &span::Attributes<'_>, id: &span::Id, ctx: LayerContext<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanStart(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: LayerContext<'_, S>) {
+        let phase = ctx.span(&id).and_then(|span| {
+            let elapsed = span.extensions().get::<SpanStart>()?.0.elapsed();
+            Some((span.name(), elapsed))
+        });
+        if let Some((name, elapsed)) = phase {
+            debug!("{} finished in {:.3}s", name, elapsed.as_secs_f64());
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: LayerContext<'_, S>) {
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        // Prefix the records with the phase they have been logged in, e.g. unlock:
+        let mut record = String::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                let _ = write!(record, "{}: ", span.name());
+            }
+        }
+        record.push_str(&visitor.message);
+        record.push_str(&visitor.fields);
+
+        let level = *event.metadata().level();
+        {
+            let mut ring = RING.lock().unwrap();
+            if ring.len() == RING_RECORDS {
+                ring.pop_front();
+            }
+            ring.push_back((level, record.clone()));
+        }
+        if level > *CONSOLE_LEVEL.read().unwrap() {
+            return;
+        }
+        if TERMINAL.load(Ordering::Relaxed) {
+            for mut mirror in MIRRORS.read().unwrap().iter() {
+                let _ = writeln!(mirror, "[{}] {}", level, record);
+            }
+        }
+        if let Some(kmsg) = KMSG_FILE.lock().unwrap().as_mut() {
+            let _ = writeln!(kmsg, "<{}>initrz: {}", get_kmsg_priority(level), record);
+        }
+    }
+}
+
+/// Whether the record is printed on the terminal by the fmt layer
+pub fn is_terminal_enabled(metadata: &Metadata) -> bool {
+    TERMINAL.load(Ordering::Relaxed) && *metadata.level() <= *CONSOLE_LEVEL.read().unwrap()
+}
+
+/// Syslog priority of the level, as expected in the prefix of the /dev/kmsg records
+fn get_kmsg_priority(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// Get the console devices in the order given, e.g. console=ttyS0,115200n8 is /dev/ttyS0
+fn get_consoles(cmdline: &Cmdline) -> Vec<PathBuf> {
+    let mut consoles: Vec<PathBuf> = Vec::new();
+    for console in cmdline
+        .get_all(CONSOLE_PARAM)
+        .filter_map(|console| console.split(',').next())
+        .filter(|name| !name.is_empty() && *name != "null")
+        .map(|name| PathBuf::from("/dev").join(name))
+    {
+        // The kernel uses the last occurrence of the same console as the preferred one
+        consoles.retain(|c| *c != console);
+        consoles.push(console);
+    }
+
+    consoles
+}
+
+fn open_console(console: &Path) -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        // Do not let Ctrl-C on a serial line send SIGINT to initrz
+        .custom_flags((OFlag::O_NOCTTY | OFlag::O_NONBLOCK).bits())
+        .open(console)
+        .with_context(|| format!("unable to open console {:?}", console))
+}
+
+/// Mirror the logs to every console and use the last one, like the kernel does for
+/// /dev/console, for the prompts and the rescue shell
+pub fn setup(cmdline: &Cmdline) -> Result<()> {
+    let mut consoles = get_consoles(cmdline);
+    let interactive = match consoles.pop() {
+        Some(console) => console,
+        None => return Ok(()),
+    };
+
+    // initrz could have been started without a console attached
+    if !isatty(0).unwrap_or(false) {
+        let console = open_console(&interactive)?;
+        for fd in 0..=2 {
+            dup2(console.as_raw_fd(), fd)
+                .with_context(|| format!("unable to redirect fd {} to {:?}", fd, interactive))?;
+        }
+    }
+
+    let mut mirrors = MIRRORS.write().unwrap();
+    for console in consoles {
+        match open_console(&console) {
+            Ok(file) => mirrors.push(file),
+            Err(err) => warn!("{:?}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Show the prompt on the mirrored consoles, the answer is read from the interactive one
+pub fn mirror_prompt(prompt: &str) {
+    for mut mirror in MIRRORS.read().unwrap().iter() {
+        let _ = writeln!(mirror, "{}(type it on the primary console)", prompt);
+    }
+}
+
+/// Set the level of the records printed on the consoles, every record is kept in the ring
+pub fn set_level(level: LevelFilter) {
+    *CONSOLE_LEVEL.write().unwrap() = level;
+}
+
+/// Choose where the records are printed, the terminal and the mirrored consoles, the
+/// kernel log or both
+pub fn set_outputs(terminal: bool, kmsg: bool) -> Result<()> {
+    TERMINAL.store(terminal, Ordering::Relaxed);
+    *KMSG_FILE.lock().unwrap() = if kmsg {
+        Some(
+            OpenOptions::new()
+                .write(true)
+                .open(KMSG)
+                .with_context(|| format!("unable to open {}", KMSG))?,
+        )
+    } else {
+        None
+    };
+
+    Ok(())
+}
+
+/// Get the warnings and errors in the ring, oldest first
+pub fn get_buffered_log() -> Vec<String> {
+    RING.lock()
+        .unwrap()
+        .iter()
+        .filter(|(level, _)| *level <= Level::WARN)
+        .map(|(level, msg)| format!("[{}] {}", level, msg))
+        .collect()
+}
+
+/// Get every record of the ring, oldest first
+pub fn get_log() -> Vec<String> {
+    RING.lock()
+        .unwrap()
+        .iter()
+        .map(|(level, msg)| format!("[{}] {}", level, msg))
+        .collect()
+}
+
+/// Write every record of the ring into path, to be read from the shells
+pub fn save_log(path: &Path) -> Result<()> {
+    let log: String = get_log().iter().map(|line| format!("{}\n", line)).collect();
+    fs::write(path, log).with_context(|| format!("unable to write {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_consoles_test() {
+        let cmdline = Cmdline::from(vec![
+            "console=ttyS0,115200n8".to_string(),
+            "root=/dev/sda1".to_string(),
+            "console=tty0".to_string(),
+            "console=null".to_string(),
+        ]);
+        assert_eq!(
+            get_consoles(&cmdline),
+            vec![PathBuf::from("/dev/ttyS0"), PathBuf::from("/dev/tty0")]
+        );
+        assert_eq!(get_kmsg_priority(Level::WARN), 4);
+        assert_eq!(get_kmsg_priority(Level::TRACE), 7);
+
+        let cmdline = Cmdline::from(vec![
+            "console=tty0".to_string(),
+            "console=ttyS0".to_string(),
+            "console=tty0".to_string(),
+        ]);
+        assert_eq!(
+            get_consoles(&cmdline),
+            vec![PathBuf::from("/dev/ttyS0"), PathBuf::from("/dev/tty0")]
+        );
+    }
+}
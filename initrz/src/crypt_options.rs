@@ -0,0 +1,248 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use common::crypttab::{self, DEFAULT_TMP_FILESYSTEM};
+use tracing::warn;
+
+const LUKS_OPTIONS_PARAM: &str = "rd.luks.options";
+const DEFAULT_TRIES: u32 = 3;
+
+/// How a device with a random key is formatted after being mapped
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Format {
+    Swap,
+    /// The filesystem to create
+    Tmp(String),
+}
+
+/// Options used when unlocking an encrypted device. Each option is optional so that the
+/// per-device options can be merged with the global ones
+#[derive(Clone, Default, PartialEq, Eq, Debug)]
+pub struct CryptOptions {
+    pub discard: Option<bool>,
+    pub tries: Option<u32>,
+    /// Limit for the passphrase prompt, and for the device to appear when nofail is set
+    pub timeout: Option<Duration>,
+    /// Limit for the passphrase prompt when timeout is not set, from rd.timeout.passphrase
+    pub passphrase_timeout: Option<Duration>,
+    /// Boot without the device when it does not appear, set by nofail
+    pub nofail: Option<bool>,
+    /// Enable the device as swap once unlocked, set by x-initrd.swap
+    pub swap: Option<bool>,
+    /// Cipher and key size in bits of plain dm-crypt devices
+    pub cipher: Option<String>,
+    pub key_size: Option<u32>,
+    pub format: Option<Format>,
+}
+
+impl TryFrom<&str> for CryptOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(options: &str) -> Result<CryptOptions> {
+        CryptOptions::try_from(crypttab::parse_options(options).as_slice())
+    }
+}
+
+impl TryFrom<&[(String, Option<String>)]> for CryptOptions {
+    type Error = anyhow::Error;
+
+    fn try_from(options: &[(String, Option<String>)]) -> Result<CryptOptions> {
+        let mut crypt_options = CryptOptions::default();
+        for (key, value) in options {
+            match (key.as_str(), value.as_deref()) {
+                ("discard", None) => crypt_options.discard = Some(true),
+                ("x-initrd.swap", None) => crypt_options.swap = Some(true),
+                ("nofail", None) => crypt_options.nofail = Some(true),
+                ("swap", None) => crypt_options.format = Some(Format::Swap),
+                ("tmp", fs_type) => {
+                    crypt_options.format = Some(Format::Tmp(
+                        fs_type.unwrap_or(DEFAULT_TMP_FILESYSTEM).to_string(),
+                    ))
+                }
+                ("cipher", Some(cipher)) => crypt_options.cipher = Some(cipher.to_string()),
+                ("size", Some(size)) => {
+                    crypt_options.key_size = Some(
+                        size.parse()
+                            .with_context(|| format!("invalid value for size: {}", size))?,
+                    )
+                }
+                ("tries", Some(tries)) => {
+                    crypt_options.tries = Some(
+                        tries
+                            .parse()
+                            .with_context(|| format!("invalid value for tries: {}", tries))?,
+                    )
+                }
+                ("timeout", Some(timeout)) => {
+                    crypt_options.timeout =
+                        Some(Duration::from_secs(timeout.parse().with_context(|| {
+                            format!("invalid value for timeout: {}", timeout)
+                        })?))
+                }
+                _ => warn!("ignoring unsupported crypt option {}", key),
+            }
+        }
+
+        Ok(crypt_options)
+    }
+}
+
+impl CryptOptions {
+    /// Fill the options that have not been set with the ones in defaults
+    pub fn merge(&self, defaults: &CryptOptions) -> CryptOptions {
+        CryptOptions {
+            discard: self.discard.or(defaults.discard),
+            tries: self.tries.or(defaults.tries),
+            timeout: self.timeout.or(defaults.timeout),
+            passphrase_timeout: self.passphrase_timeout.or(defaults.passphrase_timeout),
+            nofail: self.nofail.or(defaults.nofail),
+            swap: self.swap.or(defaults.swap),
+            cipher: self.cipher.clone().or_else(|| defaults.cipher.clone()),
+            key_size: self.key_size.or(defaults.key_size),
+            format: self.format.clone().or_else(|| defaults.format.clone()),
+        }
+    }
+
+    pub fn allow_discards(&self) -> bool {
+        self.discard.unwrap_or(false)
+    }
+
+    /// The boot waits for the required devices until the root timeout
+    pub fn is_required(&self) -> bool {
+        !self.nofail.unwrap_or(false)
+    }
+
+    /// Swap formatted on every boot is enabled as well
+    pub fn is_swap(&self) -> bool {
+        self.swap.unwrap_or(false) || self.format == Some(Format::Swap)
+    }
+
+    /// Limit for the device to appear, the required devices are waited for as long as the
+    /// root and the nofail ones until their timeout or the settle timeout
+    pub fn get_device_timeout(&self, root_timeout: Duration, settle_timeout: Duration) -> Duration {
+        match self.is_required() {
+            true => root_timeout,
+            false => self.timeout.unwrap_or(settle_timeout),
+        }
+    }
+
+    pub fn get_passphrase_timeout(&self) -> Option<Duration> {
+        self.timeout.or(self.passphrase_timeout)
+    }
+
+    /// Number of passphrase attempts, 0 means no limit
+    pub fn get_tries(&self) -> u32 {
+        self.tries.unwrap_or(DEFAULT_TRIES)
+    }
+}
+
+/// An invalid option is only logged, the others still apply
+pub fn get_crypt_options_from_cmdline(cmdline: &Cmdline) -> CryptOptions {
+    cmdline
+        .get_all(LUKS_OPTIONS_PARAM)
+        .flat_map(crypttab::parse_options)
+        .fold(CryptOptions::default(), |acc, option| {
+            // options given later on the cmdline take precedence
+            match CryptOptions::try_from(std::slice::from_ref(&option)) {
+                Ok(options) => options.merge(&acc),
+                Err(err) => {
+                    warn!("ignoring option of {}: {:?}", LUKS_OPTIONS_PARAM, err);
+                    acc
+                }
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options_test() {
+        let options =
+            CryptOptions::try_from("discard,tries=5,timeout=30,x-initrd.swap,nofail").unwrap();
+        assert_eq!(
+            options,
+            CryptOptions {
+                discard: Some(true),
+                tries: Some(5),
+                timeout: Some(Duration::from_secs(30)),
+                swap: Some(true),
+                nofail: Some(true),
+                ..CryptOptions::default()
+            }
+        );
+        assert!(!options.is_required());
+        assert!(CryptOptions::default().is_required());
+
+        assert_eq!(
+            CryptOptions::try_from("none").unwrap(),
+            CryptOptions::default()
+        );
+        assert!(CryptOptions::try_from("tries=three").is_err());
+
+        let options = CryptOptions::try_from("swap,cipher=aes-cbc-essiv:sha256,size=256").unwrap();
+        assert_eq!(options.format, Some(Format::Swap));
+        assert_eq!(options.cipher.as_deref(), Some("aes-cbc-essiv:sha256"));
+        assert_eq!(options.key_size, Some(256));
+        assert!(options.is_swap());
+        assert_eq!(
+            CryptOptions::try_from("tmp").unwrap().format,
+            Some(Format::Tmp("ext4".to_string()))
+        );
+        assert_eq!(
+            CryptOptions::try_from("tmp=xfs").unwrap().format,
+            Some(Format::Tmp("xfs".to_string()))
+        );
+    }
+
+    #[test]
+    fn merge_options_test() {
+        let cmdline: Cmdline = [
+            "rd.luks.options=discard,tries=1",
+            "rd.luks.options=tries=2,timeout=never",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        // The invalid timeout does not discard the other options
+        let global = get_crypt_options_from_cmdline(&cmdline);
+        assert_eq!(global.tries, Some(2));
+        assert_eq!(global.timeout, None);
+        assert!(global.allow_discards());
+
+        let device = CryptOptions::try_from("tries=0,timeout=10").unwrap();
+        let merged = device.merge(&global);
+        assert_eq!(merged.get_tries(), 0);
+        assert_eq!(merged.timeout, Some(Duration::from_secs(10)));
+        assert!(merged.allow_discards());
+
+        // rd.timeout.passphrase only limits the prompt of the devices without timeout=
+        let global = CryptOptions {
+            passphrase_timeout: Some(Duration::from_secs(60)),
+            ..CryptOptions::default()
+        };
+        assert_eq!(
+            merged.merge(&global).get_passphrase_timeout(),
+            Some(Duration::from_secs(10))
+        );
+        let merged = CryptOptions::try_from("nofail").unwrap().merge(&global);
+        assert_eq!(merged.timeout, None);
+        assert_eq!(
+            merged.get_passphrase_timeout(),
+            Some(Duration::from_secs(60))
+        );
+
+        // nor how long the devices are waited for
+        let (root, settle) = (Duration::from_secs(180), Duration::from_secs(30));
+        assert_eq!(merged.get_device_timeout(root, settle), settle);
+        assert_eq!(global.get_device_timeout(root, settle), root);
+        let nofail = CryptOptions::try_from("nofail,timeout=5").unwrap();
+        assert_eq!(
+            nofail.merge(&global).get_device_timeout(root, settle),
+            Duration::from_secs(5)
+        );
+    }
+}
@@ -1,80 +1,260 @@
 extern crate rpassword;
 
 use anyhow::{bail, Context, Result};
-use libcryptsetup_rs::consts::flags::{CryptActivate, CryptKeyfile};
+use common::cmdline::Cmdline;
+use common::crypttab::{self, CrypttabFormat, EncryptionType, UnlockType};
+use libcryptsetup_rs::consts::flags::CryptActivate;
 use libcryptsetup_rs::consts::vals::EncryptionFormat;
-use libcryptsetup_rs::CryptInit;
+use libcryptsetup_rs::{CryptDevice, CryptInit, LibcryptErr};
+use tracing::{error, info, instrument, warn};
 
-use std::fs::File;
-use std::io::{BufRead, BufReader};
-use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::sync::mpsc::Receiver;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::encrypted_device::EncryptedDevice;
-use crate::encryption_type::EncryptionType;
+use crate::bcache::{self, BCACHE_DEVICE_TIMEOUT, BCACHE_MODULE, BCACHE_TAG_VALUE};
+use crate::console;
+use crate::crypt_options::{get_crypt_options_from_cmdline, CryptOptions};
+use crate::device_mapper;
+use crate::device_registry::DeviceRegistry;
+use crate::encrypted_device::{
+    get_encrypted_devices_from_cmdline, get_key_from_cmdline, EncryptedDevice,
+};
+use crate::event_loop::{Event, EventLoop};
 use crate::identifier::Identifier;
+use crate::input;
+use crate::lvm::LvmActivator;
+use crate::module_loader::ModuleLoader;
+use crate::multipath::{self, MultipathActivator};
+use crate::passphrase;
+use crate::plain_crypt;
+use crate::plymouth;
+use crate::probe::{self, Superblock};
 use crate::root_device::{get_root_from_cmdline, RootDevice};
-use crate::unlock_type::UnlockType;
-use crate::utils::get_blkid_cache;
+use crate::ssh;
+use crate::state::{DeviceState, EncryptedDeviceState, State};
+use crate::verity::{get_verity_from_cmdline, VerityDevice};
 
-const UUID_TAG: &str = "UUID";
+const LVM_TAG_VALUE: &str = "LVM2_member";
+const MIN_REPROBE_INTERVAL: Duration = Duration::from_millis(250);
+const MAX_REPROBE_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct DeviceHandler {
     root: RootDevice,
     encrypted_devices: Vec<EncryptedDevice>,
+    verity: Option<VerityDevice>,
+    lvm: LvmActivator,
+    /// None when disabled with rd.multipath=0
+    multipath: Option<MultipathActivator>,
+    module_loader: Arc<ModuleLoader>,
+    device_registry: Arc<DeviceRegistry>,
+    /// Devices already processed, either from uevents or from probing
+    handled: HashSet<String>,
+    /// Names of the encrypted devices whose backing device has appeared
+    appeared: HashSet<String>,
+    /// Saved in the state of the breakpoint and emergency shells
+    cmdline: Vec<String>,
 }
 
 impl DeviceHandler {
-    pub fn init(crypttab_path: &str, cmdline: &[String]) -> Result<DeviceHandler> {
-        let encrypted_devices = match Path::new(crypttab_path).exists() {
+    pub fn init(
+        crypttab_path: &str,
+        cmdline: &Cmdline,
+        passphrase_timeout: Option<Duration>,
+        module_loader: Arc<ModuleLoader>,
+        device_registry: Arc<DeviceRegistry>,
+    ) -> Result<DeviceHandler> {
+        let mut encrypted_devices = match Path::new(crypttab_path).exists() {
             true => parse_crypttab(crypttab_path)?,
             false => Vec::new(),
         };
 
-        // TODO: Mix the devices from the cmdline
+        // Devices declared in crypttab take precedence over the ones from the cmdline
+        let cmdline_devices = get_encrypted_devices_from_cmdline(cmdline)
+            .into_iter()
+            .filter(|device| {
+                !encrypted_devices
+                    .iter()
+                    .any(|d| d.identifier == device.identifier)
+            })
+            .collect::<Vec<EncryptedDevice>>();
+        encrypted_devices.extend(cmdline_devices);
+
+        encrypted_devices.iter_mut().for_each(|device| {
+            device.cmdline_key = get_key_from_cmdline(cmdline, &device.identifier)
+        });
+
+        let global_options = get_crypt_options_from_cmdline(cmdline).merge(&CryptOptions {
+            passphrase_timeout,
+            ..CryptOptions::default()
+        });
+        encrypted_devices
+            .iter_mut()
+            .for_each(|device| device.options = device.options.merge(&global_options));
 
         Ok(DeviceHandler {
             root: get_root_from_cmdline(cmdline)?,
             encrypted_devices,
+            verity: get_verity_from_cmdline(cmdline)?,
+            lvm: LvmActivator::default(),
+            multipath: multipath::is_enabled(cmdline).then(MultipathActivator::default),
+            module_loader,
+            device_registry,
+            handled: HashSet::new(),
+            appeared: HashSet::new(),
+            cmdline: cmdline.to_vec(),
         })
     }
 
-    pub fn listen(&mut self, device_rx: Receiver<String>) -> Result<()> {
-        loop {
-            let received = device_rx.try_recv();
-            if let Err(err) = received {
-                if err == std::sync::mpsc::TryRecvError::Disconnected {
+    /// Handle the devices as they appear until the root is found or the timeout expires
+    pub fn listen(&mut self, event_loop: &mut EventLoop, timeout: Duration) -> Result<()> {
+        event_loop.set_timeout(timeout)?;
+        let mut reprobe_interval = MIN_REPROBE_INTERVAL;
+        while self.root.devpath.is_none() {
+            match event_loop.next_within(reprobe_interval)? {
+                Some(Event::Device(path)) => self.handle(&path)?,
+                Some(Event::Timeout) => {
+                    warn!("timed out waiting for the root device");
                     break;
                 }
-                break;
+                None => {
+                    // Catch the devices whose uevents have been missed, e.g. the ones
+                    // emitted before the listener was bound
+                    self.rescan()?;
+                    reprobe_interval = (reprobe_interval * 2).min(MAX_REPROBE_INTERVAL);
+                }
             }
-            let received = received.unwrap();
-            self.handle(&received)?;
+        }
+        // Unlock the other devices in crypttab that have already appeared
+        while let Some(path) = event_loop.try_next()? {
+            self.handle(&path)?;
         }
 
         Ok(())
     }
 
+    /// Keep handling the devices until the encrypted devices in crypttab have appeared.
+    /// The nofail ones are skipped once their timeout expires
+    pub fn wait_for_encrypted_devices(
+        &mut self,
+        event_loop: &mut EventLoop,
+        start: Instant,
+        root_timeout: Duration,
+        settle_timeout: Duration,
+    ) -> Result<()> {
+        let mut reprobe_interval = MIN_REPROBE_INTERVAL;
+        let mut logged = false;
+        loop {
+            let pending: Vec<(&str, Duration)> = self
+                .encrypted_devices
+                .iter()
+                .filter(|device| !self.appeared.contains(&device.name))
+                .map(|device| {
+                    let timeout = device
+                        .options
+                        .get_device_timeout(root_timeout, settle_timeout);
+                    (device.name.as_str(), timeout)
+                })
+                .collect();
+            let remaining = pending
+                .iter()
+                .filter_map(|(_, timeout)| timeout.checked_sub(start.elapsed()))
+                .filter(|remaining| !remaining.is_zero())
+                .min();
+            let remaining = match remaining {
+                Some(remaining) => remaining,
+                None => {
+                    for (name, _) in pending {
+                        warn!("encrypted device {} not found, skipping it", name);
+                    }
+                    return Ok(());
+                }
+            };
+            if !logged {
+                info!(
+                    "waiting for encrypted devices {}",
+                    pending
+                        .iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<&str>>()
+                        .join(", ")
+                );
+                logged = true;
+            }
+            match event_loop.next_within(reprobe_interval.min(remaining))? {
+                Some(Event::Device(path)) => self.handle(&path)?,
+                Some(Event::Timeout) => {}
+                None => {
+                    self.rescan()?;
+                    reprobe_interval = (reprobe_interval * 2).min(MAX_REPROBE_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Devices mapped with a random key have no signature, they are matched by path
+    fn has_random_key(&self, path: &str) -> bool {
+        self.encrypted_devices.iter().any(|device| {
+            matches!(device.unlock, UnlockType::Random) && device.identifier.is_path(path)
+        })
+    }
+
     fn get_encrypted_device(&self, path: &str) -> Option<&EncryptedDevice> {
-        let blkid_cache = get_blkid_cache();
-        let uuid = blkid_cache
-            .get_tag_value("UUID", &PathBuf::from(path))
-            .unwrap_or_default();
+        let superblock = self.device_registry.get(path);
+        // Devices given by path take precedence
         self.encrypted_devices
             .iter()
-            .find(|d| match &d.identifier {
-                Identifier::Path(saved_path) => saved_path == path,
-                Identifier::Uuid(_) => false,
-            })
+            .find(|d| d.identifier.is_path(path))
             .or_else(|| {
-                self.encrypted_devices.iter().find(|d| match &d.identifier {
-                    Identifier::Path(_) => false,
-                    Identifier::Uuid(saved_uuid) => saved_uuid == &uuid,
-                })
+                self.encrypted_devices
+                    .iter()
+                    .find(|d| d.identifier.matches(path, superblock.as_ref()))
             })
     }
 
+    /// Unlink the keys stored in the initramfs once every device has been unlocked, so that
+    /// they are not copied into /run/initramfs for the shutdown
+    pub fn remove_embedded_keys(&self) {
+        let rootfs_dev = match fs::symlink_metadata("/") {
+            Ok(metadata) => metadata.dev(),
+            Err(_) => return,
+        };
+        for key in self
+            .encrypted_devices
+            .iter()
+            .flat_map(|device| device.get_keys())
+        {
+            let embedded = fs::symlink_metadata(key)
+                .map(|metadata| metadata.dev() == rootfs_dev)
+                .unwrap_or(false);
+            if embedded {
+                if let Err(err) = fs::remove_file(key) {
+                    warn!("unable to remove key {}: {}", key, err);
+                }
+            }
+        }
+    }
+
+    /// Get the unlocked devices to be enabled as swap
+    pub fn get_swap_devices(&self) -> Vec<String> {
+        self.encrypted_devices
+            .iter()
+            .filter(|device| device.options.is_swap())
+            .map(|device| format!("/dev/mapper/{}", device.name))
+            .filter(|path| Path::new(path).exists())
+            .collect()
+    }
+
+    pub fn is_root_found(&self) -> bool {
+        self.root.devpath.is_some()
+    }
+
     pub fn get_root(self) -> Option<RootDevice> {
         if self.root.devpath.is_some() {
             Some(self.root)
@@ -86,22 +266,49 @@ impl DeviceHandler {
     pub fn is_root(&self, devname: &str) -> bool {
         self.root
             .identifier
-            .get_path()
+            .get_path(&self.device_registry)
             .map(|path| path == devname)
             .unwrap_or(false)
     }
 
+    fn is_multipath_member(&self, path: &str) -> bool {
+        self.multipath
+            .as_ref()
+            .map(|multipath| multipath.is_member(path))
+            .unwrap_or(false)
+    }
+
+    /// Register the device as a possible multipath path. Return true if it is part of
+    /// a multipath device and must not be used directly
+    fn add_multipath_path(&mut self, path: &str) -> Result<bool> {
+        if let Some(multipath) = &mut self.multipath {
+            multipath.add_path(path)?;
+            multipath.activate_all()?;
+        }
+        if !self.is_multipath_member(path) {
+            return Ok(false);
+        }
+        // The root has been found on a single path before its siblings appeared
+        if self
+            .root
+            .devpath
+            .as_ref()
+            .map(|devpath| self.is_multipath_member(devpath))
+            .unwrap_or(false)
+        {
+            self.root.devpath = None;
+        }
+
+        Ok(true)
+    }
+
     pub fn search_root(&mut self) -> Result<bool> {
-        for device in get_blkid_cache().iter() {
-            let devname = device.devname()?;
-            let root_identifier = &self.root.identifier;
-            if match root_identifier {
-                Identifier::Uuid(uuid) => device
-                    .tag_iter()
-                    .any(|tag| tag == (String::from(UUID_TAG), String::from(uuid))),
-                Identifier::Path(path) => devname.to_str().unwrap() == path,
-            } {
-                self.root.devpath = Some(devname.to_str().unwrap().to_string());
+        for (devname, superblock) in self.device_registry.scan() {
+            if self.is_multipath_member(&devname) {
+                continue;
+            }
+            if self.root.identifier.matches(&devname, superblock.as_ref()) {
+                self.root.devpath = Some(devname);
                 return Ok(true);
             }
         }
@@ -109,36 +316,153 @@ impl DeviceHandler {
         Ok(false)
     }
 
-    pub fn unlock_available_devices(&self) -> Result<()> {
-        for device in get_blkid_cache().iter() {
-            let devname = device.devname()?;
-            for encrypted_device in &self.encrypted_devices {
-                if match &encrypted_device.identifier {
-                    Identifier::Uuid(uuid) => device
-                        .tag_iter()
-                        .any(|tag| tag == (String::from(UUID_TAG), String::from(uuid))),
-                    Identifier::Path(path) => devname.to_str().unwrap() == path,
-                } {
-                    unlock_luks_device(devname.to_str().unwrap(), encrypted_device)?;
-                }
-            }
+    /// Set up the verity mapping once both its data and hash devices are available
+    fn setup_verity(&mut self) -> Result<()> {
+        if let Some(verity) = &self.verity {
+            let (data, hash) = match (
+                verity.data.get_path(&self.device_registry),
+                verity.hash.get_path(&self.device_registry),
+            ) {
+                (Ok(data), Ok(hash)) => (data, hash),
+                _ => return Ok(()),
+            };
+            verity.activate(&data, &hash)?;
+            self.verity = None;
         }
 
         Ok(())
     }
 
+    fn register_bcache(&self, path: &str) -> Result<()> {
+        if !self.module_loader.load_module(BCACHE_MODULE)? {
+            // Do not fail here because the module could be builtin
+            warn!("module {} not found", BCACHE_MODULE);
+        }
+        info!("registering bcache device {}", path);
+        if let Err(err) = bcache::register_device(path) {
+            warn!("{:?}", err);
+        }
+
+        Ok(())
+    }
+
+    /// A root=/dev/bcacheN device only appears once its devices have been registered
+    fn wait_for_bcache_root(&mut self) {
+        if self.root.devpath.is_some() {
+            return;
+        }
+        if let Identifier::Path(path) = &self.root.identifier {
+            if bcache::is_bcache_device(path)
+                && bcache::wait_for_device(path, BCACHE_DEVICE_TIMEOUT).is_ok()
+            {
+                self.root.devpath = Some(path.clone());
+            }
+        }
+    }
+
+    #[instrument(name = "unlock", skip_all)]
+    pub fn unlock_available_devices(&mut self) -> Result<()> {
+        if self.multipath.is_some() {
+            // Assemble the multipath devices before anything else uses their paths
+            for devname in probe::get_block_devices() {
+                self.add_multipath_path(&devname)?;
+            }
+        }
+
+        self.rescan()
+    }
+
     pub fn unlock_device(&self, path: &str, encrypted_device: &EncryptedDevice) -> Result<()> {
-        match &encrypted_device.encryption_type {
-            EncryptionType::Luks => unlock_luks_device(path, encrypted_device)?,
+        unlock_encrypted_device(path, encrypted_device, &self.module_loader)
+    }
+
+    /// Handle the device announced by a uevent
+    pub fn handle(&mut self, path: &str) -> Result<()> {
+        self.device_registry.update(path);
+        self.handle_device(path)?;
+        // The device could have activated a new layer, e.g. LVM on LUKS or LUKS on LVM
+        self.rescan()
+    }
+
+    /// Process the devices that appeared since the last scan until no new device shows
+    /// up, so that nested storage stacks are activated one layer after the other
+    pub fn rescan(&mut self) -> Result<()> {
+        let devices = loop {
+            // Devices mapped by other tools, e.g. libcryptsetup, could lack their node
+            device_mapper::create_missing_nodes();
+            let devices = self.device_registry.scan();
+            // Devices without a signature yet are retried on their next uevent
+            let new_devices = devices
+                .iter()
+                .filter(|(devname, superblock)| {
+                    (superblock.is_some() || self.has_random_key(devname))
+                        && !self.handled.contains(devname)
+                })
+                .map(|(devname, _)| devname.clone())
+                .collect::<Vec<String>>();
+            if new_devices.is_empty() {
+                break devices;
+            }
+            for path in new_devices {
+                self.handle_device(&path)?;
+            }
         };
+        self.wait_for_bcache_root();
+        if self.root.devpath.is_none() {
+            self.search_root()?;
+        }
+        self.setup_verity()?;
+
+        if let Err(err) = self.get_state(&devices).save() {
+            warn!("{:?}", err);
+        }
 
         Ok(())
     }
 
-    pub fn handle(&mut self, path: &str) -> Result<()> {
+    fn get_state(&self, devices: &[(String, Option<Superblock>)]) -> State {
+        State {
+            cmdline: self.cmdline.clone(),
+            root: self.root.identifier.to_string(),
+            root_device: self.root.devpath.clone(),
+            encrypted_devices: self
+                .encrypted_devices
+                .iter()
+                .map(|device| EncryptedDeviceState {
+                    name: device.name.clone(),
+                    identifier: device.identifier.to_string(),
+                    unlocked: Path::new("/dev/mapper").join(&device.name).exists(),
+                })
+                .collect(),
+            devices: devices
+                .iter()
+                .map(|(path, superblock)| DeviceState::new(path, superblock.as_ref()))
+                .collect(),
+        }
+    }
+
+    fn handle_device(&mut self, path: &str) -> Result<()> {
+        if !self.handled.insert(path.to_string()) {
+            return Ok(());
+        }
+        if self.add_multipath_path(path)? {
+            return Ok(());
+        }
+
         if let Some(encrypted_device) = self.get_encrypted_device(path) {
+            let name = encrypted_device.name.clone();
             // TODO: execute in another thread and save the result
-            self.unlock_device(path, encrypted_device)?;
+            // A device that cannot be unlocked must not stop the others from being unlocked
+            if let Err(err) = self.unlock_device(path, encrypted_device) {
+                // Nobody is typing the passphrase, apply the emergency policy
+                if passphrase::is_timeout(&err) && encrypted_device.options.is_required() {
+                    return Err(err).with_context(|| {
+                        format!("unable to unlock device {}", encrypted_device.identifier)
+                    });
+                }
+                error!("{:?}", err);
+            }
+            self.appeared.insert(name);
             return Ok(());
         }
 
@@ -147,86 +471,285 @@ impl DeviceHandler {
             return Ok(());
         }
 
-        let mut blkid_cache = get_blkid_cache();
-        blkid_cache.probe_all_new()?;
-
-        let filesystem = blkid_cache.get_tag_value("TYPE", &PathBuf::from(path));
-        if filesystem.is_err() {
-            // We have got a block device with no filesystem, skip
-            return Ok(());
-        }
-        let filesystem = filesystem.unwrap();
-        if filesystem == "lvm" {
-            let output = Command::new("/bin/vgchange")
-                .arg("-ay")
-                .output()
-                .with_context(|| "unable to run vgchange command")?;
-            if !output.status.success() {
-                bail!(
-                    "vgchange command failed:\n{:?}",
-                    String::from_utf8(output.stderr)
-                )
-            }
-
-            let output = Command::new("/bin/vgmknodes")
-                .output()
-                .with_context(|| "unable to run vgmknodes command")?;
-            if !output.status.success() {
-                bail!(
-                    "vgmknodes command failed:\n{:?}",
-                    String::from_utf8(output.stderr)
-                )
+        let filesystem = match self.device_registry.get(path) {
+            Some(superblock) => superblock.fs_type,
+            None => {
+                // We have got a block device with no filesystem, skip until its next uevent
+                self.handled.remove(path);
+                return Ok(());
             }
+        };
+        if filesystem == LVM_TAG_VALUE {
+            self.lvm.add_physical_volume(path)?;
+        }
+        if filesystem == BCACHE_TAG_VALUE {
+            self.register_bcache(path)?;
+            self.wait_for_bcache_root();
         }
 
-        blkid_cache.put_cache();
         Ok(())
     }
 }
 
-fn unlock_luks_device(path: &str, encrypted_device: &EncryptedDevice) -> Result<()> {
+fn unlock_encrypted_device(
+    path: &str,
+    encrypted_device: &EncryptedDevice,
+    module_loader: &ModuleLoader,
+) -> Result<()> {
+    match (&encrypted_device.encryption_type, &encrypted_device.unlock) {
+        (EncryptionType::Luks, _) => unlock_luks_device(path, encrypted_device, module_loader),
+        (EncryptionType::Plain, UnlockType::Random) => {
+            plain_crypt::setup(path, encrypted_device, module_loader)
+        }
+        (EncryptionType::Plain, _) => bail!(
+            "device {}: plain dm-crypt is only supported with a random key",
+            encrypted_device.identifier
+        ),
+    }
+}
+
+fn unlock_luks_device(
+    path: &str,
+    encrypted_device: &EncryptedDevice,
+    module_loader: &ModuleLoader,
+) -> Result<()> {
+    // The device could have been unlocked already when probing after the uevent listener started
+    if Path::new("/dev/mapper")
+        .join(&encrypted_device.name)
+        .exists()
+    {
+        return Ok(());
+    }
     let mut device = CryptInit::init(Path::new(path))?;
     device
         .context_handle()
         .load::<()>(Some(EncryptionFormat::Luks2), None)?;
 
-    match &encrypted_device.unlock {
-        UnlockType::Key(key) => {
-            device
-                .keyfile_handle()
-                .device_read(Path::new(key), 0, None, CryptKeyfile::empty())?;
+    let options = &encrypted_device.options;
+    for key in encrypted_device.get_keys() {
+        match unlock_with_key(
+            &mut device,
+            &encrypted_device.name,
+            key,
+            get_activate_flags(options),
+        ) {
+            Ok(()) => return Ok(()),
+            Err(err) => warn!(
+                "unable to unlock device {} with key {}: {:?}",
+                encrypted_device.identifier, key, err
+            ),
+        }
+    }
+
+    let tries = options.get_tries();
+    let start = Instant::now();
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        let remaining = options
+            .get_passphrase_timeout()
+            .map(|timeout| timeout.saturating_sub(start.elapsed()));
+        let mut passphrase =
+            match ask_passphrase_for_device(encrypted_device, module_loader, remaining) {
+                Ok(passphrase) => passphrase.into_bytes(),
+                Err(err) if passphrase::is_timeout(&err) && !options.is_required() => {
+                    warn!(
+                        "no passphrase entered for device {}, skipping it",
+                        encrypted_device.identifier
+                    );
+                    return Ok(());
+                }
+                Err(err) => return Err(err),
+            };
+        let res = device.activate_handle().activate_by_passphrase(
+            Some(&encrypted_device.name),
+            None,
+            &passphrase,
+            get_activate_flags(options),
+        );
+        zeroize(&mut passphrase);
+        let err = match res {
+            Ok(_) => break,
+            Err(err) => err,
+        };
+        if !is_wrong_passphrase(&err) {
+            return Err(err).with_context(|| {
+                format!("unable to unlock device {}", encrypted_device.identifier)
+            });
         }
-        UnlockType::AskPassphrase => {
-            device.activate_handle().activate_by_passphrase(
-                Some(&encrypted_device.name),
-                None,
-                ask_passphrase_for_device(encrypted_device)?.as_bytes(),
-                CryptActivate::empty(),
-            )?;
+        if (tries != 0 && attempt >= tries)
+            || options
+                .get_passphrase_timeout()
+                .map(|timeout| start.elapsed() >= timeout)
+                .unwrap_or(false)
+        {
+            bail!(
+                "incorrect passphrase for device {}, no attempts left",
+                encrypted_device.identifier
+            );
         }
-    };
+        match tries {
+            0 => warn!(
+                "incorrect passphrase for device {}",
+                encrypted_device.identifier
+            ),
+            _ => warn!(
+                "incorrect passphrase for device {}, {} attempts left",
+                encrypted_device.identifier,
+                tries - attempt
+            ),
+        }
+    }
 
     Ok(())
 }
 
+fn get_activate_flags(options: &CryptOptions) -> CryptActivate {
+    if options.allow_discards() {
+        CryptActivate::ALLOW_DISCARDS
+    } else {
+        CryptActivate::empty()
+    }
+}
+
+fn unlock_with_key(
+    device: &mut CryptDevice,
+    name: &str,
+    key: &str,
+    flags: CryptActivate,
+) -> Result<()> {
+    let mut buf = fs::read(key).with_context(|| format!("unable to read key {}", key))?;
+    let res = device
+        .activate_handle()
+        .activate_by_passphrase(Some(name), None, &buf, flags);
+    zeroize(&mut buf);
+    res?;
+
+    Ok(())
+}
+
+/// Overwrite the key material once used. The writes are volatile so that they are not
+/// optimized away for a buffer about to be freed
+pub fn zeroize(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        unsafe { ptr::write_volatile(byte, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Unlock a device declared in the crypttab of the new root, after chrooting into it
+pub fn unlock_system_crypttab_device(
+    crypttab_path: &str,
+    name: &str,
+    module_loader: &ModuleLoader,
+    device_registry: &DeviceRegistry,
+) -> Result<()> {
+    let crypttab = fs::read_to_string(crypttab_path)
+        .with_context(|| format!("unable to read {}", crypttab_path))?;
+    let entry = crypttab::parse_crypttab(&crypttab, CrypttabFormat::System)
+        .into_iter()
+        .filter_map(Result::ok)
+        .find(|entry| entry.name == name)
+        .with_context(|| format!("unable to find {} in {}", name, crypttab_path))?;
+    let encrypted_device = EncryptedDevice::try_from(entry)
+        .with_context(|| format!("invalid entry {} in {}", name, crypttab_path))?;
+    let path = encrypted_device.identifier.get_path(device_registry)?;
+    unlock_encrypted_device(&path, &encrypted_device, module_loader)?;
+    // The uevent listener has already been shut down
+    device_mapper::create_missing_nodes();
+
+    Ok(())
+}
+
+/// Entry point of `/init unlock-device` and of the unlock builtin of the rescue shell, the
+/// name defaults to luks-<device name>
+pub fn unlock_from_shell(path: &str, name: Option<&str>) -> Result<()> {
+    let name = name.map(String::from).unwrap_or_else(|| {
+        format!(
+            "luks-{}",
+            Path::new(path)
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+        )
+    });
+    let mut device = CryptInit::init(Path::new(path))?;
+    device
+        .context_handle()
+        .load::<()>(Some(EncryptionFormat::Luks2), None)?;
+    let mut passphrase = rpassword::prompt_password(format!("Password for device {}: ", path))
+        .context("unable to read password from stdin")?
+        .into_bytes();
+    let res = device.activate_handle().activate_by_passphrase(
+        Some(&name),
+        None,
+        &passphrase,
+        CryptActivate::empty(),
+    );
+    zeroize(&mut passphrase);
+    res.with_context(|| format!("unable to unlock device {}", path))?;
+    // There is no uevent listener creating the node
+    device_mapper::create_missing_nodes();
+
+    Ok(())
+}
+
+/// libcryptsetup fails with EPERM when no keyslot can be opened with the passphrase
+fn is_wrong_passphrase(err: &LibcryptErr) -> bool {
+    matches!(err, LibcryptErr::IOError(err) if err.raw_os_error() == Some(libc::EPERM))
+}
+
+/// Parse the crypttab of the initramfs, the invalid entries are skipped
 fn parse_crypttab(crypttab_path: &str) -> Result<Vec<EncryptedDevice>> {
-    let file =
-        File::open(crypttab_path).with_context(|| format!("unable to open {:?}", crypttab_path))?;
-    // TODO: Print errors
-    Ok(BufReader::new(file)
-        .lines()
-        .map_while(Result::ok)
-        .filter(|line| !line.starts_with('#'))
-        .filter(|line| !line.is_empty())
-        .map(EncryptedDevice::from_line)
-        .filter_map(|device| device.ok())
-        .collect())
+    let crypttab = fs::read_to_string(crypttab_path)
+        .with_context(|| format!("unable to read {:?}", crypttab_path))?;
+    let devices = crypttab::parse_crypttab(&crypttab, CrypttabFormat::Initramfs)
+        .into_iter()
+        .filter_map(|entry| match entry.and_then(EncryptedDevice::try_from) {
+            Ok(device) => Some(device),
+            Err(err) => {
+                warn!("skipping entry of {}: {:?}", crypttab_path, err);
+                None
+            }
+        })
+        .collect();
+
+    Ok(devices)
 }
 
-fn ask_passphrase_for_device(encrypted_device: &EncryptedDevice) -> Result<String> {
-    rpassword::prompt_password(format!(
-        "Password for device {}: ",
-        encrypted_device.identifier
-    ))
-    .context("unable to read password from stdin")
+/// Only the console prompt gives up after the timeout
+fn ask_passphrase_for_device(
+    encrypted_device: &EncryptedDevice,
+    module_loader: &ModuleLoader,
+    timeout: Option<Duration>,
+) -> Result<String> {
+    let prompt = format!("Password for device {}: ", encrypted_device.identifier);
+    console::mirror_prompt(&prompt);
+    if ssh::is_running() {
+        return ssh::ask_for_password(&prompt);
+    }
+    input::wait_for_keyboard(module_loader);
+    if plymouth::is_running() {
+        return plymouth::ask_for_password(&prompt);
+    }
+
+    passphrase::prompt(&prompt, timeout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io;
+
+    #[test]
+    fn is_wrong_passphrase_test() {
+        assert!(is_wrong_passphrase(&LibcryptErr::IOError(
+            io::Error::from_raw_os_error(libc::EPERM)
+        )));
+        assert!(!is_wrong_passphrase(&LibcryptErr::IOError(
+            io::Error::from_raw_os_error(libc::EIO)
+        )));
+        assert!(!is_wrong_passphrase(&LibcryptErr::NullPtr));
+    }
 }
@@ -0,0 +1,310 @@
+// Minimal device-mapper client talking directly to /dev/mapper/control
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/dm-ioctl.h
+
+use anyhow::{bail, Context, Result};
+use nix::sys::stat::{major, makedev, minor, mknod, Mode, SFlag};
+use nix::{ioctl_readwrite, libc::dev_t};
+use tracing::warn;
+
+use std::fs::{self, File, OpenOptions};
+use std::mem::size_of;
+use std::os::unix::{fs::symlink, io::AsRawFd};
+use std::path::{Path, PathBuf};
+
+const DM_CONTROL: &str = "/dev/mapper/control";
+const SYS_BLOCK: &str = "/sys/class/block";
+const DM_IOCTL: u8 = 0xfd;
+const DM_VERSION: [u32; 3] = [4, 0, 0];
+const DM_NAME_LEN: usize = 128;
+const DM_UUID_LEN: usize = 129;
+const DM_MAX_TYPE_NAME: usize = 16;
+
+const DM_DEV_CREATE_CMD: u8 = 3;
+const DM_DEV_REMOVE_CMD: u8 = 4;
+const DM_DEV_SUSPEND_CMD: u8 = 6;
+const DM_TABLE_LOAD_CMD: u8 = 9;
+
+const DM_READONLY_FLAG: u32 = 1 << 0;
+
+#[repr(C)]
+struct DmIoctl {
+    version: [u32; 3],
+    data_size: u32,
+    data_start: u32,
+    target_count: u32,
+    open_count: i32,
+    flags: u32,
+    event_nr: u32,
+    padding: u32,
+    dev: u64,
+    name: [u8; DM_NAME_LEN],
+    uuid: [u8; DM_UUID_LEN],
+    data: [u8; 7],
+}
+
+#[repr(C)]
+struct DmTargetSpec {
+    sector_start: u64,
+    length: u64,
+    status: i32,
+    next: u32,
+    target_type: [u8; DM_MAX_TYPE_NAME],
+}
+
+ioctl_readwrite!(dm_dev_create, DM_IOCTL, DM_DEV_CREATE_CMD, DmIoctl);
+ioctl_readwrite!(dm_dev_remove, DM_IOCTL, DM_DEV_REMOVE_CMD, DmIoctl);
+ioctl_readwrite!(dm_dev_suspend, DM_IOCTL, DM_DEV_SUSPEND_CMD, DmIoctl);
+ioctl_readwrite!(dm_table_load, DM_IOCTL, DM_TABLE_LOAD_CMD, DmIoctl);
+
+/// A line of a device-mapper table, all the sizes are in sectors
+#[derive(Debug, PartialEq, Eq)]
+pub struct Target {
+    pub start: u64,
+    pub length: u64,
+    pub target_type: String,
+    pub params: String,
+}
+
+/// Buffer holding a dm_ioctl struct followed by its payload. It is backed by u64 so that
+/// both the header and the target specs are correctly aligned
+struct IoctlBuffer {
+    buf: Vec<u64>,
+}
+
+impl IoctlBuffer {
+    fn new(name: &str, uuid: Option<&str>, flags: u32, payload: &[u8]) -> Result<IoctlBuffer> {
+        if name.len() >= DM_NAME_LEN {
+            bail!("device-mapper name {} is too long", name);
+        }
+        if uuid.map(|uuid| uuid.len() >= DM_UUID_LEN).unwrap_or(false) {
+            bail!("device-mapper uuid for {} is too long", name);
+        }
+
+        let header_size = size_of::<DmIoctl>();
+        let data_size = header_size + payload.len();
+        let words = data_size.div_ceil(8);
+        let mut buffer = IoctlBuffer {
+            buf: vec![0; words],
+        };
+        let header = buffer.header();
+        header.version = DM_VERSION;
+        header.data_size = (words * 8) as u32;
+        header.data_start = header_size as u32;
+        header.flags = flags;
+        header.name[..name.len()].copy_from_slice(name.as_bytes());
+        if let Some(uuid) = uuid {
+            header.uuid[..uuid.len()].copy_from_slice(uuid.as_bytes());
+        }
+        buffer.bytes()[header_size..data_size].copy_from_slice(payload);
+
+        Ok(buffer)
+    }
+
+    fn header(&mut self) -> &mut DmIoctl {
+        unsafe { &mut *(self.buf.as_mut_ptr() as *mut DmIoctl) }
+    }
+
+    fn bytes(&mut self) -> &mut [u8] {
+        unsafe {
+            std::slice::from_raw_parts_mut(self.buf.as_mut_ptr() as *mut u8, self.buf.len() * 8)
+        }
+    }
+}
+
+/// Serialize the targets as a sequence of dm_target_spec, each one followed by its
+/// parameters and padded to 8 bytes
+fn serialize_targets(targets: &[Target]) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    for target in targets {
+        if target.target_type.len() >= DM_MAX_TYPE_NAME {
+            bail!("invalid device-mapper target type {}", target.target_type);
+        }
+        let spec_size = size_of::<DmTargetSpec>() + target.params.len() + 1;
+        let next = spec_size.div_ceil(8) * 8;
+        let begin = payload.len();
+        payload.extend_from_slice(&target.start.to_ne_bytes());
+        payload.extend_from_slice(&target.length.to_ne_bytes());
+        payload.extend_from_slice(&0i32.to_ne_bytes());
+        payload.extend_from_slice(&(next as u32).to_ne_bytes());
+        let mut target_type = [0u8; DM_MAX_TYPE_NAME];
+        target_type[..target.target_type.len()].copy_from_slice(target.target_type.as_bytes());
+        payload.extend_from_slice(&target_type);
+        payload.extend_from_slice(target.params.as_bytes());
+        payload.resize(begin + next, 0);
+    }
+
+    Ok(payload)
+}
+
+fn open_control() -> Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(DM_CONTROL)
+        .with_context(|| format!("unable to open {}", DM_CONTROL))
+}
+
+/// Create and activate a device-mapper device, then create its node in /dev/mapper.
+/// Returns the device number of the new device
+pub fn create_device(
+    name: &str,
+    uuid: Option<&str>,
+    targets: &[Target],
+    readonly: bool,
+) -> Result<dev_t> {
+    let control = open_control()?;
+    let mut create = IoctlBuffer::new(name, uuid, 0, &[])?;
+    unsafe { dm_dev_create(control.as_raw_fd(), create.header()) }
+        .with_context(|| format!("unable to create device-mapper device {}", name))?;
+    let dev = create.header().dev as dev_t;
+
+    // Remove the device left without a table, a later attempt would fail with EBUSY
+    if let Err(err) = load_table(name, targets, readonly).and_then(|_| create_node(name, dev)) {
+        if let Err(remove_err) = remove_device(name) {
+            warn!("{:?}", remove_err);
+        }
+        return Err(err);
+    }
+
+    Ok(dev)
+}
+
+/// Create /dev/mapper/<name>, devtmpfs only provides /dev/dm-N
+pub fn create_node(name: &str, dev: dev_t) -> Result<PathBuf> {
+    let node = Path::new("/dev/mapper").join(name);
+    if !node.exists() {
+        mknod(&node, SFlag::S_IFBLK, Mode::from_bits_truncate(0o600), dev)
+            .with_context(|| format!("unable to create node {:?}", node))?;
+    }
+
+    Ok(node)
+}
+
+fn parse_device_number(dev: &str) -> Option<dev_t> {
+    let (major, minor) = dev.trim().split_once(':')?;
+    Some(makedev(major.parse().ok()?, minor.parse().ok()?))
+}
+
+/// Get the name and the device number of every device-mapper device
+pub fn get_devices() -> Vec<(String, dev_t)> {
+    let entries = match fs::read_dir(SYS_BLOCK) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("dm-"))
+        .filter_map(|entry| {
+            let name = fs::read_to_string(entry.path().join("dm/name")).ok()?;
+            let dev = parse_device_number(&fs::read_to_string(entry.path().join("dev")).ok()?)?;
+            Some((name.trim().to_string(), dev))
+        })
+        // The name is empty until the device has been fully created
+        .filter(|(name, _)| !name.is_empty())
+        .collect()
+}
+
+/// Create the /dev/mapper nodes of the devices created by other tools, so that they
+/// can be found by name, e.g. root=/dev/mapper/cryptroot
+pub fn create_missing_nodes() {
+    for (name, dev) in get_devices() {
+        if let Err(err) = create_node(&name, dev) {
+            warn!("{:?}", err);
+        }
+    }
+}
+
+/// Load a new table into an existing device-mapper device and make it active
+pub fn load_table(name: &str, targets: &[Target], readonly: bool) -> Result<()> {
+    let control = open_control()?;
+    let fd = control.as_raw_fd();
+
+    let mut load = IoctlBuffer::new(
+        name,
+        None,
+        if readonly { DM_READONLY_FLAG } else { 0 },
+        &serialize_targets(targets)?,
+    )?;
+    load.header().target_count = targets.len() as u32;
+    unsafe { dm_table_load(fd, load.header()) }
+        .with_context(|| format!("unable to load table for device-mapper device {}", name))?;
+
+    // resuming a device without the suspend flag activates the loaded table
+    let mut resume = IoctlBuffer::new(name, None, 0, &[])?;
+    unsafe { dm_dev_suspend(fd, resume.header()) }
+        .with_context(|| format!("unable to resume device-mapper device {}", name))?;
+
+    Ok(())
+}
+
+/// Deactivate and remove a device-mapper device, it fails while the device is in use
+pub fn remove_device(name: &str) -> Result<()> {
+    let control = open_control()?;
+    let mut remove = IoctlBuffer::new(name, None, 0, &[])?;
+    unsafe { dm_dev_remove(control.as_raw_fd(), remove.header()) }
+        .with_context(|| format!("unable to remove device-mapper device {}", name))?;
+
+    Ok(())
+}
+
+/// Format the device number as accepted in device-mapper tables
+pub fn get_device_string(dev: dev_t) -> String {
+    format!("{}:{}", major(dev), minor(dev))
+}
+
+/// Create the /dev/<dir>/<name> symlink pointing to /dev/mapper/<dm_name>
+pub fn create_symlink(dir: &str, name: &str, dm_name: &str) -> Result<()> {
+    let dir = Path::new("/dev").join(dir);
+    fs::create_dir_all(&dir).with_context(|| format!("unable to create {:?}", dir))?;
+    let link = dir.join(name);
+    if !link.exists() {
+        symlink(Path::new("../mapper").join(dm_name), &link)
+            .with_context(|| format!("unable to create symlink {:?}", link))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dm_ioctl_layout_test() {
+        assert_eq!(size_of::<DmIoctl>(), 312);
+        assert_eq!(size_of::<DmTargetSpec>(), 40);
+    }
+
+    #[test]
+    fn parse_device_number_test() {
+        assert_eq!(parse_device_number("254:3\n"), Some(makedev(254, 3)));
+        assert_eq!(parse_device_number("254"), None);
+    }
+
+    #[test]
+    fn serialize_targets_test() {
+        let payload = serialize_targets(&[
+            Target {
+                start: 0,
+                length: 2048,
+                target_type: "linear".to_string(),
+                params: "8:1 2048".to_string(),
+            },
+            Target {
+                start: 2048,
+                length: 1024,
+                target_type: "linear".to_string(),
+                params: "8:17 2048".to_string(),
+            },
+        ])
+        .unwrap();
+
+        // 40 bytes of spec + "8:1 2048\0" padded to 8 bytes
+        assert_eq!(&payload[20..24], &56u32.to_ne_bytes());
+        assert_eq!(&payload[24..30], b"linear");
+        assert_eq!(&payload[40..49], b"8:1 2048\0");
+        assert_eq!(&payload[56..64], &2048u64.to_ne_bytes());
+        assert_eq!(&payload[96..106], b"8:17 2048\0");
+        assert_eq!(payload.len() % 8, 0);
+    }
+}
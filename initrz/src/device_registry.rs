@@ -0,0 +1,86 @@
+// Superblocks of the block devices, kept in memory so that each device is read when it
+// appears instead of on every lookup. The uevent announcing that a device is ready probes
+// it again, as its content could have changed
+
+use tracing::debug;
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::probe::{self, Superblock};
+
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: RwLock<HashMap<String, Option<Superblock>>>,
+}
+
+impl DeviceRegistry {
+    /// Get the superblock of the device, it is only probed the first time
+    pub fn get(&self, path: &str) -> Option<Superblock> {
+        if let Some(superblock) = self.devices.read().unwrap().get(path) {
+            return superblock.clone();
+        }
+        self.update(path)
+    }
+
+    /// Probe the device again, e.g. when its uevent is received
+    pub fn update(&self, path: &str) -> Option<Superblock> {
+        let superblock = probe::probe_device(path).unwrap_or_else(|err| {
+            debug!("{:?}", err);
+            None
+        });
+        self.devices
+            .write()
+            .unwrap()
+            .insert(path.to_string(), superblock.clone());
+        superblock
+    }
+
+    /// Get every block device with its superblock, the ones without a known signature are
+    /// included. Only the new devices are probed and the removed ones are forgotten
+    pub fn scan(&self) -> Vec<(String, Option<Superblock>)> {
+        let paths = probe::get_block_devices();
+        self.devices
+            .write()
+            .unwrap()
+            .retain(|path, _| paths.contains(path));
+        paths
+            .into_iter()
+            .map(|path| {
+                let superblock = self.get(&path);
+                (path, superblock)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    #[test]
+    fn device_registry_test() {
+        let path = std::env::temp_dir().join("initrz-device-registry.img");
+        let path_str = path.to_str().unwrap();
+        fs::write(&path, vec![0; 4096]).unwrap();
+        let device_registry = DeviceRegistry::default();
+        assert_eq!(device_registry.get(path_str), None);
+
+        let mut header = vec![0; 4096];
+        header[..6].copy_from_slice(b"LUKS\xba\xbe");
+        fs::write(&path, &header).unwrap();
+        // The device is not read again until its uevent
+        assert_eq!(device_registry.get(path_str), None);
+        assert_eq!(
+            device_registry.update(path_str).map(|sb| sb.fs_type),
+            Some("crypto_LUKS")
+        );
+        assert_eq!(
+            device_registry.get(path_str).map(|sb| sb.fs_type),
+            Some("crypto_LUKS")
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
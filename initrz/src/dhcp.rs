@@ -0,0 +1,385 @@
+// Minimal DHCPv4 client, enough to get a lease at boot
+// https://datatracker.ietf.org/doc/html/rfc2131
+
+use anyhow::{bail, Context, Result};
+use nix::sys::socket::{setsockopt, sockopt::BindToDevice};
+use tracing::{debug, warn};
+
+use std::convert::TryInto;
+use std::ffi::OsString;
+use std::fs;
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::hostname;
+
+const CLIENT_PORT: u16 = 68;
+const SERVER_PORT: u16 = 67;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+// Ask the server to broadcast its replies, we cannot receive unicast without an address
+const FLAG_BROADCAST: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const HEADER_SIZE: usize = 236;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_DOMAIN_NAME: u8 = 15;
+const OPTION_REQUESTED_IP: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETER_LIST: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+
+const ATTEMPTS: u32 = 5;
+const REPLY_TIMEOUT: Duration = Duration::from_secs(4);
+const LEASE_DIR: &str = "/run/initrz/net";
+
+/// Configuration received from the DHCP server
+#[derive(PartialEq, Eq, Debug)]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub netmask: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: Vec<Ipv4Addr>,
+    pub domain: Option<String>,
+    pub hostname: Option<String>,
+    pub server: Option<Ipv4Addr>,
+    pub lease_time: Option<u32>,
+}
+
+fn build_message(
+    message_type: u8,
+    xid: u32,
+    mac: &[u8; 6],
+    offer: Option<&Lease>,
+    hostname: Option<&str>,
+) -> Vec<u8> {
+    let mut message = vec![0u8; HEADER_SIZE];
+    message[0] = BOOTREQUEST;
+    message[1] = HTYPE_ETHERNET;
+    message[2] = mac.len() as u8;
+    message[4..8].copy_from_slice(&xid.to_be_bytes());
+    message[10..12].copy_from_slice(&FLAG_BROADCAST.to_be_bytes());
+    message[28..34].copy_from_slice(mac);
+    message.extend_from_slice(&MAGIC_COOKIE);
+
+    message.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+    if let Some(offer) = offer {
+        message.extend_from_slice(&[OPTION_REQUESTED_IP, 4]);
+        message.extend_from_slice(&offer.address.octets());
+        if let Some(server) = offer.server {
+            message.extend_from_slice(&[OPTION_SERVER_ID, 4]);
+            message.extend_from_slice(&server.octets());
+        }
+    }
+    // Let the server identify the machine, e.g. to register it in the DNS
+    if let Some(hostname) = hostname.filter(|hostname| hostname.len() <= u8::MAX as usize) {
+        message.extend_from_slice(&[OPTION_HOSTNAME, hostname.len() as u8]);
+        message.extend_from_slice(hostname.as_bytes());
+    }
+    message.extend_from_slice(&[
+        OPTION_PARAMETER_LIST,
+        5,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_HOSTNAME,
+        OPTION_DOMAIN_NAME,
+    ]);
+    message.push(OPTION_END);
+
+    message
+}
+
+fn parse_addresses(value: &[u8]) -> Vec<Ipv4Addr> {
+    value
+        .chunks_exact(4)
+        .map(|octets| Ipv4Addr::new(octets[0], octets[1], octets[2], octets[3]))
+        .collect()
+}
+
+/// Parse a reply for our transaction, returning its message type and the lease
+fn parse_reply(reply: &[u8], xid: u32, mac: &[u8; 6]) -> Result<(u8, Lease)> {
+    if reply.len() < HEADER_SIZE + MAGIC_COOKIE.len()
+        || reply[0] != BOOTREPLY
+        || reply[4..8] != xid.to_be_bytes()
+        || &reply[28..34] != mac
+        || reply[HEADER_SIZE..HEADER_SIZE + 4] != MAGIC_COOKIE
+    {
+        bail!("not a reply to our request");
+    }
+
+    let mut lease = Lease::new(Ipv4Addr::new(reply[16], reply[17], reply[18], reply[19]));
+    let mut message_type = None;
+    let mut options = &reply[HEADER_SIZE + MAGIC_COOKIE.len()..];
+    while let Some((&code, rest)) = options.split_first() {
+        if code == OPTION_END {
+            break;
+        }
+        if code == OPTION_PAD {
+            options = rest;
+            continue;
+        }
+        let (&len, rest) = rest.split_first().with_context(|| "truncated option")?;
+        if rest.len() < len as usize {
+            bail!("truncated option {}", code);
+        }
+        let (value, rest) = rest.split_at(len as usize);
+        options = rest;
+
+        match code {
+            OPTION_MESSAGE_TYPE => message_type = value.first().copied(),
+            OPTION_SUBNET_MASK => lease.netmask = parse_addresses(value).first().copied(),
+            OPTION_ROUTER => lease.gateway = parse_addresses(value).first().copied(),
+            OPTION_DNS => lease.dns = parse_addresses(value),
+            OPTION_SERVER_ID => lease.server = parse_addresses(value).first().copied(),
+            OPTION_HOSTNAME => lease.hostname = Some(String::from_utf8_lossy(value).to_string()),
+            OPTION_DOMAIN_NAME => lease.domain = Some(String::from_utf8_lossy(value).to_string()),
+            OPTION_LEASE_TIME if len == 4 => {
+                lease.lease_time = Some(u32::from_be_bytes(value.try_into()?))
+            }
+            _ => {}
+        }
+    }
+
+    Ok((
+        message_type.with_context(|| "missing DHCP message type")?,
+        lease,
+    ))
+}
+
+fn get_mac_address(interface: &str) -> Result<[u8; 6]> {
+    let path = Path::new("/sys/class/net").join(interface).join("address");
+    let address =
+        fs::read_to_string(&path).with_context(|| format!("unable to read {:?}", path))?;
+    let octets = address
+        .trim()
+        .split(':')
+        .map(|octet| u8::from_str_radix(octet, 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .with_context(|| format!("invalid MAC address {}", address.trim()))?;
+
+    octets
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("{} is not an ethernet interface", interface))
+}
+
+struct Client {
+    socket: UdpSocket,
+    mac: [u8; 6],
+    xid: u32,
+    hostname: Option<String>,
+}
+
+impl Client {
+    fn new(interface: &str) -> Result<Client> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_PORT))
+            .with_context(|| "unable to bind the DHCP client port")?;
+        setsockopt(&socket, BindToDevice, &OsString::from(interface))
+            .with_context(|| format!("unable to bind the DHCP socket to {}", interface))?;
+        socket.set_broadcast(true)?;
+
+        Ok(Client {
+            socket,
+            mac: get_mac_address(interface)?,
+            xid: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.subsec_nanos())
+                .unwrap_or_default(),
+            hostname: hostname::get(),
+        })
+    }
+
+    /// Broadcast a message and wait for a reply of the expected type
+    fn exchange(&self, message: &[u8], expected: u8) -> Result<Lease> {
+        self.socket
+            .send_to(message, (Ipv4Addr::BROADCAST, SERVER_PORT))
+            .with_context(|| "unable to send DHCP message")?;
+
+        let start = Instant::now();
+        let mut buf = [0u8; 1500];
+        while start.elapsed() < REPLY_TIMEOUT {
+            self.socket
+                .set_read_timeout(Some(REPLY_TIMEOUT - start.elapsed()))?;
+            let len = match self.socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(err)
+                    if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut =>
+                {
+                    break
+                }
+                Err(err) => return Err(err).with_context(|| "unable to receive DHCP message"),
+            };
+            match parse_reply(&buf[..len], self.xid, &self.mac) {
+                Ok((message_type, lease)) if message_type == expected => return Ok(lease),
+                Ok((DHCPNAK, _)) => bail!("the DHCP server refused our request"),
+                Ok(_) => {}
+                Err(err) => debug!("ignoring DHCP message: {:?}", err),
+            }
+        }
+
+        bail!("timed out waiting for a DHCP reply")
+    }
+
+    fn request_lease(&self) -> Result<Lease> {
+        let offer = self.exchange(
+            &build_message(
+                DHCPDISCOVER,
+                self.xid,
+                &self.mac,
+                None,
+                self.hostname.as_deref(),
+            ),
+            DHCPOFFER,
+        )?;
+        self.exchange(
+            &build_message(
+                DHCPREQUEST,
+                self.xid,
+                &self.mac,
+                Some(&offer),
+                self.hostname.as_deref(),
+            ),
+            DHCPACK,
+        )
+    }
+}
+
+/// Get a lease for the interface, which must be up
+pub fn request_lease(interface: &str) -> Result<Lease> {
+    let client = Client::new(interface)?;
+    let mut attempt = 0;
+    loop {
+        attempt += 1;
+        match client.request_lease() {
+            Ok(lease) => return Ok(lease),
+            Err(err) if attempt < ATTEMPTS => warn!("DHCP on {} failed: {:?}", interface, err),
+            Err(err) => {
+                return Err(err).with_context(|| format!("unable to get a lease on {}", interface))
+            }
+        }
+    }
+}
+
+impl Lease {
+    fn new(address: Ipv4Addr) -> Lease {
+        Lease {
+            address,
+            netmask: None,
+            gateway: None,
+            dns: Vec::new(),
+            domain: None,
+            hostname: None,
+            server: None,
+            lease_time: None,
+        }
+    }
+
+    /// Record the lease in /run, so that the real system can take over the configuration
+    pub fn save(&self, interface: &str) -> Result<()> {
+        let join = |addresses: &[Ipv4Addr]| {
+            addresses
+                .iter()
+                .map(|address| address.to_string())
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        let mut content = format!("IPADDR={}\n", self.address);
+        let mut add = |key: &str, value: Option<String>| {
+            if let Some(value) = value {
+                content.push_str(&format!("{}={}\n", key, value));
+            }
+        };
+        add("NETMASK", self.netmask.map(|netmask| netmask.to_string()));
+        add("GATEWAY", self.gateway.map(|gateway| gateway.to_string()));
+        add("DNS", (!self.dns.is_empty()).then(|| join(&self.dns)));
+        add("DOMAIN", self.domain.clone());
+        add("HOSTNAME", self.hostname.clone());
+        add("SERVER", self.server.map(|server| server.to_string()));
+        add("LEASETIME", self.lease_time.map(|time| time.to_string()));
+
+        fs::create_dir_all(LEASE_DIR).with_context(|| format!("unable to create {}", LEASE_DIR))?;
+        let lease_file = Path::new(LEASE_DIR).join(format!("{}.lease", interface));
+        fs::write(&lease_file, content).with_context(|| format!("unable to write {:?}", lease_file))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAC: [u8; 6] = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+
+    fn build_reply(xid: u32, message_type: u8) -> Vec<u8> {
+        let mut reply = vec![0u8; HEADER_SIZE];
+        reply[0] = BOOTREPLY;
+        reply[4..8].copy_from_slice(&xid.to_be_bytes());
+        reply[16..20].copy_from_slice(&[10, 0, 2, 15]);
+        reply[28..34].copy_from_slice(&MAC);
+        reply.extend_from_slice(&MAGIC_COOKIE);
+        reply.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, message_type]);
+        reply.extend_from_slice(&[OPTION_SUBNET_MASK, 4, 255, 255, 255, 0]);
+        reply.extend_from_slice(&[OPTION_ROUTER, 4, 10, 0, 2, 2]);
+        reply.extend_from_slice(&[OPTION_DNS, 8, 10, 0, 2, 3, 1, 1, 1, 1]);
+        reply.push(OPTION_PAD);
+        reply.extend_from_slice(&[OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+        reply.extend_from_slice(&[OPTION_LEASE_TIME, 4, 0, 0, 0x0e, 0x10]);
+        reply.extend_from_slice(&[OPTION_DOMAIN_NAME, 7]);
+        reply.extend_from_slice(b"example");
+        reply.push(OPTION_END);
+        reply
+    }
+
+    #[test]
+    fn build_message_test() {
+        let mut offer = Lease::new(Ipv4Addr::new(10, 0, 2, 15));
+        offer.server = Some(Ipv4Addr::new(10, 0, 2, 2));
+        let message = build_message(DHCPREQUEST, 0x1234, &MAC, Some(&offer), Some("node1"));
+        assert_eq!(message[0], BOOTREQUEST);
+        assert_eq!(&message[4..8], &[0, 0, 0x12, 0x34]);
+        assert_eq!(&message[28..34], &MAC);
+        let options = &message[HEADER_SIZE + 4..];
+        assert_eq!(&options[..3], &[OPTION_MESSAGE_TYPE, 1, DHCPREQUEST]);
+        assert_eq!(&options[3..9], &[OPTION_REQUESTED_IP, 4, 10, 0, 2, 15]);
+        assert_eq!(&options[9..15], &[OPTION_SERVER_ID, 4, 10, 0, 2, 2]);
+        assert_eq!(&options[15..17], &[OPTION_HOSTNAME, 5]);
+        assert_eq!(&options[17..22], b"node1");
+        assert_eq!(message.last(), Some(&OPTION_END));
+    }
+
+    #[test]
+    fn parse_reply_test() {
+        let (message_type, lease) = parse_reply(&build_reply(42, DHCPACK), 42, &MAC).unwrap();
+        assert_eq!(message_type, DHCPACK);
+        assert_eq!(
+            lease,
+            Lease {
+                address: Ipv4Addr::new(10, 0, 2, 15),
+                netmask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+                gateway: Some(Ipv4Addr::new(10, 0, 2, 2)),
+                dns: vec![Ipv4Addr::new(10, 0, 2, 3), Ipv4Addr::new(1, 1, 1, 1)],
+                domain: Some("example".to_string()),
+                hostname: None,
+                server: Some(Ipv4Addr::new(10, 0, 2, 2)),
+                lease_time: Some(3600),
+            }
+        );
+
+        assert!(parse_reply(&build_reply(42, DHCPACK), 43, &MAC).is_err());
+        assert!(parse_reply(&build_reply(42, DHCPACK)[..100], 42, &MAC).is_err());
+    }
+}
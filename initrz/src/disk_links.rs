@@ -0,0 +1,269 @@
+// Names of the /dev/disk/by-id and /dev/disk/by-path symlinks that udev would create for a
+// block device. There is no udev in the initramfs, so they are computed from sysfs to match
+// the devices given by these paths. Only the common rules of 60-persistent-storage.rules
+// are followed: the ata, nvme, virtio and wwn ids, and the pci paths of the ata, nvme and
+// virtio disks
+
+use std::fs;
+use std::path::Path;
+
+const SYS_BLOCK: &str = "/sys/class/block";
+pub const BY_ID: &str = "/dev/disk/by-id/";
+pub const BY_PATH: &str = "/dev/disk/by-path/";
+// The VPD page 0x80 starts with a 4 bytes header followed by the serial number
+const VPD_PG80_HEADER: usize = 4;
+
+/// The attributes of a disk used to build its by-id names
+#[derive(Default, Debug)]
+struct DiskIds {
+    /// The prefix of the model and serial id, e.g. ata or nvme
+    bus: &'static str,
+    model: Option<String>,
+    serial: Option<String>,
+    /// The world wide identifier, e.g. naa.5000c500a1b2c3d4 or eui.0025388b71b1a2c1
+    wwid: Option<String>,
+}
+
+pub fn is_disk_link(path: &str) -> bool {
+    path.starts_with(BY_ID) || path.starts_with(BY_PATH)
+}
+
+/// Collapse the whitespace into single underscores, like udev does for the ids
+fn encode(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<&str>>().join("_")
+}
+
+fn read_attribute(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// Get the serial number from the raw VPD page 0x80 of a SCSI device
+fn parse_vpd_pg80(page: &[u8]) -> Option<String> {
+    page.get(VPD_PG80_HEADER..)
+        .map(|serial| {
+            String::from_utf8_lossy(serial)
+                .trim_matches(['\0', ' '])
+                .to_string()
+        })
+        .filter(|serial| !serial.is_empty())
+}
+
+fn read_disk_ids(disk: &str) -> Option<DiskIds> {
+    let sys = Path::new(SYS_BLOCK).join(disk);
+    if disk.starts_with("nvme") {
+        Some(DiskIds {
+            bus: "nvme",
+            model: read_attribute(&sys.join("device/model")),
+            serial: read_attribute(&sys.join("device/serial")),
+            wwid: read_attribute(&sys.join("wwid")),
+        })
+    } else if disk.starts_with("vd") {
+        Some(DiskIds {
+            bus: "virtio",
+            serial: read_attribute(&sys.join("serial")),
+            ..DiskIds::default()
+        })
+    } else if disk.starts_with("sd") {
+        // Only the ATA disks behind libata have an ata- id, the others only a wwn- one
+        let is_ata = read_attribute(&sys.join("device/vendor")).as_deref() == Some("ATA");
+        Some(DiskIds {
+            bus: if is_ata { "ata" } else { "" },
+            model: read_attribute(&sys.join("device/model")),
+            serial: fs::read(sys.join("device/vpd_pg80"))
+                .ok()
+                .and_then(|page| parse_vpd_pg80(&page)),
+            wwid: read_attribute(&sys.join("device/wwid")),
+        })
+    } else {
+        None
+    }
+}
+
+/// Get the by-id names of the disk, without the partition suffix
+fn get_ids(ids: &DiskIds) -> Vec<String> {
+    let mut names = Vec::new();
+    match (ids.bus, &ids.model, &ids.serial) {
+        ("virtio", _, Some(serial)) => names.push(format!("virtio-{}", encode(serial))),
+        ("ata" | "nvme", Some(model), Some(serial)) => {
+            names.push(format!("{}-{}_{}", ids.bus, encode(model), encode(serial)))
+        }
+        _ => {}
+    }
+    match ids.wwid.as_deref() {
+        Some(wwid) if ids.bus == "nvme" => names.push(format!("nvme-{}", encode(wwid))),
+        Some(wwid) => {
+            if let Some(naa) = wwid.strip_prefix("naa.") {
+                names.push(format!("wwn-0x{}", naa.to_lowercase()));
+            }
+        }
+        None => {}
+    }
+
+    names
+}
+
+/// Get the by-path names of the disk from its sysfs device path, without the partition
+/// suffix, e.g. /devices/pci0000:00/0000:00:1f.2/ata1/host0/target0:0:0/0:0:0:0/block/sda
+/// is pci-0000:00:1f.2-ata-1.0 and pci-0000:00:1f.2-ata-1
+fn get_paths(devpath: &str, nsid: Option<&str>) -> Vec<String> {
+    let components: Vec<&str> = devpath.split('/').collect();
+    let Some(pci_index) = components
+        .iter()
+        .rposition(|component| is_pci_address(component))
+    else {
+        return Vec::new();
+    };
+    let pci = format!("pci-{}", components[pci_index]);
+    let rest = &components[pci_index + 1..];
+    if rest.first() == Some(&"nvme") {
+        return nsid
+            .map(|nsid| vec![format!("{}-nvme-{}", pci, nsid)])
+            .unwrap_or_default();
+    }
+    if let Some(port) = rest
+        .iter()
+        .find_map(|component| component.strip_prefix("ata"))
+        .filter(|port| port.parse::<u32>().is_ok())
+    {
+        // The old name without the port multiplier is kept for compatibility
+        return vec![
+            format!("{}-ata-{}.0", pci, port),
+            format!("{}-ata-{}", pci, port),
+        ];
+    }
+    if rest
+        .first()
+        .is_some_and(|component| component.starts_with("virtio"))
+    {
+        return vec![pci];
+    }
+
+    Vec::new()
+}
+
+/// Check for an address like 0000:00:1f.2
+fn is_pci_address(component: &str) -> bool {
+    let bytes = component.as_bytes();
+    bytes.len() == 12
+        && bytes[4] == b':'
+        && bytes[7] == b':'
+        && bytes[10] == b'.'
+        && component
+            .chars()
+            .enumerate()
+            .all(|(i, c)| [4, 7, 10].contains(&i) || c.is_ascii_hexdigit())
+}
+
+/// Get the /dev/disk/by-id and /dev/disk/by-path symlinks that udev would create for the
+/// device, e.g. /dev/sda1
+pub fn get_links(devname: &str) -> Vec<String> {
+    let Some(name) = devname
+        .strip_prefix("/dev/")
+        .filter(|name| !name.contains('/'))
+    else {
+        return Vec::new();
+    };
+    let sys = Path::new(SYS_BLOCK).join(name);
+    let partition = read_attribute(&sys.join("partition"));
+    let disk_sys = match partition {
+        Some(_) => match fs::canonicalize(&sys)
+            .ok()
+            .and_then(|sys| sys.parent().map(Path::to_path_buf))
+        {
+            Some(disk_sys) => disk_sys,
+            None => return Vec::new(),
+        },
+        None => match fs::canonicalize(&sys) {
+            Ok(sys) => sys,
+            Err(_) => return Vec::new(),
+        },
+    };
+    let Some(disk) = disk_sys.file_name().and_then(|disk| disk.to_str()) else {
+        return Vec::new();
+    };
+    let suffix = partition
+        .map(|partition| format!("-part{}", partition))
+        .unwrap_or_default();
+    let ids = read_disk_ids(disk)
+        .map(|ids| get_ids(&ids))
+        .unwrap_or_default();
+    let nsid = read_attribute(&disk_sys.join("nsid"));
+    let paths = get_paths(&disk_sys.to_string_lossy(), nsid.as_deref());
+    ids.iter()
+        .map(|id| format!("{}{}{}", BY_ID, id, suffix))
+        .chain(
+            paths
+                .iter()
+                .map(|path| format!("{}{}{}", BY_PATH, path, suffix)),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_ids_test() {
+        let ids = DiskIds {
+            bus: "ata",
+            model: Some("Samsung SSD 860 EVO 500GB".to_string()),
+            serial: parse_vpd_pg80(b"\0\x80\0\x14S3Z1NB0K123456X    "),
+            wwid: Some("naa.5002538E40A1B2C3".to_string()),
+        };
+        assert_eq!(
+            get_ids(&ids),
+            vec![
+                "ata-Samsung_SSD_860_EVO_500GB_S3Z1NB0K123456X",
+                "wwn-0x5002538e40a1b2c3"
+            ]
+        );
+        let ids = DiskIds {
+            bus: "nvme",
+            model: Some("WDC  PC SN730".to_string()),
+            serial: Some("20243A801234".to_string()),
+            wwid: Some("eui.e8238fa6bf530001001b448b45a2c3d1".to_string()),
+        };
+        assert_eq!(
+            get_ids(&ids),
+            vec![
+                "nvme-WDC_PC_SN730_20243A801234",
+                "nvme-eui.e8238fa6bf530001001b448b45a2c3d1"
+            ]
+        );
+        assert!(get_ids(&DiskIds {
+            bus: "virtio",
+            ..DiskIds::default()
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn get_paths_test() {
+        assert_eq!(
+            get_paths(
+                "/sys/devices/pci0000:00/0000:00:1f.2/ata1/host0/target0:0:0/0:0:0:0/block/sda",
+                None
+            ),
+            vec!["pci-0000:00:1f.2-ata-1.0", "pci-0000:00:1f.2-ata-1"]
+        );
+        assert_eq!(
+            get_paths(
+                "/sys/devices/pci0000:00/0000:00:1d.0/0000:3d:00.0/nvme/nvme0/nvme0n1",
+                Some("1")
+            ),
+            vec!["pci-0000:3d:00.0-nvme-1"]
+        );
+        assert_eq!(
+            get_paths(
+                "/sys/devices/pci0000:00/0000:00:04.0/virtio1/block/vda",
+                None
+            ),
+            vec!["pci-0000:00:04.0"]
+        );
+        assert!(get_paths("/sys/devices/virtual/block/loop0", None).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+// Failure handler, run when initrz cannot boot the system
+
+use anyhow::Error;
+use common::cmdline::Cmdline;
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::unistd::sync;
+use tracing::{error, info, warn};
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::process::CommandExt;
+
+use crate::console;
+use crate::probe::{self, Superblock};
+use crate::rescue_shell;
+use crate::state;
+
+const SHELL_PARAM: &str = "rd.shell";
+const EMERGENCY_PARAM: &str = "rd.emergency";
+const RESCUE_PARAM: &str = "rd.rescue";
+const KMSG: &str = "/dev/kmsg";
+
+#[derive(Debug, PartialEq)]
+enum Action {
+    Reboot,
+    Poweroff,
+    Halt,
+}
+
+impl Action {
+    fn from_cmdline(cmdline: &Cmdline) -> Option<Action> {
+        cmdline
+            .get(EMERGENCY_PARAM)
+            .and_then(|action| match action {
+                "reboot" => Some(Action::Reboot),
+                "poweroff" => Some(Action::Poweroff),
+                "halt" => Some(Action::Halt),
+                _ => {
+                    warn!("unknown {}={}, ignoring", EMERGENCY_PARAM, action);
+                    None
+                }
+            })
+    }
+
+    fn run(&self) {
+        let mode = match self {
+            Action::Reboot => RebootMode::RB_AUTOBOOT,
+            Action::Poweroff => RebootMode::RB_POWER_OFF,
+            Action::Halt => RebootMode::RB_HALT_SYSTEM,
+        };
+        sync();
+        let Err(err) = reboot(mode);
+        error!("unable to run {:?}: {}", self, err);
+    }
+}
+
+fn is_shell_allowed(cmdline: &Cmdline) -> bool {
+    cmdline.get_bool(SHELL_PARAM) != Some(false)
+}
+
+/// rd.rescue gives the shell once the devices have been probed, without mounting root
+pub fn is_rescue_requested(cmdline: &Cmdline) -> bool {
+    cmdline.has_flag(RESCUE_PARAM)
+}
+
+fn format_device(path: &str, superblock: &Option<Superblock>) -> String {
+    match superblock {
+        Some(superblock) => format!(
+            "{}: TYPE={} UUID={} LABEL={}",
+            path,
+            superblock.fs_type,
+            superblock.uuid.as_deref().unwrap_or(""),
+            superblock.label.as_deref().unwrap_or("")
+        ),
+        None => format!("{}: no signature", path),
+    }
+}
+
+/// Print the lines on the console and in the kernel log, which survives when the console
+/// is not visible
+fn dump(title: &str, lines: &[String]) {
+    let mut kmsg = OpenOptions::new().write(true).open(KMSG).ok();
+    for line in std::iter::once(&title.to_string()).chain(lines.iter()) {
+        eprintln!("{}", line);
+        if let Some(kmsg) = kmsg.as_mut() {
+            let _ = writeln!(kmsg, "<3>initrz: {}", line);
+        }
+    }
+}
+
+/// Report the failure and then give a shell or run the action requested by rd.emergency=.
+/// Never returns, as PID 1 exiting makes the kernel panic
+pub fn handle(err: &Error) -> ! {
+    error!("{:?}", err);
+    sync();
+
+    dump(
+        "buffered warnings and errors:",
+        &console::get_buffered_log(),
+    );
+    let devices: Vec<String> = probe::probe_all()
+        .iter()
+        .map(|(path, superblock)| format_device(path, superblock))
+        .collect();
+    dump("block devices:", &devices);
+
+    let cmdline = crate::parse_cmdline().unwrap_or_default();
+    let action = Action::from_cmdline(&cmdline);
+    if action.is_none() && is_shell_allowed(&cmdline) {
+        run_shell(&cmdline);
+    }
+
+    finish(action)
+}
+
+/// Give the shell requested by rd.rescue, the boot does not go on from here
+pub fn rescue(cmdline: &Cmdline) -> ! {
+    info!("rd.rescue given, starting the rescue shell");
+    run_shell(cmdline);
+    finish(Action::from_cmdline(cmdline))
+}
+
+/// Only returns when the shell cannot be executed or the built-in one exits
+fn run_shell(cmdline: &Cmdline) {
+    if !rescue_shell::is_selected(cmdline) {
+        // exec keeps PID 1, so that the user can still switch_root from the shell
+        let err = state::shell().exec();
+        error!("unable to execute the emergency shell: {}", err);
+    }
+    rescue_shell::run();
+}
+
+fn finish(action: Option<Action>) -> ! {
+    let action = action.unwrap_or(Action::Halt);
+    action.run();
+    loop {
+        std::thread::park();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn action_test() {
+        assert_eq!(Action::from_cmdline(&Cmdline::parse("quiet")), None);
+        assert_eq!(
+            Action::from_cmdline(&Cmdline::parse("rd.emergency=halt rd.emergency=reboot")),
+            Some(Action::Reboot)
+        );
+        assert_eq!(
+            Action::from_cmdline(&Cmdline::parse("rd.emergency=poweroff")),
+            Some(Action::Poweroff)
+        );
+        assert_eq!(
+            Action::from_cmdline(&Cmdline::parse("rd.emergency=foo")),
+            None
+        );
+    }
+
+    #[test]
+    fn is_rescue_requested_test() {
+        assert!(is_rescue_requested(&Cmdline::parse("quiet rd.rescue")));
+        assert!(!is_rescue_requested(&Cmdline::parse("rd.rescue.foo")));
+    }
+
+    #[test]
+    fn is_shell_allowed_test() {
+        assert!(is_shell_allowed(&Cmdline::parse("")));
+        assert!(is_shell_allowed(&Cmdline::parse("rd.shell=0 rd.shell=1")));
+        assert!(!is_shell_allowed(&Cmdline::parse("rd.shell=no")));
+    }
+}
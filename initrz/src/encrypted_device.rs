@@ -1,38 +1,204 @@
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 
-use anyhow::{Context, Result};
+use anyhow::Result;
+use common::cmdline::Cmdline;
+use common::crypttab::{CrypttabEntry, EncryptionType, UnlockType};
+use tracing::warn;
 
-use crate::encryption_type::EncryptionType;
+use crate::crypt_options::CryptOptions;
 use crate::identifier::Identifier;
-use crate::unlock_type::UnlockType;
+
+const LUKS_UUID_PARAM: &str = "rd.luks.uuid=";
+const LUKS_NAME_PARAM: &str = "rd.luks.name=";
+const LUKS_KEY_PARAM: &str = "rd.luks.key";
 
 pub struct EncryptedDevice {
     pub name: String,
     pub identifier: Identifier,
     pub encryption_type: EncryptionType,
     pub unlock: UnlockType,
+    pub options: CryptOptions,
+    /// Key given with rd.luks.key=, it takes precedence over the crypttab one
+    pub cmdline_key: Option<String>,
 }
 
-impl EncryptedDevice {
-    pub fn from_line(line: String) -> Result<EncryptedDevice> {
-        let mut split = line.split_whitespace();
+impl TryFrom<CrypttabEntry> for EncryptedDevice {
+    type Error = anyhow::Error;
+
+    fn try_from(entry: CrypttabEntry) -> Result<EncryptedDevice> {
         Ok(EncryptedDevice {
-            name: split
-                .next()
-                .with_context(|| format!("unable to find name in line:\n{}", line))?
-                .to_string(),
-            identifier: split
-                .next()
-                .with_context(|| format!("unable to find name in line:\n{}", line))?
-                .into(),
-            encryption_type: split
-                .next()
-                .with_context(|| format!("unable to find name in line:\n{}", line))?
-                .try_into()?,
-            unlock: split
-                .next()
-                .with_context(|| format!("unable to find name in line:\n{}", line))?
-                .into(),
+            name: entry.name,
+            identifier: entry.device.into(),
+            encryption_type: entry.encryption_type,
+            unlock: entry.unlock,
+            options: entry.options.as_slice().try_into()?,
+            cmdline_key: None,
         })
     }
 }
+
+impl EncryptedDevice {
+    /// The keys to try in order of precedence, the passphrase is asked when none of
+    /// them unlocks the device
+    pub fn get_keys(&self) -> Vec<&str> {
+        let mut keys: Vec<&str> = self.cmdline_key.iter().map(String::as_str).collect();
+        if let UnlockType::Key(key) = &self.unlock {
+            keys.push(key);
+        }
+
+        keys
+    }
+
+    fn from_luks_uuid(uuid: &str, name: Option<&str>) -> EncryptedDevice {
+        EncryptedDevice {
+            name: name
+                .map(String::from)
+                .unwrap_or_else(|| format!("luks-{}", uuid)),
+            identifier: Identifier::Uuid(uuid.to_string()),
+            encryption_type: EncryptionType::Luks,
+            unlock: UnlockType::AskPassphrase,
+            options: CryptOptions::default(),
+            cmdline_key: None,
+        }
+    }
+}
+
+// dracut accepts the uuid with or without the "luks-" prefix
+fn strip_luks_prefix(uuid: &str) -> &str {
+    uuid.strip_prefix("luks-").unwrap_or(uuid)
+}
+
+/// Parse the encrypted devices declared with rd.luks.uuid=<uuid> and
+/// rd.luks.name=<uuid>=<name>
+pub fn get_encrypted_devices_from_cmdline(cmdline: &Cmdline) -> Vec<EncryptedDevice> {
+    let mut devices: Vec<EncryptedDevice> = Vec::new();
+    for arg in cmdline.iter() {
+        if let Some(uuid) = arg.strip_prefix(LUKS_UUID_PARAM) {
+            let uuid = strip_luks_prefix(uuid);
+            let identifier = Identifier::Uuid(uuid.to_string());
+            if !devices.iter().any(|device| device.identifier == identifier) {
+                devices.push(EncryptedDevice::from_luks_uuid(uuid, None));
+            }
+        } else if let Some(value) = arg.strip_prefix(LUKS_NAME_PARAM) {
+            let (uuid, name) = match value.split_once('=') {
+                Some((uuid, name)) if !uuid.is_empty() && !name.is_empty() => {
+                    (strip_luks_prefix(uuid), name)
+                }
+                _ => {
+                    warn!("ignoring malformed parameter {}", arg);
+                    continue;
+                }
+            };
+            let identifier = Identifier::Uuid(uuid.to_string());
+            // rd.luks.name overrides the default name given by rd.luks.uuid
+            match devices
+                .iter_mut()
+                .find(|device| device.identifier == identifier)
+            {
+                Some(device) => device.name = name.to_string(),
+                None => devices.push(EncryptedDevice::from_luks_uuid(uuid, Some(name))),
+            }
+        }
+    }
+
+    devices
+}
+
+/// Get the key of the device from rd.luks.key=<uuid>=<keyfile>, falling back to the key
+/// of every device given with rd.luks.key=<keyfile>
+pub fn get_key_from_cmdline(cmdline: &Cmdline, identifier: &Identifier) -> Option<String> {
+    let keys: Vec<&str> = cmdline.get_all(LUKS_KEY_PARAM).rev().collect();
+    keys.iter()
+        .find_map(|key| match key.split_once('=') {
+            Some((uuid, key))
+                if *identifier == Identifier::Uuid(strip_luks_prefix(uuid).to_string()) =>
+            {
+                Some(key.to_string())
+            }
+            _ => None,
+        })
+        .or_else(|| {
+            keys.iter()
+                .find(|key| !key.contains('='))
+                .map(|key| key.to_string())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use common::crypttab::CrypttabFormat;
+
+    fn from_line(line: &str, format: CrypttabFormat) -> Result<EncryptedDevice> {
+        EncryptedDevice::try_from(CrypttabEntry::parse(line, format)?)
+    }
+
+    #[test]
+    fn luks_uuid_test() {
+        let devices = get_encrypted_devices_from_cmdline(&Cmdline::parse(
+            "root=/dev/mapper/root rd.luks.uuid=1234 rd.luks.uuid=luks-5678 rd.luks.uuid=1234",
+        ));
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "luks-1234");
+        assert!(devices[0].identifier == Identifier::Uuid("1234".to_string()));
+        assert_eq!(devices[1].name, "luks-5678");
+        assert!(devices[1].identifier == Identifier::Uuid("5678".to_string()));
+    }
+
+    #[test]
+    fn luks_key_test() {
+        let cmdline =
+            Cmdline::parse("rd.luks.key=/etc/default.key rd.luks.key=luks-1234=/etc/root.key");
+        assert_eq!(
+            get_key_from_cmdline(&cmdline, &Identifier::Uuid("1234".to_string())),
+            Some("/etc/root.key".to_string())
+        );
+        assert_eq!(
+            get_key_from_cmdline(&cmdline, &Identifier::Uuid("5678".to_string())),
+            Some("/etc/default.key".to_string())
+        );
+        assert_eq!(
+            get_key_from_cmdline(&Cmdline::default(), &Identifier::Uuid("1234".to_string())),
+            None
+        );
+
+        let mut device = from_line(
+            "root UUID=1234 luks /etc/crypttab.key",
+            CrypttabFormat::Initramfs,
+        )
+        .unwrap();
+        device.cmdline_key = get_key_from_cmdline(&cmdline, &device.identifier);
+        assert_eq!(
+            device.get_keys(),
+            vec!["/etc/root.key", "/etc/crypttab.key"]
+        );
+    }
+
+    #[test]
+    fn system_crypttab_test() {
+        let device = from_line("var UUID=1234 - discard", CrypttabFormat::System).unwrap();
+        assert_eq!(device.name, "var");
+        assert!(device.identifier == Identifier::Uuid("1234".to_string()));
+        assert!(matches!(device.unlock, UnlockType::AskPassphrase));
+        assert_eq!(device.options.discard, Some(true));
+
+        let device = from_line("home /dev/sda3 /etc/home.key", CrypttabFormat::System).unwrap();
+        assert!(matches!(device.unlock, UnlockType::Key(key) if key == "/etc/home.key"));
+        assert!(from_line("swap", CrypttabFormat::System).is_err());
+    }
+
+    #[test]
+    fn luks_name_test() {
+        let devices = get_encrypted_devices_from_cmdline(&Cmdline::parse(
+            "rd.luks.uuid=1234 rd.luks.name=1234=root rd.luks.name=5678=home rd.luks.name=9abc",
+        ));
+
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "root");
+        assert!(devices[0].identifier == Identifier::Uuid("1234".to_string()));
+        assert_eq!(devices[1].name, "home");
+        assert!(devices[1].identifier == Identifier::Uuid("5678".to_string()));
+    }
+}
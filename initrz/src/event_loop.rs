@@ -0,0 +1,140 @@
+// Wait for uevents, signals and timeouts on a single epoll instance, instead of
+// spinning on a channel fed by a listener thread
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::sys::signal::Signal;
+use nix::sys::signalfd::SignalFd;
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+
+use std::convert::{TryFrom, TryInto};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::time::Duration;
+
+use crate::signal_handler;
+use crate::uevent_listener::UeventListener;
+
+const UEVENT_TOKEN: u64 = 0;
+const SIGNAL_TOKEN: u64 = 1;
+const TIMER_TOKEN: u64 = 2;
+
+pub enum Event {
+    /// A block device is ready to be probed
+    Device(String),
+    Timeout,
+}
+
+pub struct EventLoop {
+    epoll: Epoll,
+    uevent_listener: UeventListener,
+    signal_fd: SignalFd,
+    timer: TimerFd,
+}
+
+impl EventLoop {
+    pub fn new(uevent_listener: UeventListener) -> Result<EventLoop> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)
+            .with_context(|| "unable to create epoll instance")?;
+        let signal_fd = signal_handler::signal_fd()?;
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )
+        .with_context(|| "unable to create timerfd")?;
+
+        // The netlink socket is owned by the listener, which outlives the epoll registration
+        let uevent_fd = unsafe { BorrowedFd::borrow_raw(uevent_listener.as_raw_fd()) };
+        epoll.add(
+            uevent_fd,
+            EpollEvent::new(EpollFlags::EPOLLIN, UEVENT_TOKEN),
+        )?;
+        epoll.add(
+            &signal_fd,
+            EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL_TOKEN),
+        )?;
+        epoll.add(&timer, EpollEvent::new(EpollFlags::EPOLLIN, TIMER_TOKEN))?;
+
+        Ok(EventLoop {
+            epoll,
+            uevent_listener,
+            signal_fd,
+            timer,
+        })
+    }
+
+    /// Make next_within() return Event::Timeout once the timeout has elapsed
+    pub fn set_timeout(&self, timeout: Duration) -> Result<()> {
+        // A zero expiration disarms the timer instead of expiring immediately
+        let timeout = timeout.max(Duration::from_nanos(1));
+        self.timer
+            .set(
+                Expiration::OneShot(TimeSpec::from_duration(timeout)),
+                TimerSetTimeFlags::empty(),
+            )
+            .with_context(|| "unable to arm timerfd")
+    }
+
+    /// Block until a device is ready or the timeout expires, returning None when nothing
+    /// happens within the interval
+    pub fn next_within(&mut self, interval: Duration) -> Result<Option<Event>> {
+        self.wait(interval.as_millis().try_into().unwrap_or(isize::MAX))
+    }
+
+    /// Return the devices that are already queued, without blocking
+    pub fn try_next(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.wait(0)? {
+                Some(Event::Device(path)) => return Ok(Some(path)),
+                // A timeout does not matter when not waiting
+                Some(Event::Timeout) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Stop listening for uevents, logging the ones still queued, and close every fd
+    pub fn shutdown(self) {
+        self.uevent_listener.shutdown();
+    }
+
+    /// Wait for at most timeout milliseconds, or forever when negative. Signals are
+    /// handled here, they do not produce an event
+    fn wait(&mut self, timeout: isize) -> Result<Option<Event>> {
+        let mut events = [EpollEvent::empty(); 3];
+        let ready = match self.epoll.wait(&mut events, timeout) {
+            Ok(ready) => ready,
+            Err(Errno::EINTR) => return Ok(None),
+            Err(err) => return Err(err).with_context(|| "unable to wait for events"),
+        };
+        if ready == 0 {
+            return Ok(None);
+        }
+
+        for event in &events[..ready] {
+            match event.data() {
+                SIGNAL_TOKEN => {
+                    // The signal could have been consumed by the signal listener thread
+                    while let Some(info) = self.signal_fd.read_signal()? {
+                        if let Ok(signal) = Signal::try_from(info.ssi_signo as i32) {
+                            signal_handler::handle(signal);
+                        }
+                    }
+                }
+                TIMER_TOKEN => {
+                    let _ = self.timer.wait();
+                    return Ok(Some(Event::Timeout));
+                }
+                _ => {
+                    // Messages left in the socket are reported again by the next wait
+                    if let Some(path) = self.uevent_listener.receive()? {
+                        return Ok(Some(Event::Device(path)));
+                    }
+                }
+            }
+        }
+
+        Ok(None)
+    }
+}
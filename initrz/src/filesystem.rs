@@ -1,13 +1,17 @@
-use std::{convert::TryFrom, path::PathBuf};
+use std::convert::TryFrom;
 
 use anyhow::{bail, Context, Result};
 
-use crate::utils::get_blkid_cache;
+use crate::probe;
 
 #[derive(PartialEq, Eq)]
 pub enum Filesystem {
     Auto,
     Ext4,
+    Btrfs,
+    Zfs,
+    Squashfs,
+    Nfs,
 }
 
 impl TryFrom<&str> for Filesystem {
@@ -16,6 +20,10 @@ impl TryFrom<&str> for Filesystem {
     fn try_from(filesystem: &str) -> Result<Self, Self::Error> {
         Ok(match filesystem {
             "ext4" => Filesystem::Ext4,
+            "btrfs" => Filesystem::Btrfs,
+            "zfs" => Filesystem::Zfs,
+            "squashfs" => Filesystem::Squashfs,
+            "nfs" => Filesystem::Nfs,
             "auto" => Filesystem::Auto,
             _ => bail!("{} is not a supported filesystem", filesystem),
         })
@@ -26,9 +34,22 @@ impl Filesystem {
     pub fn get_filesystem_string(&self, path: &str) -> Result<String> {
         Ok(match self {
             Filesystem::Ext4 => String::from("ext4"),
-            Filesystem::Auto => get_blkid_cache()
-                .get_tag_value("TYPE", &PathBuf::from(path))
+            Filesystem::Btrfs => String::from("btrfs"),
+            Filesystem::Zfs => String::from("zfs"),
+            Filesystem::Squashfs => String::from("squashfs"),
+            Filesystem::Nfs => String::from("nfs"),
+            Filesystem::Auto => probe::probe_device(path)?
+                .map(|superblock| superblock.fs_type.to_string())
                 .with_context(|| format!("unable to get filesystem type for device {:?}", path))?,
         })
     }
 }
+
+/// Get the kernel module providing the filesystem
+pub fn get_filesystem_module(filesystem: &str) -> &str {
+    match filesystem {
+        "iso9660" => "isofs",
+        "vfat" | "msdos" => "fat",
+        filesystem => filesystem,
+    }
+}
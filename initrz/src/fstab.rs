@@ -0,0 +1,184 @@
+// Mount the fstab entries of the new root marked with x-initrd.mount, e.g. a separate
+// /var or /usr needed before init runs
+
+use anyhow::{Context, Result};
+use nix::mount::{mount, MsFlags};
+use tracing::{info, warn};
+
+use std::fs;
+use std::path::Path;
+
+use crate::device_handler::unlock_system_crypttab_device;
+use crate::device_registry::DeviceRegistry;
+use crate::filesystem::Filesystem;
+use crate::identifier::Identifier;
+use crate::module_loader::ModuleLoader;
+use crate::mounts::load_filesystem_module;
+
+const FSTAB: &str = "/etc/fstab";
+const CRYPTTAB: &str = "/etc/crypttab";
+const INITRD_MOUNT_OPTION: &str = "x-initrd.mount";
+
+#[derive(Debug, PartialEq)]
+pub struct FstabEntry {
+    pub spec: String,
+    pub target: String,
+    pub fs_type: String,
+    pub options: Vec<String>,
+}
+
+pub fn parse_fstab(fstab: &str) -> Vec<FstabEntry> {
+    fstab
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut split = line.split_whitespace();
+            Some(FstabEntry {
+                spec: split.next()?.to_string(),
+                target: split.next()?.to_string(),
+                fs_type: split.next().unwrap_or("auto").to_string(),
+                options: split
+                    .next()
+                    .unwrap_or("defaults")
+                    .split(',')
+                    .map(String::from)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/// Split the options into mount flags and the data given to the filesystem. The options
+/// only meaningful to mount(8) and the other userspace tools are dropped
+pub fn parse_mount_options(options: &[String]) -> (MsFlags, String) {
+    let mut flags = MsFlags::empty();
+    let mut data = Vec::new();
+    for option in options {
+        match option.as_str() {
+            "ro" => flags |= MsFlags::MS_RDONLY,
+            "nosuid" => flags |= MsFlags::MS_NOSUID,
+            "nodev" => flags |= MsFlags::MS_NODEV,
+            "noexec" => flags |= MsFlags::MS_NOEXEC,
+            "noatime" => flags |= MsFlags::MS_NOATIME,
+            "nodiratime" => flags |= MsFlags::MS_NODIRATIME,
+            "relatime" => flags |= MsFlags::MS_RELATIME,
+            "strictatime" => flags |= MsFlags::MS_STRICTATIME,
+            "sync" => flags |= MsFlags::MS_SYNCHRONOUS,
+            "defaults" | "rw" | "suid" | "dev" | "exec" | "async" | "auto" | "noauto"
+            | "nofail" | "user" | "nouser" | "users" | "_netdev" => {}
+            option if option.starts_with("x-") || option.starts_with("comment=") => {}
+            option => data.push(option),
+        }
+    }
+
+    (flags, data.join(","))
+}
+
+fn mount_entry(
+    entry: &FstabEntry,
+    module_loader: &ModuleLoader,
+    device_registry: &DeviceRegistry,
+) -> Result<()> {
+    let identifier = Identifier::from(entry.spec.as_str());
+    if let Identifier::Path(path) = &identifier {
+        if let Some(name) = path.strip_prefix("/dev/mapper/") {
+            if !Path::new(path).exists() && Path::new(CRYPTTAB).exists() {
+                unlock_system_crypttab_device(CRYPTTAB, name, module_loader, device_registry)?;
+            }
+        }
+    }
+    let devname = match &identifier {
+        // Virtual filesystems, e.g. tmpfs
+        Identifier::Path(source) if !source.starts_with('/') => source.clone(),
+        identifier => identifier.get_path(device_registry)?,
+    };
+    let fs_type = match entry.fs_type.as_str() {
+        "auto" => Filesystem::Auto.get_filesystem_string(&devname)?,
+        fs_type => fs_type.to_string(),
+    };
+    load_filesystem_module(&fs_type, module_loader)?;
+    let (flags, data) = parse_mount_options(&entry.options);
+    fs::create_dir_all(&entry.target)
+        .with_context(|| format!("unable to create {}", entry.target))?;
+    mount(
+        Some(devname.as_str()),
+        entry.target.as_str(),
+        Some(fs_type.as_str()),
+        flags,
+        (!data.is_empty()).then_some(data.as_str()),
+    )
+    .with_context(|| format!("unable to mount {} on {}", devname, entry.target))
+}
+
+/// Mount the x-initrd.mount entries of the new root's fstab, it must be called after
+/// chrooting into the new root. Entries with nofail do not stop the boot
+pub fn mount_initrd_entries(
+    module_loader: &ModuleLoader,
+    device_registry: &DeviceRegistry,
+) -> Result<()> {
+    let fstab = match fs::read_to_string(FSTAB) {
+        Ok(fstab) => fstab,
+        Err(_) => return Ok(()),
+    };
+    for entry in parse_fstab(&fstab)
+        .iter()
+        // The root has already been mounted
+        .filter(|entry| entry.target != "/")
+        .filter(|entry| entry.options.iter().any(|o| o == INITRD_MOUNT_OPTION))
+    {
+        info!("mounting {} on {}", entry.spec, entry.target);
+        if let Err(err) = mount_entry(entry, module_loader, device_registry) {
+            if !entry.options.iter().any(|o| o == "nofail") {
+                return Err(err);
+            }
+            warn!("{:?}", err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fstab_test() {
+        let entries = parse_fstab(
+            "# /etc/fstab
+UUID=1234 / ext4 defaults 0 1
+
+/dev/mapper/var /var xfs noatime,x-initrd.mount 0 2
+tmpfs /tmp tmpfs
+",
+        );
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[1],
+            FstabEntry {
+                spec: "/dev/mapper/var".to_string(),
+                target: "/var".to_string(),
+                fs_type: "xfs".to_string(),
+                options: vec!["noatime".to_string(), "x-initrd.mount".to_string()],
+            }
+        );
+        assert_eq!(entries[2].options, vec!["defaults"]);
+    }
+
+    #[test]
+    fn parse_mount_options_test() {
+        let options: Vec<String> =
+            "defaults,ro,noatime,x-initrd.mount,nofail,compress=zstd,subvol=@var"
+                .split(',')
+                .map(String::from)
+                .collect();
+        assert_eq!(
+            parse_mount_options(&options),
+            (
+                MsFlags::MS_RDONLY | MsFlags::MS_NOATIME,
+                "compress=zstd,subvol=@var".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,106 @@
+// User hooks run at the boot stages, the executables in /etc/initrz/hooks/<stage>.d are
+// run in lexical order with this environment:
+//   INITRZ_STAGE  the stage being run, e.g. pre-mount
+//   NEWROOT       where the new root is mounted, only populated from pre-pivot on
+// The kernel parameters are in /proc/cmdline. A failing hook does not stop the boot
+
+use anyhow::{Context, Result};
+use tracing::{info, warn};
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::signal_handler::GuardedCommand;
+use crate::switch_root::NEW_ROOT;
+
+const HOOKS_DIR: &str = "/etc/initrz/hooks";
+
+/// The stages the hooks run before
+#[derive(Clone, Copy, Debug)]
+pub enum Stage {
+    /// Before loading the drivers of the devices found in /sys
+    Trigger,
+    /// After finding the root device, before mounting it
+    Mount,
+    /// After mounting the root in /new_root, before moving into it
+    Pivot,
+}
+
+impl Stage {
+    fn get_name(&self) -> &'static str {
+        match self {
+            Stage::Trigger => "pre-trigger",
+            Stage::Mount => "pre-mount",
+            Stage::Pivot => "pre-pivot",
+        }
+    }
+}
+
+/// Return the executable files in dir sorted by name
+fn get_hooks(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut hooks: Vec<PathBuf> = fs::read_dir(dir)
+        .with_context(|| format!("unable to read directory {:?}", dir))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            fs::metadata(path)
+                .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+                .unwrap_or(false)
+        })
+        .collect();
+    hooks.sort();
+
+    Ok(hooks)
+}
+
+pub fn run(stage: Stage) {
+    let dir = Path::new(HOOKS_DIR).join(format!("{}.d", stage.get_name()));
+    if !dir.exists() {
+        return;
+    }
+    let hooks = match get_hooks(&dir) {
+        Ok(hooks) => hooks,
+        Err(err) => {
+            warn!("{:?}", err);
+            return;
+        }
+    };
+    for hook in hooks {
+        info!("running {} hook {:?}", stage.get_name(), hook);
+        match Command::new(&hook)
+            .env("INITRZ_STAGE", stage.get_name())
+            .env("NEWROOT", NEW_ROOT)
+            .guarded_status()
+        {
+            Ok(status) if !status.success() => {
+                warn!("hook {:?} exited with {}", hook, status)
+            }
+            Ok(_) => {}
+            Err(err) => warn!("unable to execute hook {:?}: {}", hook, err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_hooks_test() {
+        let dir = std::env::temp_dir().join("initrz-hooks-test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("subdir")).unwrap();
+        for (name, mode) in [("20-b", 0o755), ("10-a", 0o700), ("README", 0o644)] {
+            let path = dir.join(name);
+            fs::write(&path, "#!/bin/sh\n").unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+        }
+        assert_eq!(
+            get_hooks(&dir).unwrap(),
+            vec![dir.join("10-a"), dir.join("20-b")]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use nix::unistd::{gethostname, sethostname};
+
+use std::fs;
+
+const HOSTNAME_PARAM: &str = "hostname";
+// Embedded by mkinitrz in host-only initramfs
+const HOSTNAME_FILE: &str = "/etc/hostname";
+// Names reported by the kernel when no hostname has been set
+const UNSET_HOSTNAMES: [&str; 2] = ["(none)", "localhost"];
+
+fn parse_hostname_file(content: &str) -> Option<String> {
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+}
+
+/// Get the hostname given by hostname=, falling back to /etc/hostname
+fn get_hostname_from_cmdline(cmdline: &Cmdline) -> Option<String> {
+    cmdline
+        .get(HOSTNAME_PARAM)
+        .filter(|hostname| !hostname.is_empty())
+        .map(String::from)
+        .or_else(|| {
+            fs::read_to_string(HOSTNAME_FILE)
+                .ok()
+                .and_then(|content| parse_hostname_file(&content))
+        })
+}
+
+pub fn set(hostname: &str) -> Result<()> {
+    sethostname(hostname).with_context(|| format!("unable to set hostname to {}", hostname))
+}
+
+/// Get the current hostname, if one has been set
+pub fn get() -> Option<String> {
+    gethostname()
+        .ok()
+        .and_then(|hostname| hostname.into_string().ok())
+        .filter(|hostname| !hostname.is_empty() && !UNSET_HOSTNAMES.contains(&hostname.as_str()))
+}
+
+pub fn setup_from_cmdline(cmdline: &Cmdline) -> Result<()> {
+    if let Some(hostname) = get_hostname_from_cmdline(cmdline) {
+        set(&hostname)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hostname_test() {
+        assert_eq!(
+            parse_hostname_file("# comment\n\nserver01\n"),
+            Some("server01".to_string())
+        );
+        assert_eq!(parse_hostname_file(""), None);
+        assert_eq!(
+            get_hostname_from_cmdline(&Cmdline::parse("hostname=node1")),
+            Some("node1".to_string())
+        );
+    }
+}
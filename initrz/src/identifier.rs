@@ -1,47 +1,92 @@
 use std::{fmt, path::Path};
 
-use anyhow::{bail, Result};
-use either::Right;
+use anyhow::{bail, Context, Result};
+use common::crypttab::DeviceSpec;
 
-use crate::utils::get_blkid_cache;
+use crate::device_registry::DeviceRegistry;
+use crate::disk_links;
+use crate::probe::{self, Superblock};
 
-const UUID_TAG: &str = "UUID";
-
-#[derive(PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub enum Identifier {
     Path(String),
     Uuid(String),
+    Label(String),
 }
 
 impl From<&str> for Identifier {
     fn from(identifier: &str) -> Identifier {
         if let Some(stripped) = identifier.strip_prefix("UUID=") {
             Identifier::Uuid(stripped.to_string())
+        } else if let Some(stripped) = identifier.strip_prefix("LABEL=") {
+            Identifier::Label(stripped.to_string())
         } else {
             Identifier::Path(identifier.to_string())
         }
     }
 }
 
+impl From<DeviceSpec> for Identifier {
+    fn from(device: DeviceSpec) -> Identifier {
+        match device {
+            DeviceSpec::Path(path) => Identifier::Path(path),
+            DeviceSpec::Uuid(uuid) => Identifier::Uuid(uuid),
+            DeviceSpec::Label(label) => Identifier::Label(label),
+        }
+    }
+}
+
 impl fmt::Display for Identifier {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self {
             Identifier::Path(path) => write!(f, "{:?}", path),
             Identifier::Uuid(uuid) => write!(f, "{}", uuid),
+            Identifier::Label(label) => write!(f, "LABEL={}", label),
         }
     }
 }
 
 impl Identifier {
-    pub fn get_path(&self) -> Result<String> {
+    pub fn get_path(&self, device_registry: &DeviceRegistry) -> Result<String> {
         Ok(match self {
-            Identifier::Uuid(uuid) => get_blkid_cache().get_devname(Right((&UUID_TAG, &uuid)))?,
-            Identifier::Path(path) => {
-                if Path::new(path).exists() {
-                    bail!("unable to find device in path {:?}", path);
-                }
-                path.clone()
-            }
+            Identifier::Uuid(_) | Identifier::Label(_) => device_registry
+                .scan()
+                .into_iter()
+                .find(|(path, superblock)| self.matches(path, superblock.as_ref()))
+                .map(|(path, _)| path)
+                .with_context(|| format!("unable to find device {}", self))?,
+            Identifier::Path(path) if Path::new(path).exists() => path.clone(),
+            // There is no udev creating the symlinks, find the device they would point to
+            Identifier::Path(path) if disk_links::is_disk_link(path) => probe::get_block_devices()
+                .into_iter()
+                .find(|devname| self.is_path(devname))
+                .with_context(|| format!("unable to find device in path {:?}", path))?,
+            Identifier::Path(path) => bail!("unable to find device in path {:?}", path),
         })
     }
+
+    /// Check if the device is the one given by path, directly or by one of its
+    /// /dev/disk/by-id and /dev/disk/by-path symlinks
+    pub fn is_path(&self, path: &str) -> bool {
+        match self {
+            Identifier::Path(saved_path) if saved_path == path => true,
+            Identifier::Path(saved_path) if disk_links::is_disk_link(saved_path) => {
+                disk_links::get_links(path).contains(saved_path)
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if the device, probed with the given superblock, is the one identified
+    pub fn matches(&self, path: &str, superblock: Option<&Superblock>) -> bool {
+        match self {
+            Identifier::Path(_) => self.is_path(path),
+            Identifier::Uuid(uuid) => {
+                superblock.and_then(|superblock| superblock.uuid.as_ref()) == Some(uuid)
+            }
+            Identifier::Label(label) => {
+                superblock.and_then(|superblock| superblock.label.as_ref()) == Some(label)
+            }
+        }
+    }
 }
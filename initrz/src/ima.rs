@@ -0,0 +1,58 @@
+// Load the IMA policy before executing init, so that its appraisal covers init itself
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::mounts::mount_securityfs;
+
+const IMA_POLICY_FILE: &str = "/sys/kernel/security/ima/policy";
+// Policy of the new root, the same file loaded by systemd
+const ROOT_IMA_POLICY: &str = "/etc/ima/ima-policy";
+// Embedded by mkinitrz when ima_policy is set in its configuration
+const EMBEDDED_IMA_POLICY: &str = "/etc/initrz/ima-policy";
+
+/// Read the embedded policy, it must be done before the initramfs contents are deleted
+pub fn read_embedded_policy() -> Option<Vec<u8>> {
+    fs::read(EMBEDDED_IMA_POLICY).ok()
+}
+
+fn write_policy(policy: &[u8]) -> Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open(IMA_POLICY_FILE)
+        .and_then(|mut file| file.write_all(policy))
+        .with_context(|| format!("unable to write {}", IMA_POLICY_FILE))
+}
+
+/// Must be called after chrooting into the new root. Its policy takes precedence over
+/// the embedded one
+pub fn load_policy(embedded_policy: Option<Vec<u8>>) -> Result<()> {
+    let root_policy = Path::new(ROOT_IMA_POLICY).exists();
+    if !root_policy && embedded_policy.is_none() {
+        return Ok(());
+    }
+    mount_securityfs()?;
+    if !Path::new(IMA_POLICY_FILE).exists() {
+        info!("IMA is not enabled, skipping its policy");
+        return Ok(());
+    }
+
+    if root_policy {
+        // Writing the path lets the kernel appraise a signed policy
+        write_policy(ROOT_IMA_POLICY.as_bytes()).or_else(|_| {
+            write_policy(
+                &fs::read(ROOT_IMA_POLICY)
+                    .with_context(|| format!("unable to read {}", ROOT_IMA_POLICY))?,
+            )
+        })?;
+    } else if let Some(policy) = embedded_policy {
+        write_policy(&policy)?;
+    }
+    info!("loaded the IMA policy");
+
+    Ok(())
+}
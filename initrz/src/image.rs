@@ -0,0 +1,73 @@
+// Immutable image roots: a squashfs verified by dm-verity, mounted read-only under a
+// writable overlay
+
+use anyhow::{bail, Result};
+use common::cmdline::Cmdline;
+
+use crate::identifier::Identifier;
+use crate::overlay::Upper;
+use crate::verity::ROOTHASH_PARAM;
+
+const IMAGE_PARAM: &str = "rd.image";
+const IMAGE_HASH_PARAM: &str = "rd.image.hash";
+const IMAGE_UPPER_PARAM: &str = "rd.image.upper";
+
+/// rd.image=<data> rd.image.hash=<hash> [rd.image.upper=tmpfs|<device>]
+#[derive(PartialEq, Eq, Debug)]
+pub struct Image {
+    /// Device containing the squashfs
+    pub data: Identifier,
+    /// Device containing the verity hash tree of the data device
+    pub hash: Option<Identifier>,
+    pub upper: Upper,
+}
+
+pub fn get_image_from_cmdline(cmdline: &Cmdline) -> Result<Option<Image>> {
+    let data = match cmdline.get(IMAGE_PARAM) {
+        Some(data) => data,
+        None => return Ok(None),
+    };
+    let hash = cmdline.get(IMAGE_HASH_PARAM);
+    if hash.is_none() && cmdline.get(ROOTHASH_PARAM).is_some() {
+        bail!(
+            "{} is required to verify {}={}",
+            IMAGE_HASH_PARAM,
+            IMAGE_PARAM,
+            data
+        );
+    }
+
+    Ok(Some(Image {
+        data: data.into(),
+        hash: hash.map(Identifier::from),
+        upper: match cmdline.get(IMAGE_UPPER_PARAM) {
+            None | Some("tmpfs") => Upper::Tmpfs,
+            Some(device) => Upper::Device(device.into()),
+        },
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_cmdline_test() {
+        assert_eq!(
+            get_image_from_cmdline(&Cmdline::parse("root=/dev/sda1")).unwrap(),
+            None
+        );
+        assert_eq!(
+            get_image_from_cmdline(&Cmdline::parse("rd.image=/dev/sda2 rd.image.hash=/dev/sda3 roothash=abcd rd.image.upper=LABEL=data"))
+            .unwrap(),
+            Some(Image {
+                data: Identifier::Path("/dev/sda2".to_string()),
+                hash: Some(Identifier::Path("/dev/sda3".to_string())),
+                upper: Upper::Device(Identifier::Label("data".to_string())),
+            })
+        );
+        assert!(
+            get_image_from_cmdline(&Cmdline::parse("rd.image=/dev/sda2 roothash=abcd")).is_err()
+        );
+    }
+}
@@ -0,0 +1,144 @@
+// Runtime configuration embedded by mkinitrz in /etc/initrz.conf. Every setting is turned
+// into the matching kernel parameter and put before the real ones, so that the kernel
+// cmdline still overrides the defaults of the image
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use serde::Deserialize;
+
+use std::fs;
+use std::path::Path;
+
+const INITRZ_CONF: &str = "/etc/initrz.conf";
+
+/// Timeouts in seconds, same as rd.timeout.<stage>=
+#[derive(Default, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TimeoutsConf {
+    root: Option<u64>,
+    settle: Option<u64>,
+    network: Option<u64>,
+    passphrase: Option<u64>,
+}
+
+#[derive(Default, Debug, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InitrzConf {
+    #[serde(default)]
+    timeouts: TimeoutsConf,
+    /// Same as rd.loglevel=
+    log_level: Option<String>,
+    /// Same as rd.emergency=
+    emergency: Option<String>,
+    /// Same as rd.shell=
+    shell: Option<String>,
+    /// Same as console=, the last one is the preferred console
+    #[serde(default)]
+    consoles: Vec<String>,
+}
+
+impl InitrzConf {
+    fn parse(conf: &str) -> Result<InitrzConf> {
+        // An empty file is valid and sets nothing
+        if conf.trim().is_empty() {
+            return Ok(InitrzConf::default());
+        }
+        Ok(serde_yaml::from_str(conf)?)
+    }
+
+    /// Read the configuration if the image has one
+    pub fn load() -> Result<Option<InitrzConf>> {
+        if !Path::new(INITRZ_CONF).exists() {
+            return Ok(None);
+        }
+        let conf = fs::read_to_string(INITRZ_CONF)
+            .with_context(|| format!("unable to read {}", INITRZ_CONF))?;
+        InitrzConf::parse(&conf)
+            .map(Some)
+            .with_context(|| format!("invalid configuration in {}", INITRZ_CONF))
+    }
+
+    /// Get the kernel parameters equivalent to the configuration
+    pub fn to_cmdline(&self) -> Vec<String> {
+        let timeouts = [
+            ("root", self.timeouts.root),
+            ("settle", self.timeouts.settle),
+            ("network", self.timeouts.network),
+            ("passphrase", self.timeouts.passphrase),
+        ];
+        timeouts
+            .iter()
+            .filter_map(|(stage, timeout)| {
+                timeout.map(|timeout| format!("rd.timeout.{}={}", stage, timeout))
+            })
+            .chain(
+                self.log_level
+                    .iter()
+                    .map(|level| format!("rd.loglevel={}", level)),
+            )
+            .chain(
+                self.emergency
+                    .iter()
+                    .map(|action| format!("rd.emergency={}", action)),
+            )
+            .chain(self.shell.iter().map(|shell| format!("rd.shell={}", shell)))
+            .chain(
+                self.consoles
+                    .iter()
+                    .map(|console| format!("console={}", console)),
+            )
+            .collect()
+    }
+}
+
+/// Put the parameters of the configuration before the ones of the kernel cmdline
+pub fn merge(conf: &InitrzConf, cmdline: Cmdline) -> Cmdline {
+    conf.to_cmdline()
+        .into_iter()
+        .chain(cmdline.into_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(InitrzConf::parse("").unwrap(), InitrzConf::default());
+        assert!(InitrzConf::parse("timeout: 10").is_err());
+
+        let conf = InitrzConf::parse(
+            "timeouts:
+  root: 60
+  passphrase: 0
+log_level: warn
+emergency: reboot
+consoles:
+  - tty0
+  - ttyS0,115200n8
+",
+        )
+        .unwrap();
+        assert_eq!(
+            conf.to_cmdline(),
+            vec![
+                "rd.timeout.root=60",
+                "rd.timeout.passphrase=0",
+                "rd.loglevel=warn",
+                "rd.emergency=reboot",
+                "console=tty0",
+                "console=ttyS0,115200n8"
+            ]
+        );
+    }
+
+    #[test]
+    fn merge_test() {
+        let conf = InitrzConf::parse("emergency: reboot\nshell: rust").unwrap();
+        assert_eq!(
+            *merge(&conf, Cmdline::parse("rd.emergency=halt")),
+            ["rd.emergency=reboot", "rd.shell=rust", "rd.emergency=halt"]
+        );
+    }
+}
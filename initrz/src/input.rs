@@ -0,0 +1,99 @@
+// Wait for a keyboard before the first passphrase prompt, otherwise a USB keyboard
+// could still be probing when the user starts typing
+
+use tracing::{info, warn};
+
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::module_loader::ModuleLoader;
+
+const INPUT_DEVICES: &str = "/proc/bus/input/devices";
+// Host controllers first, so that usbhid finds the devices connected to them
+const KEYBOARD_MODULES: [&str; 8] = [
+    "i8042",
+    "atkbd",
+    "xhci-pci",
+    "ehci-pci",
+    "ohci-pci",
+    "uhci-hcd",
+    "usbhid",
+    "hid-generic",
+];
+const KEYBOARD_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+// Keyboards repeat keys, unlike power buttons and lid switches that also have a kbd handler
+const EV_REP: u32 = 0x14;
+
+static WAITED: AtomicBool = AtomicBool::new(false);
+
+/// Return true if any of the devices listed in /proc/bus/input/devices is a keyboard
+fn has_keyboard(devices: &str) -> bool {
+    devices.split("\n\n").any(|device| {
+        let handlers = device
+            .lines()
+            .find_map(|line| line.strip_prefix("H: Handlers="))
+            .unwrap_or("");
+        let events = device
+            .lines()
+            .find_map(|line| line.strip_prefix("B: EV="))
+            .and_then(|ev| u32::from_str_radix(ev.trim(), 16).ok())
+            .unwrap_or(0);
+        handlers.split_whitespace().any(|handler| handler == "kbd") && events & (1 << EV_REP) != 0
+    })
+}
+
+fn is_keyboard_available() -> bool {
+    fs::read_to_string(INPUT_DEVICES)
+        .map(|devices| has_keyboard(&devices))
+        .unwrap_or(false)
+}
+
+/// Load the keyboard drivers and wait for a keyboard to appear. Only the first call waits,
+/// a keyboard missing by then is not going to appear for the next prompts
+pub fn wait_for_keyboard(module_loader: &ModuleLoader) {
+    if WAITED.swap(true, Ordering::SeqCst) || is_keyboard_available() {
+        return;
+    }
+
+    info!("waiting for a keyboard");
+    for module in KEYBOARD_MODULES {
+        // Do not fail here because the module could be builtin
+        if let Err(err) = module_loader.load_module(module) {
+            warn!("unable to load module {}: {:?}", module, err);
+        }
+    }
+    let start = Instant::now();
+    while !is_keyboard_available() {
+        if start.elapsed() >= KEYBOARD_TIMEOUT {
+            warn!("no keyboard found, prompting anyway");
+            return;
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICES: &str = "I: Bus=0019 Vendor=0000 Product=0001 Version=0000
+N: Name=\"Power Button\"
+H: Handlers=kbd event0
+B: EV=3
+
+I: Bus=0003 Vendor=046d Product=c31c Version=0110
+N: Name=\"Logitech USB Keyboard\"
+H: Handlers=sysrq kbd leds event1
+B: EV=120013
+";
+
+    #[test]
+    fn has_keyboard_test() {
+        assert!(has_keyboard(DEVICES));
+        assert!(!has_keyboard(DEVICES.split("\n\n").next().unwrap()));
+        assert!(!has_keyboard(""));
+    }
+}
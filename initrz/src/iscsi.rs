@@ -0,0 +1,278 @@
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use tracing::info;
+
+use std::fs;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::signal_handler::GuardedCommand;
+
+// Installed by mkinitrz when iscsi is enabled in its configuration
+const ISCSISTART: &str = "/usr/bin/iscsistart";
+const INITIATOR_NAME_FILE: &str = "/etc/iscsi/initiatorname.iscsi";
+const ISCSI_PARAM: &str = "rd.iscsi.";
+const NETROOT_PREFIX: &str = "iscsi:";
+const DEFAULT_PORT: u16 = 3260;
+const DEFAULT_GROUP: &str = "1";
+const SESSION_CLASS: &str = "/sys/class/iscsi_session";
+pub const ISCSI_MODULE: &str = "iscsi_tcp";
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct IscsiTarget {
+    pub name: String,
+    pub address: String,
+    pub port: u16,
+    pub group: String,
+    pub lun: Option<u32>,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub in_username: Option<String>,
+    pub in_password: Option<String>,
+}
+
+/// How to find the targets to log in to
+#[derive(PartialEq, Eq, Debug)]
+pub enum IscsiConfig {
+    /// Targets described by the iSCSI Boot Firmware Table
+    Firmware,
+    Targets(Vec<IscsiTarget>),
+}
+
+fn get_iscsi_param<'a>(cmdline: &'a Cmdline, name: &str) -> Option<&'a str> {
+    cmdline.get(&format!("{}{}", ISCSI_PARAM, name))
+}
+
+/// Parse netroot=iscsi:[<server>]:[<protocol>]:[<port>]:[<lun>]:<targetname>
+fn parse_netroot(netroot: &str) -> Result<IscsiTarget> {
+    let mut fields = netroot.splitn(5, ':');
+    let mut next = || fields.next().unwrap_or_default();
+    let (address, _protocol, port, lun, name) = (next(), next(), next(), next(), next());
+    if address.is_empty() || name.is_empty() {
+        bail!("{} is not a valid iSCSI netroot", netroot);
+    }
+
+    Ok(IscsiTarget {
+        name: name.to_string(),
+        address: address.to_string(),
+        port: match port {
+            "" => DEFAULT_PORT,
+            port => port
+                .parse()
+                .with_context(|| format!("invalid iSCSI port {}", port))?,
+        },
+        group: DEFAULT_GROUP.to_string(),
+        lun: match lun {
+            "" => None,
+            lun => Some(
+                lun.parse()
+                    .with_context(|| format!("invalid iSCSI LUN {}", lun))?,
+            ),
+        },
+        ..Default::default()
+    })
+}
+
+pub fn get_iscsi_config_from_cmdline(cmdline: &Cmdline) -> Result<Option<IscsiConfig>> {
+    if cmdline.get_bool("rd.iscsi.firmware") == Some(true) {
+        return Ok(Some(IscsiConfig::Firmware));
+    }
+
+    let mut targets = cmdline
+        .get_all("netroot")
+        .filter_map(|netroot| netroot.strip_prefix(NETROOT_PREFIX))
+        .map(parse_netroot)
+        .collect::<Result<Vec<IscsiTarget>>>()?;
+    if let Some(name) = get_iscsi_param(cmdline, "target.name") {
+        targets.push(IscsiTarget {
+            name: name.to_string(),
+            address: get_iscsi_param(cmdline, "target.ip")
+                .with_context(|| "rd.iscsi.target.ip is required by rd.iscsi.target.name")?
+                .to_string(),
+            port: get_iscsi_param(cmdline, "target.port")
+                .map(|port| {
+                    port.parse()
+                        .with_context(|| format!("invalid iSCSI port {}", port))
+                })
+                .transpose()?
+                .unwrap_or(DEFAULT_PORT),
+            group: get_iscsi_param(cmdline, "target.group")
+                .unwrap_or(DEFAULT_GROUP)
+                .to_string(),
+            ..Default::default()
+        });
+    }
+    if targets.is_empty() {
+        return Ok(None);
+    }
+
+    // The credentials are shared by all the targets
+    for target in &mut targets {
+        target.username = get_iscsi_param(cmdline, "username").map(String::from);
+        target.password = get_iscsi_param(cmdline, "password").map(String::from);
+        target.in_username = get_iscsi_param(cmdline, "in.username").map(String::from);
+        target.in_password = get_iscsi_param(cmdline, "in.password").map(String::from);
+    }
+
+    Ok(Some(IscsiConfig::Targets(targets)))
+}
+
+/// Use rd.iscsi.initiator= or the initiator name of the host
+fn get_initiator_name(cmdline: &Cmdline) -> Result<String> {
+    if let Some(initiator) = get_iscsi_param(cmdline, "initiator") {
+        return Ok(initiator.to_string());
+    }
+
+    fs::read_to_string(INITIATOR_NAME_FILE)
+        .with_context(|| format!("unable to read {}", INITIATOR_NAME_FILE))?
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("InitiatorName="))
+        .map(String::from)
+        .with_context(|| format!("no InitiatorName found in {}", INITIATOR_NAME_FILE))
+}
+
+fn run_iscsistart(args: &[&str]) -> Result<()> {
+    let output = Command::new(ISCSISTART)
+        .args(args)
+        .guarded_output()
+        .with_context(|| "unable to run iscsistart command")?;
+    if !output.status.success() {
+        bail!(
+            "iscsistart command failed:\n{:?}",
+            String::from_utf8(output.stderr)
+        )
+    }
+
+    Ok(())
+}
+
+/// Return true once every iSCSI session has exposed at least a disk
+fn are_disks_available() -> bool {
+    let sessions = match fs::read_dir(SESSION_CLASS) {
+        Ok(sessions) => sessions.filter_map(|entry| entry.ok()).collect::<Vec<_>>(),
+        Err(_) => return false,
+    };
+    !sessions.is_empty()
+        && sessions.iter().all(|session| {
+            glob::glob(&format!(
+                "{}/device/target*/*/block/*",
+                session.path().to_string_lossy()
+            ))
+            .map(|mut disks| disks.next().is_some())
+            .unwrap_or(false)
+        })
+}
+
+impl IscsiTarget {
+    fn login(&self, initiator: &str) -> Result<()> {
+        info!("logging in to iSCSI target {}", self.name);
+        let port = self.port.to_string();
+        let mut args = vec![
+            "-i",
+            initiator,
+            "-t",
+            &self.name,
+            "-g",
+            &self.group,
+            "-a",
+            &self.address,
+            "-p",
+            &port,
+        ];
+        for (flag, value) in [
+            ("-u", &self.username),
+            ("-w", &self.password),
+            ("-U", &self.in_username),
+            ("-W", &self.in_password),
+        ] {
+            if let Some(value) = value {
+                args.extend([flag, value.as_str()]);
+            }
+        }
+        run_iscsistart(&args).with_context(|| format!("unable to log in to {}", self.name))
+    }
+}
+
+impl IscsiConfig {
+    /// Log in to the targets and wait for their disks to appear
+    pub fn login(&self, cmdline: &Cmdline, timeout: Duration) -> Result<()> {
+        match self {
+            IscsiConfig::Firmware => {
+                info!("logging in to the iSCSI targets from the firmware");
+                run_iscsistart(&["-b"])?;
+            }
+            IscsiConfig::Targets(targets) => {
+                let initiator = get_initiator_name(cmdline)?;
+                targets
+                    .iter()
+                    .try_for_each(|target| target.login(&initiator))?;
+            }
+        }
+
+        let start = Instant::now();
+        while !are_disks_available() {
+            if start.elapsed() >= timeout {
+                bail!("timed out waiting for the iSCSI disks");
+            }
+            sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn netroot_test() {
+        let config = get_iscsi_config_from_cmdline(&Cmdline::parse("netroot=iscsi:192.168.1.2::3261:1:iqn.2023-01.org.example:root rd.iscsi.username=user rd.iscsi.password=secret"))
+        .unwrap();
+        assert_eq!(
+            config,
+            Some(IscsiConfig::Targets(vec![IscsiTarget {
+                name: "iqn.2023-01.org.example:root".to_string(),
+                address: "192.168.1.2".to_string(),
+                port: 3261,
+                group: "1".to_string(),
+                lun: Some(1),
+                username: Some("user".to_string()),
+                password: Some("secret".to_string()),
+                in_username: None,
+                in_password: None,
+            }]))
+        );
+        assert!(parse_netroot(":::1:").is_err());
+    }
+
+    #[test]
+    fn iscsi_params_test() {
+        let config = get_iscsi_config_from_cmdline(&Cmdline::parse(
+            "rd.iscsi.target.name=iqn.2023-01.org.example:disk rd.iscsi.target.ip=10.0.0.1",
+        ))
+        .unwrap()
+        .unwrap();
+        match config {
+            IscsiConfig::Targets(targets) => {
+                assert_eq!(targets[0].port, DEFAULT_PORT);
+                assert_eq!(targets[0].address, "10.0.0.1");
+            }
+            IscsiConfig::Firmware => panic!("expected targets"),
+        }
+
+        assert_eq!(
+            get_iscsi_config_from_cmdline(&Cmdline::parse("rd.iscsi.firmware=1")).unwrap(),
+            Some(IscsiConfig::Firmware)
+        );
+        assert!(get_iscsi_config_from_cmdline(&Cmdline::parse("quiet"))
+            .unwrap()
+            .is_none());
+        assert!(get_iscsi_config_from_cmdline(&Cmdline::parse(
+            "rd.iscsi.target.name=iqn.2023-01.org.example:disk"
+        ))
+        .is_err());
+    }
+}
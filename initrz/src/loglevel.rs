@@ -0,0 +1,106 @@
+// Honor quiet and loglevel= for both the kernel console and the initrz logger, and rd.log=
+// to choose where the initrz records are printed
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use tracing::level_filters::LevelFilter;
+
+use std::fs;
+
+use crate::console;
+
+const PRINTK: &str = "/proc/sys/kernel/printk";
+const LOGLEVEL_PARAM: &str = "loglevel=";
+const RD_LOGLEVEL_PARAM: &str = "rd.loglevel";
+const RD_LOG_PARAM: &str = "rd.log";
+// Same console loglevel set by the kernel for quiet
+const QUIET_LOGLEVEL: u8 = 4;
+
+/// Get the console loglevel, the last parameter given on the cmdline wins
+fn get_console_loglevel(cmdline: &Cmdline) -> Option<u8> {
+    cmdline.iter().rev().find_map(|arg| {
+        if arg == "quiet" {
+            Some(QUIET_LOGLEVEL)
+        } else {
+            arg.strip_prefix(LOGLEVEL_PARAM)
+                .and_then(|level| level.parse().ok())
+        }
+    })
+}
+
+fn get_level_filter(console_loglevel: u8) -> LevelFilter {
+    match console_loglevel {
+        0..=3 => LevelFilter::ERROR,
+        4 => LevelFilter::WARN,
+        5 | 6 => LevelFilter::INFO,
+        7 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+/// rd.loglevel= only sets the level of initrz, taking precedence over the console loglevel
+fn get_log_level(cmdline: &Cmdline) -> Result<Option<LevelFilter>> {
+    match cmdline.get_parsed(RD_LOGLEVEL_PARAM)? {
+        Some(level) => Ok(Some(level)),
+        None => Ok(get_console_loglevel(cmdline).map(get_level_filter)),
+    }
+}
+
+/// Get whether the records go to the terminal and to the kernel log, like dracut rd.log=
+fn get_log_outputs(cmdline: &Cmdline) -> Result<(bool, bool)> {
+    Ok(match cmdline.get(RD_LOG_PARAM) {
+        None | Some("console") | Some("tty") => (true, false),
+        Some("kmsg") => (false, true),
+        Some("all") => (true, true),
+        Some(value) => bail!("invalid value for {}={}", RD_LOG_PARAM, value),
+    })
+}
+
+pub fn apply_from_cmdline(cmdline: &Cmdline) -> Result<()> {
+    if let Some(console_loglevel) = get_console_loglevel(cmdline) {
+        fs::write(PRINTK, console_loglevel.to_string())
+            .with_context(|| format!("unable to write to {}", PRINTK))?;
+    }
+    if let Some(level) = get_log_level(cmdline)? {
+        console::set_level(level);
+    }
+    let (terminal, kmsg) = get_log_outputs(cmdline)?;
+    console::set_outputs(terminal, kmsg)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loglevel_test() {
+        assert_eq!(get_console_loglevel(&Cmdline::parse("quiet")), Some(4));
+        assert_eq!(
+            get_console_loglevel(&Cmdline::parse("quiet loglevel=7")),
+            Some(7)
+        );
+        assert_eq!(get_console_loglevel(&Cmdline::parse("ro")), None);
+
+        assert_eq!(
+            get_log_level(&Cmdline::parse("quiet")).unwrap(),
+            Some(LevelFilter::WARN)
+        );
+        assert_eq!(
+            get_log_level(&Cmdline::parse("quiet rd.loglevel=debug")).unwrap(),
+            Some(LevelFilter::DEBUG)
+        );
+        assert!(get_log_level(&Cmdline::parse("rd.loglevel=loud")).is_err());
+
+        assert_eq!(
+            get_log_outputs(&Cmdline::parse("ro")).unwrap(),
+            (true, false)
+        );
+        assert_eq!(
+            get_log_outputs(&Cmdline::parse("rd.log=kmsg")).unwrap(),
+            (false, true)
+        );
+        assert!(get_log_outputs(&Cmdline::parse("rd.log=syslog")).is_err());
+    }
+}
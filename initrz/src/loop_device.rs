@@ -0,0 +1,38 @@
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/loop.h
+
+use anyhow::{Context, Result};
+use nix::{ioctl_none_bad, ioctl_write_int_bad};
+
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+const LOOP_CONTROL: &str = "/dev/loop-control";
+const LOOP_SET_FD: u16 = 0x4c00;
+const LOOP_CTL_GET_FREE: u16 = 0x4c82;
+
+ioctl_none_bad!(loop_ctl_get_free, LOOP_CTL_GET_FREE);
+ioctl_write_int_bad!(loop_set_fd, LOOP_SET_FD);
+
+/// Attach a file to a free loop device and return the loop device path. The file is
+/// opened read-only, so the loop device is read-only as well
+pub fn attach(file: &Path) -> Result<String> {
+    let control = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(LOOP_CONTROL)
+        .with_context(|| format!("unable to open {}", LOOP_CONTROL))?;
+    let number = unsafe { loop_ctl_get_free(control.as_raw_fd()) }
+        .with_context(|| "unable to get a free loop device")?;
+    let path = format!("/dev/loop{}", number);
+
+    let backing = File::open(file).with_context(|| format!("unable to open {:?}", file))?;
+    let device = OpenOptions::new()
+        .read(true)
+        .open(&path)
+        .with_context(|| format!("unable to open {}", path))?;
+    unsafe { loop_set_fd(device.as_raw_fd(), backing.as_raw_fd()) }
+        .with_context(|| format!("unable to attach {:?} to {}", file, path))?;
+
+    Ok(path)
+}
@@ -0,0 +1,1031 @@
+// Native LVM2 activation, replacing `vgchange -ay` and `vgmknodes`
+// https://github.com/lvmteam/lvm2/blob/main/lib/format_text/layout.h
+
+use anyhow::{bail, Context, Result};
+use nix::libc::dev_t;
+use tracing::{debug, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::iter::Peekable;
+use std::path::Path;
+use std::str::Chars;
+
+use crate::device_mapper::{self, Target};
+
+const SECTOR_SIZE: u64 = 512;
+const LABEL_SCAN_SECTORS: u64 = 4;
+const LABEL_ID: &[u8] = b"LABELONE";
+const LABEL_TYPE: &[u8] = b"LVM2 001";
+const MDA_MAGIC: &[u8] = b" LVM2 x[5A%r0N*>";
+const MDA_HEADER_SIZE: u64 = 512;
+const ID_LEN: usize = 32;
+
+/// Value of a key in the LVM2 text metadata format
+#[derive(Debug, PartialEq)]
+pub enum Value {
+    Number(i64),
+    String(String),
+    Array(Vec<Value>),
+    Section(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Section(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn get_number(&self, key: &str) -> Result<u64> {
+        match self.get(key) {
+            Some(Value::Number(number)) => (*number)
+                .try_into()
+                .with_context(|| format!("{} must be positive", key)),
+            _ => bail!("unable to find number {} in metadata", key),
+        }
+    }
+
+    fn get_string(&self, key: &str) -> Result<&str> {
+        match self.get(key) {
+            Some(Value::String(string)) => Ok(string),
+            _ => bail!("unable to find string {} in metadata", key),
+        }
+    }
+
+    fn get_array(&self, key: &str) -> Result<&[Value]> {
+        match self.get(key) {
+            Some(Value::Array(array)) => Ok(array),
+            _ => bail!("unable to find array {} in metadata", key),
+        }
+    }
+
+    fn sections(&self) -> impl Iterator<Item = (&String, &Value)> {
+        let entries: &[(String, Value)] = match self {
+            Value::Section(entries) => entries,
+            _ => &[],
+        };
+        entries
+            .iter()
+            .filter(|(_, v)| matches!(v, Value::Section(_)))
+            .map(|(k, v)| (k, v))
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_whitespace(&mut self) {
+        while let Some(c) = self.chars.peek() {
+            if c.is_whitespace() {
+                self.chars.next();
+            } else if *c == '#' {
+                // comments last until the end of the line
+                self.chars.by_ref().find(|c| *c == '\n');
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        self.skip_whitespace();
+        match self.chars.next() {
+            Some(c) if c == expected => Ok(()),
+            c => bail!("expected {:?} in metadata, found {:?}", expected, c),
+        }
+    }
+
+    fn parse_identifier(&mut self) -> Result<String> {
+        self.skip_whitespace();
+        let mut identifier = String::new();
+        while let Some(c) = self.chars.peek() {
+            if c.is_alphanumeric() || "_.+-".contains(*c) {
+                identifier.push(*c);
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+        if identifier.is_empty() {
+            bail!(
+                "expected identifier in metadata, found {:?}",
+                self.chars.peek()
+            );
+        }
+
+        Ok(identifier)
+    }
+
+    fn parse_string(&mut self) -> Result<String> {
+        self.expect('"')?;
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(string),
+                Some('\\') => {
+                    string.push(self.chars.next().with_context(|| "unterminated string")?)
+                }
+                Some(c) => string.push(c),
+                None => bail!("unterminated string in metadata"),
+            }
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        Ok(match self.chars.peek() {
+            Some('"') => Value::String(self.parse_string()?),
+            Some('[') => {
+                self.chars.next();
+                let mut array = Vec::new();
+                loop {
+                    self.skip_whitespace();
+                    match self.chars.peek() {
+                        Some(']') => {
+                            self.chars.next();
+                            break;
+                        }
+                        Some(',') => {
+                            self.chars.next();
+                        }
+                        _ => array.push(self.parse_value()?),
+                    }
+                }
+                Value::Array(array)
+            }
+            _ => {
+                let number = self.parse_identifier()?;
+                Value::Number(
+                    number
+                        .parse()
+                        .with_context(|| format!("{} is not a valid number", number))?,
+                )
+            }
+        })
+    }
+
+    /// Parse key = value and section { ... } entries until the end of the section
+    fn parse_section(&mut self, top_level: bool) -> Result<Vec<(String, Value)>> {
+        let mut entries = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.chars.peek() {
+                None if top_level => return Ok(entries),
+                None => bail!("unterminated section in metadata"),
+                Some('}') if !top_level => {
+                    self.chars.next();
+                    return Ok(entries);
+                }
+                _ => {}
+            }
+            let key = self.parse_identifier()?;
+            self.skip_whitespace();
+            match self.chars.next() {
+                Some('=') => entries.push((key, self.parse_value()?)),
+                Some('{') => entries.push((key, Value::Section(self.parse_section(false)?))),
+                c => bail!("unexpected {:?} after {} in metadata", c, key),
+            }
+        }
+    }
+}
+
+pub fn parse_metadata(metadata: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: metadata.chars().peekable(),
+    };
+    Ok(Value::Section(parser.parse_section(true)?))
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SegmentType {
+    /// Linear segments are striped segments with a single stripe
+    Striped {
+        stripe_size: u64,
+        /// Physical volume name and its starting extent
+        stripes: Vec<(String, u64)>,
+    },
+    ThinPool {
+        metadata: String,
+        data: String,
+        chunk_size: u64,
+        zero: bool,
+    },
+    Thin {
+        pool: String,
+        device_id: u64,
+    },
+    /// Classic snapshot, activated under the name of its COW store
+    Snapshot {
+        origin: String,
+        cow_store: String,
+        chunk_size: u64,
+    },
+    Unsupported(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub start_extent: u64,
+    pub extent_count: u64,
+    pub segment_type: SegmentType,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct LogicalVolume {
+    pub name: String,
+    pub id: String,
+    pub visible: bool,
+    pub writable: bool,
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct PhysicalVolumeInfo {
+    pub name: String,
+    pub id: String,
+    pub pe_start: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct VolumeGroup {
+    pub name: String,
+    pub id: String,
+    pub seqno: u64,
+    pub extent_size: u64,
+    pub physical_volumes: Vec<PhysicalVolumeInfo>,
+    pub logical_volumes: Vec<LogicalVolume>,
+}
+
+fn has_flag(section: &Value, flag: &str) -> bool {
+    section
+        .get_array("status")
+        .map(|status| status.contains(&Value::String(flag.to_string())))
+        .unwrap_or(false)
+}
+
+fn parse_segment(segment: &Value) -> Result<Segment> {
+    let segment_type = segment.get_string("type")?;
+    Ok(Segment {
+        start_extent: segment.get_number("start_extent")?,
+        extent_count: segment.get_number("extent_count")?,
+        segment_type: match segment_type {
+            "striped" => SegmentType::Striped {
+                stripe_size: segment.get_number("stripe_size").unwrap_or(0),
+                stripes: segment
+                    .get_array("stripes")?
+                    .chunks(2)
+                    .map(|stripe| match stripe {
+                        [Value::String(pv), Value::Number(extent)] => Ok((
+                            pv.clone(),
+                            (*extent).try_into().with_context(|| "invalid stripe")?,
+                        )),
+                        _ => bail!("invalid stripe in metadata"),
+                    })
+                    .collect::<Result<Vec<(String, u64)>>>()?,
+            },
+            "thin-pool" => SegmentType::ThinPool {
+                metadata: segment.get_string("metadata")?.to_string(),
+                data: segment.get_string("pool")?.to_string(),
+                chunk_size: segment.get_number("chunk_size")?,
+                zero: segment.get_number("zero_new_blocks").unwrap_or(0) != 0,
+            },
+            "thin" => SegmentType::Thin {
+                pool: segment.get_string("thin_pool")?.to_string(),
+                device_id: segment.get_number("device_id")?,
+            },
+            "snapshot" => SegmentType::Snapshot {
+                origin: segment.get_string("origin")?.to_string(),
+                cow_store: segment.get_string("cow_store")?.to_string(),
+                chunk_size: segment.get_number("chunk_size")?,
+            },
+            _ => SegmentType::Unsupported(segment_type.to_string()),
+        },
+    })
+}
+
+impl VolumeGroup {
+    pub fn from_metadata(metadata: &str) -> Result<VolumeGroup> {
+        let root = parse_metadata(metadata)?;
+        // the only section at top level is the volume group
+        let (name, vg) = root
+            .sections()
+            .next()
+            .with_context(|| "unable to find volume group in metadata")?;
+
+        Ok(VolumeGroup {
+            name: name.clone(),
+            id: vg.get_string("id")?.to_string(),
+            seqno: vg.get_number("seqno")?,
+            extent_size: vg.get_number("extent_size")?,
+            physical_volumes: match vg.get("physical_volumes") {
+                Some(pvs) => pvs
+                    .sections()
+                    .map(|(name, pv)| {
+                        Ok(PhysicalVolumeInfo {
+                            name: name.clone(),
+                            id: pv.get_string("id")?.to_string(),
+                            pe_start: pv.get_number("pe_start")?,
+                        })
+                    })
+                    .collect::<Result<Vec<PhysicalVolumeInfo>>>()?,
+                None => Vec::new(),
+            },
+            logical_volumes: match vg.get("logical_volumes") {
+                Some(lvs) => lvs
+                    .sections()
+                    .map(|(name, lv)| {
+                        Ok(LogicalVolume {
+                            name: name.clone(),
+                            id: lv.get_string("id")?.to_string(),
+                            visible: has_flag(lv, "VISIBLE"),
+                            writable: has_flag(lv, "WRITE"),
+                            segments: lv
+                                .sections()
+                                .filter(|(name, _)| name.starts_with("segment"))
+                                .map(|(_, segment)| parse_segment(segment))
+                                .collect::<Result<Vec<Segment>>>()?,
+                        })
+                    })
+                    .collect::<Result<Vec<LogicalVolume>>>()?,
+                None => Vec::new(),
+            },
+        })
+    }
+
+    pub fn get_logical_volume(&self, name: &str) -> Result<&LogicalVolume> {
+        self.logical_volumes
+            .iter()
+            .find(|lv| lv.name == name)
+            .with_context(|| format!("logical volume {} not found in {}", name, self.name))
+    }
+
+    /// Name of the device-mapper device, dashes are escaped by doubling them
+    pub fn get_dm_name(&self, lv: &LogicalVolume) -> String {
+        format!(
+            "{}-{}",
+            self.name.replace('-', "--"),
+            lv.name.replace('-', "--")
+        )
+    }
+
+    fn get_dm_uuid(&self, lv: &LogicalVolume) -> String {
+        format!("LVM-{}{}", self.id.replace('-', ""), lv.id.replace('-', ""))
+    }
+
+    /// Compute the device-mapper table of a logical volume. pv_devices maps the
+    /// physical volume names to their devices and lv_devices the already active
+    /// logical volumes to theirs
+    pub fn get_table(
+        &self,
+        lv: &LogicalVolume,
+        pv_devices: &HashMap<String, String>,
+        lv_devices: &HashMap<String, String>,
+    ) -> Result<Vec<Target>> {
+        let get_lv_device = |name: &str| {
+            lv_devices
+                .get(name)
+                .with_context(|| format!("logical volume {} is not active", name))
+        };
+        lv.segments
+            .iter()
+            .map(|segment| {
+                let length = segment.extent_count * self.extent_size;
+                let (target_type, params) = match &segment.segment_type {
+                    SegmentType::Striped {
+                        stripe_size,
+                        stripes,
+                    } => {
+                        let devices = stripes
+                            .iter()
+                            .map(|(pv_name, extent)| {
+                                let pv = self
+                                    .physical_volumes
+                                    .iter()
+                                    .find(|pv| &pv.name == pv_name)
+                                    .with_context(|| {
+                                        format!("unknown physical volume {}", pv_name)
+                                    })?;
+                                let device = pv_devices.get(pv_name).with_context(|| {
+                                    format!("physical volume {} not found", pv_name)
+                                })?;
+                                Ok(format!(
+                                    "{} {}",
+                                    device,
+                                    pv.pe_start + extent * self.extent_size
+                                ))
+                            })
+                            .collect::<Result<Vec<String>>>()?;
+                        if devices.len() == 1 {
+                            ("linear", devices[0].clone())
+                        } else {
+                            (
+                                "striped",
+                                format!("{} {} {}", devices.len(), stripe_size, devices.join(" ")),
+                            )
+                        }
+                    }
+                    SegmentType::ThinPool {
+                        metadata,
+                        data,
+                        chunk_size,
+                        zero,
+                    } => (
+                        "thin-pool",
+                        format!(
+                            "{} {} {} 0{}",
+                            get_lv_device(metadata)?,
+                            get_lv_device(data)?,
+                            chunk_size,
+                            if *zero { "" } else { " 1 skip_block_zeroing" }
+                        ),
+                    ),
+                    SegmentType::Thin { pool, device_id } => {
+                        ("thin", format!("{} {}", get_lv_device(pool)?, device_id))
+                    }
+                    SegmentType::Snapshot { cow_store, .. } => {
+                        bail!("snapshot segments are activated with {}", cow_store)
+                    }
+                    SegmentType::Unsupported(segment_type) => {
+                        bail!("segment type {} is not supported", segment_type)
+                    }
+                };
+                Ok(Target {
+                    start: segment.start_extent * self.extent_size,
+                    length,
+                    target_type: target_type.to_string(),
+                    params,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the snapshot segment whose COW store is lv
+    pub fn get_snapshot(&self, lv: &LogicalVolume) -> Option<&Segment> {
+        self.logical_volumes
+            .iter()
+            .flat_map(|volume| &volume.segments)
+            .find(|segment| match &segment.segment_type {
+                SegmentType::Snapshot { cow_store, .. } => *cow_store == lv.name,
+                _ => false,
+            })
+    }
+
+    /// Check if lv is the origin of classic snapshots
+    fn is_snapshot_origin(&self, lv: &LogicalVolume) -> bool {
+        self.logical_volumes
+            .iter()
+            .flat_map(|volume| &volume.segments)
+            .any(|segment| match &segment.segment_type {
+                SegmentType::Snapshot { origin, .. } => *origin == lv.name,
+                _ => false,
+            })
+    }
+
+    /// Compute the table of a snapshot origin, whose segments are in the real device. The
+    /// snapshot-origin target copies the chunks to the snapshots before writing them
+    fn get_origin_table(&self, lv: &LogicalVolume, real: &str) -> Vec<Target> {
+        vec![Target {
+            start: 0,
+            length: lv
+                .segments
+                .iter()
+                .map(|segment| segment.extent_count * self.extent_size)
+                .sum(),
+            target_type: "snapshot-origin".to_string(),
+            params: real.to_string(),
+        }]
+    }
+
+    /// Compute the table of a classic snapshot, given its COW store. real_devices maps
+    /// the active origins to their real devices
+    fn get_snapshot_table(
+        &self,
+        snapshot: &Segment,
+        real_devices: &HashMap<String, String>,
+        cow: &str,
+    ) -> Result<Vec<Target>> {
+        match &snapshot.segment_type {
+            SegmentType::Snapshot {
+                origin, chunk_size, ..
+            } => Ok(vec![Target {
+                start: 0,
+                length: snapshot.extent_count * self.extent_size,
+                target_type: "snapshot".to_string(),
+                params: format!(
+                    "{} {} P {}",
+                    real_devices
+                        .get(origin)
+                        .with_context(|| format!("origin {} is not active", origin))?,
+                    cow,
+                    chunk_size
+                ),
+            }]),
+            _ => bail!("not a snapshot segment"),
+        }
+    }
+
+    /// Logical volumes that must be active before lv
+    fn get_dependencies<'a>(&'a self, lv: &'a LogicalVolume) -> Vec<&'a str> {
+        let mut dependencies: Vec<&str> = lv
+            .segments
+            .iter()
+            .flat_map(|segment| match &segment.segment_type {
+                SegmentType::ThinPool { metadata, data, .. } => {
+                    vec![metadata.as_str(), data.as_str()]
+                }
+                SegmentType::Thin { pool, .. } => vec![pool.as_str()],
+                _ => Vec::new(),
+            })
+            .collect();
+        if let Some(Segment {
+            segment_type: SegmentType::Snapshot { origin, .. },
+            ..
+        }) = self.get_snapshot(lv)
+        {
+            dependencies.push(origin);
+        }
+
+        dependencies
+    }
+}
+
+fn read_at(file: &mut File, offset: u64, len: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// A physical volume found on a device, with the metadata of its volume group
+pub struct PhysicalVolume {
+    pub id: String,
+    pub metadata: Option<String>,
+}
+
+/// Read the LVM2 label, the physical volume header and the text metadata of a device
+pub fn read_physical_volume(path: &str) -> Result<PhysicalVolume> {
+    let mut file = File::open(path).with_context(|| format!("unable to open {}", path))?;
+    let sectors = read_at(&mut file, 0, (LABEL_SCAN_SECTORS * SECTOR_SIZE) as usize)?;
+    let (sector, label) = sectors
+        .chunks(SECTOR_SIZE as usize)
+        .enumerate()
+        .find(|(_, sector)| &sector[0..8] == LABEL_ID && &sector[24..32] == LABEL_TYPE)
+        .with_context(|| format!("unable to find LVM2 label in {}", path))?;
+
+    let pv_header = sector * SECTOR_SIZE as usize + read_u32(label, 20) as usize;
+    let id_bytes = sectors
+        .get(pv_header..pv_header + ID_LEN)
+        .with_context(|| "invalid physical volume header")?;
+    let id = String::from_utf8_lossy(id_bytes).to_string();
+
+    // skip the data areas list, then read the first metadata area
+    let mut offset = pv_header + ID_LEN + 8;
+    let mut lists_ended = 0;
+    let mut metadata_area = None;
+    while lists_ended < 2 && offset + 16 <= sectors.len() {
+        let area_offset = read_u64(&sectors, offset);
+        let area_size = read_u64(&sectors, offset + 8);
+        offset += 16;
+        if area_offset == 0 && area_size == 0 {
+            lists_ended += 1;
+        } else if lists_ended == 1 && metadata_area.is_none() {
+            metadata_area = Some((area_offset, area_size));
+        }
+    }
+
+    let metadata = match metadata_area {
+        Some((area_offset, area_size)) => read_metadata_area(&mut file, area_offset, area_size)?,
+        None => None,
+    };
+
+    Ok(PhysicalVolume { id, metadata })
+}
+
+fn read_metadata_area(file: &mut File, area_offset: u64, area_size: u64) -> Result<Option<String>> {
+    let header = read_at(file, area_offset, MDA_HEADER_SIZE as usize)?;
+    if &header[4..20] != MDA_MAGIC {
+        bail!("invalid metadata area header");
+    }
+    // first raw location, it points to the current metadata
+    let offset = read_u64(&header, 40);
+    let size = read_u64(&header, 48);
+    if offset == 0 || size == 0 {
+        return Ok(None);
+    }
+
+    if offset < MDA_HEADER_SIZE || offset >= area_size || size > area_size - MDA_HEADER_SIZE {
+        bail!("invalid metadata location in the metadata area header");
+    }
+    let start = area_offset
+        .checked_add(offset)
+        .with_context(|| "invalid metadata area offset")?;
+    let end = offset
+        .checked_add(size)
+        .with_context(|| "invalid metadata size")?;
+    // the metadata area is a circular buffer starting after its header
+    let mut text = if end > area_size {
+        let first = area_size
+            .checked_sub(offset)
+            .with_context(|| "invalid metadata area offset")?;
+        let mut text = read_at(file, start, first as usize)?;
+        text.extend(read_at(
+            file,
+            area_offset + MDA_HEADER_SIZE,
+            (size - first) as usize,
+        )?);
+        text
+    } else {
+        read_at(file, start, size as usize)?
+    };
+    if let Some(end) = text.iter().position(|c| *c == 0) {
+        text.truncate(end);
+    }
+
+    Ok(Some(String::from_utf8(text)?))
+}
+
+fn normalize_id(id: &str) -> String {
+    id.replace('-', "")
+}
+
+/// Keep track of the physical volumes found and activate the logical volumes of each
+/// volume group once all of its physical volumes are available
+#[derive(Default)]
+pub struct LvmActivator {
+    /// Physical volume ids mapped to their device path
+    physical_volumes: HashMap<String, String>,
+    /// Volume group ids mapped to the most recent metadata found
+    volume_groups: HashMap<String, VolumeGroup>,
+    activated: HashSet<String>,
+}
+
+impl LvmActivator {
+    pub fn add_physical_volume(&mut self, path: &str) -> Result<()> {
+        let pv = read_physical_volume(path)?;
+        debug!("found LVM physical volume {} on {}", pv.id, path);
+        self.physical_volumes
+            .insert(normalize_id(&pv.id), path.to_string());
+        if let Some(metadata) = pv.metadata {
+            let vg = VolumeGroup::from_metadata(&metadata)
+                .with_context(|| format!("unable to parse LVM metadata on {}", path))?;
+            let newer = self
+                .volume_groups
+                .get(&vg.id)
+                .map(|old| vg.seqno > old.seqno)
+                .unwrap_or(true);
+            if newer {
+                self.volume_groups.insert(vg.id.clone(), vg);
+            }
+        }
+
+        self.activate_complete_volume_groups()
+    }
+
+    fn activate_complete_volume_groups(&mut self) -> Result<()> {
+        let complete = self
+            .volume_groups
+            .values()
+            .filter(|vg| !self.activated.contains(&vg.id))
+            .filter(|vg| {
+                vg.physical_volumes
+                    .iter()
+                    .all(|pv| self.physical_volumes.contains_key(&normalize_id(&pv.id)))
+            })
+            .map(|vg| vg.id.clone())
+            .collect::<Vec<String>>();
+
+        for id in complete {
+            self.activated.insert(id.clone());
+            self.activate_volume_group(&self.volume_groups[&id])?;
+        }
+
+        Ok(())
+    }
+
+    fn activate_volume_group(&self, vg: &VolumeGroup) -> Result<()> {
+        info!("activating LVM volume group {}", vg.name);
+        let pv_devices = vg
+            .physical_volumes
+            .iter()
+            .map(|pv| {
+                (
+                    pv.name.clone(),
+                    self.physical_volumes[&normalize_id(&pv.id)].clone(),
+                )
+            })
+            .collect::<HashMap<String, String>>();
+        let mut lv_devices = HashMap::new();
+        let mut real_devices = HashMap::new();
+        for lv in vg.logical_volumes.iter().filter(|lv| lv.visible) {
+            // Already activated as the dependency of another one, e.g. a thin pool
+            if lv_devices.contains_key(&lv.name) {
+                continue;
+            }
+            if let Err(err) =
+                activate_logical_volume(vg, lv, &pv_devices, &mut lv_devices, &mut real_devices)
+            {
+                warn!("unable to activate {}/{}: {:?}", vg.name, lv.name, err);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Activate lv after its dependencies. real_devices maps the origins of the classic
+/// snapshots to the devices holding their segments
+fn activate_logical_volume(
+    vg: &VolumeGroup,
+    lv: &LogicalVolume,
+    pv_devices: &HashMap<String, String>,
+    lv_devices: &mut HashMap<String, String>,
+    real_devices: &mut HashMap<String, String>,
+) -> Result<dev_t> {
+    for dependency in vg.get_dependencies(lv) {
+        if !lv_devices.contains_key(dependency) {
+            activate_logical_volume(
+                vg,
+                vg.get_logical_volume(dependency)?,
+                pv_devices,
+                lv_devices,
+                real_devices,
+            )?;
+        }
+    }
+
+    let dm_name = vg.get_dm_name(lv);
+    if Path::new("/dev/mapper").join(&dm_name).exists() {
+        bail!("device {} already exists", dm_name);
+    }
+    let table = if let Some(snapshot) = vg.get_snapshot(lv) {
+        // The COW store is hidden behind the snapshot, that takes its name
+        let cow = activate_layer(vg, lv, "cow", pv_devices, lv_devices)?;
+        vg.get_snapshot_table(snapshot, real_devices, &cow)?
+    } else if vg.is_snapshot_origin(lv) {
+        let real = activate_layer(vg, lv, "real", pv_devices, lv_devices)?;
+        real_devices.insert(lv.name.clone(), real.clone());
+        vg.get_origin_table(lv, &real)
+    } else {
+        vg.get_table(lv, pv_devices, lv_devices)?
+    };
+    let dev =
+        device_mapper::create_device(&dm_name, Some(&vg.get_dm_uuid(lv)), &table, !lv.writable)?;
+    lv_devices.insert(lv.name.clone(), device_mapper::get_device_string(dev));
+    if lv.visible {
+        device_mapper::create_symlink(&vg.name, &lv.name, &dm_name)?;
+    }
+
+    Ok(dev)
+}
+
+/// Activate the segments of lv in a hidden device named after it with the suffix, as
+/// LVM does for the origins and the COW stores of the snapshots
+fn activate_layer(
+    vg: &VolumeGroup,
+    lv: &LogicalVolume,
+    suffix: &str,
+    pv_devices: &HashMap<String, String>,
+    lv_devices: &HashMap<String, String>,
+) -> Result<String> {
+    let table = vg.get_table(lv, pv_devices, lv_devices)?;
+    let dev = device_mapper::create_device(
+        &format!("{}-{}", vg.get_dm_name(lv), suffix),
+        Some(&format!("{}-{}", vg.get_dm_uuid(lv), suffix)),
+        &table,
+        !lv.writable,
+    )?;
+
+    Ok(device_mapper::get_device_string(dev))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_volume_group() -> VolumeGroup {
+        let metadata =
+            std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/test/lvm.metadata"))
+                .unwrap();
+        VolumeGroup::from_metadata(&metadata).unwrap()
+    }
+
+    #[test]
+    fn read_physical_volume_test() {
+        let metadata = b"vg { id = \"abc\" seqno = 1 extent_size = 8 }\0";
+        let mut image = vec![0u8; 16384];
+        // label in the second sector, followed by the physical volume header
+        let label = &mut image[512..];
+        label[0..8].copy_from_slice(LABEL_ID);
+        label[20..24].copy_from_slice(&32u32.to_le_bytes());
+        label[24..32].copy_from_slice(LABEL_TYPE);
+        label[32..64].copy_from_slice(b"Q4dpKP3kfmlc2iYi6X4NWmeQ3OlZ2sWf");
+        // one data area, then one metadata area at 4096
+        label[72..80].copy_from_slice(&1048576u64.to_le_bytes());
+        label[104..112].copy_from_slice(&4096u64.to_le_bytes());
+        label[112..120].copy_from_slice(&8192u64.to_le_bytes());
+        let mda = &mut image[4096..];
+        mda[4..20].copy_from_slice(MDA_MAGIC);
+        mda[40..48].copy_from_slice(&512u64.to_le_bytes());
+        mda[48..56].copy_from_slice(&(metadata.len() as u64).to_le_bytes());
+        mda[512..512 + metadata.len()].copy_from_slice(metadata);
+
+        let path = std::env::temp_dir().join("initrz-lvm-pv.img");
+        std::fs::write(&path, &image).unwrap();
+        let pv = read_physical_volume(path.to_str().unwrap()).unwrap();
+
+        // locations of the metadata outside of its area
+        for (offset, size) in [
+            (8, 64),
+            (8192, 64),
+            (u64::MAX, 64),
+            (512, 8192),
+            (512, u64::MAX),
+        ]
+        .iter()
+        {
+            image[4096 + 40..4096 + 48].copy_from_slice(&offset.to_le_bytes());
+            image[4096 + 48..4096 + 56].copy_from_slice(&size.to_le_bytes());
+            std::fs::write(&path, &image).unwrap();
+            assert!(read_physical_volume(path.to_str().unwrap()).is_err());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pv.id, "Q4dpKP3kfmlc2iYi6X4NWmeQ3OlZ2sWf");
+        assert_eq!(
+            normalize_id("Q4dpKP-3kfm-lc2i-Yi6X-4NWm-eQ3O-lZ2sWf"),
+            pv.id
+        );
+        let vg = VolumeGroup::from_metadata(&pv.metadata.unwrap()).unwrap();
+        assert_eq!(vg.name, "vg");
+        assert_eq!(vg.extent_size, 8);
+    }
+
+    #[test]
+    fn parse_metadata_test() {
+        let vg = get_volume_group();
+        assert_eq!(vg.name, "my-vg");
+        assert_eq!(vg.seqno, 7);
+        assert_eq!(vg.extent_size, 8192);
+        assert_eq!(vg.physical_volumes.len(), 2);
+        assert_eq!(vg.physical_volumes[1].pe_start, 2048);
+        assert_eq!(vg.logical_volumes.len(), 9);
+
+        let root = &vg.logical_volumes[0];
+        assert_eq!(root.name, "root");
+        assert!(root.visible && root.writable);
+        assert_eq!(
+            root.segments,
+            vec![
+                Segment {
+                    start_extent: 0,
+                    extent_count: 10,
+                    segment_type: SegmentType::Striped {
+                        stripe_size: 0,
+                        stripes: vec![("pv0".to_string(), 0)],
+                    },
+                },
+                Segment {
+                    start_extent: 10,
+                    extent_count: 5,
+                    segment_type: SegmentType::Striped {
+                        stripe_size: 0,
+                        stripes: vec![("pv1".to_string(), 0)],
+                    },
+                },
+            ]
+        );
+        assert!(!vg.logical_volumes[3].visible);
+        assert_eq!(
+            vg.logical_volumes[8].segments[0].segment_type,
+            SegmentType::Snapshot {
+                origin: "var".to_string(),
+                cow_store: "var_snap".to_string(),
+                chunk_size: 8,
+            }
+        );
+    }
+
+    #[test]
+    fn get_table_test() {
+        let vg = get_volume_group();
+        let pv_devices: HashMap<String, String> = [("pv0", "8:1"), ("pv1", "8:17")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let mut lv_devices = HashMap::new();
+
+        let root = &vg.logical_volumes[0];
+        assert_eq!(vg.get_dm_name(root), "my--vg-root");
+        assert_eq!(
+            vg.get_table(root, &pv_devices, &lv_devices).unwrap(),
+            vec![
+                Target {
+                    start: 0,
+                    length: 81920,
+                    target_type: "linear".to_string(),
+                    params: "8:1 2048".to_string(),
+                },
+                Target {
+                    start: 81920,
+                    length: 40960,
+                    target_type: "linear".to_string(),
+                    params: "8:17 2048".to_string(),
+                },
+            ]
+        );
+
+        let home = &vg.logical_volumes[1];
+        assert_eq!(
+            vg.get_table(home, &pv_devices, &lv_devices).unwrap(),
+            vec![Target {
+                start: 0,
+                length: 16384,
+                target_type: "striped".to_string(),
+                params: "2 128 8:1 83968 8:17 43008".to_string(),
+            }]
+        );
+
+        let thin = &vg.logical_volumes[5];
+        assert_eq!(vg.get_dependencies(thin), vec!["pool"]);
+        assert!(vg.get_table(thin, &pv_devices, &lv_devices).is_err());
+        lv_devices.insert("pool".to_string(), "253:2".to_string());
+        assert_eq!(
+            vg.get_table(thin, &pv_devices, &lv_devices).unwrap()[0].params,
+            "253:2 1"
+        );
+
+        let pool = &vg.logical_volumes[2];
+        lv_devices.insert("pool_tmeta".to_string(), "253:0".to_string());
+        lv_devices.insert("pool_tdata".to_string(), "253:1".to_string());
+        assert_eq!(
+            vg.get_table(pool, &pv_devices, &lv_devices).unwrap()[0].params,
+            "253:0 253:1 128 0 1 skip_block_zeroing"
+        );
+    }
+
+    #[test]
+    fn get_snapshot_table_test() {
+        let vg = get_volume_group();
+        let pv_devices: HashMap<String, String> = [("pv0", "8:1"), ("pv1", "8:17")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let lv_devices = HashMap::new();
+        let (var, var_snap, snapshot0) = (
+            &vg.logical_volumes[6],
+            &vg.logical_volumes[7],
+            &vg.logical_volumes[8],
+        );
+
+        assert!(vg.is_snapshot_origin(var));
+        assert!(!vg.is_snapshot_origin(var_snap));
+        assert!(vg.get_snapshot(var).is_none());
+        assert_eq!(vg.get_dependencies(var_snap), vec!["var"]);
+        // the origin keeps its segments in the real device
+        assert_eq!(
+            vg.get_origin_table(var, "253:3"),
+            vec![Target {
+                start: 0,
+                length: 24576,
+                target_type: "snapshot-origin".to_string(),
+                params: "253:3".to_string(),
+            }]
+        );
+        // the snapshot has the size of the origin, its COW store is a hidden device
+        let snapshot = vg.get_snapshot(var_snap).unwrap();
+        let mut real_devices = HashMap::new();
+        assert!(vg
+            .get_snapshot_table(snapshot, &real_devices, "253:4")
+            .is_err());
+        real_devices.insert("var".to_string(), "253:3".to_string());
+        assert_eq!(
+            vg.get_snapshot_table(snapshot, &real_devices, "253:4")
+                .unwrap(),
+            vec![Target {
+                start: 0,
+                length: 24576,
+                target_type: "snapshot".to_string(),
+                params: "253:3 253:4 P 8".to_string(),
+            }]
+        );
+        assert_eq!(
+            vg.get_table(var_snap, &pv_devices, &lv_devices).unwrap()[0].params,
+            "8:17 92160"
+        );
+        assert!(vg.get_table(snapshot0, &pv_devices, &lv_devices).is_err());
+    }
+}
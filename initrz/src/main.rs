@@ -1,36 +1,91 @@
+mod bcache;
+mod btrfs;
+mod cmdline;
+mod console;
+mod crypt_options;
 mod device_handler;
+mod device_mapper;
+mod device_registry;
+mod dhcp;
+mod disk_links;
+mod emergency;
 mod encrypted_device;
-mod encryption_type;
+mod event_loop;
 mod filesystem;
+mod fstab;
+mod hooks;
+mod hostname;
 mod identifier;
+mod ima;
+mod image;
+mod initrz_conf;
+mod input;
+mod iscsi;
+mod loglevel;
+mod loop_device;
+mod lvm;
+mod metrics;
 mod module_loader;
+mod module_manifest;
 mod mounts;
+mod multipath;
+mod network;
+mod nfs;
+mod nvmf;
+mod overlay;
+mod passphrase;
+mod plain_crypt;
+mod plymouth;
+mod probe;
+mod rescue_shell;
 mod root_device;
+mod rtnetlink;
+mod selinux;
+mod shutdown;
+mod signal_handler;
+mod snapshot;
+mod ssh;
+mod state;
+mod swap;
+mod switch_root;
+mod sysctl;
+mod timeouts;
 mod uevent_listener;
-mod unlock_type;
-mod utils;
+mod vconsole;
+mod verity;
+mod watchdog;
+mod zfs;
 
 use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use common::crypttab::CRYPTTAB_INITRAMFS;
 use dowser::Dowser;
-use log::{error, info};
-use nix::unistd::chroot;
+use nix::sys::reboot::{reboot, RebootMode};
 use rayon::prelude::*;
-use simplelog::{ColorChoice, Config, LevelFilter, TermLogger, TerminalMode};
+use tracing::{error, info, instrument, warn};
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::prelude::*;
 
 use std::{
-    env, fs,
-    os::unix::process::CommandExt,
-    path::Path,
-    process::Command,
-    sync::{mpsc::channel, Arc},
-    thread,
+    env, fs, io, os::unix::process::CommandExt, path::Path, process::Command, sync::Arc,
+    time::Instant,
 };
 
+use console::ConsoleLayer;
 use device_handler::DeviceHandler;
+use device_registry::DeviceRegistry;
+use event_loop::EventLoop;
+use hooks::Stage;
+use initrz_conf::InitrzConf;
+use metrics::Metrics;
 use module_loader::ModuleLoader;
 use mounts::Mounts;
+use signal_handler::GuardedCommand;
+use timeouts::Timeouts;
 use uevent_listener::UeventListener;
-use utils::get_blkid_cache;
+use watchdog::Watchdog;
+
+const INIT: &str = "/sbin/init";
 
 // Copyright (c) 2015 Guillaume Gomez
 // https://github.com/GuillaumeGomez/sysinfo/blob/master/src/linux/system.rs#L524
@@ -53,98 +108,316 @@ fn get_kernel_version() -> Result<String> {
     }
 }
 
-pub fn parse_cmdline() -> Result<Vec<String>> {
-    Ok(String::from_utf8(fs::read("/proc/cmdline")?)?
-        .split_whitespace()
-        .collect::<Vec<&str>>()
-        .iter()
-        .map(|s| String::from(*s))
-        .collect())
+pub fn parse_cmdline() -> Result<Cmdline> {
+    let cmdline = cmdline::add_fallback(Cmdline::parse(&fs::read_to_string("/proc/cmdline")?));
+    // The configuration only provides defaults, a broken one must not stop the boot
+    match InitrzConf::load() {
+        Ok(Some(conf)) => Ok(initrz_conf::merge(&conf, cmdline)),
+        Ok(None) => Ok(cmdline),
+        Err(err) => {
+            warn!("{:?}", err);
+            Ok(cmdline)
+        }
+    }
+}
+
+fn init_logger() -> Result<()> {
+    tracing_subscriber::registry()
+        .with(ConsoleLayer)
+        .with(
+            tracing_subscriber::fmt::layer()
+                .without_time()
+                .with_target(false)
+                .with_writer(io::stderr)
+                .with_filter(filter_fn(console::is_terminal_enabled)),
+        )
+        .try_init()?;
+
+    Ok(())
+}
+
+/// Load the modules of the devices found before initrz started
+#[instrument(skip_all)]
+fn coldplug(module_loader: &ModuleLoader, cmdline: &Cmdline) {
+    info!("traversing /sys modalias files");
+    Dowser::default()
+        .with_path("/sys")
+        .into_vec_filtered(|p: &Path| {
+            p.file_name()
+                .filter(|filename| filename.to_str().unwrap_or("") == "modalias")
+                .is_some()
+        })
+        .par_iter()
+        .filter_map(|modalias| modalias.to_str())
+        .for_each(|modalias| module_loader.load_modalias(modalias));
+    if module_loader::is_hostonly_disabled(cmdline) {
+        // The image could have been generated for other hardware
+        info!("rd.hostonly=0 given, loading every module");
+        module_loader.load_all_modules();
+    }
 }
 
 fn initrz() -> Result<()> {
-    TermLogger::init(
-        LevelFilter::Trace,
-        Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )?;
+    let mut metrics = Metrics::new();
+    init_logger()?;
+    signal_handler::init()?;
 
     info!("mounting special filesystems");
     let mounts = Arc::new(Mounts::with_default_mounts()?);
+    metrics.record("mounts");
 
     info!("parsing command line");
     let cmdline = parse_cmdline()?;
+    if let Err(err) = console::setup(&cmdline) {
+        warn!("unable to set up the consoles: {:?}", err);
+    }
+    if let Err(err) = loglevel::apply_from_cmdline(&cmdline) {
+        warn!("unable to set the loglevel: {:?}", err);
+    }
+    let timeouts = Timeouts::from_cmdline(&cmdline)?;
+    let mut watchdog = Watchdog::start_from_cmdline(&cmdline)?;
+    if let Err(err) = hostname::setup_from_cmdline(&cmdline) {
+        warn!("unable to set the hostname: {:?}", err);
+    }
+    if let Err(err) = sysctl::apply(&cmdline) {
+        warn!("unable to apply the sysctl settings: {:?}", err);
+    }
+    // Before any passphrase prompt
+    if let Err(err) = vconsole::setup() {
+        warn!("unable to set up the console keymap and font: {:?}", err);
+    }
+
+    if plymouth::is_available() {
+        info!("starting plymouth");
+        // The splash is not essential, keep booting without it
+        if let Err(err) = plymouth::start() {
+            warn!("unable to start plymouth: {:?}", err);
+        }
+    }
 
     info!("creating internal objects");
-    let module_loader = Arc::new(ModuleLoader::init(&get_kernel_version()?)?);
-    let mut device_handler = DeviceHandler::init("/etc/crypttab.initramfs", &cmdline)?;
-    let uevent_listener = UeventListener::init(module_loader.clone())?;
+    let module_loader = Arc::new(ModuleLoader::init(&get_kernel_version()?, &cmdline)?);
+    let device_registry = Arc::new(DeviceRegistry::default());
+    let mut device_handler = DeviceHandler::init(
+        CRYPTTAB_INITRAMFS,
+        &cmdline,
+        timeouts.passphrase,
+        module_loader.clone(),
+        device_registry.clone(),
+    )?;
+    let mut event_loop = EventLoop::new(UeventListener::init(module_loader.clone())?)?;
+    let iscsi_config = iscsi::get_iscsi_config_from_cmdline(&cmdline)?;
+    let nvmf_config = nvmf::get_nvmf_config_from_cmdline(&cmdline)?;
+    // iSCSI and NVMe/TCP roots need the network before the root device can appear
+    let network_needed = iscsi_config.is_some() || nvmf_config.is_some();
+    let ssh_enabled = ssh::is_enabled(&cmdline);
+
+    info!("loading preload modules");
+    module_loader.load_preload_modules(&cmdline);
+
+    if multipath::is_enabled(&cmdline) {
+        for module in multipath::MULTIPATH_MODULES {
+            if !module_loader.load_module(module)? {
+                // Do not fail here because the module could be builtin
+                warn!("module {} not found", module);
+            }
+        }
+    }
+
+    // With SSH access the passphrases can be entered remotely, wait for the network
+    if !ssh_enabled {
+        info!("unlocking available devices");
+        device_handler.unlock_available_devices()?;
+        info!("searching for root");
+        device_handler.search_root()?;
+        metrics.record("unlock");
+    }
+
+    hooks::run(Stage::Trigger);
+    coldplug(&module_loader, &cmdline);
+    metrics.record("coldplug");
+
+    // The watchdog driver could have been loaded as a module
+    if watchdog.is_none() && Watchdog::is_required(&cmdline) {
+        watchdog = Watchdog::start_from_cmdline(&cmdline)?;
+        if watchdog.is_none() {
+            warn!("rd.watchdog=1 given but no watchdog device found");
+        }
+    }
 
-    info!("loading qemu modules");
-    module_loader.load_module("virtio_blk")?;
-    module_loader.load_module("virtio_pci")?;
-    // module_loader.load_module("virtio_net")?;
+    if network_needed || ssh_enabled {
+        info!("setting up network");
+        network::setup_from_cmdline(&cmdline, timeouts.network)?;
+        metrics.record("network");
+    }
+    if ssh_enabled {
+        info!("starting ssh server");
+        ssh::start(&cmdline)?;
+        info!("unlocking available devices");
+        device_handler.unlock_available_devices()?;
+        info!("searching for root");
+        device_handler.search_root()?;
+        metrics.record("unlock");
+    }
+    if let Some(iscsi_config) = &iscsi_config {
+        if !module_loader.load_module(iscsi::ISCSI_MODULE)? {
+            warn!("module {} not found", iscsi::ISCSI_MODULE);
+        }
+        iscsi_config.login(&cmdline, timeouts.settle)?;
+    }
+    if let Some(nvmf_config) = &nvmf_config {
+        info!("connecting to NVMe over Fabrics targets");
+        nvmf_config.connect_all(&module_loader, timeouts.settle)?;
+    }
 
-    // info!("loading essential modules");
-    // module_loader.load_module("usbhid")?;
+    info!("waiting for the root device");
+    let wait_start = Instant::now();
+    device_handler.listen(&mut event_loop, timeouts.settle)?;
+    if emergency::is_rescue_requested(&cmdline) {
+        if plymouth::is_running() {
+            if let Err(err) = plymouth::quit() {
+                warn!("unable to stop plymouth: {:?}", err);
+            }
+        }
+        // Save the devices found for the shell
+        device_handler.rescan()?;
+        event_loop.shutdown();
+        emergency::rescue(&cmdline);
+    }
+    if !device_handler.is_root_found() {
+        // The driver could be missing from the modaliases, e.g. in a host-only initramfs
+        // generated on another machine
+        warn!("root device not found, loading every storage driver");
+        module_loader.load_storage_modules();
+        device_handler.listen(
+            &mut event_loop,
+            timeouts.root.saturating_sub(timeouts.settle),
+        )?;
+    }
+    if device_handler.is_root_found() {
+        device_handler.wait_for_encrypted_devices(
+            &mut event_loop,
+            wait_start,
+            timeouts.root,
+            timeouts.settle,
+        )?;
+    }
+    device_handler.remove_embedded_keys();
+    swap::activate(&device_handler.get_swap_devices(), &device_registry);
+    let root = device_handler.get_root().with_context(|| {
+        // A missing driver is the likely cause
+        match module_loader.get_failed_modules() {
+            failed if failed.is_empty() => "unable to find root device".to_string(),
+            failed => format!(
+                "unable to find root device, these modules failed to load: {}",
+                failed.join(", ")
+            ),
+        }
+    })?;
+    metrics.record("root");
+    info!("loading post modules");
+    module_loader.load_post_modules(&cmdline);
+    // The uevents from now on are for the udev of the new root
+    event_loop.shutdown();
 
-    // module_loader.load_all_modules()?;
+    if root.nfs.is_some() && !network_needed && !ssh_enabled {
+        info!("setting up network");
+        network::setup_from_cmdline(&cmdline, timeouts.network)?;
+    }
 
-    info!("probing available devices");
-    let mut cache = get_blkid_cache();
-    cache.probe_all()?;
-    cache.probe_all_removable()?;
-    cache.put_cache();
-    std::mem::drop(cache);
+    if ssh_enabled {
+        info!("stopping ssh server");
+        if let Err(err) = ssh::stop() {
+            warn!("unable to stop the ssh server: {:?}", err);
+        }
+    }
 
-    info!("creating channels");
-    let (tx, rx) = channel::<String>();
+    if plymouth::is_running() {
+        info!("stopping plymouth");
+        if let Err(err) = plymouth::quit() {
+            warn!("unable to stop plymouth: {:?}", err);
+        }
+    }
 
-    info!("unlocking available devices");
-    device_handler.unlock_available_devices()?;
-    info!("searching for root");
-    device_handler.search_root()?;
+    info!("copying the initramfs for shutdown");
+    if let Err(err) = shutdown::prepare() {
+        warn!("unable to prepare the shutdown initramfs: {:?}", err);
+    }
 
-    info!("starting uevent listener thread");
-    thread::spawn(move || uevent_listener.listen(tx));
+    // The initramfs contents are deleted when moving into the new root
+    let embedded_ima_policy = ima::read_embedded_policy();
 
-    info!("traversing /sys modalias files");
-    Dowser::default()
-        .with_path("/sys")
-        .into_vec_filtered(|p: &Path| {
-            p.file_name()
-                .filter(|filename| filename.to_str().unwrap_or("") == "modalias")
-                .is_some()
-        })
-        .par_iter()
-        .filter_map(|modalias| modalias.to_str())
-        .try_for_each(|modalias| module_loader.load_modalias(modalias))?;
-
-    info!("receiving unlock results");
-    device_handler.listen(rx)?;
-
-    info!("moving /new_root into /");
-    // switch_root
-    // https://github.com/mirror/busybox/blob/9ec836c033fc6e55e80f3309b3e05acdf09bb297/util-linux/switch_root.c#L297
-    mounts.mount_root(
-        device_handler
-            .get_root()
-            .with_context(|| "unable to find root device")?,
-        &module_loader,
-    )?;
+    if state::is_break_requested(&cmdline) {
+        info!("rd.break given, exit the shell to continue booting");
+        if rescue_shell::is_selected(&cmdline) {
+            rescue_shell::run();
+        } else if let Err(err) = state::shell().guarded_status() {
+            warn!("unable to run the breakpoint shell: {}", err);
+        }
+    }
 
-    info!("chrooting");
-    chroot(".").with_context(|| "unable to chroot")?;
-    env::set_current_dir("/")?;
-    Command::new("/sbin/init").exec();
+    hooks::run(Stage::Mount);
+    info!("mounting the root in {}", switch_root::NEW_ROOT);
+    mounts.mount_root(root, &module_loader, &device_registry)?;
 
-    Ok(())
+    // Do not leave zombies to the real init
+    signal_handler::reap_zombies();
+    signal_handler::restore_ctrl_alt_del();
+    if let Some(watchdog) = watchdog {
+        info!("disarming the watchdog");
+        watchdog.stop();
+    }
+
+    info!("moving {} into /", switch_root::NEW_ROOT);
+    switch_root::switch_root(Path::new(switch_root::NEW_ROOT), Path::new(INIT))?;
+    fstab::mount_initrd_entries(&module_loader, &device_registry)?;
+    selinux::setup(&cmdline, Path::new(INIT))?;
+    ima::load_policy(embedded_ima_policy)?;
+    metrics.record("pivot");
+    if let Err(err) = metrics.save() {
+        warn!("unable to save boot metrics: {:?}", err);
+    }
+    let err = Command::new(INIT).exec();
+
+    Err(err).with_context(|| format!("unable to execute {}", INIT))
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.first().is_some_and(|arg0| shutdown::is_shutdown(arg0)) {
+        let _ = init_logger();
+        let verb = args.get(1).map(String::as_str).unwrap_or("reboot");
+        if let Err(err) = shutdown::run(verb) {
+            error!("{:?}", err);
+        }
+        // Never return, as PID 1 exiting makes the kernel panic
+        let _ = reboot(RebootMode::RB_HALT_SYSTEM);
+        return;
+    }
+
+    // Run from an SSH session, see ssh::unlock
+    if std::process::id() != 1 && args.get(1).map(String::as_str) == Some("unlock") {
+        if let Err(err) = ssh::unlock() {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Run from the breakpoint and emergency shells, see state::shell
+    if std::process::id() != 1 && args.get(1).map(String::as_str) == Some("unlock-device") {
+        let Some(path) = args.get(2) else {
+            eprintln!("usage: unlock-device <device> [name]");
+            std::process::exit(1);
+        };
+        if let Err(err) = device_handler::unlock_from_shell(path, args.get(3).map(String::as_str)) {
+            eprintln!("{:?}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     if let Err(err) = initrz() {
-        error!("{:?}", err);
-        Command::new("busybox").arg("sh").exec();
+        emergency::handle(&err);
     }
 }
@@ -0,0 +1,130 @@
+// Timing of the boot phases, so that users can see where the early boot time goes
+
+use anyhow::{Context, Result};
+use nix::time::{clock_gettime, ClockId};
+use serde::Serialize;
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+const METRICS_DIR: &str = "/run/initrz";
+const METRICS_FILE: &str = "metrics.json";
+const KMSG: &str = "/dev/kmsg";
+
+#[derive(Serialize)]
+struct Phase {
+    name: &'static str,
+    /// Time since boot when the phase ended, in microseconds
+    end_us: u128,
+    duration_us: u128,
+}
+
+#[derive(Serialize)]
+pub struct Metrics {
+    /// Time spent in the kernel before initrz started, in microseconds
+    start_us: u128,
+    phases: Vec<Phase>,
+}
+
+/// Monotonic time since boot, the same clock used in the kernel log
+fn now() -> Duration {
+    clock_gettime(ClockId::CLOCK_MONOTONIC)
+        .map(Duration::from)
+        .unwrap_or_default()
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics {
+            start_us: now().as_micros(),
+            phases: Vec::new(),
+        }
+    }
+
+    /// Mark the end of a phase, which started at the end of the previous one
+    pub fn record(&mut self, name: &'static str) {
+        let end_us = now().as_micros();
+        let start_us = self
+            .phases
+            .last()
+            .map(|phase| phase.end_us)
+            .unwrap_or(self.start_us);
+        self.phases.push(Phase {
+            name,
+            end_us,
+            duration_us: end_us.saturating_sub(start_us),
+        });
+    }
+
+    fn get_summary(&self) -> String {
+        let end_us = self
+            .phases
+            .last()
+            .map(|phase| phase.end_us)
+            .unwrap_or(self.start_us);
+        let phases = self
+            .phases
+            .iter()
+            .map(|phase| format!("{} {:.3}s", phase.name, phase.duration_us as f64 / 1e6))
+            .collect::<Vec<String>>()
+            .join(", ");
+        format!(
+            "initrz: finished in {:.3}s ({})",
+            end_us.saturating_sub(self.start_us) as f64 / 1e6,
+            phases
+        )
+    }
+
+    /// Write the metrics to /run/initrz/metrics.json and a summary to the kernel log
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(METRICS_DIR)
+            .with_context(|| format!("unable to create {}", METRICS_DIR))?;
+        let metrics_file = Path::new(METRICS_DIR).join(METRICS_FILE);
+        fs::write(&metrics_file, serde_json::to_string(self)?)
+            .with_context(|| format!("unable to write {:?}", metrics_file))?;
+
+        let mut kmsg = OpenOptions::new()
+            .write(true)
+            .open(KMSG)
+            .with_context(|| format!("unable to open {}", KMSG))?;
+        // <6> is the info loglevel
+        kmsg.write_all(format!("<6>{}\n", self.get_summary()).as_bytes())
+            .with_context(|| format!("unable to write to {}", KMSG))?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_test() {
+        let metrics = Metrics {
+            start_us: 1_000_000,
+            phases: vec![
+                Phase {
+                    name: "mounts",
+                    end_us: 1_010_000,
+                    duration_us: 10_000,
+                },
+                Phase {
+                    name: "coldplug",
+                    end_us: 1_510_000,
+                    duration_us: 500_000,
+                },
+            ],
+        };
+        assert_eq!(
+            metrics.get_summary(),
+            "initrz: finished in 0.510s (mounts 0.010s, coldplug 0.500s)"
+        );
+        assert_eq!(
+            serde_json::to_string(&metrics).unwrap(),
+            r#"{"start_us":1000000,"phases":[{"name":"mounts","end_us":1010000,"duration_us":10000},{"name":"coldplug","end_us":1510000,"duration_us":500000}]}"#
+        );
+    }
+}
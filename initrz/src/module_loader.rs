@@ -1,131 +1,288 @@
 use anyhow::{bail, Context, Result};
 use glob::{glob, Pattern};
-use log::{debug, warn};
+use nix::errno::Errno;
 use nix::kmod::init_module;
+use tracing::{debug, warn};
 use xz2::bufread::XzDecoder;
+use zstd::dict::DecoderDictionary;
 
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::fs;
+use std::io::Read;
 use std::mem::drop;
 use std::path::{Path, PathBuf};
 use std::sync::RwLock;
 use std::ffi::CString;
 use file_format::FileFormat;
-use zstd;
+
+use common::cmdline::Cmdline;
+use common::modules::{self, Module, SoftDep};
+
+use crate::module_manifest::{self, ModuleManifest};
+use crate::mounts::mount_securityfs;
+
+const ALIAS_BLACKLIST_PARAM: &str = "rd.alias.blacklist";
+const HOSTONLY_PARAM: &str = "rd.hostonly";
+const PRELOAD_PARAM: &str = "rd.driver.pre";
+const POST_PARAM: &str = "rd.driver.post";
+// Written by mkinitrz from the preload_modules setting, one module per line
+const PRELOAD_MODULES: &str = "/etc/initrz/preload-modules";
+// Written by mkinitrz --zstd-dictionary, the zstd modules have been compressed with it
+const DICTIONARY: &str = "/etc/initrz/modules.zdict";
+const SIG_ENFORCE: &str = "/sys/module/module/parameters/sig_enforce";
+const LOCKDOWN: &str = "/sys/kernel/security/lockdown";
+// Appended by scripts/sign-file after the PKCS#7 signature
+const MODULE_SIGNATURE_MAGIC: &[u8] = b"~Module signature appended~\n";
+// Loaded as a last resort when the root device does not appear, like dracut does
+const STORAGE_DRIVER_DIRS: [&str; 9] = [
+    "kernel/drivers/ata/",
+    "kernel/drivers/block/",
+    "kernel/drivers/firewire/",
+    "kernel/drivers/message/fusion/",
+    "kernel/drivers/mmc/",
+    "kernel/drivers/nvme/",
+    "kernel/drivers/scsi/",
+    "kernel/drivers/usb/",
+    "kernel/drivers/virtio/",
+];
+
+/// Why the kernel refuses to load unsigned modules, if it does
+#[derive(Debug, PartialEq)]
+pub enum SignaturePolicy {
+    Permissive,
+    /// module.sig_enforce=1 or CONFIG_MODULE_SIG_FORCE
+    Enforced,
+    /// The lockdown mode, e.g. integrity or confidentiality
+    Lockdown(String),
+}
+
+impl SignaturePolicy {
+    fn from_sysfs() -> SignaturePolicy {
+        // Without securityfs the lockdown mode is unknown, rely on the finit_module errors
+        if let Err(err) = mount_securityfs() {
+            debug!("{:?}", err);
+        }
+        if let Some(mode) = fs::read_to_string(LOCKDOWN)
+            .ok()
+            .and_then(|lockdown| parse_lockdown(&lockdown))
+        {
+            return SignaturePolicy::Lockdown(mode);
+        }
+        match fs::read_to_string(SIG_ENFORCE) {
+            Ok(enforce) if enforce.trim() == "Y" => SignaturePolicy::Enforced,
+            _ => SignaturePolicy::Permissive,
+        }
+    }
+
+    fn get_reason(&self) -> String {
+        match self {
+            SignaturePolicy::Permissive => String::from("signature rejected"),
+            SignaturePolicy::Enforced => String::from("unsigned, module.sig_enforce is set"),
+            SignaturePolicy::Lockdown(mode) => {
+                format!("unsigned, kernel is in lockdown ({})", mode)
+            }
+        }
+    }
+}
+
+/// Get the active mode from e.g. "none [integrity] confidentiality"
+fn parse_lockdown(lockdown: &str) -> Option<String> {
+    lockdown
+        .split_whitespace()
+        .find_map(|mode| {
+            mode.strip_prefix('[')
+                .and_then(|mode| mode.strip_suffix(']'))
+        })
+        .filter(|mode| *mode != "none")
+        .map(String::from)
+}
+
+fn is_signed(module: &[u8]) -> bool {
+    module.ends_with(MODULE_SIGNATURE_MAGIC)
+}
+
+/// Explain why init_module failed when the error comes from the signature check
+fn get_load_error(module_name: &str, err: Errno, policy: &SignaturePolicy) -> String {
+    match err {
+        Errno::EKEYREJECTED | Errno::EPERM if *policy != SignaturePolicy::Permissive => {
+            format!("module {} rejected: {}", module_name, policy.get_reason())
+        }
+        Errno::ENOKEY => format!(
+            "module {} rejected: signed with an unknown key",
+            module_name
+        ),
+        Errno::EBADMSG => format!("module {} rejected: malformed signature", module_name),
+        _ => format!("finit_module call failed when loading {}", module_name),
+    }
+}
 
 pub struct ModAlias {
     pattern: Pattern,
     module: String,
 }
 
-pub struct Module {
-    pub filename: String,
-    pub deps: Vec<String>,
-}
-
 pub struct ModuleLoader {
     modules: HashMap<String, Module>,
     aliases: Vec<ModAlias>,
+    softdeps: HashMap<String, SoftDep>,
+    /// Built into the kernel, there is nothing to load
+    builtin: HashSet<String>,
+    /// Devices whose modalias matches are ignored, their driver can still be loaded for
+    /// other devices
+    alias_blacklist: Vec<Pattern>,
     modules_loaded: RwLock<HashSet<String>>,
+    /// The modules that could not be loaded and why, they are not tried again
+    failed_modules: RwLock<HashMap<String, String>>,
     kernel_root: PathBuf,
+    signature_policy: SignaturePolicy,
+    /// Set with rd.verify=1, the modules are checked against it before being loaded
+    manifest: Option<ModuleManifest>,
+    dictionary: Option<DecoderDictionary<'static>>,
 }
 
-pub fn parse_module_dep(filename: &Path) -> Result<HashMap<String, Module>> {
-    let file =
-        File::open(filename).with_context(|| format!("unable to open filename {:?}", filename))?;
-    let lines = BufReader::new(file).lines();
-    Ok(lines
-        .map_while(Result::ok)
-        .map(|line| -> Result<(String, Module)> {
-            let token_index = line
-                .find(':')
-                .with_context(|| format!("could not find ':' in line:\n{}", line))?;
-            let module_filename = &line[0..token_index];
-            let module = if let Ok(module) = get_module_name(module_filename) {
-                module
-            } else {
-                bail!("{} is not a valid module name", module_filename);
-            };
-            let mut deps: Vec<String> = Vec::new();
-            let rest_of_line = &line[token_index + 1..];
-            if !rest_of_line.is_empty() {
-                // iter.rest() returns " kernel/..." so skip the first space
-                let split = rest_of_line[1..].split(' ');
-                split
-                    .filter_map(|dep| get_module_name(dep).ok())
-                    .for_each(|dep| deps.push(dep));
-
-                deps.reverse();
+/// Parse rd.alias.blacklist=, a comma separated list of modalias patterns
+fn get_alias_blacklist_from_cmdline(cmdline: &Cmdline) -> Vec<Pattern> {
+    cmdline
+        .get_all(ALIAS_BLACKLIST_PARAM)
+        .flat_map(|patterns| patterns.split(','))
+        .filter(|pattern| !pattern.is_empty())
+        .filter_map(|pattern| match Pattern::new(pattern) {
+            Ok(pattern) => Some(pattern),
+            Err(err) => {
+                warn!(
+                    "invalid pattern {} in {}: {}",
+                    pattern, ALIAS_BLACKLIST_PARAM, err
+                );
+                None
             }
-            Ok((
-                module,
-                Module {
-                    filename: module_filename.to_string(),
-                    deps,
-                },
-            ))
         })
-        .filter_map(|res| res.ok())
-        .collect())
+        .collect()
 }
 
-fn get_module_name(filename: &str) -> Result<String> {
-    Ok(Path::new(filename)
-        .file_stem()
-        .and_then(|module| std::path::Path::new(module).file_stem())
-        .with_context(|| format!("failed to get module name of file {}", filename))?
-        .to_str()
-        .with_context(|| {
-            format!(
-                "failed to convert the module name in file {} from OsStr to Str",
-                filename
-            )
-        })?
-        .to_string())
+/// rd.hostonly=0 loads every module in the initramfs, e.g. when a host-only image boots on
+/// other hardware
+pub fn is_hostonly_disabled(cmdline: &Cmdline) -> bool {
+    cmdline.get_bool(HOSTONLY_PARAM) == Some(false)
 }
 
-pub fn parse_module_alias(filename: &Path) -> Result<Vec<ModAlias>> {
-    let file =
-        File::open(filename).with_context(|| format!("unable to open file {:?}", filename))?;
-    let lines = BufReader::new(file).lines();
-
-    Ok(lines
-        .map_while(Result::ok)
-        .map(|line| -> Result<ModAlias> {
-            let mut split = line[6..].splitn(2, ' ');
-            Ok(ModAlias {
-                pattern: Pattern::new(split.next().with_context(|| "no pattern found")?)?,
-                module: split
-                    .next()
-                    .with_context(|| "no modalias found")?
-                    .to_string(),
-            })
-        })
-        .filter_map(|res| {
-            if res.is_err() {
-                warn!("unable to parse modalias line");
-            }
-            res.ok()
-        })
-        .collect())
+/// Get the modules listed one per line in the embedded file, followed by the comma
+/// separated lists given by the kernel parameter
+fn get_listed_modules(embedded: &str, cmdline: &Cmdline, param: &str) -> Vec<String> {
+    let mut listed: Vec<String> = Vec::new();
+    for module in embedded
+        .lines()
+        .map(str::trim)
+        .chain(
+            cmdline
+                .get_all(param)
+                .flat_map(|modules| modules.split(',')),
+        )
+        .filter(|module| !module.is_empty() && !module.starts_with('#'))
+        .map(modules::normalize_name)
+    {
+        if !listed.contains(&module) {
+            listed.push(module);
+        }
+    }
+
+    listed
+}
+
+/// Decompress a zstd module, with the dictionary when the frame has been compressed with one
+fn decompress_zstd(contents: &[u8], dictionary: Option<&DecoderDictionary>) -> Result<Vec<u8>> {
+    let dict_id = zstd::zstd_safe::get_dict_id_from_frame(contents);
+    let mut buf = Vec::new();
+    match (dict_id, dictionary) {
+        (None, _) => buf = zstd::stream::decode_all(contents)?,
+        (Some(_), Some(dictionary)) => {
+            _ = zstd::stream::read::Decoder::with_prepared_dictionary(contents, dictionary)?
+                .read_to_end(&mut buf)?
+        }
+        (Some(dict_id), None) => bail!(
+            "compressed with the dictionary {}, but {} is missing",
+            dict_id,
+            DICTIONARY
+        ),
+    }
+
+    Ok(buf)
+}
+
+fn is_storage_driver(filename: &str) -> bool {
+    STORAGE_DRIVER_DIRS
+        .iter()
+        .any(|dir| filename.starts_with(dir))
 }
 
 impl ModuleLoader {
-    pub fn init(kernel_version: &str) -> Result<ModuleLoader> {
+    pub fn init(kernel_version: &str, cmdline: &Cmdline) -> Result<ModuleLoader> {
         let kernel_root = Path::new("/lib/modules").join(kernel_version);
         let mut modules = HashSet::new();
 
         modules.reserve(glob(&kernel_root.join("*.ko.{xz,zst}").as_os_str().to_string_lossy())?.count());
+        let manifest = match module_manifest::is_verify_requested(cmdline) {
+            true => {
+                let manifest = ModuleManifest::load()?;
+                if manifest.is_none() {
+                    warn!("rd.verify=1 given but the initramfs has no module manifest");
+                }
+                manifest
+            }
+            false => None,
+        };
+        let read_index = |index: &str| {
+            let path = kernel_root.join(index);
+            fs::read_to_string(&path).with_context(|| format!("unable to read {:?}", path))
+        };
+        let aliases = modules::parse_modules_alias(&read_index(modules::MODULES_ALIAS)?)
+            .into_iter()
+            .filter_map(|alias| match Pattern::new(&alias.pattern) {
+                Ok(pattern) => Some(ModAlias {
+                    pattern,
+                    module: alias.module,
+                }),
+                Err(err) => {
+                    warn!("invalid modalias {}: {}", alias.pattern, err);
+                    None
+                }
+            })
+            .collect();
+        // Older initramfs images do not ship them
+        let softdeps = read_index(modules::MODULES_SOFTDEP)
+            .map(|softdep| modules::parse_modules_softdep(&softdep))
+            .unwrap_or_default();
+        let builtin = read_index(modules::MODULES_BUILTIN)
+            .map(|builtin| modules::parse_modules_builtin(&builtin))
+            .unwrap_or_default();
+        let mut modules_dep = modules::parse_modules_dep(&read_index(modules::MODULES_DEP)?);
+        for problem in modules::check_modules_dep(&mut modules_dep) {
+            warn!("{}", problem);
+        }
         Ok(ModuleLoader {
-            modules: parse_module_dep(&kernel_root.join("modules.dep"))?,
-            aliases: parse_module_alias(&kernel_root.join("modules.alias"))?,
+            modules: modules_dep,
+            aliases,
+            softdeps,
+            builtin,
+            alias_blacklist: get_alias_blacklist_from_cmdline(cmdline),
             modules_loaded: RwLock::new(modules),
+            failed_modules: RwLock::new(HashMap::new()),
             kernel_root,
+            signature_policy: SignaturePolicy::from_sysfs(),
+            manifest,
+            dictionary: fs::read(DICTIONARY)
+                .ok()
+                .map(|dictionary| DecoderDictionary::copy(&dictionary)),
         })
     }
 
     pub fn load_module(&self, module_name: &str) -> Result<bool> {
+        let module_name = &modules::normalize_name(module_name);
+        if self.builtin.contains(module_name) {
+            return Ok(true);
+        }
+        if let Some(err) = self.failed_modules.read().unwrap().get(module_name) {
+            bail!("module {} failed to load: {}", module_name, err);
+        }
         let modules_loaded = self.modules_loaded.read().unwrap();
         if !modules_loaded.contains(module_name) {
             drop(modules_loaded);
@@ -135,6 +292,8 @@ impl ModuleLoader {
                 return Ok(false);
             }
             let module = module.unwrap();
+            let softdep = self.softdeps.get(module_name);
+            self.load_softdeps(softdep.map(|softdep| &softdep.pre));
             // Some modules could be builtin, do not block
             module.deps.iter().try_for_each(|dep| -> Result<()> {
                 self.load_module(dep)?;
@@ -146,32 +305,162 @@ impl ModuleLoader {
             }
             // unlock so that other modules can be loaded in parallel
             drop(modules_loaded);
-            let filename = self.kernel_root.join(&module.filename);
-            let module_file =
-                File::open(&filename).with_context(|| format!("unable to find {:?}", filename))?;
-
-            let mut buf = Vec::new();
-            match FileFormat::from_file(&filename)? {
-                FileFormat::Zstandard => buf = zstd::stream::decode_all(BufReader::new(module_file))?,
-                FileFormat::Xz => _ = XzDecoder::new(BufReader::new(module_file)).read_to_end(&mut buf)?,
-                unknown_format => warn!("unsupported format for module {}: {}", filename.to_str().unwrap(), unknown_format)
+            if let Err(err) = self.insert_module(module_name, module) {
+                self.failed_modules
+                    .write()
+                    .unwrap()
+                    .insert(module_name.to_string(), format!("{:#}", err));
+                self.modules_loaded.write().unwrap().remove(module_name);
+                return Err(err);
             }
-
-            init_module(&buf, &CString::new("")?).with_context(|| {
-                format!("finit_module call failed when loading {}", module_name)
-            })?;
+            self.load_softdeps(softdep.map(|softdep| &softdep.post));
         }
 
         Ok(true)
     }
 
-    pub fn load_modalias(&self, modalias: &str) -> Result<()> {
+    /// The soft dependencies are optional, a failure is only logged
+    fn load_softdeps(&self, softdeps: Option<&Vec<String>>) {
+        for softdep in softdeps.into_iter().flatten() {
+            match self.load_module(softdep) {
+                Ok(true) => {}
+                Ok(false) => debug!("soft dependency {} not found", softdep),
+                Err(err) => warn!("{:?}", err),
+            }
+        }
+    }
+
+    /// Read the module file, decompressed, checking it against the manifest if any
+    fn read_module(&self, filename: &Path) -> Result<Vec<u8>> {
+        let contents =
+            fs::read(filename).with_context(|| format!("unable to find {:?}", filename))?;
+        if let Some(manifest) = &self.manifest {
+            manifest.verify(filename, &contents)?;
+        }
+
+        Ok(match FileFormat::from_bytes(&contents) {
+            FileFormat::Zstandard => decompress_zstd(&contents, self.dictionary.as_ref())
+                .with_context(|| format!("unable to decompress {:?}", filename))?,
+            FileFormat::Xz => {
+                let mut buf = Vec::new();
+                XzDecoder::new(contents.as_slice()).read_to_end(&mut buf)?;
+                buf
+            }
+            FileFormat::ExecutableAndLinkableFormat => contents,
+            unknown_format => bail!(
+                "unsupported format for module {:?}: {}",
+                filename,
+                unknown_format
+            ),
+        })
+    }
+
+    /// Read the module file and load it into the kernel, unsigned modules are not even tried
+    /// when the kernel would reject them
+    fn insert_module(&self, module_name: &str, module: &Module) -> Result<()> {
+        let buf = self.read_module(&self.kernel_root.join(&module.filename))?;
+
+        // Out-of-tree modules are often unsigned, do not even try loading them
+        if self.signature_policy != SignaturePolicy::Permissive && !is_signed(&buf) {
+            bail!(
+                "module {} skipped: {}",
+                module_name,
+                self.signature_policy.get_reason()
+            );
+        }
+
+        init_module(&buf, &CString::new("")?).map_err(|err| {
+            anyhow::Error::new(err).context(get_load_error(
+                module_name,
+                err,
+                &self.signature_policy,
+            ))
+        })?;
+
+        Ok(())
+    }
+
+    /// Load the platform drivers that cannot be found by modalias, e.g. hv_storvsc or
+    /// xen-blkfront, from the preload_modules of mkinitrz and rd.driver.pre=
+    pub fn load_preload_modules(&self, cmdline: &Cmdline) {
+        let embedded = fs::read_to_string(PRELOAD_MODULES).unwrap_or_default();
+        self.load_listed_modules(get_listed_modules(&embedded, cmdline, PRELOAD_PARAM));
+    }
+
+    /// Load the modules given by rd.driver.post=, once the root has been found. They are
+    /// expected by the real init, e.g. the watchdog or the sensor drivers
+    pub fn load_post_modules(&self, cmdline: &Cmdline) {
+        self.load_listed_modules(get_listed_modules("", cmdline, POST_PARAM));
+    }
+
+    /// A failure is only logged, the driver could be missing on this machine
+    fn load_listed_modules(&self, modules: Vec<String>) {
+        for module in modules {
+            match self.load_module(&module) {
+                Ok(true) => {}
+                Ok(false) => warn!("module {} not found", module),
+                Err(err) => warn!("{:?}", err),
+            }
+        }
+    }
+
+    /// Load every storage driver in the initramfs, for the devices that have not been
+    /// detected by their modalias
+    pub fn load_storage_modules(&self) {
+        self.load_modules(|module| is_storage_driver(&module.filename));
+    }
+
+    /// Load every module in the initramfs, not only the ones matching a modalias
+    pub fn load_all_modules(&self) {
+        self.load_modules(|_| true);
+    }
+
+    /// Load the modules matching the filter in order, a failure is only logged
+    fn load_modules(&self, filter: impl Fn(&Module) -> bool) {
+        let mut modules: Vec<&String> = self
+            .modules
+            .iter()
+            .filter(|(_, module)| filter(module))
+            .map(|(name, _)| name)
+            .collect();
+        modules.sort();
+        for module in modules {
+            if let Err(err) = self.load_module(module) {
+                warn!("{:?}", err);
+            }
+        }
+    }
+
+    /// Load the module matching the modalias. A failure is only logged, so that one broken
+    /// module, e.g. an out-of-tree one with unknown symbols, does not stop the others
+    pub fn load_modalias(&self, modalias: &str) {
+        if self
+            .alias_blacklist
+            .iter()
+            .any(|pattern| pattern.matches(modalias))
+        {
+            debug!("ignoring device {}, blacklisted", modalias);
+            return;
+        }
         let modalias = &self.aliases.iter().find(|m| m.pattern.matches(modalias));
         if let Some(modalias) = modalias {
-            self.load_module(&modalias.module)?;
+            if let Err(err) = self.load_module(&modalias.module) {
+                warn!("{:?}", err);
+            }
         }
+    }
 
-        Ok(())
+    /// Get the modules that could not be loaded, with the reason
+    pub fn get_failed_modules(&self) -> Vec<String> {
+        let mut failed: Vec<String> = self
+            .failed_modules
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(module, err)| format!("{} ({})", module, err))
+            .collect();
+        failed.sort();
+        failed
     }
 }
 
@@ -180,46 +469,203 @@ mod tests {
     use super::*;
 
     #[test]
-    fn parse_module_dep_test() {
-        let filename = Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/test/module.dep"));
-        let map = parse_module_dep(filename).unwrap();
-
-        let mut expected_map = HashMap::new();
-
-        let mhi_deps: Vec<String> = ["mhi", "ns", "qrtr"]
-            .iter()
-            .map(|s| s.to_string())
-            .collect();
-        expected_map.insert(
-            "qrtr-mhi".to_string(),
+    fn failed_module_test() {
+        let mut modules = HashMap::new();
+        modules.insert(
+            "missing".to_string(),
             Module {
-                filename: String::from("kernel/net/qrtr/qrtr-mhi.ko.xz"),
-                deps: mhi_deps,
+                filename: "kernel/missing.ko.zst".to_string(),
+                deps: Vec::new(),
             },
         );
+        let module_loader = ModuleLoader {
+            modules,
+            aliases: vec![ModAlias {
+                pattern: Pattern::new("pci:v00001234d*").unwrap(),
+                module: "missing".to_string(),
+            }],
+            softdeps: HashMap::new(),
+            builtin: HashSet::new(),
+            alias_blacklist: get_alias_blacklist_from_cmdline(&Cmdline::from(vec![
+                "rd.alias.blacklist=usb:v046D*,pci:v00001234d00009999*".to_string(),
+            ])),
+            modules_loaded: RwLock::new(HashSet::new()),
+            failed_modules: RwLock::new(HashMap::new()),
+            kernel_root: std::env::temp_dir().join("initrz-missing-kernel"),
+            signature_policy: SignaturePolicy::Permissive,
+            manifest: None,
+            dictionary: None,
+        };
+        module_loader.load_modalias("pci:v00001234d00009999sv00001AF4");
+        assert!(module_loader.get_failed_modules().is_empty());
+        module_loader.load_modalias("pci:v00001234d00005678");
+        assert_eq!(module_loader.get_failed_modules().len(), 1);
+        assert!(module_loader.get_failed_modules()[0].starts_with("missing (unable to find"));
+        // The failure is reported again instead of the module looking loaded
+        assert!(module_loader.load_module("missing").is_err());
+        assert!(!module_loader.load_module("unknown").unwrap());
+    }
 
-        let nvidia_uvm_deps: Vec<String> = vec!["nvidia".to_string()];
-        expected_map.insert(
-            "nvidia-uvm".to_string(),
-            Module {
-                filename: String::from("kernel/drivers/video/nvidia-uvm.ko.xz"),
-                deps: nvidia_uvm_deps,
-            },
+    #[test]
+    fn unsigned_module_test() {
+        let kernel_root = std::env::temp_dir().join("initrz-unsigned-kernel");
+        fs::create_dir_all(kernel_root.join("kernel")).unwrap();
+        let unsigned = b"\x7fELF...".to_vec();
+        let signed = [&unsigned[..], MODULE_SIGNATURE_MAGIC].concat();
+        let files = vec![
+            (
+                "unsigned.ko.zst",
+                zstd::stream::encode_all(&unsigned[..], 3).unwrap(),
+            ),
+            (
+                "signed.ko.zst",
+                zstd::stream::encode_all(&signed[..], 3).unwrap(),
+            ),
+            ("signed.ko", signed.clone()),
+            ("unknown.ko", b"not a module".to_vec()),
+        ];
+        let mut modules = HashMap::new();
+        for (filename, contents) in files {
+            fs::write(kernel_root.join("kernel").join(filename), contents).unwrap();
+            modules.insert(
+                filename.split('.').next().unwrap().to_string(),
+                Module {
+                    filename: format!("kernel/{}", filename),
+                    deps: Vec::new(),
+                },
+            );
+        }
+        let module_loader = ModuleLoader {
+            modules,
+            aliases: Vec::new(),
+            softdeps: HashMap::new(),
+            builtin: HashSet::new(),
+            alias_blacklist: Vec::new(),
+            modules_loaded: RwLock::new(HashSet::new()),
+            failed_modules: RwLock::new(HashMap::new()),
+            kernel_root: kernel_root.clone(),
+            signature_policy: SignaturePolicy::Enforced,
+            manifest: None,
+            dictionary: None,
+        };
+
+        // The signature is looked for in the decompressed module
+        let read = |filename: &str| module_loader.read_module(&kernel_root.join(filename));
+        assert_eq!(read("kernel/signed.ko.zst").unwrap(), signed);
+        assert_eq!(read("kernel/signed.ko").unwrap(), signed);
+        assert_eq!(read("kernel/unsigned.ko.zst").unwrap(), unsigned);
+        assert!(read("kernel/unknown.ko").is_err());
+
+        assert!(module_loader.load_module("unsigned").is_err());
+        assert_eq!(
+            module_loader.get_failed_modules(),
+            vec!["unsigned (module unsigned skipped: unsigned, module.sig_enforce is set)"]
         );
+        assert!(!module_loader
+            .modules_loaded
+            .read()
+            .unwrap()
+            .contains("unsigned"));
+        fs::remove_dir_all(kernel_root).unwrap();
+    }
 
-        expected_map.insert(
-            "nvidia".to_string(),
-            Module {
-                filename: String::from("kernel/drivers/video/nvidia.ko.xz"),
-                deps: Vec::new(),
-            },
+    #[test]
+    fn alias_blacklist_test() {
+        let blacklist = get_alias_blacklist_from_cmdline(&Cmdline::from(vec![
+            "rd.alias.blacklist=pci:v000010DE*,".to_string(),
+            "rd.alias.blacklist=usb:v046Dp[".to_string(),
+            "rd.alias.blacklist=acpi*:PNP0C0A:*".to_string(),
+        ]));
+        assert_eq!(blacklist.len(), 2);
+        assert!(blacklist[0].matches("pci:v000010DEd00001C82sv00001043"));
+        assert!(!blacklist[0].matches("pci:v00008086d00001C82"));
+    }
+
+    #[test]
+    fn is_hostonly_disabled_test() {
+        assert!(!is_hostonly_disabled(&Cmdline::parse("quiet")));
+        assert!(is_hostonly_disabled(&Cmdline::parse(
+            "rd.hostonly=1 rd.hostonly=0"
+        )));
+        assert!(!is_hostonly_disabled(&Cmdline::parse(
+            "rd.hostonly=0 rd.hostonly=1"
+        )));
+    }
+
+    #[test]
+    fn get_listed_modules_test() {
+        let cmdline = Cmdline::parse("rd.driver.pre=xen-blkfront,hv_storvsc, rd.driver.pre=virtio_blk rd.driver.post=iTCO_wdt");
+        assert_eq!(
+            get_listed_modules(
+                "# platform drivers\nvirtio_pci\n\nhv-storvsc\n",
+                &cmdline,
+                PRELOAD_PARAM
+            ),
+            vec!["virtio_pci", "hv_storvsc", "xen_blkfront", "virtio_blk"]
         );
+        assert_eq!(
+            get_listed_modules("", &cmdline, POST_PARAM),
+            vec!["iTCO_wdt"]
+        );
+        assert!(get_listed_modules("", &Cmdline::parse("quiet"), PRELOAD_PARAM).is_empty());
+    }
 
-        assert_eq!(map.len(), 3);
-        for (module_name, module) in map {
-            let expected_module = expected_map.get(&module_name).expect("no module found");
-            assert_eq!(module.filename, expected_module.filename);
-            assert_eq!(module.deps, expected_module.deps);
-        }
+    #[test]
+    fn is_storage_driver_test() {
+        assert!(is_storage_driver("kernel/drivers/ata/ahci.ko.zst"));
+        assert!(is_storage_driver(
+            "kernel/drivers/usb/storage/usb-storage.ko.xz"
+        ));
+        assert!(!is_storage_driver(
+            "kernel/drivers/net/e1000e/e1000e.ko.zst"
+        ));
+    }
+
+    #[test]
+    fn signature_policy_test() {
+        assert_eq!(
+            parse_lockdown("none [integrity] confidentiality\n"),
+            Some("integrity".to_string())
+        );
+        assert_eq!(parse_lockdown("[none] integrity confidentiality\n"), None);
+        assert!(is_signed(b"\x7fELF...~Module signature appended~\n"));
+        assert!(!is_signed(b"\x7fELF..."));
+        assert_eq!(
+            get_load_error(
+                "nvidia",
+                Errno::EPERM,
+                &SignaturePolicy::Lockdown("integrity".to_string())
+            ),
+            "module nvidia rejected: unsigned, kernel is in lockdown (integrity)"
+        );
+        assert_eq!(
+            get_load_error("nvidia", Errno::EPERM, &SignaturePolicy::Permissive),
+            "finit_module call failed when loading nvidia"
+        );
+    }
+
+    #[test]
+    fn decompress_zstd_test() {
+        let samples: Vec<Vec<u8>> = (0..256u32)
+            .map(|i| format!("\x7fELF alias=pci:v{:08X}d{:08X}sv*sd*bc*sc*i* license=GPL depends=scsi_mod,libata{}", i * 7, i * 13, i % 5).into_bytes())
+            .collect();
+        let dictionary = zstd::dict::from_samples(&samples, 1024).unwrap();
+        let compressed = zstd::bulk::Compressor::with_dictionary(3, &dictionary)
+            .unwrap()
+            .compress(&samples[0])
+            .unwrap();
+        let prepared = DecoderDictionary::copy(&dictionary);
+        assert_eq!(
+            decompress_zstd(&compressed, Some(&prepared)).unwrap(),
+            samples[0]
+        );
+        assert!(decompress_zstd(&compressed, None).is_err());
+
+        // The modules compressed without the dictionary are still read
+        let compressed = zstd::stream::encode_all(&samples[1][..], 3).unwrap();
+        assert_eq!(
+            decompress_zstd(&compressed, Some(&prepared)).unwrap(),
+            samples[1]
+        );
     }
 }
@@ -0,0 +1,155 @@
+// Verify the modules against the SHA256 manifest written by mkinitrz before loading them,
+// when rd.verify=1 is given. Unlike the kernel module signatures, it also covers the
+// out-of-tree and unsigned modules. The hashes are computed by the kernel crypto API
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use nix::sys::socket::{
+    accept, bind, send, socket, AddressFamily, AlgAddr, MsgFlags, SockFlag, SockType,
+};
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+const MANIFEST: &str = "/etc/initrz/modules.sha256";
+const VERIFY_PARAM: &str = "rd.verify";
+const SHA256_LEN: usize = 32;
+// Data sent to the hash socket at once
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn is_verify_requested(cmdline: &Cmdline) -> bool {
+    cmdline.get_bool(VERIFY_PARAM) == Some(true)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Hash the data with the sha256 implementation of the kernel
+fn sha256(data: &[u8]) -> Result<String> {
+    let alg = socket(
+        AddressFamily::Alg,
+        SockType::SeqPacket,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )
+    .context("unable to create an AF_ALG socket")?;
+    bind(alg.as_raw_fd(), &AlgAddr::new("hash", "sha256"))
+        .context("unable to bind to sha256, is CONFIG_CRYPTO_USER_API_HASH enabled?")?;
+    let fd = accept(alg.as_raw_fd()).context("unable to accept on the AF_ALG socket")?;
+    let mut hash = unsafe { File::from_raw_fd(fd) };
+    // MSG_MORE is not exposed by nix
+    let more = MsgFlags::from_bits_retain(libc::MSG_MORE);
+    for chunk in data.chunks(CHUNK_SIZE) {
+        let mut sent = 0;
+        while sent < chunk.len() {
+            sent += send(fd, &chunk[sent..], more).context("unable to hash data")?;
+        }
+    }
+    // Finalize the hash
+    send(fd, &[], MsgFlags::empty()).context("unable to hash data")?;
+    let mut digest = [0; SHA256_LEN];
+    hash.read_exact(&mut digest)
+        .context("unable to read the hash")?;
+
+    Ok(to_hex(&digest))
+}
+
+/// Hashes of the modules by their path in the initramfs, in the sha256sum(1) format
+#[derive(Debug, PartialEq, Eq)]
+pub struct ModuleManifest {
+    hashes: HashMap<String, String>,
+}
+
+impl ModuleManifest {
+    fn parse(manifest: &str) -> Result<ModuleManifest> {
+        let hashes = manifest
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                let (hash, path) = line
+                    .split_once(char::is_whitespace)
+                    .with_context(|| format!("invalid line in the manifest:\n{}", line))?;
+                // sha256sum marks the files read in binary mode with '*'
+                let path = path.trim_start().trim_start_matches('*');
+                if hash.len() != SHA256_LEN * 2 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    bail!("invalid hash for {} in the manifest", path);
+                }
+                Ok((path.to_string(), hash.to_ascii_lowercase()))
+            })
+            .collect::<Result<HashMap<String, String>>>()?;
+
+        Ok(ModuleManifest { hashes })
+    }
+
+    /// Read the manifest of the initramfs, None when there is none
+    pub fn load() -> Result<Option<ModuleManifest>> {
+        if !Path::new(MANIFEST).exists() {
+            return Ok(None);
+        }
+        let manifest =
+            fs::read_to_string(MANIFEST).with_context(|| format!("unable to read {}", MANIFEST))?;
+        ModuleManifest::parse(&manifest)
+            .map(Some)
+            .with_context(|| format!("invalid manifest {}", MANIFEST))
+    }
+
+    fn check(&self, path: &Path, hash: &str) -> Result<()> {
+        let path = path.to_string_lossy();
+        match self.hashes.get(path.as_ref()) {
+            Some(expected) if expected == hash => Ok(()),
+            Some(_) => bail!("module {} does not match its hash in the manifest", path),
+            None => bail!("module {} is not in the manifest", path),
+        }
+    }
+
+    /// Fail unless the contents of the module file match the manifest
+    pub fn verify(&self, path: &Path, contents: &[u8]) -> Result<()> {
+        self.check(path, &sha256(contents)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    #[test]
+    fn is_verify_requested_test() {
+        assert!(!is_verify_requested(&Cmdline::parse("quiet")));
+        assert!(is_verify_requested(&Cmdline::parse(
+            "rd.verify=0 rd.verify=1"
+        )));
+        assert!(!is_verify_requested(&Cmdline::parse(
+            "rd.verify=1 rd.verify=0"
+        )));
+    }
+
+    #[test]
+    fn check_test() {
+        let manifest = ModuleManifest::parse(&format!(
+            "{}  /lib/modules/6.6.1/kernel/fs/ext4/ext4.ko.zst
+{} */lib/modules/6.6.1/extra/zfs.ko.xz
+",
+            EMPTY_HASH,
+            EMPTY_HASH.to_ascii_uppercase()
+        ))
+        .unwrap();
+        let ext4 = Path::new("/lib/modules/6.6.1/kernel/fs/ext4/ext4.ko.zst");
+        assert!(manifest.check(ext4, EMPTY_HASH).is_ok());
+        assert!(manifest
+            .check(Path::new("/lib/modules/6.6.1/extra/zfs.ko.xz"), EMPTY_HASH)
+            .is_ok());
+        assert!(manifest.check(ext4, &"0".repeat(64)).is_err());
+        assert!(manifest
+            .check(Path::new("/lib/modules/6.6.1/extra/spl.ko.xz"), EMPTY_HASH)
+            .is_err());
+
+        assert!(ModuleManifest::parse("1234  /lib/modules/a.ko").is_err());
+        assert!(ModuleManifest::parse(EMPTY_HASH).is_err());
+    }
+}
@@ -1,16 +1,71 @@
 use std::{
-    env,
     ffi::CString,
-    fs::File,
-    os::unix::io::{AsRawFd, RawFd},
+    fs::{self, File},
+    os::unix::io::AsRawFd,
+    path::Path,
 };
 
-use anyhow::{Context, Result};
-use log::warn;
+use anyhow::{bail, Context, Result};
 use mount_api::{Fs, FsmountFlags, FsopenFlags, Mount, MountAttrFlags, MoveMountFlags};
+use nix::mount::{mount, MsFlags};
+use tracing::{info, instrument, warn};
 
+use crate::btrfs;
+use crate::device_registry::DeviceRegistry;
+use crate::filesystem::{get_filesystem_module, Filesystem};
+use crate::hooks::{self, Stage};
+use crate::loop_device;
 use crate::module_loader::ModuleLoader;
+use crate::overlay::{self, Overlay, Upper};
 use crate::root_device::RootDevice;
+use crate::snapshot;
+use crate::switch_root::NEW_ROOT;
+use crate::zfs;
+
+const OVERLAY_DIR: &str = "/run/initrz/overlay";
+const LIVE_DIR: &str = "/run/initrz/live";
+const SECURITYFS: &str = "/sys/kernel/security";
+
+/// A filesystem mounted at startup and moved into the new root at switch_root, so that
+/// the state in /run is kept. Nested mounts must come after their parent
+struct DefaultMount {
+    path: &'static str,
+    filesystem: &'static str,
+    options: &'static [(&'static str, Option<&'static str>)],
+}
+
+const DEFAULT_MOUNTS: [DefaultMount; 6] = [
+    DefaultMount {
+        path: "dev",
+        filesystem: "devtmpfs",
+        options: &[],
+    },
+    DefaultMount {
+        path: "dev/pts",
+        filesystem: "devpts",
+        options: &[("mode", Some("0620")), ("ptmxmode", Some("0666"))],
+    },
+    DefaultMount {
+        path: "dev/shm",
+        filesystem: "tmpfs",
+        options: &[("mode", Some("1777"))],
+    },
+    DefaultMount {
+        path: "sys",
+        filesystem: "sysfs",
+        options: &[],
+    },
+    DefaultMount {
+        path: "proc",
+        filesystem: "proc",
+        options: &[],
+    },
+    DefaultMount {
+        path: "run",
+        filesystem: "tmpfs",
+        options: &[("mode", Some("0755"))],
+    },
+];
 
 pub struct Mounts {
     mountpoints: Vec<(String, Mount)>,
@@ -20,52 +75,104 @@ pub struct Mounts {
 impl Mounts {
     pub fn with_default_mounts() -> Result<Mounts> {
         let root_file = File::open("/").with_context(|| "unable to open / dir")?;
-        Ok(Mounts {
-            mountpoints: [("dev", "devtmpfs"), ("sys", "sysfs"), ("proc", "proc")]
-                .iter()
-                .map(|(name, fs)| -> Result<(String, Mount)> {
-                    Ok((
-                        name.to_string(),
-                        mount_special_filesystem(root_file.as_raw_fd(), name, fs)
-                            .with_context(|| format!("unable to mount /{}", name))?,
-                    ))
-                })
-                .collect::<Result<Vec<(_, _)>>>()?,
+        let mut mounts = Mounts {
+            mountpoints: Vec::new(),
             root_file,
-        })
+        };
+        for default_mount in &DEFAULT_MOUNTS {
+            let path = Path::new("/").join(default_mount.path);
+            fs::create_dir_all(&path).with_context(|| format!("unable to create {:?}", path))?;
+            let mount = mount_filesystem(default_mount.filesystem, default_mount.options)
+                .with_context(|| format!("unable to mount {:?}", path))?;
+            mounts.attach(&mount, &path)?;
+            // Nested mounts are moved together with their parent
+            if !default_mount.path.contains('/') {
+                mounts
+                    .mountpoints
+                    .push((default_mount.path.to_string(), mount));
+            }
+        }
+
+        Ok(mounts)
     }
 
-    pub fn mount_root(&self, root: RootDevice, module_loader: &ModuleLoader) -> Result<()> {
+    #[instrument(name = "mount", skip_all)]
+    pub fn mount_root(
+        &self,
+        root: RootDevice,
+        module_loader: &ModuleLoader,
+        device_registry: &DeviceRegistry,
+    ) -> Result<()> {
         // Load essential module
         module_loader.load_module("crc32c_generic")?;
 
-        let devname = root.devpath.unwrap();
-        let filesystem = root.filesystem.get_filesystem_string(&devname)?;
-        if !module_loader.load_module(&filesystem)? {
-            // Do not fail here because the module could be builtin
-            warn!("module {} not found", filesystem);
+        let mut devname = root.devpath.unwrap();
+        if let Some(image) = &root.live {
+            devname = self.setup_live_image(&devname, image, module_loader)?;
+        }
+        let mut btrfs_snapshot = None;
+        if let Some(snapshot) = &root.snapshot {
+            if let Some(device) = snapshot::get_lvm_snapshot(&devname, snapshot)? {
+                info!("booting LVM snapshot {}", snapshot);
+                devname = device;
+            } else {
+                btrfs_snapshot = Some(snapshot.as_str());
+            }
+        }
+        let mut filesystem = root.filesystem.get_filesystem_string(&devname)?;
+        load_filesystem_module(&filesystem, module_loader)?;
+        if let Some(nfs) = &root.nfs {
+            load_filesystem_module(nfs.get_module(), module_loader)?;
+            filesystem = nfs.get_filesystem().to_string();
+        }
+        if filesystem == "btrfs" {
+            // Every member of a multi-device filesystem must be known before mounting it
+            btrfs::scan_devices(device_registry)?;
+        }
+        if root.filesystem == Filesystem::Zfs {
+            zfs::import_pool(&devname, root.readonly)?;
         }
-        let filesystem = CString::new(filesystem)?;
 
-        let fs = Fs::open(&filesystem, FsopenFlags::empty()).with_context(|| {
-            format!(
-                "unable to open a filesystem context of type {:?}",
-                &filesystem
-            )
-        })?;
-        let source_str: CString = CString::new("source")?;
-        let devname = CString::new(devname)?;
-        fs.set_string(&source_str, &devname)
-            .with_context(|| format!("unable to set source {:?} for filesystem", devname))?;
-        fs.create().with_context(|| {
-            format!(
-                "unable to create filesystem context of type {:?} for device {:?}",
-                &filesystem, devname
-            )
-        })?;
-        let mount = fs
-            .mount(FsmountFlags::empty(), MountAttrFlags::empty())
+        let auto_overlay = root.auto_overlay;
+        let overlay = root.overlay.or_else(|| {
+            (auto_overlay && overlay::is_read_only_medium(&devname, &filesystem)).then(|| {
+                info!("the root medium is read-only, its changes are kept in memory");
+                Overlay {
+                    upper: Upper::Tmpfs,
+                }
+            })
+        });
+
+        let nfs_options = match &root.nfs {
+            Some(nfs) => nfs.get_mount_options()?,
+            None => Vec::new(),
+        };
+        let mut options = vec![("source", Some(devname.as_str()))];
+        options.extend(
+            nfs_options
+                .iter()
+                .map(|(key, value)| (key.as_str(), value.as_deref())),
+        );
+        // The lower layer of an overlay is never written
+        if root.readonly || overlay.is_some() {
+            options.push(("ro", None));
+        }
+        if root.filesystem == Filesystem::Zfs {
+            // Allow mounting datasets whose mountpoint property is not legacy
+            options.push(("zfsutil", None));
+        }
+        if let Some(snapshot) = btrfs_snapshot {
+            if filesystem != "btrfs" {
+                bail!("rd.snapshot= needs an LVM or a btrfs root");
+            }
+            info!("booting btrfs snapshot {}", snapshot);
+            options.push(("subvol", Some(snapshot)));
+        }
+        let mut mount = mount_filesystem(&filesystem, &options)
             .with_context(|| format!("unable to mount {:?}", devname))?;
+        if let Some(overlay) = &overlay {
+            mount = self.mount_overlay(mount, overlay, module_loader, device_registry)?;
+        }
 
         mount.move_mount(
             self.root_file.as_raw_fd(),
@@ -73,7 +180,7 @@ impl Mounts {
             MoveMountFlags::empty(),
         )?;
 
-        let new_root_dir = File::open("/new_root")?;
+        let new_root_dir = File::open(NEW_ROOT)?;
         self.mountpoints
             .iter()
             .try_for_each(|(name, mount)| -> Result<()> {
@@ -83,25 +190,156 @@ impl Mounts {
                         name.as_str(),
                         MoveMountFlags::empty(),
                     )
-                    .with_context(|| "unable to move root device into /")
+                    .with_context(|| format!("unable to move /{} into the new root", name))
             })?;
 
-        env::set_current_dir("/new_root")?;
+        // The hooks are deleted with the rest of the initramfs
+        hooks::run(Stage::Pivot);
 
+        Ok(())
+    }
+}
+
+impl Mounts {
+    /// Return the device containing the squashfs root of a live medium. The image is
+    /// attached to a loop device unless the device is a squashfs itself
+    fn setup_live_image(
+        &self,
+        devname: &str,
+        image: &str,
+        module_loader: &ModuleLoader,
+    ) -> Result<String> {
+        let filesystem = Filesystem::Auto.get_filesystem_string(devname)?;
+        if filesystem == "squashfs" {
+            return Ok(devname.to_string());
+        }
+
+        load_filesystem_module(&filesystem, module_loader)?;
+        let mount = mount_filesystem(&filesystem, &[("source", Some(devname)), ("ro", None)])
+            .with_context(|| format!("unable to mount live device {:?}", devname))?;
+        let live_dir = Path::new(LIVE_DIR);
+        fs::create_dir_all(live_dir).with_context(|| format!("unable to create {}", LIVE_DIR))?;
+        self.attach(&mount, live_dir)?;
+
+        let image_path = live_dir.join(image);
+        if !image_path.exists() {
+            bail!("unable to find live image {} in {:?}", image, devname);
+        }
+        if !module_loader.load_module("loop")? {
+            warn!("module loop not found");
+        }
+        loop_device::attach(&image_path)
+    }
+
+    /// Stack a writable overlayfs on top of the read-only root
+    fn mount_overlay(
+        &self,
+        lower: Mount,
+        overlay: &Overlay,
+        module_loader: &ModuleLoader,
+        device_registry: &DeviceRegistry,
+    ) -> Result<Mount> {
+        let lower_dir = Path::new(OVERLAY_DIR).join("lower");
+        let rw_dir = Path::new(OVERLAY_DIR).join("rw");
+        for dir in [&lower_dir, &rw_dir] {
+            fs::create_dir_all(dir).with_context(|| format!("unable to create {:?}", dir))?;
+        }
+        self.attach(&lower, &lower_dir)?;
+
+        let upper = match &overlay.upper {
+            Upper::Tmpfs => mount_filesystem("tmpfs", &[("mode", Some("0755"))])?,
+            Upper::Device(identifier) => {
+                let devname = identifier.get_path(device_registry)?;
+                let filesystem = Filesystem::Auto.get_filesystem_string(&devname)?;
+                load_filesystem_module(&filesystem, module_loader)?;
+                mount_filesystem(&filesystem, &[("source", Some(&devname))])
+                    .with_context(|| format!("unable to mount overlay device {:?}", devname))?
+            }
+        };
+        self.attach(&upper, &rw_dir)?;
+        let upper_dir = rw_dir.join("upper");
+        let work_dir = rw_dir.join("work");
+        for dir in [&upper_dir, &work_dir] {
+            fs::create_dir_all(dir).with_context(|| format!("unable to create {:?}", dir))?;
+        }
+
+        if !module_loader.load_module("overlay")? {
+            warn!("module overlay not found");
+        }
+        mount_filesystem(
+            "overlay",
+            &[
+                ("lowerdir", lower_dir.to_str()),
+                ("upperdir", upper_dir.to_str()),
+                ("workdir", work_dir.to_str()),
+            ],
+        )
+        .with_context(|| "unable to mount the overlay root")
+    }
+
+    /// Attach a mount to an absolute path of the initramfs
+    fn attach(&self, mount: &Mount, path: &Path) -> Result<()> {
         mount
-            .move_mount(self.root_file.as_raw_fd(), ".", MoveMountFlags::empty())
-            .with_context(|| "unable to move root device into /")?;
+            .move_mount(
+                self.root_file.as_raw_fd(),
+                path.strip_prefix("/")?,
+                MoveMountFlags::empty(),
+            )
+            .with_context(|| format!("unable to mount {:?}", path))
+    }
+}
 
-        Ok(())
+/// Mount securityfs, needed to read the lockdown mode and to load the IMA policy. It
+/// is moved into the new root together with /sys
+pub fn mount_securityfs() -> Result<()> {
+    let mounted = fs::read_dir(SECURITYFS)
+        .map(|mut entries| entries.next().is_some())
+        .unwrap_or(false);
+    if mounted {
+        return Ok(());
+    }
+    mount(
+        Some("securityfs"),
+        SECURITYFS,
+        Some("securityfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        None::<&str>,
+    )
+    .with_context(|| format!("unable to mount securityfs on {}", SECURITYFS))
+}
+
+pub fn load_filesystem_module(filesystem: &str, module_loader: &ModuleLoader) -> Result<()> {
+    let module = get_filesystem_module(filesystem);
+    if !module_loader.load_module(module)? {
+        // Do not fail here because the module could be builtin
+        warn!("module {} not found", module);
     }
+
+    Ok(())
 }
 
-fn mount_special_filesystem(parent_dir: RawFd, mount_folder: &str, fs_name: &str) -> Result<Mount> {
-    let fs = Fs::open(&CString::new(fs_name)?, FsopenFlags::empty())
-        .with_context(|| format!("failed to open a filesystem context of type {}", fs_name))?;
-    fs.create()
-        .with_context(|| format!("unable to create filesystem context for type {}", fs_name))?;
-    let mount = fs.mount(FsmountFlags::empty(), MountAttrFlags::empty())?;
-    mount.move_mount(parent_dir, mount_folder, MoveMountFlags::empty())?;
-    Ok(mount)
+/// Create a new mount, options without a value are set as flags
+fn mount_filesystem(filesystem: &str, options: &[(&str, Option<&str>)]) -> Result<Mount> {
+    let fs = Fs::open(&CString::new(filesystem)?, FsopenFlags::empty()).with_context(|| {
+        format!(
+            "unable to open a filesystem context of type {:?}",
+            filesystem
+        )
+    })?;
+    for (key, value) in options {
+        let key = CString::new(*key)?;
+        match value {
+            Some(value) => fs.set_string(&key, &CString::new(*value)?),
+            None => fs.set_flag(&key),
+        }
+        .with_context(|| format!("unable to set option {:?} for {}", key, filesystem))?;
+    }
+    fs.create().with_context(|| {
+        format!(
+            "unable to create filesystem context of type {:?}",
+            filesystem
+        )
+    })?;
+
+    Ok(fs.mount(FsmountFlags::empty(), MountAttrFlags::empty())?)
 }
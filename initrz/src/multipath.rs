@@ -0,0 +1,256 @@
+// Assemble dm-multipath devices for disks reachable through more than one path, so
+// that the root is never mounted through a single path
+// https://docs.kernel.org/admin-guide/device-mapper/dm-multipath.html
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use tracing::{debug, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+use crate::device_mapper::{self, Target};
+
+const MULTIPATH_PARAM: &str = "rd.multipath";
+pub const MULTIPATH_MODULES: [&str; 2] = ["dm-multipath", "dm-round-robin"];
+const ROUND_ROBIN_REPEAT_COUNT: u32 = 1000;
+
+pub fn is_enabled(cmdline: &Cmdline) -> bool {
+    cmdline.get_bool(MULTIPATH_PARAM) != Some(false)
+}
+
+fn get_sysfs_path(path: &str) -> Option<std::path::PathBuf> {
+    Path::new(path)
+        .file_name()
+        .map(|name| Path::new("/sys/class/block").join(name))
+}
+
+fn read_sysfs(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)
+        .with_context(|| format!("unable to read {:?}", path))?
+        .trim()
+        .to_string())
+}
+
+/// Get the World Wide Identifier of a whole disk, partitions have none
+fn get_wwid(path: &str) -> Option<String> {
+    let sysfs = get_sysfs_path(path)?;
+    if sysfs.join("partition").exists() {
+        return None;
+    }
+    ["device/wwid", "wwid"]
+        .iter()
+        .find_map(|file| read_sysfs(&sysfs.join(file)).ok())
+        .filter(|wwid| !wwid.is_empty())
+}
+
+struct Partition {
+    name: String,
+    number: u32,
+    /// Start and size in sectors
+    start: u64,
+    size: u64,
+}
+
+fn get_partitions(path: &str) -> Result<Vec<Partition>> {
+    let sysfs = match get_sysfs_path(path) {
+        Some(sysfs) => sysfs,
+        None => return Ok(Vec::new()),
+    };
+    let mut partitions = Vec::new();
+    for entry in fs::read_dir(&sysfs).with_context(|| format!("unable to read {:?}", sysfs))? {
+        let entry = entry?;
+        let number = match read_sysfs(&entry.path().join("partition")) {
+            Ok(number) => number.parse()?,
+            Err(_) => continue,
+        };
+        partitions.push(Partition {
+            name: entry.file_name().to_string_lossy().to_string(),
+            number,
+            start: read_sysfs(&entry.path().join("start"))?.parse()?,
+            size: read_sysfs(&entry.path().join("size"))?.parse()?,
+        });
+    }
+    partitions.sort_by_key(|partition| partition.number);
+
+    Ok(partitions)
+}
+
+/// Device-mapper names cannot contain slashes, and spaces are unpractical
+fn get_dm_name(wwid: &str) -> String {
+    wwid.chars()
+        .map(|c| {
+            if c.is_whitespace() || c == '/' {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
+
+fn get_table(size: u64, devices: &[String]) -> Target {
+    // no features, no hardware handler, a single round-robin priority group
+    let paths = devices
+        .iter()
+        .map(|device| format!("{} {}", device, ROUND_ROBIN_REPEAT_COUNT))
+        .collect::<Vec<String>>()
+        .join(" ");
+    Target {
+        start: 0,
+        length: size,
+        target_type: "multipath".to_string(),
+        params: format!("0 0 1 1 round-robin 0 {} 1 {}", devices.len(), paths),
+    }
+}
+
+/// Group the disks by their WWID and create a multipath device for each one
+/// having more than one path
+#[derive(Default)]
+pub struct MultipathActivator {
+    /// WWIDs mapped to the disks sharing it
+    paths: HashMap<String, Vec<String>>,
+    /// Disks and partitions used by a multipath device
+    members: HashSet<String>,
+    activated: HashSet<String>,
+}
+
+impl MultipathActivator {
+    /// Return true if the device is a path of a multipath device and must not be
+    /// used directly
+    pub fn is_member(&self, path: &str) -> bool {
+        self.members.contains(path)
+    }
+
+    pub fn add_path(&mut self, path: &str) -> Result<()> {
+        let wwid = match get_wwid(path) {
+            Some(wwid) => wwid,
+            None => return Ok(()),
+        };
+        let paths = self.paths.entry(wwid.clone()).or_default();
+        if paths.iter().any(|p| p == path) {
+            return Ok(());
+        }
+        debug!("found path {} for wwid {}", path, wwid);
+        paths.push(path.to_string());
+        if paths.len() < 2 {
+            return Ok(());
+        }
+
+        for path in paths.iter() {
+            self.members.insert(path.clone());
+            for partition in get_partitions(path)? {
+                self.members.insert(
+                    Path::new(path)
+                        .with_file_name(partition.name)
+                        .to_string_lossy()
+                        .to_string(),
+                );
+            }
+        }
+
+        // The multipath device already exists, add the new path to its table
+        if self.activated.contains(&wwid) {
+            if let Err(err) = self.activate(&wwid) {
+                warn!("unable to add {} to multipath device: {:?}", path, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create the multipath devices for all the disks with more than one path
+    pub fn activate_all(&mut self) -> Result<()> {
+        let pending = self
+            .paths
+            .iter()
+            .filter(|(wwid, paths)| paths.len() > 1 && !self.activated.contains(*wwid))
+            .map(|(wwid, _)| wwid.clone())
+            .collect::<Vec<String>>();
+        for wwid in pending {
+            self.activated.insert(wwid.clone());
+            if let Err(err) = self.activate(&wwid) {
+                warn!("unable to create multipath device {}: {:?}", wwid, err);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn activate(&self, wwid: &str) -> Result<()> {
+        let paths = &self.paths[wwid];
+        let first = get_sysfs_path(&paths[0]).with_context(|| "invalid device path")?;
+        let size = read_sysfs(&first.join("size"))?.parse()?;
+        let devices = paths
+            .iter()
+            .map(|path| {
+                read_sysfs(
+                    &get_sysfs_path(path)
+                        .with_context(|| "invalid device path")?
+                        .join("dev"),
+                )
+            })
+            .collect::<Result<Vec<String>>>()?;
+        let table = [get_table(size, &devices)];
+
+        let name = get_dm_name(wwid);
+        if Path::new("/dev/mapper").join(&name).exists() {
+            return device_mapper::load_table(&name, &table, false);
+        }
+
+        info!(
+            "creating multipath device {} over {}",
+            name,
+            paths.join(", ")
+        );
+        let dev =
+            device_mapper::create_device(&name, Some(&format!("mpath-{}", wwid)), &table, false)?;
+
+        // The kernel does not scan partitions on device-mapper devices
+        for partition in get_partitions(&paths[0])? {
+            device_mapper::create_device(
+                &format!("{}-part{}", name, partition.number),
+                Some(&format!("part{}-mpath-{}", partition.number, wwid)),
+                &[Target {
+                    start: 0,
+                    length: partition.size,
+                    target_type: "linear".to_string(),
+                    params: format!(
+                        "{} {}",
+                        device_mapper::get_device_string(dev),
+                        partition.start
+                    ),
+                }],
+                false,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multipath_table_test() {
+        let target = get_table(2048, &Cmdline::parse("8:16 8:32"));
+        assert_eq!(target.length, 2048);
+        assert_eq!(
+            target.params,
+            "0 0 1 1 round-robin 0 2 1 8:16 1000 8:32 1000"
+        );
+    }
+
+    #[test]
+    fn multipath_cmdline_test() {
+        assert!(is_enabled(&Cmdline::default()));
+        assert!(!is_enabled(&Cmdline::parse("rd.multipath=0")));
+        assert_eq!(
+            get_dm_name("t10.ATA     QEMU HARDDISK/1"),
+            "t10.ATA_____QEMU_HARDDISK_1"
+        );
+    }
+}
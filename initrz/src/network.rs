@@ -0,0 +1,321 @@
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use tracing::info;
+
+use std::convert::{TryFrom, TryInto};
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::dhcp;
+use crate::hostname;
+use crate::rtnetlink;
+
+const IP_PARAM: &str = "ip";
+const NET_CLASS: &str = "/sys/class/net";
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// Recorded for the real system, next to the DHCP leases
+const RUN_RESOLV_CONF: &str = "/run/initrz/net/resolv.conf";
+const DEFAULT_PREFIX_LENGTH: u8 = 24;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(PartialEq, Eq, Debug, Default)]
+pub enum Autoconf {
+    #[default]
+    Dhcp,
+    Static,
+}
+
+impl TryFrom<&str> for Autoconf {
+    type Error = anyhow::Error;
+
+    fn try_from(autoconf: &str) -> Result<Autoconf> {
+        Ok(match autoconf {
+            "dhcp" | "on" | "any" => Autoconf::Dhcp,
+            "" | "off" | "none" | "static" => Autoconf::Static,
+            _ => bail!("{} is not a supported autoconfiguration method", autoconf),
+        })
+    }
+}
+
+/// Network configuration from
+/// ip=<client>:<server>:<gw>:<netmask>:<hostname>:<iface>:<autoconf>:<dns0>:<dns1>:<ntp0>,
+/// ip=<iface>:<autoconf> or ip=<autoconf>
+#[derive(PartialEq, Eq, Debug, Default)]
+pub struct IpConfig {
+    pub client: Option<Ipv4Addr>,
+    /// Used as NFS server when nfsroot= does not specify one
+    pub server: Option<Ipv4Addr>,
+    pub gateway: Option<Ipv4Addr>,
+    pub prefix_length: Option<u8>,
+    pub hostname: Option<String>,
+    pub device: Option<String>,
+    pub autoconf: Autoconf,
+    pub dns: Vec<Ipv4Addr>,
+}
+
+fn parse_address(address: &str) -> Result<Option<Ipv4Addr>> {
+    if address.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(address.parse().with_context(|| {
+        format!("{} is not a valid IPv4 address", address)
+    })?))
+}
+
+/// The netmask can be either in dotted form or a prefix length
+fn parse_netmask(netmask: &str) -> Result<Option<u8>> {
+    if let Ok(prefix_length) = netmask.parse::<u8>() {
+        if prefix_length > 32 {
+            bail!("{} is not a valid prefix length", netmask);
+        }
+        return Ok(Some(prefix_length));
+    }
+    let netmask = match parse_address(netmask)? {
+        Some(netmask) => u32::from(netmask),
+        None => return Ok(None),
+    };
+    if netmask.leading_ones() + netmask.trailing_zeros() != 32 {
+        bail!("{} is not a valid netmask", Ipv4Addr::from(netmask));
+    }
+
+    Ok(Some(netmask.leading_ones() as u8))
+}
+
+fn non_empty(field: &str) -> Option<String> {
+    Some(field)
+        .filter(|field| !field.is_empty())
+        .map(String::from)
+}
+
+impl TryFrom<&str> for IpConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(ip: &str) -> Result<IpConfig> {
+        let fields = ip.split(':').collect::<Vec<&str>>();
+        match fields.len() {
+            1 => {
+                return Ok(IpConfig {
+                    autoconf: ip.try_into()?,
+                    ..Default::default()
+                })
+            }
+            2 => {
+                return Ok(IpConfig {
+                    device: non_empty(fields[0]),
+                    autoconf: fields[1].try_into()?,
+                    ..Default::default()
+                })
+            }
+            _ => {}
+        }
+
+        let field = |index: usize| fields.get(index).copied().unwrap_or_default();
+        let config = IpConfig {
+            client: parse_address(field(0))?,
+            server: parse_address(field(1))?,
+            gateway: parse_address(field(2))?,
+            prefix_length: parse_netmask(field(3))?,
+            hostname: non_empty(field(4)),
+            device: non_empty(field(5)),
+            autoconf: field(6).try_into()?,
+            dns: [field(7), field(8)]
+                .iter()
+                .filter_map(|dns| parse_address(dns).transpose())
+                .collect::<Result<Vec<Ipv4Addr>>>()?,
+        };
+        if config.autoconf == Autoconf::Static && config.client.is_none() {
+            bail!("a client address is required for static configuration");
+        }
+
+        Ok(config)
+    }
+}
+
+/// Parse every ip= parameter, ip=off and ip=none alone disable the network
+pub fn get_ip_configs_from_cmdline(cmdline: &Cmdline) -> Result<Vec<IpConfig>> {
+    cmdline
+        .get_all(IP_PARAM)
+        .filter(|ip| *ip != "off" && *ip != "none")
+        .map(IpConfig::try_from)
+        .collect()
+}
+
+/// Wait for the network interface to appear, the first one found when no device is given
+fn wait_for_interface(device: Option<&str>, timeout: Duration) -> Result<String> {
+    let start = Instant::now();
+    loop {
+        let found = match device {
+            Some(device) => Path::new(NET_CLASS)
+                .join(device)
+                .exists()
+                .then(|| device.to_string()),
+            None => fs::read_dir(NET_CLASS)
+                .with_context(|| format!("unable to read {}", NET_CLASS))?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .filter(|name| name != "lo")
+                .min(),
+        };
+        if let Some(interface) = found {
+            return Ok(interface);
+        }
+        if start.elapsed() >= timeout {
+            bail!("timed out waiting for a network interface");
+        }
+        sleep(POLL_INTERVAL);
+    }
+}
+
+fn get_prefix_length(netmask: Ipv4Addr) -> u8 {
+    u32::from(netmask).leading_ones() as u8
+}
+
+fn write_resolv_conf(domain: Option<&str>, dns: &[Ipv4Addr]) -> Result<()> {
+    if dns.is_empty() {
+        return Ok(());
+    }
+    let mut resolv = String::new();
+    if let Some(domain) = domain {
+        resolv.push_str(&format!("search {}\n", domain));
+    }
+    for server in dns {
+        resolv.push_str(&format!("nameserver {}\n", server));
+    }
+
+    let run_resolv = Path::new(RUN_RESOLV_CONF);
+    if let Some(parent) = run_resolv.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("unable to create {:?}", parent))?;
+    }
+    fs::write(run_resolv, &resolv).with_context(|| format!("unable to write {:?}", run_resolv))?;
+    // Used by initrz itself to resolve host names
+    fs::write(RESOLV_CONF, &resolv).with_context(|| format!("unable to write {}", RESOLV_CONF))
+}
+
+/// Bring up the loopback interface, needed by the RPC services of network filesystems
+fn setup_loopback() -> Result<()> {
+    rtnetlink::set_link_up(rtnetlink::get_interface_index("lo")?)
+}
+
+/// Configure the interfaces given by ip=, or the first one found using DHCP
+pub fn setup_from_cmdline(cmdline: &Cmdline, timeout: Duration) -> Result<()> {
+    setup_loopback()?;
+    let mut ip_configs = get_ip_configs_from_cmdline(cmdline)?;
+    if ip_configs.is_empty() {
+        ip_configs.push(IpConfig::default());
+    }
+
+    ip_configs
+        .iter()
+        .try_for_each(|config| config.configure(timeout))
+}
+
+impl IpConfig {
+    /// Bring the interface up and configure its address and default route
+    pub fn configure(&self, timeout: Duration) -> Result<()> {
+        let interface = wait_for_interface(self.device.as_deref(), timeout)?;
+        info!("configuring network interface {}", interface);
+        let index = rtnetlink::get_interface_index(&interface)?;
+        rtnetlink::set_link_up(index)?;
+
+        match self.autoconf {
+            Autoconf::Dhcp => {
+                let lease = dhcp::request_lease(&interface)?;
+                info!("got address {} on {}", lease.address, interface);
+                rtnetlink::add_address(
+                    index,
+                    lease.address,
+                    lease
+                        .netmask
+                        .map(get_prefix_length)
+                        .or(self.prefix_length)
+                        .unwrap_or(DEFAULT_PREFIX_LENGTH),
+                )?;
+                if let Some(gateway) = lease.gateway {
+                    rtnetlink::add_default_route(index, gateway)?;
+                }
+                lease.save(&interface)?;
+                write_resolv_conf(lease.domain.as_deref(), &lease.dns)?;
+                // The hostname given by the server does not override the one of the user
+                let lease_hostname = lease
+                    .hostname
+                    .as_ref()
+                    .filter(|_| hostname::get().is_none());
+                if let Some(hostname) = self.hostname.as_ref().or(lease_hostname) {
+                    hostname::set(hostname)?;
+                }
+            }
+            Autoconf::Static => {
+                rtnetlink::add_address(
+                    index,
+                    self.client.unwrap(),
+                    self.prefix_length.unwrap_or(DEFAULT_PREFIX_LENGTH),
+                )?;
+                if let Some(gateway) = self.gateway {
+                    rtnetlink::add_default_route(index, gateway)?;
+                }
+                write_resolv_conf(None, &self.dns)?;
+                if let Some(hostname) = &self.hostname {
+                    hostname::set(hostname)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ip_config_test() {
+        assert_eq!(IpConfig::try_from("dhcp").unwrap(), IpConfig::default());
+        assert_eq!(
+            IpConfig::try_from("eth1:dhcp").unwrap(),
+            IpConfig {
+                device: Some("eth1".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            IpConfig::try_from(
+                "192.168.1.10:192.168.1.2:192.168.1.1:255.255.255.0:client:eth0:off:192.168.1.3"
+            )
+            .unwrap(),
+            IpConfig {
+                client: Some(Ipv4Addr::new(192, 168, 1, 10)),
+                server: Some(Ipv4Addr::new(192, 168, 1, 2)),
+                gateway: Some(Ipv4Addr::new(192, 168, 1, 1)),
+                prefix_length: Some(24),
+                hostname: Some("client".to_string()),
+                device: Some("eth0".to_string()),
+                autoconf: Autoconf::Static,
+                dns: vec![Ipv4Addr::new(192, 168, 1, 3)],
+            }
+        );
+        assert_eq!(
+            IpConfig::try_from("10.0.0.2::10.0.0.1:16::::")
+                .unwrap()
+                .prefix_length,
+            Some(16)
+        );
+        assert!(IpConfig::try_from(":::::eth0:off").is_err());
+        assert!(IpConfig::try_from("10.0.0.2::10.0.0.1:255.0.255.0:::off").is_err());
+        assert!(IpConfig::try_from("bootp").is_err());
+        assert_eq!(get_prefix_length(Ipv4Addr::new(255, 255, 240, 0)), 20);
+    }
+
+    #[test]
+    fn ip_configs_cmdline_test() {
+        let configs =
+            get_ip_configs_from_cmdline(&Cmdline::parse("ip=eth0:dhcp ip=eth1:dhcp")).unwrap();
+        assert_eq!(configs.len(), 2);
+        assert!(get_ip_configs_from_cmdline(&Cmdline::parse("ip=off"))
+            .unwrap()
+            .is_empty());
+    }
+}
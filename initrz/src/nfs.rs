@@ -0,0 +1,178 @@
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+
+use std::net::{IpAddr, ToSocketAddrs};
+
+use crate::network::get_ip_configs_from_cmdline;
+
+const NFS_ROOT_PREFIX: &str = "nfs:";
+const NFS4_ROOT_PREFIX: &str = "nfs4:";
+// Kernel style root=/dev/nfs nfsroot=[<server>:]<path>[,<options>]
+const KERNEL_NFS_ROOT: &str = "/dev/nfs";
+const NFSROOT_PARAM: &str = "nfsroot";
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct NfsRoot {
+    pub version4: bool,
+    pub server: String,
+    pub export: String,
+    pub options: Vec<String>,
+}
+
+fn parse_nfs_root(root: &str, version4: bool, options_separator: char) -> Result<NfsRoot> {
+    let (server, rest) = root
+        .split_once(':')
+        .with_context(|| format!("{} is not in the form <server>:<export>", root))?;
+    let (export, options) = match rest.split_once(options_separator) {
+        Some((export, options)) => (export, options),
+        None => (rest, ""),
+    };
+    if server.is_empty() || !export.starts_with('/') {
+        bail!("{} is not in the form <server>:<export>", root);
+    }
+
+    Ok(NfsRoot {
+        version4,
+        server: server.to_string(),
+        export: export.to_string(),
+        options: options
+            .split(',')
+            .filter(|option| !option.is_empty())
+            .map(String::from)
+            .collect(),
+    })
+}
+
+/// Parse root=nfs:<server>:<export>[:<options>], root=nfs4:... and
+/// root=/dev/nfs nfsroot=[<server>:]<export>[,<options>]
+pub fn get_nfs_root(root: &str, cmdline: &Cmdline) -> Result<Option<NfsRoot>> {
+    if let Some(root) = root.strip_prefix(NFS_ROOT_PREFIX) {
+        return parse_nfs_root(root, false, ':').map(Some);
+    }
+    if let Some(root) = root.strip_prefix(NFS4_ROOT_PREFIX) {
+        return parse_nfs_root(root, true, ':').map(Some);
+    }
+    if root == KERNEL_NFS_ROOT {
+        let nfsroot = cmdline
+            .get(NFSROOT_PARAM)
+            .with_context(|| format!("{} is required by root={}", NFSROOT_PARAM, root))?;
+        // Without a server, use the one given in ip=
+        let nfsroot = match nfsroot.starts_with('/') {
+            true => format!(
+                "{}:{}",
+                get_ip_configs_from_cmdline(cmdline)?
+                    .iter()
+                    .find_map(|config| config.server)
+                    .with_context(|| format!("no NFS server found for {}", nfsroot))?,
+                nfsroot
+            ),
+            false => nfsroot.to_string(),
+        };
+        let mut nfs = parse_nfs_root(&nfsroot, false, ',')?;
+        nfs.version4 = nfs
+            .options
+            .iter()
+            .any(|option| option.starts_with("vers=4") || option.starts_with("nfsvers=4"));
+        return Ok(Some(nfs));
+    }
+
+    Ok(None)
+}
+
+impl NfsRoot {
+    pub fn get_filesystem(&self) -> &str {
+        if self.version4 {
+            "nfs4"
+        } else {
+            "nfs"
+        }
+    }
+
+    pub fn get_module(&self) -> &str {
+        if self.version4 {
+            "nfsv4"
+        } else {
+            "nfsv3"
+        }
+    }
+
+    pub fn get_source(&self) -> String {
+        format!("{}:{}", self.server, self.export)
+    }
+
+    /// Options for the kernel NFS client. It needs the server address, because it
+    /// cannot resolve host names by itself
+    pub fn get_mount_options(&self) -> Result<Vec<(String, Option<String>)>> {
+        let address = match self.server.parse::<IpAddr>() {
+            Ok(address) => address,
+            Err(_) => (self.server.as_str(), 0)
+                .to_socket_addrs()
+                .with_context(|| format!("unable to resolve NFS server {}", self.server))?
+                .next()
+                .with_context(|| format!("unable to resolve NFS server {}", self.server))?
+                .ip(),
+        };
+
+        let mut options = vec![("addr".to_string(), Some(address.to_string()))];
+        // There is no rpc.statd in the initramfs
+        if !self.version4 {
+            options.push(("nolock".to_string(), None));
+        }
+        options.extend(
+            self.options
+                .iter()
+                .map(|option| match option.split_once('=') {
+                    Some((key, value)) => (key.to_string(), Some(value.to_string())),
+                    None => (option.clone(), None),
+                }),
+        );
+
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nfs_root_test() {
+        let nfs = get_nfs_root("nfs:192.168.1.1:/srv/root:ro,tcp", &Cmdline::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(nfs.get_filesystem(), "nfs");
+        assert_eq!(nfs.get_source(), "192.168.1.1:/srv/root");
+        assert_eq!(nfs.options, vec!["ro", "tcp"]);
+        assert_eq!(
+            nfs.get_mount_options().unwrap()[..2],
+            [
+                ("addr".to_string(), Some("192.168.1.1".to_string())),
+                ("nolock".to_string(), None)
+            ]
+        );
+
+        let nfs = get_nfs_root("nfs4:10.0.0.1:/", &Cmdline::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(nfs.get_filesystem(), "nfs4");
+        assert_eq!(nfs.export, "/");
+
+        let cmdline = Cmdline::parse("nfsroot=10.0.0.1:/export,vers=4.2");
+        let nfs = get_nfs_root("/dev/nfs", &cmdline).unwrap().unwrap();
+        assert!(nfs.version4);
+        assert_eq!(nfs.get_source(), "10.0.0.1:/export");
+
+        let cmdline = Cmdline::from(vec![
+            "nfsroot=/export".to_string(),
+            "ip=10.0.0.2:10.0.0.1::::eth0:off".to_string(),
+        ]);
+        let nfs = get_nfs_root("/dev/nfs", &cmdline).unwrap().unwrap();
+        assert_eq!(nfs.get_source(), "10.0.0.1:/export");
+
+        assert!(get_nfs_root("nfs:10.0.0.1", &Cmdline::default()).is_err());
+        assert!(get_nfs_root("/dev/nfs", &Cmdline::default()).is_err());
+        assert!(get_nfs_root("/dev/sda1", &Cmdline::default())
+            .unwrap()
+            .is_none());
+    }
+}
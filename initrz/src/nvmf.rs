@@ -0,0 +1,369 @@
+// NVMe over Fabrics discovery and connection, like `nvme connect-all`
+// https://github.com/torvalds/linux/blob/master/drivers/nvme/host/fabrics.c
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use nix::ioctl_readwrite;
+use tracing::{info, warn};
+
+use std::convert::{TryFrom, TryInto};
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use crate::module_loader::ModuleLoader;
+
+const NVME_FABRICS: &str = "/dev/nvme-fabrics";
+const NVME_CLASS: &str = "/sys/class/nvme";
+const HOSTNQN_FILE: &str = "/etc/nvme/hostnqn";
+const HOSTID_FILE: &str = "/etc/nvme/hostid";
+const DISCOVER_PARAM: &str = "rd.nvmf.discover";
+const HOSTNQN_PARAM: &str = "rd.nvmf.hostnqn";
+const HOSTID_PARAM: &str = "rd.nvmf.hostid";
+const DISCOVERY_NQN: &str = "nqn.2014-08.org.nvmexpress.discovery";
+const DEFAULT_DISCOVERY_PORT: &str = "8009";
+const FABRICS_MODULE: &str = "nvme-fabrics";
+
+const NVME_ADMIN_GET_LOG_PAGE: u8 = 0x02;
+const DISCOVERY_LOG_PAGE: u32 = 0x70;
+const DISCOVERY_HEADER_SIZE: usize = 1024;
+const DISCOVERY_ENTRY_SIZE: usize = 1024;
+const SUBTYPE_NVME: u8 = 2;
+const TRTYPE_RDMA: u8 = 1;
+const TRTYPE_FC: u8 = 2;
+const TRTYPE_TCP: u8 = 3;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[repr(C)]
+#[derive(Default)]
+struct NvmePassthruCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+ioctl_readwrite!(nvme_admin_cmd, b'N', 0x41, NvmePassthruCmd);
+
+/// A discovery controller given by
+/// rd.nvmf.discover=<transport>,<traddr>,[<host_traddr>],[<trsvcid>]
+#[derive(PartialEq, Eq, Debug)]
+pub struct DiscoveryController {
+    pub transport: String,
+    pub address: String,
+    pub host_address: Option<String>,
+    pub port: Option<String>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub struct NvmfConfig {
+    pub hostnqn: Option<String>,
+    pub hostid: Option<String>,
+    pub controllers: Vec<DiscoveryController>,
+}
+
+/// A subsystem found in the discovery log page
+#[derive(PartialEq, Eq, Debug)]
+struct Subsystem {
+    transport: String,
+    address: String,
+    port: String,
+    nqn: String,
+}
+
+fn non_empty(field: Option<&str>) -> Option<String> {
+    field.filter(|field| !field.is_empty()).map(String::from)
+}
+
+impl TryFrom<&str> for DiscoveryController {
+    type Error = anyhow::Error;
+
+    fn try_from(discover: &str) -> Result<DiscoveryController> {
+        let mut fields = discover.split(',');
+        let transport = non_empty(fields.next())
+            .with_context(|| format!("missing transport in {}", discover))?;
+        let address =
+            non_empty(fields.next()).with_context(|| format!("missing address in {}", discover))?;
+
+        Ok(DiscoveryController {
+            transport,
+            address,
+            host_address: non_empty(fields.next()),
+            port: non_empty(fields.next()),
+        })
+    }
+}
+
+fn read_host_file(param: Option<&str>, file: &str) -> Option<String> {
+    param
+        .map(String::from)
+        .or_else(|| {
+            fs::read_to_string(file)
+                .ok()
+                .map(|id| id.trim().to_string())
+        })
+        .filter(|id| !id.is_empty())
+}
+
+pub fn get_nvmf_config_from_cmdline(cmdline: &Cmdline) -> Result<Option<NvmfConfig>> {
+    let controllers = cmdline
+        .get_all(DISCOVER_PARAM)
+        .map(DiscoveryController::try_from)
+        .collect::<Result<Vec<DiscoveryController>>>()?;
+    if controllers.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(NvmfConfig {
+        hostnqn: read_host_file(cmdline.get(HOSTNQN_PARAM), HOSTNQN_FILE),
+        hostid: read_host_file(cmdline.get(HOSTID_PARAM), HOSTID_FILE),
+        controllers,
+    }))
+}
+
+fn get_transport_name(trtype: u8) -> Option<&'static str> {
+    match trtype {
+        TRTYPE_RDMA => Some("rdma"),
+        TRTYPE_FC => Some("fc"),
+        TRTYPE_TCP => Some("tcp"),
+        _ => None,
+    }
+}
+
+fn get_transport_module(transport: &str) -> String {
+    format!("nvme-{}", transport)
+}
+
+fn parse_string(field: &[u8]) -> String {
+    String::from_utf8_lossy(field)
+        .trim_end_matches(['\0', ' '])
+        .to_string()
+}
+
+/// Parse the NVM subsystems entries of a discovery log page
+fn parse_discovery_log(log: &[u8]) -> Vec<Subsystem> {
+    log[DISCOVERY_HEADER_SIZE.min(log.len())..]
+        .chunks_exact(DISCOVERY_ENTRY_SIZE)
+        .filter(|entry| entry[2] == SUBTYPE_NVME)
+        .filter_map(|entry| {
+            Some(Subsystem {
+                transport: get_transport_name(entry[0])?.to_string(),
+                port: parse_string(&entry[32..64]),
+                nqn: parse_string(&entry[256..512]),
+                address: parse_string(&entry[512..768]),
+            })
+        })
+        .collect()
+}
+
+fn get_log_page(device: &str, len: usize) -> Result<Vec<u8>> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(device)
+        .with_context(|| format!("unable to open {}", device))?;
+    let mut buf = vec![0u8; len];
+    let dwords = (len / 4 - 1) as u32;
+    let mut cmd = NvmePassthruCmd {
+        opcode: NVME_ADMIN_GET_LOG_PAGE,
+        addr: buf.as_mut_ptr() as u64,
+        data_len: len as u32,
+        cdw10: DISCOVERY_LOG_PAGE | ((dwords & 0xffff) << 16),
+        cdw11: dwords >> 16,
+        ..Default::default()
+    };
+    unsafe { nvme_admin_cmd(file.as_raw_fd(), &mut cmd) }
+        .with_context(|| format!("unable to get the discovery log page from {}", device))?;
+
+    Ok(buf)
+}
+
+impl NvmfConfig {
+    /// Ask the kernel to connect to a controller, returning its instance number
+    fn connect(&self, options: &str) -> Result<u32> {
+        let mut options = options.to_string();
+        if let Some(hostnqn) = &self.hostnqn {
+            options.push_str(&format!(",hostnqn={}", hostnqn));
+        }
+        if let Some(hostid) = &self.hostid {
+            options.push_str(&format!(",hostid={}", hostid));
+        }
+
+        let mut fabrics = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(NVME_FABRICS)
+            .with_context(|| format!("unable to open {}", NVME_FABRICS))?;
+        fabrics
+            .write_all(options.as_bytes())
+            .with_context(|| format!("unable to connect with {}", options))?;
+        let mut reply = String::new();
+        fabrics.read_to_string(&mut reply)?;
+
+        // instance=<n>,cntlid=<id>
+        reply
+            .trim()
+            .split(',')
+            .find_map(|field| field.strip_prefix("instance="))
+            .and_then(|instance| instance.parse().ok())
+            .with_context(|| format!("unexpected reply from {}: {}", NVME_FABRICS, reply))
+    }
+
+    fn discover(&self, controller: &DiscoveryController) -> Result<Vec<Subsystem>> {
+        let mut options = format!(
+            "nqn={},transport={},traddr={},trsvcid={}",
+            DISCOVERY_NQN,
+            controller.transport,
+            controller.address,
+            controller.port.as_deref().unwrap_or(DEFAULT_DISCOVERY_PORT)
+        );
+        if let Some(host_address) = &controller.host_address {
+            options.push_str(&format!(",host_traddr={}", host_address));
+        }
+        let instance = self.connect(&options)?;
+        let device = format!("/dev/nvme{}", instance);
+
+        let header = get_log_page(&device, DISCOVERY_HEADER_SIZE);
+        let subsystems = header.and_then(|header| {
+            let records = u64::from_le_bytes(header[8..16].try_into()?) as usize;
+            get_log_page(
+                &device,
+                DISCOVERY_HEADER_SIZE + records * DISCOVERY_ENTRY_SIZE,
+            )
+        });
+        // The discovery controller is not needed anymore
+        let delete = Path::new(NVME_CLASS)
+            .join(format!("nvme{}", instance))
+            .join("delete_controller");
+        if let Err(err) = fs::write(&delete, "1") {
+            warn!("unable to delete discovery controller {}: {}", device, err);
+        }
+
+        Ok(parse_discovery_log(&subsystems?))
+    }
+
+    /// Discover the subsystems and connect to them, waiting for their namespaces
+    pub fn connect_all(&self, module_loader: &ModuleLoader, timeout: Duration) -> Result<()> {
+        let mut modules = vec![FABRICS_MODULE.to_string()];
+        modules.extend(
+            self.controllers
+                .iter()
+                .map(|controller| get_transport_module(&controller.transport)),
+        );
+        for module in modules {
+            if !module_loader.load_module(&module)? {
+                // Do not fail here because the module could be builtin
+                warn!("module {} not found", module);
+            }
+        }
+
+        let mut instances = Vec::new();
+        for controller in &self.controllers {
+            for subsystem in self.discover(controller)? {
+                info!("connecting to NVMe subsystem {}", subsystem.nqn);
+                instances.push(self.connect(&format!(
+                    "nqn={},transport={},traddr={},trsvcid={}",
+                    subsystem.nqn, subsystem.transport, subsystem.address, subsystem.port
+                ))?);
+            }
+        }
+        if instances.is_empty() {
+            bail!("no NVMe subsystem found");
+        }
+
+        let start = Instant::now();
+        while !instances.iter().all(|instance| has_namespaces(*instance)) {
+            if start.elapsed() >= timeout {
+                bail!("timed out waiting for the NVMe namespaces");
+            }
+            sleep(POLL_INTERVAL);
+        }
+
+        Ok(())
+    }
+}
+
+fn has_namespaces(instance: u32) -> bool {
+    glob::glob(&format!("{}/nvme{}/nvme*n*", NVME_CLASS, instance))
+        .map(|mut namespaces| namespaces.next().is_some())
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem::size_of;
+
+    #[test]
+    fn passthru_layout_test() {
+        assert_eq!(size_of::<NvmePassthruCmd>(), 72);
+    }
+
+    #[test]
+    fn nvmf_cmdline_test() {
+        let cmdline = Cmdline::from(vec![
+            "rd.nvmf.discover=tcp,192.168.1.3,,4420".to_string(),
+            "rd.nvmf.hostnqn=nqn.2014-08.org.nvmexpress:uuid:1234".to_string(),
+        ]);
+        let config = get_nvmf_config_from_cmdline(&cmdline).unwrap().unwrap();
+        assert_eq!(
+            config.hostnqn.as_deref(),
+            Some("nqn.2014-08.org.nvmexpress:uuid:1234")
+        );
+        assert_eq!(
+            config.controllers,
+            vec![DiscoveryController {
+                transport: "tcp".to_string(),
+                address: "192.168.1.3".to_string(),
+                host_address: None,
+                port: Some("4420".to_string()),
+            }]
+        );
+        assert!(get_nvmf_config_from_cmdline(&Cmdline::parse("rd.nvmf.discover=tcp")).is_err());
+        assert!(get_nvmf_config_from_cmdline(&Cmdline::default())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn parse_discovery_log_test() {
+        let mut log = vec![0u8; DISCOVERY_HEADER_SIZE + 2 * DISCOVERY_ENTRY_SIZE];
+        let entry = &mut log[DISCOVERY_HEADER_SIZE..];
+        entry[0] = TRTYPE_TCP;
+        entry[2] = SUBTYPE_NVME;
+        entry[32..36].copy_from_slice(b"4420");
+        entry[256..266].copy_from_slice(b"nqn.target");
+        entry[512..523].copy_from_slice(b"192.168.1.3");
+        // a referral to another discovery controller
+        let entry = &mut log[DISCOVERY_HEADER_SIZE + DISCOVERY_ENTRY_SIZE..];
+        entry[0] = TRTYPE_TCP;
+        entry[2] = 1;
+
+        assert_eq!(
+            parse_discovery_log(&log),
+            vec![Subsystem {
+                transport: "tcp".to_string(),
+                address: "192.168.1.3".to_string(),
+                port: "4420".to_string(),
+                nqn: "nqn.target".to_string(),
+            }]
+        );
+    }
+}
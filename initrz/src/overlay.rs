@@ -0,0 +1,122 @@
+use common::cmdline::Cmdline;
+
+use std::fs;
+use std::path::Path;
+
+use crate::identifier::Identifier;
+
+const OVERLAY_PARAM: &str = "rd.overlay";
+// dracut name of the overlay of live media
+const LIVE_OVERLAY_PARAM: &str = "rd.live.overlay=";
+// Filesystems that cannot be written to
+const READ_ONLY_FILESYSTEMS: [&str; 3] = ["squashfs", "iso9660", "erofs"];
+
+/// Where the changes made to an overlay root are stored
+#[derive(PartialEq, Eq, Debug)]
+pub enum Upper {
+    /// Changes are lost on reboot
+    Tmpfs,
+    /// Changes are stored on a persistent device
+    Device(Identifier),
+}
+
+/// Mount the root read-only and stack a writable overlayfs on top of it
+#[derive(PartialEq, Eq, Debug)]
+pub struct Overlay {
+    pub upper: Upper,
+}
+
+fn get_overlay_value(cmdline: &Cmdline) -> Option<&str> {
+    cmdline.iter().rev().find_map(|arg| {
+        if arg == OVERLAY_PARAM {
+            Some("tmpfs")
+        } else {
+            arg.strip_prefix(OVERLAY_PARAM)
+                .and_then(|arg| arg.strip_prefix('='))
+                .or_else(|| arg.strip_prefix(LIVE_OVERLAY_PARAM))
+        }
+    })
+}
+
+/// rd.overlay=0 also disables the overlay stacked on read-only media
+pub fn is_overlay_disabled(cmdline: &Cmdline) -> bool {
+    matches!(get_overlay_value(cmdline), Some("0") | Some("no"))
+}
+
+/// Parse rd.overlay, rd.overlay=tmpfs or rd.overlay=<device>. rd.overlay=0 disables it.
+/// rd.live.overlay= is accepted as well
+pub fn get_overlay_from_cmdline(cmdline: &Cmdline) -> Option<Overlay> {
+    match get_overlay_value(cmdline)? {
+        "0" | "no" => None,
+        "1" | "tmpfs" => Some(Overlay {
+            upper: Upper::Tmpfs,
+        }),
+        device => Some(Overlay {
+            upper: Upper::Device(device.into()),
+        }),
+    }
+}
+
+/// Return true if the root cannot be written to, either because of its filesystem or
+/// because the device is read-only, e.g. a DVD or a write protected SD card
+pub fn is_read_only_medium(devname: &str, filesystem: &str) -> bool {
+    if READ_ONLY_FILESYSTEMS.contains(&filesystem) {
+        return true;
+    }
+    fs::canonicalize(devname)
+        .ok()
+        .and_then(|path| {
+            fs::read_to_string(
+                Path::new("/sys/class/block")
+                    .join(path.file_name()?)
+                    .join("ro"),
+            )
+            .ok()
+        })
+        .map(|ro| ro.trim() == "1")
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_cmdline_test() {
+        assert_eq!(get_overlay_from_cmdline(&Cmdline::parse("quiet")), None);
+        assert_eq!(
+            get_overlay_from_cmdline(&Cmdline::parse("rd.overlay")),
+            Some(Overlay {
+                upper: Upper::Tmpfs
+            })
+        );
+        assert_eq!(
+            get_overlay_from_cmdline(&Cmdline::parse("rd.overlay=UUID=1234")),
+            Some(Overlay {
+                upper: Upper::Device(Identifier::Uuid("1234".to_string()))
+            })
+        );
+        assert_eq!(
+            get_overlay_from_cmdline(&Cmdline::parse("rd.overlay rd.overlay=0")),
+            None
+        );
+        assert_eq!(
+            get_overlay_from_cmdline(&Cmdline::parse("rd.overlayfoo")),
+            None
+        );
+        assert_eq!(
+            get_overlay_from_cmdline(&Cmdline::parse("rd.live.overlay=tmpfs")),
+            Some(Overlay {
+                upper: Upper::Tmpfs
+            })
+        );
+        assert!(is_overlay_disabled(&Cmdline::parse("rd.overlay=0")));
+        assert!(!is_overlay_disabled(&Cmdline::parse("quiet")));
+    }
+
+    #[test]
+    fn is_read_only_medium_test() {
+        assert!(is_read_only_medium("/dev/sr0", "iso9660"));
+        assert!(!is_read_only_medium("/nonexistent", "ext4"));
+    }
+}
@@ -0,0 +1,69 @@
+// Passphrase prompt on the console giving up after the crypttab timeout= or
+// rd.timeout.passphrase, so that headless machines do not wait at a prompt nobody sees
+
+use anyhow::{Context, Result};
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::time::Duration;
+
+/// Nothing has been entered before the timeout, the nofail devices are skipped and the
+/// required ones trigger the emergency policy
+#[derive(Debug)]
+pub struct PassphraseTimeout;
+
+impl fmt::Display for PassphraseTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "timed out waiting for the passphrase")
+    }
+}
+
+impl Error for PassphraseTimeout {}
+
+/// Ask for the passphrase on the console, waiting forever without a timeout
+pub fn prompt(prompt: &str, timeout: Option<Duration>) -> Result<String> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => {
+            return rpassword::prompt_password(prompt).context("unable to read password from stdin")
+        }
+    };
+
+    let stdin = io::stdin();
+    // The console could be missing on headless machines
+    let termios = tcgetattr(&stdin).ok();
+    if let Some(termios) = &termios {
+        let mut noecho = termios.clone();
+        noecho.local_flags.remove(LocalFlags::ECHO);
+        noecho.local_flags.insert(LocalFlags::ECHONL);
+        tcsetattr(&stdin, SetArg::TCSANOW, &noecho)?;
+    }
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    // In canonical mode the console is readable once a whole line has been entered
+    let mut fds = [PollFd::new(&stdin, PollFlags::POLLIN)];
+    let res = poll(&mut fds, timeout.as_millis().try_into().unwrap_or(i32::MAX))
+        .with_context(|| "unable to wait for a password");
+    if let Some(termios) = &termios {
+        tcsetattr(&stdin, SetArg::TCSANOW, termios)?;
+    }
+    if res? == 0 {
+        println!();
+        return Err(PassphraseTimeout.into());
+    }
+
+    let mut line = String::new();
+    stdin.lock().read_line(&mut line)?;
+
+    Ok(line.trim_end_matches('\n').to_string())
+}
+
+/// Check if the passphrase has not been entered in time
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    err.is::<PassphraseTimeout>()
+}
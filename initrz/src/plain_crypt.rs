@@ -0,0 +1,163 @@
+// Plain dm-crypt devices mapped with a random key, declared in crypttab with /dev/urandom
+// as key. Their content does not survive a reboot, so they are formatted every time as
+// requested by the swap and tmp options
+
+use anyhow::{bail, Context, Result};
+use tracing::{info, warn};
+
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+
+use crate::crypt_options::Format;
+use crate::device_handler::zeroize;
+use crate::device_mapper::{self, Target};
+use crate::encrypted_device::EncryptedDevice;
+use crate::module_loader::ModuleLoader;
+use crate::probe;
+use crate::signal_handler::GuardedCommand;
+use crate::swap;
+
+const DM_CRYPT_MODULE: &str = "dm_crypt";
+const DEFAULT_CIPHER: &str = "aes-xts-plain64";
+// In bits, xts splits it into two keys
+const DEFAULT_KEY_SIZE: u32 = 512;
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn get_table(cipher: &str, key: &[u8], device: &str, size: u64, discard: bool) -> Target {
+    Target {
+        start: 0,
+        length: size,
+        target_type: "crypt".to_string(),
+        params: format!(
+            "{} {} 0 {} 0{}",
+            cipher,
+            to_hex(key),
+            device,
+            if discard { " 1 allow_discards" } else { "" }
+        ),
+    }
+}
+
+/// Size of the block device in sectors
+fn get_size(path: &str) -> Result<u64> {
+    let path = fs::canonicalize(path).with_context(|| format!("unable to resolve {}", path))?;
+    let size = Path::new("/sys/class/block")
+        .join(path.file_name().unwrap_or_default())
+        .join("size");
+    fs::read_to_string(&size)
+        .with_context(|| format!("unable to read {:?}", size))?
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid size in {:?}", size))
+}
+
+fn mkfs(fs_type: &str, path: &str) -> Result<()> {
+    let mkfs = format!("mkfs.{}", fs_type);
+    let output = Command::new(&mkfs)
+        .arg(path)
+        .guarded_output()
+        .with_context(|| format!("unable to execute {}", mkfs))?;
+    if !output.status.success() {
+        bail!(
+            "{} failed on {}: {}",
+            mkfs,
+            path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Map the device with a new random key and format it
+pub fn setup(
+    path: &str,
+    encrypted_device: &EncryptedDevice,
+    module_loader: &ModuleLoader,
+) -> Result<()> {
+    let mapped = format!("/dev/mapper/{}", encrypted_device.name);
+    if Path::new(&mapped).exists() {
+        return Ok(());
+    }
+    // The device is overwritten, make sure that it is not holding data because of a
+    // wrong path in crypttab
+    if let Some(superblock) = probe::probe_device(path)? {
+        if superblock.fs_type != "swap" {
+            bail!(
+                "refusing to map {} with a random key, it contains a {} signature",
+                path,
+                superblock.fs_type
+            );
+        }
+    }
+    if !module_loader.load_module(DM_CRYPT_MODULE)? {
+        // Do not fail here because the module could be builtin
+        warn!("module {} not found", DM_CRYPT_MODULE);
+    }
+
+    let options = &encrypted_device.options;
+    let key_size = options.key_size.unwrap_or(DEFAULT_KEY_SIZE);
+    if key_size == 0 || !key_size.is_multiple_of(8) {
+        bail!("invalid key size {} for device {}", key_size, path);
+    }
+    let mut key = vec![0; (key_size / 8) as usize];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut key))
+        .context("unable to read from /dev/urandom")?;
+    let table = get_table(
+        options.cipher.as_deref().unwrap_or(DEFAULT_CIPHER),
+        &key,
+        path,
+        get_size(path)?,
+        options.allow_discards(),
+    );
+    zeroize(&mut key);
+    info!("mapping {} with a random key", path);
+    let res = device_mapper::create_device(
+        &encrypted_device.name,
+        None,
+        std::slice::from_ref(&table),
+        false,
+    );
+    zeroize(&mut table.params.into_bytes());
+    res?;
+
+    match &options.format {
+        Some(Format::Swap) => swap::mkswap(&mapped),
+        Some(Format::Tmp(fs_type)) => mkfs(fs_type, &mapped),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_table_test() {
+        assert_eq!(
+            get_table(
+                "aes-xts-plain64",
+                &[0x00, 0xab, 0x10],
+                "/dev/sda2",
+                2048,
+                true
+            ),
+            Target {
+                start: 0,
+                length: 2048,
+                target_type: "crypt".to_string(),
+                params: "aes-xts-plain64 00ab10 0 /dev/sda2 0 1 allow_discards".to_string(),
+            }
+        );
+        assert_eq!(
+            get_table("aes-xts-plain64", &[0xff], "/dev/sda2", 2048, false).params,
+            "aes-xts-plain64 ff 0 /dev/sda2 0"
+        );
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, Context, Result};
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Output};
+
+use crate::signal_handler::GuardedCommand;
+
+const PLYMOUTHD_PATHS: [&str; 2] = ["/usr/sbin/plymouthd", "/sbin/plymouthd"];
+const PLYMOUTH: &str = "/usr/bin/plymouth";
+const PLYMOUTH_RUN_DIR: &str = "/run/plymouth";
+
+fn get_plymouthd() -> Option<&'static str> {
+    PLYMOUTHD_PATHS
+        .iter()
+        .find(|path| Path::new(path).exists())
+        .copied()
+}
+
+fn run(command: &mut Command, name: &str) -> Result<Output> {
+    let output = command
+        .guarded_output()
+        .with_context(|| format!("unable to run {} command", name))?;
+    if !output.status.success() {
+        bail!(
+            "{} command failed:\n{:?}",
+            name,
+            String::from_utf8(output.stderr)
+        )
+    }
+
+    Ok(output)
+}
+
+/// Return true if plymouthd has been shipped in the initramfs
+pub fn is_available() -> bool {
+    get_plymouthd().is_some() && Path::new(PLYMOUTH).exists()
+}
+
+/// Return true if plymouthd is up and answering requests
+pub fn is_running() -> bool {
+    Command::new(PLYMOUTH)
+        .arg("--ping")
+        .guarded_status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+/// Start the plymouth daemon and show the splash screen
+pub fn start() -> Result<()> {
+    let plymouthd = get_plymouthd().with_context(|| "unable to find plymouthd")?;
+    fs::create_dir_all(PLYMOUTH_RUN_DIR)
+        .with_context(|| format!("unable to create {}", PLYMOUTH_RUN_DIR))?;
+    run(
+        Command::new(plymouthd)
+            .arg("--mode=boot")
+            .arg("--attach-to-session")
+            .arg(format!("--pid-file={}/pid", PLYMOUTH_RUN_DIR)),
+        "plymouthd",
+    )?;
+    run(Command::new(PLYMOUTH).arg("show-splash"), "plymouth")?;
+
+    Ok(())
+}
+
+/// Ask for a password using the splash screen
+pub fn ask_for_password(prompt: &str) -> Result<String> {
+    let output = run(
+        Command::new(PLYMOUTH)
+            .arg("ask-for-password")
+            .arg(format!("--prompt={}", prompt)),
+        "plymouth",
+    )?;
+
+    String::from_utf8(output.stdout).with_context(|| "plymouth returned an invalid password")
+}
+
+/// Stop the daemon, keeping the splash on screen so that the real init can take it over
+pub fn quit() -> Result<()> {
+    run(
+        Command::new(PLYMOUTH).arg("quit").arg("--retain-splash"),
+        "plymouth",
+    )?;
+
+    Ok(())
+}
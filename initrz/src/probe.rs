@@ -0,0 +1,585 @@
+// Native superblock prober, extracting the same TYPE, UUID and LABEL values as libblkid
+// for the signatures found in an initramfs
+
+use anyhow::{Context, Result};
+use tracing::debug;
+
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SYS_BLOCK: &str = "/sys/class/block";
+
+const LUKS_MAGIC: &[u8] = b"LUKS\xba\xbe";
+const LVM_LABEL_ID: &[u8] = b"LABELONE";
+const LVM_LABEL_TYPE: &[u8] = b"LVM2 001";
+const LVM_LABEL_SECTORS: u64 = 4;
+const MD_MAGIC: u32 = 0xa92b_4efc;
+const MD_RESERVED_BYTES: u64 = 64 * 1024;
+const BCACHE_MAGIC: [u8; 16] = [
+    0xc6, 0x85, 0x73, 0xf6, 0x4e, 0x1a, 0x45, 0xca, 0x82, 0x65, 0xf5, 0x7f, 0x48, 0xba, 0x6d, 0x81,
+];
+const BCACHE_SB_OFFSET: u64 = 4096;
+const EXT_SB_OFFSET: u64 = 1024;
+const EXT_MAGIC: u16 = 0xef53;
+const EXT_COMPAT_HAS_JOURNAL: u32 = 0x4;
+// Features understood by ext3, anything else requires ext4
+const EXT3_INCOMPAT: u32 = 0x2 | 0x4 | 0x10;
+const EXT3_RO_COMPAT: u32 = 0x1 | 0x2 | 0x4;
+const XFS_MAGIC: &[u8] = b"XFSB";
+const BTRFS_SB_OFFSET: u64 = 64 * 1024;
+const BTRFS_MAGIC: &[u8] = b"_BHRfS_M";
+const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+const ISO9660_SB_OFFSET: u64 = 32 * 1024;
+const ISO9660_MAGIC: &[u8] = b"CD001";
+const SWAP_PAGE_SIZES: [u64; 4] = [4096, 8192, 16384, 65536];
+const SWAP_MAGICS: [&[u8]; 2] = [b"SWAPSPACE2", b"SWAP-SPACE"];
+const FAT_SIGNATURE: [u8; 2] = [0x55, 0xaa];
+const FAT_NO_LABEL: &str = "NO NAME";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Superblock {
+    pub fs_type: &'static str,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+impl Superblock {
+    fn new(fs_type: &'static str) -> Superblock {
+        Superblock {
+            fs_type,
+            uuid: None,
+            label: None,
+        }
+    }
+
+    fn with_uuid(mut self, uuid: String) -> Superblock {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    fn with_label(mut self, label: Option<String>) -> Superblock {
+        self.label = label;
+        self
+    }
+}
+
+struct Device<R: Read + Seek> {
+    reader: R,
+    size: u64,
+}
+
+impl<R: Read + Seek> Device<R> {
+    fn new(mut reader: R) -> Result<Device<R>> {
+        let size = reader
+            .seek(SeekFrom::End(0))
+            .with_context(|| "unable to get the device size")?;
+        Ok(Device { reader, size })
+    }
+
+    fn read_at(&mut self, offset: u64, len: usize) -> Option<Vec<u8>> {
+        if offset.checked_add(len as u64)? > self.size {
+            return None;
+        }
+        let mut buf = vec![0; len];
+        self.reader.seek(SeekFrom::Start(offset)).ok()?;
+        self.reader.read_exact(&mut buf).ok()?;
+        Some(buf)
+    }
+}
+
+fn le_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+fn le_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn format_uuid(uuid: &[u8]) -> Option<String> {
+    if uuid.iter().all(|byte| *byte == 0) {
+        return None;
+    }
+    let hex = uuid
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+fn parse_string(buf: &[u8]) -> Option<String> {
+    let end = buf.iter().position(|c| *c == 0).unwrap_or(buf.len());
+    let string = String::from_utf8_lossy(&buf[..end]).trim_end().to_string();
+    if string.is_empty() {
+        None
+    } else {
+        Some(string)
+    }
+}
+
+fn probe_luks<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let header = device.read_at(0, 256)?;
+    if &header[0..6] != LUKS_MAGIC {
+        return None;
+    }
+    let mut superblock = Superblock::new("crypto_LUKS");
+    superblock.uuid = parse_string(&header[168..208]);
+    // Only LUKS2 has a label
+    if header[6..8] == [0, 2] {
+        superblock.label = parse_string(&header[24..72]);
+    }
+    Some(superblock)
+}
+
+fn probe_lvm<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    (0..LVM_LABEL_SECTORS).find_map(|sector| {
+        let label = device.read_at(sector * 512, 512)?;
+        if &label[0..8] != LVM_LABEL_ID || &label[24..32] != LVM_LABEL_TYPE {
+            return None;
+        }
+        let offset = le_u32(&label, 20) as usize;
+        let id = label.get(offset..offset + 32)?;
+        // Same grouping used by the LVM tools, the id is sliced before the conversion so
+        // that a non ASCII byte cannot split a char
+        let uuid = [0..6, 6..10, 10..14, 14..18, 18..22, 22..26, 26..32]
+            .iter()
+            .map(|range| String::from_utf8_lossy(&id[range.clone()]))
+            .collect::<Vec<_>>()
+            .join("-");
+        Some(Superblock::new("LVM2_member").with_uuid(uuid))
+    })
+}
+
+fn probe_md_v1<R: Read + Seek>(device: &mut Device<R>, offset: u64) -> Option<Superblock> {
+    let sb = device.read_at(offset, 64)?;
+    if le_u32(&sb, 0) != MD_MAGIC || le_u32(&sb, 4) != 1 {
+        return None;
+    }
+    let mut superblock = Superblock::new("linux_raid_member");
+    superblock.uuid = format_uuid(&sb[16..32]);
+    superblock.label = parse_string(&sb[32..64]);
+    Some(superblock)
+}
+
+fn probe_md_v090<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    if device.size < 2 * MD_RESERVED_BYTES {
+        return None;
+    }
+    let offset = (device.size & !(MD_RESERVED_BYTES - 1)) - MD_RESERVED_BYTES;
+    let sb = device.read_at(offset, 64)?;
+    if le_u32(&sb, 0) != MD_MAGIC || le_u32(&sb, 4) != 0 {
+        return None;
+    }
+    let mut uuid = sb[20..24].to_vec();
+    uuid.extend_from_slice(&sb[52..64]);
+    let mut superblock = Superblock::new("linux_raid_member");
+    superblock.uuid = format_uuid(&uuid);
+    Some(superblock)
+}
+
+/// Superblock versions 1.1 and 1.2 are at the start of the device, 1.0 and 0.90 at the end
+fn probe_md<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let v1_0_offset = (((device.size >> 9).checked_sub(16)?) & !7) << 9;
+    [0, 4096, v1_0_offset]
+        .iter()
+        .find_map(|offset| probe_md_v1(device, *offset))
+        .or_else(|| probe_md_v090(device))
+}
+
+fn probe_bcache<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(BCACHE_SB_OFFSET, 104)?;
+    if sb[24..40] != BCACHE_MAGIC {
+        return None;
+    }
+    let mut superblock = Superblock::new("bcache");
+    superblock.uuid = format_uuid(&sb[40..56]);
+    superblock.label = parse_string(&sb[72..104]);
+    Some(superblock)
+}
+
+fn probe_ext<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(EXT_SB_OFFSET, 256)?;
+    if le_u16(&sb, 0x38) != EXT_MAGIC {
+        return None;
+    }
+    let compat = le_u32(&sb, 0x5c);
+    let incompat = le_u32(&sb, 0x60);
+    let ro_compat = le_u32(&sb, 0x64);
+    let fs_type = if incompat & !EXT3_INCOMPAT != 0 || ro_compat & !EXT3_RO_COMPAT != 0 {
+        "ext4"
+    } else if compat & EXT_COMPAT_HAS_JOURNAL != 0 {
+        "ext3"
+    } else {
+        "ext2"
+    };
+    let mut superblock = Superblock::new(fs_type);
+    superblock.uuid = format_uuid(&sb[0x68..0x78]);
+    superblock.label = parse_string(&sb[0x78..0x88]);
+    Some(superblock)
+}
+
+fn probe_xfs<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(0, 120)?;
+    if &sb[0..4] != XFS_MAGIC {
+        return None;
+    }
+    let mut superblock = Superblock::new("xfs");
+    superblock.uuid = format_uuid(&sb[32..48]);
+    superblock.label = parse_string(&sb[108..120]);
+    Some(superblock)
+}
+
+fn probe_btrfs<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(BTRFS_SB_OFFSET, 0x12b + 256)?;
+    if &sb[64..72] != BTRFS_MAGIC {
+        return None;
+    }
+    let mut superblock = Superblock::new("btrfs");
+    superblock.uuid = format_uuid(&sb[32..48]);
+    superblock.label = parse_string(&sb[0x12b..]);
+    Some(superblock)
+}
+
+fn probe_squashfs<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(0, 4)?;
+    (sb == SQUASHFS_MAGIC).then(|| Superblock::new("squashfs"))
+}
+
+fn probe_iso9660<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(ISO9660_SB_OFFSET, 2048)?;
+    if &sb[1..6] != ISO9660_MAGIC {
+        return None;
+    }
+    // The UUID is made from the creation date, e.g. 2023-11-14-10-30-00-00
+    let date = &sb[813..829];
+    let uuid =
+        (date.iter().all(u8::is_ascii_digit) && date.iter().any(|c| *c != b'0')).then(|| {
+            let date = String::from_utf8_lossy(date);
+            [0..4, 4..6, 6..8, 8..10, 10..12, 12..14, 14..16]
+                .iter()
+                .map(|range| &date[range.clone()])
+                .collect::<Vec<&str>>()
+                .join("-")
+        });
+    let mut superblock = Superblock::new("iso9660");
+    superblock.uuid = uuid;
+    superblock.label = parse_string(&sb[40..72]);
+    Some(superblock)
+}
+
+fn probe_swap<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let page_size = SWAP_PAGE_SIZES.iter().copied().find(|page_size| {
+        device
+            .read_at(page_size - 10, 10)
+            .is_some_and(|magic| SWAP_MAGICS.contains(&magic.as_slice()))
+    })?;
+    let mut superblock = Superblock::new("swap");
+    // Only the new style swap space has an UUID and a label
+    if let Some(header) = device
+        .read_at(page_size - 10, 10)
+        .filter(|magic| magic == SWAP_MAGICS[0])
+        .and_then(|_| device.read_at(1024, 44))
+    {
+        superblock.uuid = format_uuid(&header[12..28]);
+        superblock.label = parse_string(&header[28..44]);
+    }
+    Some(superblock)
+}
+
+fn probe_vfat<R: Read + Seek>(device: &mut Device<R>) -> Option<Superblock> {
+    let sb = device.read_at(0, 512)?;
+    if sb[510..512] != FAT_SIGNATURE {
+        return None;
+    }
+    // FAT32 has a bigger BIOS parameter block, moving the volume id and the label
+    let (id_offset, label_offset) = if &sb[82..87] == b"FAT32" {
+        (67, 71)
+    } else if &sb[54..57] == b"FAT" {
+        (39, 43)
+    } else {
+        return None;
+    };
+    let id = le_u32(&sb, id_offset);
+    let label =
+        parse_string(&sb[label_offset..label_offset + 11]).filter(|label| label != FAT_NO_LABEL);
+    Some(
+        Superblock::new("vfat")
+            .with_uuid(format!("{:04X}-{:04X}", id >> 16, id & 0xffff))
+            .with_label(label),
+    )
+}
+
+fn probe<R: Read + Seek>(reader: R) -> Result<Option<Superblock>> {
+    let mut device = Device::new(reader)?;
+    // Containers first, as they can also hold a valid filesystem signature, e.g. the
+    // members of a RAID1 array with the superblock at the end
+    let probers = [
+        probe_luks,
+        probe_lvm,
+        probe_md,
+        probe_bcache,
+        probe_xfs,
+        probe_ext,
+        probe_btrfs,
+        probe_squashfs,
+        probe_iso9660,
+        probe_swap,
+        probe_vfat,
+    ];
+
+    Ok(probers.iter().find_map(|prober| prober(&mut device)))
+}
+
+/// Probe the device for a known signature
+pub fn probe_device(path: &str) -> Result<Option<Superblock>> {
+    probe(File::open(path).with_context(|| format!("unable to open device {}", path))?)
+        .with_context(|| format!("unable to probe device {}", path))
+}
+
+/// Get the path of every block device, device-mapper devices by their /dev/mapper name
+pub fn get_block_devices() -> Vec<String> {
+    let entries = match fs::read_dir(SYS_BLOCK) {
+        Ok(entries) => entries,
+        Err(err) => {
+            debug!("unable to read {}: {}", SYS_BLOCK, err);
+            return Vec::new();
+        }
+    };
+    let mut devices = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        // Skip the devices without media, e.g. unused loop devices
+        .filter(|name| {
+            fs::read_to_string(Path::new(SYS_BLOCK).join(name).join("size"))
+                .is_ok_and(|size| size.trim() != "0")
+        })
+        .filter_map(|name| {
+            if name.starts_with("dm-") {
+                let dm_name =
+                    fs::read_to_string(Path::new(SYS_BLOCK).join(&name).join("dm/name")).ok()?;
+                Some(format!("/dev/mapper/{}", dm_name.trim()))
+            } else {
+                Some(format!("/dev/{}", name))
+            }
+        })
+        .filter(|path| Path::new(path).exists())
+        .collect::<Vec<String>>();
+    devices.sort();
+    devices
+}
+
+/// Probe every block device, the ones without a known signature are included
+pub fn probe_all() -> Vec<(String, Option<Superblock>)> {
+    get_block_devices()
+        .into_iter()
+        .map(|path| {
+            let superblock = probe_device(&path).unwrap_or_else(|err| {
+                debug!("{:?}", err);
+                None
+            });
+            (path, superblock)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn image(size: usize, writes: &[(usize, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut data = vec![0; size];
+        for (offset, bytes) in writes {
+            data[*offset..*offset + bytes.len()].copy_from_slice(bytes);
+        }
+        Cursor::new(data)
+    }
+
+    const UUID: [u8; 16] = [
+        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf0, 0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde,
+        0xf0,
+    ];
+    const UUID_STRING: &str = "12345678-9abc-def0-1234-56789abcdef0";
+
+    #[test]
+    fn probe_ext_test() {
+        let incompat_extents = 0x40_u32.to_le_bytes();
+        let superblock = probe(image(
+            4096,
+            &[
+                (1024 + 0x38, &EXT_MAGIC.to_le_bytes()),
+                (1024 + 0x60, &incompat_extents),
+                (1024 + 0x68, &UUID),
+                (1024 + 0x78, b"root"),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            superblock,
+            Superblock {
+                fs_type: "ext4",
+                uuid: Some(UUID_STRING.to_string()),
+                label: Some("root".to_string()),
+            }
+        );
+
+        let journal = EXT_COMPAT_HAS_JOURNAL.to_le_bytes();
+        let superblock = probe(image(
+            4096,
+            &[
+                (1024 + 0x38, &EXT_MAGIC.to_le_bytes()),
+                (1024 + 0x5c, &journal),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "ext3");
+        assert_eq!(superblock.uuid, None);
+    }
+
+    #[test]
+    fn probe_btrfs_test() {
+        let superblock = probe(image(
+            128 * 1024,
+            &[
+                (65536 + 32, &UUID),
+                (65536 + 64, BTRFS_MAGIC),
+                (65536 + 0x12b, b"data"),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "btrfs");
+        assert_eq!(superblock.uuid.as_deref(), Some(UUID_STRING));
+        assert_eq!(superblock.label.as_deref(), Some("data"));
+    }
+
+    #[test]
+    fn probe_vfat_test() {
+        let superblock = probe(image(
+            4096,
+            &[
+                (67, &0x1234abcd_u32.to_le_bytes()),
+                (71, b"NO NAME    "),
+                (82, b"FAT32   "),
+                (510, &FAT_SIGNATURE),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            superblock,
+            Superblock {
+                fs_type: "vfat",
+                uuid: Some("1234-ABCD".to_string()),
+                label: None,
+            }
+        );
+    }
+
+    #[test]
+    fn probe_luks_test() {
+        let uuid = UUID_STRING.as_bytes();
+        let superblock = probe(image(
+            4096,
+            &[
+                (0, LUKS_MAGIC),
+                (6, &[0, 2]),
+                (24, b"cryptroot"),
+                (168, uuid),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "crypto_LUKS");
+        assert_eq!(superblock.uuid.as_deref(), Some(UUID_STRING));
+        assert_eq!(superblock.label.as_deref(), Some("cryptroot"));
+    }
+
+    #[test]
+    fn probe_lvm_test() {
+        let offset = 32_u32.to_le_bytes();
+        let superblock = probe(image(
+            4096,
+            &[
+                (512, LVM_LABEL_ID),
+                (512 + 20, &offset),
+                (512 + 24, LVM_LABEL_TYPE),
+                (512 + 32, b"abcdefghijklmnopqrstuvwxyz012345"),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "LVM2_member");
+        assert_eq!(
+            superblock.uuid.as_deref(),
+            Some("abcdef-ghij-klmn-opqr-stuv-wxyz-012345")
+        );
+
+        // A corrupted id must not panic
+        let superblock = probe(image(
+            4096,
+            &[
+                (512, LVM_LABEL_ID),
+                (512 + 20, &offset),
+                (512 + 24, LVM_LABEL_TYPE),
+                (512 + 32, b"abcde\xc3\xa9fghijklmnopqrstuvwxyz0123"),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "LVM2_member");
+    }
+
+    #[test]
+    fn probe_md_test() {
+        let magic = MD_MAGIC.to_le_bytes();
+        let version = 1_u32.to_le_bytes();
+        // Version 1.0 keeps the filesystem at the start of the device
+        let size = 256 * 1024;
+        let offset = ((size / 512 - 16) & !7) * 512;
+        let superblock = probe(image(
+            size,
+            &[
+                (0, XFS_MAGIC),
+                (offset, &magic),
+                (offset + 4, &version),
+                (offset + 16, &UUID),
+                (offset + 32, b"host:0"),
+            ],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "linux_raid_member");
+        assert_eq!(superblock.uuid.as_deref(), Some(UUID_STRING));
+        assert_eq!(superblock.label.as_deref(), Some("host:0"));
+    }
+
+    #[test]
+    fn probe_swap_test() {
+        let superblock = probe(image(
+            8192,
+            &[(1024 + 12, &UUID), (4096 - 10, b"SWAPSPACE2")],
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(superblock.fs_type, "swap");
+        assert_eq!(superblock.uuid.as_deref(), Some(UUID_STRING));
+        assert_eq!(superblock.label, None);
+    }
+
+    #[test]
+    fn probe_unknown_test() {
+        assert_eq!(probe(image(8192, &[])).unwrap(), None);
+        assert_eq!(probe(image(16, &[])).unwrap(), None);
+    }
+}
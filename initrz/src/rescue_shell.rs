@@ -0,0 +1,285 @@
+// Minimal shell built into initrz, used by the breakpoint and emergency shells when
+// busybox is not in the initramfs or when rd.shell=rust is given. The line editing
+// (backspace, ^U, ^W) is done by the terminal in canonical mode
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use nix::mount::{mount, umount};
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::unistd::sync;
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+
+use crate::console;
+use crate::device_handler;
+use crate::filesystem::Filesystem;
+use crate::fstab::parse_mount_options;
+use crate::probe;
+use crate::signal_handler::GuardedCommand;
+
+const SHELL_PARAM: &str = "rd.shell";
+const BUSYBOX: &str = "/bin/busybox";
+const INIT: &str = "/init";
+const PROMPT: &str = "initrz# ";
+const HELP: &str = "builtins:
+  ls [dir]...                         list the directories
+  cat <file>...                       print the files
+  echo [arg]...                       print the arguments
+  cd [dir], pwd                       change and print the working directory
+  mkdir <dir>...                      create the directories and their parents
+  mount <device> <dir> [type] [opts]  mount a device, the type is probed if omitted
+  umount <dir>                        unmount a filesystem
+  blkid                               list the block devices and their signatures
+  unlock <device> [name]              unlock a LUKS device
+  log                                 print the log of initrz
+  retry-root                          start initrz again
+  reboot, poweroff                    restart or turn off the system
+  exit                                leave the shell
+any other command is executed from $PATH";
+
+/// Use the built-in shell when requested with rd.shell=rust or when busybox is missing
+pub fn is_selected(cmdline: &Cmdline) -> bool {
+    cmdline.get(SHELL_PARAM) == Some("rust") || !Path::new(BUSYBOX).exists()
+}
+
+/// Split a line into words, honoring single and double quotes and backslash escapes
+fn split_words(line: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"'), '\\') | (None, '\\') => {
+                word.push(chars.next().context("trailing backslash")?);
+                in_word = true;
+            }
+            (Some(_), c) => word.push(c),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        bail!("unterminated quote");
+    }
+    if in_word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+fn ls(dirs: &[String]) -> Result<()> {
+    let dirs = if dirs.is_empty() {
+        vec![".".to_string()]
+    } else {
+        dirs.to_vec()
+    };
+    for dir in &dirs {
+        if dirs.len() > 1 {
+            println!("{}:", dir);
+        }
+        let mut entries: Vec<String> = fs::read_dir(dir)
+            .with_context(|| format!("unable to read directory {}", dir))?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| {
+                let name = entry.file_name().to_string_lossy().to_string();
+                match entry.file_type() {
+                    Ok(file_type) if file_type.is_dir() => format!("{}/", name),
+                    Ok(file_type) if file_type.is_symlink() => format!("{}@", name),
+                    _ => name,
+                }
+            })
+            .collect();
+        entries.sort();
+        for entry in entries {
+            println!("{}", entry);
+        }
+    }
+
+    Ok(())
+}
+
+fn cat(files: &[String]) -> Result<()> {
+    let mut stdout = io::stdout();
+    for file in files {
+        let contents = fs::read(file).with_context(|| format!("unable to read {}", file))?;
+        stdout.write_all(&contents)?;
+    }
+
+    Ok(())
+}
+
+fn mount_device(args: &[String]) -> Result<()> {
+    let (device, target) = match args {
+        [device, target, ..] => (device, target),
+        _ => bail!("usage: mount <device> <dir> [type] [options]"),
+    };
+    let fs_type = match args.get(2).map(String::as_str) {
+        Some("auto") | None => Filesystem::Auto.get_filesystem_string(device)?,
+        Some(fs_type) => fs_type.to_string(),
+    };
+    let options: Vec<String> = args
+        .get(3)
+        .map(|options| options.split(',').map(String::from).collect())
+        .unwrap_or_default();
+    let (flags, data) = parse_mount_options(&options);
+    mount(
+        Some(device.as_str()),
+        target.as_str(),
+        Some(fs_type.as_str()),
+        flags,
+        (!data.is_empty()).then_some(data.as_str()),
+    )
+    .with_context(|| format!("unable to mount {} on {}", device, target))
+}
+
+fn blkid() {
+    for (path, superblock) in probe::probe_all() {
+        match superblock {
+            Some(superblock) => println!(
+                "{}: TYPE=\"{}\" UUID=\"{}\" LABEL=\"{}\"",
+                path,
+                superblock.fs_type,
+                superblock.uuid.unwrap_or_default(),
+                superblock.label.unwrap_or_default()
+            ),
+            None => println!("{}", path),
+        }
+    }
+}
+
+fn unlock(args: &[String]) -> Result<()> {
+    let path = args.first().context("usage: unlock <device> [name]")?;
+    device_handler::unlock_from_shell(path, args.get(1).map(String::as_str))
+}
+
+fn power(mode: RebootMode) -> Result<()> {
+    sync();
+    let Err(err) = reboot(mode);
+    Err(err).context("unable to reboot")
+}
+
+fn execute(command: &str, args: &[String]) -> Result<()> {
+    match command {
+        "help" => println!("{}", HELP),
+        "ls" => ls(args)?,
+        "cat" => cat(args)?,
+        "echo" => println!("{}", args.join(" ")),
+        "cd" => {
+            let dir = args.first().map(String::as_str).unwrap_or("/");
+            env::set_current_dir(dir).with_context(|| format!("unable to change to {}", dir))?;
+        }
+        "pwd" => println!("{}", env::current_dir()?.display()),
+        "mkdir" => {
+            for dir in args {
+                fs::create_dir_all(dir).with_context(|| format!("unable to create {}", dir))?;
+            }
+        }
+        "mount" => mount_device(args)?,
+        "umount" => {
+            let target = args.first().context("usage: umount <dir>")?;
+            umount(target.as_str()).with_context(|| format!("unable to unmount {}", target))?;
+        }
+        "blkid" => blkid(),
+        "unlock" => unlock(args)?,
+        "log" => {
+            for line in console::get_log() {
+                println!("{}", line);
+            }
+        }
+        "retry-root" => {
+            let err = Command::new(INIT).exec();
+            return Err(err).with_context(|| format!("unable to execute {}", INIT));
+        }
+        "reboot" => power(RebootMode::RB_AUTOBOOT)?,
+        "poweroff" => power(RebootMode::RB_POWER_OFF)?,
+        command => {
+            let status = Command::new(command)
+                .args(args)
+                .guarded_status()
+                .with_context(|| format!("unable to execute {}", command))?;
+            if !status.success() {
+                bail!("{} exited with {}", command, status);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Read and execute the commands until exit or the end of the input
+pub fn run() {
+    println!("initrz rescue shell, type help for the list of builtins");
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", PROMPT);
+        let _ = io::stdout().flush();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            // ^D or the console went away
+            _ => break,
+        };
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(err) => {
+                eprintln!("{}", err);
+                continue;
+            }
+        };
+        let Some((command, args)) = words.split_first() else {
+            continue;
+        };
+        if command == "exit" {
+            break;
+        }
+        if let Err(err) = execute(command, args) {
+            eprintln!("{:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_words_test() {
+        assert_eq!(
+            split_words("  mount /dev/sda1   /sysroot ").unwrap(),
+            vec!["mount", "/dev/sda1", "/sysroot"]
+        );
+        assert_eq!(
+            split_words(r#"echo "a b" 'c "d"' e\ f """#).unwrap(),
+            vec!["echo", "a b", "c \"d\"", "e f", ""]
+        );
+        assert!(split_words("").unwrap().is_empty());
+        assert!(split_words("echo 'a").is_err());
+        assert!(split_words("echo a\\").is_err());
+    }
+
+    #[test]
+    fn is_selected_test() {
+        assert!(is_selected(&Cmdline::parse("rd.shell=1 rd.shell=rust")));
+    }
+}
@@ -1,42 +1,174 @@
 use std::convert::TryInto;
 
 use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use tracing::warn;
 
 use crate::filesystem::Filesystem;
 use crate::identifier::Identifier;
+use crate::image::get_image_from_cmdline;
+use crate::nfs::{get_nfs_root, NfsRoot};
+use crate::overlay::{get_overlay_from_cmdline, is_overlay_disabled, Overlay};
+use crate::snapshot::get_snapshot_from_cmdline;
+use crate::verity::{ROOTHASH_PARAM, VERITY_NAME};
+use crate::zfs::ZFS_ROOT_PREFIX;
+
+const LIVE_ROOT_PREFIX: &str = "live:";
+const LIVE_IMAGE_PARAM: &str = "rd.live.squashimg";
+const DEFAULT_LIVE_IMAGE: &str = "LiveOS/squashfs.img";
 
 pub struct RootDevice {
     pub filesystem: Filesystem,
     pub identifier: Identifier,
     pub devpath: Option<String>,
+    /// Mount the root read-only, as requested by "ro" on the cmdline
+    pub readonly: bool,
+    pub overlay: Option<Overlay>,
+    /// Stack a tmpfs overlay when the root medium turns out to be read-only
+    pub auto_overlay: bool,
+    /// Path of the squashfs image inside the root device, for root=live:<device>
+    pub live: Option<String>,
+    pub nfs: Option<NfsRoot>,
+    /// LVM logical volume or btrfs subvolume to boot instead, given by rd.snapshot=
+    pub snapshot: Option<String>,
 }
 
-pub fn get_root_from_cmdline(cmdline: &[String]) -> Result<RootDevice> {
-    let auto_type = String::from("root.type=auto");
+pub fn get_root_from_cmdline(cmdline: &Cmdline) -> Result<RootDevice> {
+    // The image replaces root=, its changes are kept in the overlay
+    if let Some(image) = get_image_from_cmdline(cmdline)? {
+        let verified = cmdline.get(ROOTHASH_PARAM).is_some();
+        if !verified {
+            warn!("booting an image that is not verified, roothash= is missing");
+        }
+        return Ok(RootDevice {
+            filesystem: Filesystem::Squashfs,
+            identifier: if verified {
+                Identifier::Path(format!("/dev/mapper/{}", VERITY_NAME))
+            } else {
+                image.data
+            },
+            devpath: None,
+            readonly: true,
+            overlay: Some(Overlay { upper: image.upper }),
+            auto_overlay: false,
+            live: None,
+            nfs: None,
+            snapshot: None,
+        });
+    }
 
+    let verity_root = format!("/dev/mapper/{}", VERITY_NAME);
     let identifier = cmdline
+        .get("root")
+        // A verity protected root is mapped to /dev/mapper/root by default
+        .or_else(|| cmdline.get(ROOTHASH_PARAM).map(|_| verity_root.as_str()))
+        .with_context(|| "unable to find root device from command lines")?;
+    let readonly = cmdline
         .iter()
-        .filter(|arg| arg.starts_with("root="))
-        .last()
-        .with_context(|| "unable to find root device from command lines")?
-        .strip_prefix("root=")
-        .unwrap();
+        .rfind(|arg| *arg == "ro" || *arg == "rw")
+        .map(|arg| arg == "ro")
+        .unwrap_or(false);
+
+    // ZFS datasets are not block devices, they are available once their pool is imported
+    if let Some(dataset) = identifier.strip_prefix(ZFS_ROOT_PREFIX) {
+        return Ok(RootDevice {
+            filesystem: Filesystem::Zfs,
+            identifier: Identifier::Path(String::from(dataset)),
+            devpath: Some(String::from(dataset)),
+            readonly,
+            overlay: get_overlay_from_cmdline(cmdline),
+            auto_overlay: false,
+            live: None,
+            nfs: None,
+            snapshot: None,
+        });
+    }
+
+    // The NFS export is mounted once the network is up
+    if let Some(nfs) = get_nfs_root(identifier, cmdline)? {
+        let source = nfs.get_source();
+        return Ok(RootDevice {
+            filesystem: Filesystem::Nfs,
+            identifier: Identifier::Path(source.clone()),
+            devpath: Some(source),
+            readonly,
+            overlay: get_overlay_from_cmdline(cmdline),
+            auto_overlay: false,
+            live: None,
+            nfs: Some(nfs),
+            snapshot: None,
+        });
+    }
+
+    // The device contains a squashfs image, or is a squashfs itself
+    if let Some(device) = identifier.strip_prefix(LIVE_ROOT_PREFIX) {
+        return Ok(RootDevice {
+            filesystem: Filesystem::Squashfs,
+            identifier: device.into(),
+            devpath: None,
+            readonly: true,
+            overlay: get_overlay_from_cmdline(cmdline),
+            auto_overlay: !is_overlay_disabled(cmdline),
+            live: Some(
+                cmdline
+                    .get(LIVE_IMAGE_PARAM)
+                    .unwrap_or(DEFAULT_LIVE_IMAGE)
+                    .to_string(),
+            ),
+            nfs: None,
+            snapshot: None,
+        });
+    }
 
     Ok(RootDevice {
-        identifier: if let Some(uuid) = identifier.strip_prefix("UUID=") {
-            Identifier::Uuid(String::from(uuid))
-        } else {
-            Identifier::Path(String::from(identifier))
-        },
-        filesystem: cmdline
-            .iter()
-            .filter(|arg| arg.starts_with("root.type="))
-            .last()
-            .or(Some(&auto_type))
-            .map(|root| root.strip_prefix("root.type="))
-            .unwrap()
-            .unwrap()
-            .try_into()?,
+        identifier: identifier.into(),
+        filesystem: cmdline.get("root.type").unwrap_or("auto").try_into()?,
         devpath: None,
+        readonly,
+        overlay: get_overlay_from_cmdline(cmdline),
+        auto_overlay: !is_overlay_disabled(cmdline),
+        live: None,
+        nfs: None,
+        snapshot: get_snapshot_from_cmdline(cmdline),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_root_test() {
+        let root = get_root_from_cmdline(&Cmdline::parse(
+            "rd.image=/dev/sda2 rd.image.hash=/dev/sda3 roothash=abcd",
+        ))
+        .unwrap();
+        assert!(root.identifier == Identifier::Path("/dev/mapper/root".to_string()));
+        assert!(root.filesystem == Filesystem::Squashfs);
+        assert!(root.readonly);
+        assert!(root.overlay.is_some());
+
+        let root = get_root_from_cmdline(&Cmdline::parse("rd.image=/dev/sda2")).unwrap();
+        assert!(root.identifier == Identifier::Path("/dev/sda2".to_string()));
+    }
+
+    #[test]
+    fn live_root_test() {
+        let root =
+            get_root_from_cmdline(&Cmdline::parse("root=live:LABEL=LIVE rd.overlay")).unwrap();
+        assert!(root.identifier == Identifier::Label("LIVE".to_string()));
+        assert_eq!(root.live.as_deref(), Some("LiveOS/squashfs.img"));
+        assert!(root.overlay.is_some());
+
+        let root = get_root_from_cmdline(&Cmdline::parse(
+            "root=live:/dev/sr0 rd.live.squashimg=live/filesystem.squashfs",
+        ))
+        .unwrap();
+        assert!(root.identifier == Identifier::Path("/dev/sr0".to_string()));
+        assert_eq!(root.live.as_deref(), Some("live/filesystem.squashfs"));
+
+        let root = get_root_from_cmdline(&Cmdline::parse("root=UUID=1234")).unwrap();
+        assert!(root.identifier == Identifier::Uuid("1234".to_string()));
+        assert!(root.live.is_none());
+    }
+}
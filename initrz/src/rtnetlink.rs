@@ -0,0 +1,203 @@
+// Minimal rtnetlink client to configure the network interfaces
+// https://man7.org/linux/man-pages/man7/rtnetlink.7.html
+
+use anyhow::{bail, Context, Result};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+
+use std::fs;
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+const NLMSG_HEADER_SIZE: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_EXCL: u16 = 0x200;
+const NLM_F_CREATE: u16 = 0x400;
+
+const RTM_NEWLINK: u16 = 16;
+const RTM_NEWADDR: u16 = 20;
+const RTM_NEWROUTE: u16 = 24;
+
+const AF_UNSPEC: u8 = 0;
+const AF_INET: u8 = 2;
+const IFF_UP: u32 = 0x1;
+const IFA_ADDRESS: u16 = 1;
+const IFA_LOCAL: u16 = 2;
+const IFA_BROADCAST: u16 = 4;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RT_TABLE_MAIN: u8 = 254;
+const RTPROT_BOOT: u8 = 3;
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RTN_UNICAST: u8 = 1;
+
+fn align(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+/// A netlink request made of a family specific header followed by attributes
+struct Request {
+    buf: Vec<u8>,
+}
+
+impl Request {
+    fn new(message_type: u16, flags: u16, header: &[u8]) -> Request {
+        let mut buf = vec![0u8; NLMSG_HEADER_SIZE];
+        buf[4..6].copy_from_slice(&message_type.to_ne_bytes());
+        buf[6..8].copy_from_slice(&(flags | NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+        buf[8..12].copy_from_slice(&1u32.to_ne_bytes());
+        buf.extend_from_slice(header);
+        buf.resize(align(buf.len()), 0);
+        Request { buf }
+    }
+
+    fn attribute(mut self, attribute_type: u16, value: &[u8]) -> Request {
+        let len = 4 + value.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&attribute_type.to_ne_bytes());
+        self.buf.extend_from_slice(value);
+        self.buf.resize(align(self.buf.len()), 0);
+        self
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = self.buf.len() as u32;
+        self.buf[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buf
+    }
+
+    /// Send the request to the kernel and wait for its acknowledgment
+    fn send(self) -> Result<()> {
+        let mut socket =
+            Socket::new(NETLINK_ROUTE).with_context(|| "unable to create netlink socket")?;
+        socket
+            .bind_auto()
+            .with_context(|| "unable to bind netlink socket")?;
+        socket
+            .send_to(&self.finish(), &SocketAddr::new(0, 0), 0)
+            .with_context(|| "unable to send netlink request")?;
+
+        let (reply, _) = socket
+            .recv_from_full()
+            .with_context(|| "unable to receive netlink reply")?;
+        parse_ack(&reply)
+    }
+}
+
+fn parse_ack(reply: &[u8]) -> Result<()> {
+    if reply.len() < NLMSG_HEADER_SIZE + 4 {
+        bail!("truncated netlink reply");
+    }
+    if u16::from_ne_bytes([reply[4], reply[5]]) != NLMSG_ERROR {
+        bail!("unexpected netlink reply");
+    }
+    let error = i32::from_ne_bytes([reply[16], reply[17], reply[18], reply[19]]);
+    if error != 0 {
+        return Err(io::Error::from_raw_os_error(-error).into());
+    }
+
+    Ok(())
+}
+
+pub fn get_interface_index(interface: &str) -> Result<u32> {
+    let path = Path::new("/sys/class/net").join(interface).join("ifindex");
+    fs::read_to_string(&path)
+        .with_context(|| format!("unable to read {:?}", path))?
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid interface index for {}", interface))
+}
+
+fn link_request(index: u32) -> Request {
+    // struct ifinfomsg
+    let mut header = vec![AF_UNSPEC, 0, 0, 0];
+    header.extend_from_slice(&(index as i32).to_ne_bytes());
+    header.extend_from_slice(&IFF_UP.to_ne_bytes());
+    header.extend_from_slice(&IFF_UP.to_ne_bytes());
+    Request::new(RTM_NEWLINK, 0, &header)
+}
+
+fn address_request(index: u32, address: Ipv4Addr, prefix_length: u8) -> Request {
+    let broadcast = u32::from(address) | (u32::MAX.checked_shr(prefix_length as u32).unwrap_or(0));
+    // struct ifaddrmsg
+    let mut header = vec![AF_INET, prefix_length, 0, RT_SCOPE_UNIVERSE];
+    header.extend_from_slice(&index.to_ne_bytes());
+    Request::new(RTM_NEWADDR, NLM_F_CREATE | NLM_F_EXCL, &header)
+        .attribute(IFA_LOCAL, &address.octets())
+        .attribute(IFA_ADDRESS, &address.octets())
+        .attribute(IFA_BROADCAST, &Ipv4Addr::from(broadcast).octets())
+}
+
+fn route_request(index: u32, gateway: Ipv4Addr) -> Request {
+    // struct rtmsg, the destination is 0.0.0.0/0
+    let mut header = vec![
+        AF_INET,
+        0,
+        0,
+        0,
+        RT_TABLE_MAIN,
+        RTPROT_BOOT,
+        RT_SCOPE_UNIVERSE,
+        RTN_UNICAST,
+    ];
+    header.extend_from_slice(&0u32.to_ne_bytes());
+    Request::new(RTM_NEWROUTE, NLM_F_CREATE | NLM_F_EXCL, &header)
+        .attribute(RTA_GATEWAY, &gateway.octets())
+        .attribute(RTA_OIF, &index.to_ne_bytes())
+}
+
+pub fn set_link_up(index: u32) -> Result<()> {
+    link_request(index)
+        .send()
+        .with_context(|| format!("unable to bring up interface {}", index))
+}
+
+pub fn add_address(index: u32, address: Ipv4Addr, prefix_length: u8) -> Result<()> {
+    address_request(index, address, prefix_length)
+        .send()
+        .with_context(|| format!("unable to add address {}/{}", address, prefix_length))
+}
+
+pub fn add_default_route(index: u32, gateway: Ipv4Addr) -> Result<()> {
+    route_request(index, gateway)
+        .send()
+        .with_context(|| format!("unable to add default route via {}", gateway))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_request_test() {
+        let request = address_request(2, Ipv4Addr::new(192, 168, 1, 10), 24).finish();
+        assert_eq!(request.len(), 16 + 8 + 3 * 8);
+        assert_eq!(&request[0..4], &(request.len() as u32).to_ne_bytes());
+        assert_eq!(&request[4..6], &RTM_NEWADDR.to_ne_bytes());
+        assert_eq!(&request[16..18], &[AF_INET, 24]);
+        assert_eq!(&request[20..24], &2u32.to_ne_bytes());
+        // IFA_BROADCAST
+        assert_eq!(&request[44..48], &[192, 168, 1, 255]);
+    }
+
+    #[test]
+    fn route_request_test() {
+        let request = route_request(3, Ipv4Addr::new(10, 0, 0, 1)).finish();
+        assert_eq!(request.len(), 16 + 12 + 8 + 8);
+        assert_eq!(&request[28..30], &8u16.to_ne_bytes());
+        assert_eq!(&request[30..32], &RTA_GATEWAY.to_ne_bytes());
+        assert_eq!(&request[32..36], &[10, 0, 0, 1]);
+        assert_eq!(&request[40..44], &3u32.to_ne_bytes());
+    }
+
+    #[test]
+    fn parse_ack_test() {
+        let mut ack = vec![0u8; 36];
+        ack[4..6].copy_from_slice(&NLMSG_ERROR.to_ne_bytes());
+        assert!(parse_ack(&ack).is_ok());
+        ack[16..20].copy_from_slice(&(-17i32).to_ne_bytes());
+        assert!(parse_ack(&ack).is_err());
+    }
+}
@@ -0,0 +1,184 @@
+// Load the SELinux policy for the init systems that expect it to be loaded already
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use tracing::{info, warn};
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::signal_handler::GuardedCommand;
+
+const SELINUX_CONFIG: &str = "/etc/selinux/config";
+const LOAD_POLICY_PARAM: &str = "rd.selinux.load_policy";
+const LOAD_POLICY_PATHS: [&str; 2] = ["/usr/sbin/load_policy", "/sbin/load_policy"];
+const RESTORECON_PATHS: [&str; 2] = ["/usr/sbin/restorecon", "/sbin/restorecon"];
+// Mounts created by initrz and moved into the new root, they have no label
+const RELABEL_PATHS: [&str; 2] = ["/dev", "/run"];
+
+#[derive(Debug, PartialEq)]
+enum SelinuxMode {
+    Enforcing,
+    Permissive,
+    Disabled,
+}
+
+impl SelinuxMode {
+    /// Parse the SELINUX= entry of /etc/selinux/config
+    fn from_config(content: &str) -> SelinuxMode {
+        match content
+            .lines()
+            .rev()
+            .find_map(|line| line.trim().strip_prefix("SELINUX="))
+            .map(|mode| mode.trim().trim_matches('"'))
+        {
+            Some("enforcing") => SelinuxMode::Enforcing,
+            Some("permissive") => SelinuxMode::Permissive,
+            _ => SelinuxMode::Disabled,
+        }
+    }
+}
+
+fn is_disabled_by_cmdline(cmdline: &Cmdline) -> bool {
+    cmdline.get_bool("selinux") == Some(false)
+}
+
+fn is_enforcing(cmdline: &Cmdline, mode: &SelinuxMode) -> bool {
+    match cmdline.get_bool("enforcing") {
+        Some(enforcing) => enforcing,
+        None => *mode == SelinuxMode::Enforcing,
+    }
+}
+
+/// systemd loads the policy by itself, the other init systems rely on the initramfs
+fn is_policy_load_deferred(cmdline: &Cmdline, init: &Path) -> bool {
+    match cmdline.get_bool(LOAD_POLICY_PARAM) {
+        Some(load_policy) => !load_policy,
+        None => fs::canonicalize(init)
+            .ok()
+            .is_some_and(|init| init.file_name().is_some_and(|name| name == "systemd")),
+    }
+}
+
+fn find_executable(paths: &[&'static str]) -> Option<&'static str> {
+    paths.iter().copied().find(|path| Path::new(path).exists())
+}
+
+fn load_policy(load_policy: &str) -> Result<()> {
+    let output = Command::new(load_policy)
+        .arg("-i")
+        .guarded_output()
+        .with_context(|| format!("unable to run {}", load_policy))?;
+    if !output.status.success() {
+        bail!(
+            "unable to load the SELinux policy:\n{:?}",
+            String::from_utf8(output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+fn relabel_moved_mounts() -> Result<()> {
+    let restorecon = match find_executable(&RESTORECON_PATHS) {
+        Some(restorecon) => restorecon,
+        None => bail!("restorecon not found"),
+    };
+    let output = Command::new(restorecon)
+        .arg("-R")
+        .args(RELABEL_PATHS)
+        .guarded_output()
+        .with_context(|| format!("unable to run {}", restorecon))?;
+    if !output.status.success() {
+        bail!(
+            "unable to relabel {:?}:\n{:?}",
+            RELABEL_PATHS,
+            String::from_utf8(output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// Must be called after chrooting into the new root, before executing init
+pub fn setup(cmdline: &Cmdline, init: &Path) -> Result<()> {
+    if is_disabled_by_cmdline(cmdline) || !Path::new(SELINUX_CONFIG).exists() {
+        return Ok(());
+    }
+    let config = fs::read_to_string(SELINUX_CONFIG)
+        .with_context(|| format!("unable to read {}", SELINUX_CONFIG))?;
+    let mode = SelinuxMode::from_config(&config);
+    if mode == SelinuxMode::Disabled {
+        return Ok(());
+    }
+    if is_policy_load_deferred(cmdline, init) {
+        info!("leaving the SELinux policy load to init");
+        return Ok(());
+    }
+
+    let loaded = match find_executable(&LOAD_POLICY_PATHS) {
+        Some(path) => load_policy(path),
+        None => Err(anyhow::anyhow!("load_policy not found")),
+    };
+    if let Err(err) = loaded {
+        // Booting unconfined would defeat the point of an enforcing system
+        if is_enforcing(cmdline, &mode) {
+            return Err(err);
+        }
+        warn!("{:?}", err);
+        return Ok(());
+    }
+    info!("loaded the SELinux policy");
+
+    if let Err(err) = relabel_moved_mounts() {
+        warn!("{:?}", err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn selinux_mode_test() {
+        assert_eq!(
+            SelinuxMode::from_config("# comment\nSELINUX=enforcing\nSELINUXTYPE=targeted\n"),
+            SelinuxMode::Enforcing
+        );
+        assert_eq!(
+            SelinuxMode::from_config("SELINUX=\"permissive\""),
+            SelinuxMode::Permissive
+        );
+        assert_eq!(SelinuxMode::from_config(""), SelinuxMode::Disabled);
+    }
+
+    #[test]
+    fn is_enforcing_test() {
+        assert!(is_enforcing(&Cmdline::default(), &SelinuxMode::Enforcing));
+        assert!(!is_enforcing(
+            &Cmdline::parse("enforcing=0"),
+            &SelinuxMode::Enforcing
+        ));
+        assert!(is_enforcing(
+            &Cmdline::parse("enforcing=1"),
+            &SelinuxMode::Permissive
+        ));
+    }
+
+    #[test]
+    fn is_policy_load_deferred_test() {
+        let init = Path::new("/nonexistent/init");
+        assert!(!is_policy_load_deferred(&Cmdline::default(), init));
+        assert!(is_policy_load_deferred(
+            &Cmdline::parse("rd.selinux.load_policy=0"),
+            init
+        ));
+        assert!(!is_policy_load_deferred(
+            &Cmdline::parse("rd.selinux.load_policy=1"),
+            init
+        ));
+    }
+}
@@ -0,0 +1,232 @@
+// Shutdown support following the systemd exitrd contract: systemd-shutdown pivots into
+// /run/initramfs, leaving the old root in /oldroot, and executes /shutdown <verb>
+// https://systemd.io/INITRD_INTERFACE/
+
+use anyhow::{bail, Context, Result};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::reboot::{reboot, RebootMode};
+use nix::unistd::sync;
+use tracing::{info, warn};
+
+use std::fs;
+use std::os::unix::fs::{symlink, MetadataExt};
+use std::path::Path;
+
+use crate::device_mapper;
+
+const SHUTDOWN_DIR: &str = "/run/initramfs";
+const SHUTDOWN_NAME: &str = "shutdown";
+const OLD_ROOT: &str = "/oldroot";
+// Modules are not needed at shutdown and take most of the initramfs space
+const SKIPPED_PATHS: [&str; 1] = ["/usr/lib/modules"];
+
+/// Copy the initramfs into /run/initramfs, so that systemd can give control back to
+/// initrz at shutdown
+pub fn prepare() -> Result<()> {
+    let root = Path::new("/");
+    let rootfs_dev = fs::symlink_metadata(root)
+        .with_context(|| "unable to get metadata of /")?
+        .dev();
+    let shutdown_dir = Path::new(SHUTDOWN_DIR);
+    fs::create_dir_all(shutdown_dir)
+        .with_context(|| format!("unable to create {}", SHUTDOWN_DIR))?;
+    copy_contents(root, shutdown_dir, rootfs_dev)?;
+    let shutdown = shutdown_dir.join(SHUTDOWN_NAME);
+    if !shutdown.exists() {
+        symlink("init", &shutdown).with_context(|| format!("unable to create {:?}", shutdown))?;
+    }
+
+    Ok(())
+}
+
+/// Recursively copy the contents of src into dst, without descending into other filesystems
+fn copy_contents(src: &Path, dst: &Path, rootfs_dev: u64) -> Result<()> {
+    for entry in fs::read_dir(src).with_context(|| format!("unable to read {:?}", src))? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.dev() != rootfs_dev
+            || SKIPPED_PATHS
+                .iter()
+                .any(|skipped| path == Path::new(skipped))
+        {
+            continue;
+        }
+        let target = dst.join(entry.file_name());
+        if metadata.is_dir() {
+            fs::create_dir_all(&target)
+                .with_context(|| format!("unable to create {:?}", target))?;
+            fs::set_permissions(&target, metadata.permissions())?;
+            copy_contents(&path, &target, rootfs_dev)?;
+        } else if metadata.file_type().is_symlink() {
+            symlink(fs::read_link(&path)?, &target)
+                .with_context(|| format!("unable to create {:?}", target))?;
+        } else if metadata.is_file() {
+            fs::copy(&path, &target)
+                .with_context(|| format!("unable to copy {:?} to {:?}", path, target))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Return true when executed by systemd-shutdown
+pub fn is_shutdown(arg0: &str) -> bool {
+    Path::new(arg0)
+        .file_name()
+        .is_some_and(|name| name == SHUTDOWN_NAME)
+}
+
+/// Unescape the octal sequences used by the kernel for spaces and other characters
+fn unescape_mountpoint(mountpoint: &str) -> String {
+    let bytes = mountpoint.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let octal = bytes
+            .get(i + 1..i + 4)
+            .and_then(|octal| std::str::from_utf8(octal).ok())
+            .and_then(|octal| u8::from_str_radix(octal, 8).ok());
+        match (bytes[i], octal) {
+            (b'\\', Some(c)) => {
+                unescaped.push(c);
+                i += 4;
+            }
+            (c, _) => {
+                unescaped.push(c);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8_lossy(&unescaped).to_string()
+}
+
+/// Return the mountpoints in /proc/self/mountinfo, in the order they were mounted
+fn parse_mountinfo(mountinfo: &str) -> Vec<String> {
+    mountinfo
+        .lines()
+        .filter_map(|line| line.split(' ').nth(4))
+        .map(unescape_mountpoint)
+        .collect()
+}
+
+fn unmount_old_root() -> Result<()> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")
+        .with_context(|| "unable to read /proc/self/mountinfo")?;
+    // Unmount the children before their parents
+    for mountpoint in parse_mountinfo(&mountinfo)
+        .iter()
+        .rev()
+        .filter(|mountpoint| Path::new(mountpoint).starts_with(OLD_ROOT))
+    {
+        info!("unmounting {}", mountpoint);
+        if let Err(err) = umount2(mountpoint.as_str(), MntFlags::empty()) {
+            warn!("unable to unmount {}: {}", mountpoint, err);
+            // Make sure that no data is written after the shutdown
+            let _ = mount::<str, str, str, str>(
+                None,
+                mountpoint.as_str(),
+                None,
+                MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY,
+                None,
+            );
+            let _ = umount2(mountpoint.as_str(), MntFlags::MNT_DETACH);
+        }
+    }
+
+    Ok(())
+}
+
+fn get_device_names(prefix: &str, name_file: Option<&str>) -> Vec<String> {
+    fs::read_dir("/sys/block")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| entry.file_name().into_string().ok())
+                .filter(|name| name.starts_with(prefix))
+                .filter_map(|name| match name_file {
+                    Some(name_file) => {
+                        fs::read_to_string(Path::new("/sys/block").join(name).join(name_file))
+                            .ok()
+                            .map(|name| name.trim().to_string())
+                    }
+                    None => Some(name),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Remove the device-mapper devices, the ones stacked on top of others are removed first
+fn remove_device_mapper_devices() {
+    let mut devices = get_device_names("dm-", Some("dm/name"));
+    while !devices.is_empty() {
+        let count = devices.len();
+        devices.retain(|name| device_mapper::remove_device(name).is_err());
+        if devices.len() == count {
+            warn!("unable to remove device-mapper devices {:?}", devices);
+            break;
+        }
+    }
+}
+
+fn stop_md_arrays() {
+    for name in get_device_names("md", None) {
+        let array_state = Path::new("/sys/block").join(&name).join("md/array_state");
+        if let Err(err) = fs::write(&array_state, "clear") {
+            warn!("unable to stop md array {}: {}", name, err);
+        }
+    }
+}
+
+fn get_reboot_mode(verb: &str) -> Result<RebootMode> {
+    Ok(match verb {
+        "reboot" => RebootMode::RB_AUTOBOOT,
+        "poweroff" => RebootMode::RB_POWER_OFF,
+        "halt" => RebootMode::RB_HALT_SYSTEM,
+        "kexec" => RebootMode::RB_KEXEC,
+        _ => bail!("unknown shutdown verb {}", verb),
+    })
+}
+
+/// Tear down the storage stack of the old root and execute the shutdown verb
+pub fn run(verb: &str) -> Result<()> {
+    let mode = get_reboot_mode(verb)?;
+    sync();
+    unmount_old_root()?;
+    remove_device_mapper_devices();
+    stop_md_arrays();
+    sync();
+
+    info!("executing {}", verb);
+    reboot(mode).with_context(|| format!("unable to execute {}", verb))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mountinfo_test() {
+        let mountinfo = "\
+22 1 0:21 / / rw,relatime - tmpfs none rw
+23 22 253:0 / /oldroot rw,relatime shared:1 - ext4 /dev/mapper/root rw
+24 23 8:1 / /oldroot/home\\040dir rw - ext4 /dev/sda1 rw
+";
+        assert_eq!(
+            parse_mountinfo(mountinfo),
+            vec!["/", "/oldroot", "/oldroot/home dir"]
+        );
+    }
+
+    #[test]
+    fn shutdown_verb_test() {
+        assert!(is_shutdown("/shutdown"));
+        assert!(!is_shutdown("/init"));
+        assert!(get_reboot_mode("poweroff").is_ok());
+        assert!(get_reboot_mode("suspend").is_err());
+    }
+}
@@ -0,0 +1,118 @@
+// initrz runs as PID 1: reap the processes orphaned by the helpers it spawns (e.g.
+// daemons forking in the background) and handle the signals sent to init
+
+use anyhow::{Context, Result};
+use nix::sys::reboot::{reboot, set_cad_enabled, RebootMode};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::sync;
+use tracing::{debug, info, warn};
+
+use std::io;
+use std::process::{Command, ExitStatus, Output};
+use std::sync::RwLock;
+use std::thread;
+
+// Held for reading while a command is waited for, so that its exit status is not
+// stolen by the reaper
+static COMMANDS: RwLock<()> = RwLock::new(());
+
+/// Run commands without racing with the reaper
+pub trait GuardedCommand {
+    fn guarded_output(&mut self) -> io::Result<Output>;
+    fn guarded_status(&mut self) -> io::Result<ExitStatus>;
+}
+
+impl GuardedCommand for Command {
+    fn guarded_output(&mut self) -> io::Result<Output> {
+        let _guard = COMMANDS.read().unwrap();
+        self.output()
+    }
+
+    fn guarded_status(&mut self) -> io::Result<ExitStatus> {
+        let _guard = COMMANDS.read().unwrap();
+        self.status()
+    }
+}
+
+fn get_handled_signals() -> SigSet {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGCHLD);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGINT);
+    signals
+}
+
+/// Block the handled signals and wait for them in a dedicated thread. It must be called
+/// before spawning any other thread, as they inherit the signal mask. The mask is reset
+/// by std::process::Command in the spawned processes
+pub fn init() -> Result<()> {
+    let signals = get_handled_signals();
+    signals
+        .thread_block()
+        .with_context(|| "unable to block signals")?;
+    thread::spawn(move || listen(signals));
+    // Ctrl-Alt-Del sends SIGINT to init instead of resetting the machine immediately
+    set_cad_enabled(false).with_context(|| "unable to disable Ctrl-Alt-Del")?;
+
+    Ok(())
+}
+
+/// Give the Ctrl-Alt-Del handling back to the kernel, the real init sets its own
+pub fn restore_ctrl_alt_del() {
+    if let Err(err) = set_cad_enabled(true) {
+        warn!("unable to enable Ctrl-Alt-Del: {}", err);
+    }
+}
+
+fn reboot_on_ctrl_alt_del() {
+    info!("Ctrl-Alt-Del pressed, rebooting");
+    sync();
+    let Err(err) = reboot(RebootMode::RB_AUTOBOOT);
+    warn!("unable to reboot: {}", err);
+}
+
+/// Get a signalfd for the handled signals, to wait for them in the event loop. Either
+/// the event loop or the listener thread receives a signal, both call handle()
+pub fn signal_fd() -> Result<SignalFd> {
+    SignalFd::with_flags(
+        &get_handled_signals(),
+        SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC,
+    )
+    .with_context(|| "unable to create signalfd")
+}
+
+pub fn handle(signal: Signal) {
+    match signal {
+        Signal::SIGCHLD => reap_zombies(),
+        Signal::SIGINT => reboot_on_ctrl_alt_del(),
+        // Exiting would make the kernel panic
+        signal => warn!("ignoring {}", signal),
+    }
+}
+
+/// Handle the signals received while initrz is not waiting in the event loop, e.g.
+/// while prompting for a passphrase
+fn listen(signals: SigSet) {
+    loop {
+        match signals.wait() {
+            Ok(signal) => handle(signal),
+            Err(err) => {
+                warn!("unable to wait for signals: {}", err);
+                return;
+            }
+        }
+    }
+}
+
+/// Reap every child that has exited
+pub fn reap_zombies() {
+    let _guard = COMMANDS.write().unwrap();
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(_) => break,
+            Ok(status) => debug!("reaped child: {:?}", status),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// Boot a snapshot of the root with rd.snapshot=<name>, e.g. to go back to the last known
+// good system. The name is a logical volume in the volume group of an LVM root, or a
+// subvolume of a btrfs root
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::lvm::{self, SegmentType, VolumeGroup};
+
+const SNAPSHOT_PARAM: &str = "rd.snapshot";
+// Prefix of the device-mapper uuid of the LVM logical volumes
+const LVM_UUID_PREFIX: &str = "LVM-";
+
+pub fn get_snapshot_from_cmdline(cmdline: &Cmdline) -> Option<String> {
+    cmdline
+        .get(SNAPSHOT_PARAM)
+        .filter(|snapshot| !snapshot.is_empty())
+        .map(String::from)
+}
+
+/// Split a device-mapper name of a logical volume into its volume group and logical volume,
+/// the dashes in both of them are doubled
+fn split_dm_name(dm_name: &str) -> Option<(String, String)> {
+    let bytes = dm_name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'-' {
+            if bytes.get(i + 1) == Some(&b'-') {
+                i += 2;
+                continue;
+            }
+            return Some((
+                dm_name[..i].replace("--", "-"),
+                dm_name[i + 1..].replace("--", "-"),
+            ));
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Return the volume group of an LVM device and its sysfs directory, None for the other
+/// devices
+fn get_volume_group(devname: &str) -> Result<Option<(String, PathBuf)>> {
+    let path =
+        fs::canonicalize(devname).with_context(|| format!("unable to resolve {}", devname))?;
+    let block = Path::new("/sys/class/block").join(path.file_name().unwrap_or_default());
+    let dm = block.join("dm");
+    let is_lvm = fs::read_to_string(dm.join("uuid"))
+        .map(|uuid| uuid.starts_with(LVM_UUID_PREFIX))
+        .unwrap_or(false);
+    if !is_lvm {
+        return Ok(None);
+    }
+    let dm_name = fs::read_to_string(dm.join("name"))
+        .with_context(|| format!("unable to read the device-mapper name of {}", devname))?;
+
+    Ok(split_dm_name(dm_name.trim()).map(|(vg, _)| (vg, block)))
+}
+
+/// Read the metadata of the volume group from one of its physical volumes, walking down the
+/// slaves of the stacked devices
+fn read_volume_group(block: &Path, name: &str) -> Result<VolumeGroup> {
+    let slaves = block.join("slaves");
+    for entry in fs::read_dir(&slaves).with_context(|| format!("unable to read {:?}", slaves))? {
+        let slave = entry?.file_name();
+        let metadata = lvm::read_physical_volume(&format!("/dev/{}", slave.to_string_lossy()))
+            .ok()
+            .and_then(|pv| pv.metadata);
+        if let Some(metadata) = metadata {
+            let vg = VolumeGroup::from_metadata(&metadata)?;
+            if vg.name == name {
+                return Ok(vg);
+            }
+        }
+        if let Ok(vg) = read_volume_group(&Path::new("/sys/class/block").join(&slave), name) {
+            return Ok(vg);
+        }
+    }
+
+    bail!("unable to find the metadata of volume group {}", name)
+}
+
+/// Only thin volumes and classic snapshots, activated with the snapshot target, keep the
+/// content of the origin at the time of the snapshot
+fn check_lvm_snapshot(vg: &VolumeGroup, snapshot: &str) -> Result<()> {
+    let lv = vg.get_logical_volume(snapshot)?;
+    let is_thin = lv
+        .segments
+        .iter()
+        .any(|segment| matches!(segment.segment_type, SegmentType::Thin { .. }));
+    if !is_thin && vg.get_snapshot(lv).is_none() {
+        bail!(
+            "{} in volume group {} is neither a thin nor a classic snapshot",
+            snapshot,
+            vg.name
+        );
+    }
+
+    Ok(())
+}
+
+/// Return the device of the snapshot when the root is an LVM logical volume
+pub fn get_lvm_snapshot(devname: &str, snapshot: &str) -> Result<Option<String>> {
+    let (vg, block) = match get_volume_group(devname)? {
+        Some(vg) => vg,
+        None => return Ok(None),
+    };
+    check_lvm_snapshot(&read_volume_group(&block, &vg)?, snapshot)?;
+    let device = format!(
+        "/dev/mapper/{}-{}",
+        vg.replace('-', "--"),
+        snapshot.replace('-', "--")
+    );
+    if !Path::new(&device).exists() {
+        bail!("snapshot {} not found in volume group {}", snapshot, vg);
+    }
+
+    Ok(Some(device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_snapshot_from_cmdline_test() {
+        assert_eq!(get_snapshot_from_cmdline(&Cmdline::parse("quiet")), None);
+        assert_eq!(
+            get_snapshot_from_cmdline(&Cmdline::parse("rd.snapshot=")),
+            None
+        );
+        assert_eq!(
+            get_snapshot_from_cmdline(&Cmdline::parse("rd.snapshot=a rd.snapshot=good")),
+            Some("good".to_string())
+        );
+    }
+
+    #[test]
+    fn split_dm_name_test() {
+        assert_eq!(
+            split_dm_name("vg-root"),
+            Some(("vg".to_string(), "root".to_string()))
+        );
+        assert_eq!(
+            split_dm_name("my--vg-root--snap-1"),
+            Some(("my-vg".to_string(), "root-snap-1".to_string()))
+        );
+        assert_eq!(split_dm_name("luks--root"), None);
+    }
+
+    #[test]
+    fn check_lvm_snapshot_test() {
+        let metadata =
+            std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/test/lvm.metadata"))
+                .unwrap();
+        let vg = VolumeGroup::from_metadata(&metadata).unwrap();
+
+        // var_snap is the cow store of a classic snapshot of var
+        assert!(check_lvm_snapshot(&vg, "var_snap").is_ok());
+        assert!(check_lvm_snapshot(&vg, "thin").is_ok());
+        assert!(check_lvm_snapshot(&vg, "var").is_err());
+        assert!(check_lvm_snapshot(&vg, "root").is_err());
+        assert!(check_lvm_snapshot(&vg, "snapshot0").is_err());
+        assert!(check_lvm_snapshot(&vg, "missing").is_err());
+    }
+}
@@ -0,0 +1,210 @@
+// SSH rescue access through dropbear, shipped by mkinitrz when ssh_authorized_keys is set
+
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use nix::poll::{poll, PollFd, PollFlags};
+use nix::sys::signal::{kill, Signal};
+use nix::sys::stat::Mode;
+use nix::sys::termios::{tcgetattr, tcsetattr, LocalFlags, SetArg};
+use nix::unistd::{mkfifo, Pid};
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::Command;
+use std::thread::sleep;
+use std::time::Duration;
+
+use crate::signal_handler::GuardedCommand;
+
+const DROPBEAR: &str = "/usr/bin/dropbear";
+const HOST_KEYS: [&str; 3] = [
+    "/etc/dropbear/dropbear_ed25519_host_key",
+    "/etc/dropbear/dropbear_ecdsa_host_key",
+    "/etc/dropbear/dropbear_rsa_host_key",
+];
+const SSH_PARAM: &str = "rd.ssh";
+const SSH_PORT_PARAM: &str = "rd.ssh.port";
+const DEFAULT_PORT: u16 = 22;
+const SSH_DIR: &str = "/run/initrz/ssh";
+const PASSPHRASE_FIFO: &str = "/run/initrz/ssh/passphrase";
+const PROMPT_FILE: &str = "/run/initrz/ssh/prompt";
+const PID_FILE: &str = "/run/initrz/ssh/dropbear.pid";
+const BANNER_FILE: &str = "/run/initrz/ssh/banner";
+const BANNER: &str = "Run \"/init unlock\" to enter the passphrases of the encrypted devices\n";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// SSH access is available when dropbear has been shipped, unless disabled with rd.ssh=0
+pub fn is_enabled(cmdline: &Cmdline) -> bool {
+    Path::new(DROPBEAR).exists() && cmdline.get_bool(SSH_PARAM) != Some(false)
+}
+
+fn get_port_from_cmdline(cmdline: &Cmdline) -> Result<u16> {
+    Ok(cmdline.get_parsed(SSH_PORT_PARAM)?.unwrap_or(DEFAULT_PORT))
+}
+
+/// Start dropbear in the background, only allowing public key logins
+pub fn start(cmdline: &Cmdline) -> Result<()> {
+    let port = get_port_from_cmdline(cmdline)?;
+    fs::create_dir_all(SSH_DIR).with_context(|| format!("unable to create {}", SSH_DIR))?;
+    mkfifo(PASSPHRASE_FIFO, Mode::S_IRUSR | Mode::S_IWUSR)
+        .with_context(|| format!("unable to create {}", PASSPHRASE_FIFO))?;
+    fs::write(BANNER_FILE, BANNER).with_context(|| format!("unable to write {}", BANNER_FILE))?;
+
+    let mut command = Command::new(DROPBEAR);
+    command
+        .args(["-s", "-j", "-k"])
+        .args(["-p", &port.to_string()])
+        .args(["-P", PID_FILE, "-b", BANNER_FILE]);
+    let host_keys: Vec<&str> = HOST_KEYS
+        .iter()
+        .filter(|key| Path::new(key).exists())
+        .copied()
+        .collect();
+    if host_keys.is_empty() {
+        // Generate the missing host keys on the first connection
+        fs::create_dir_all("/etc/dropbear").with_context(|| "unable to create /etc/dropbear")?;
+        command.arg("-R");
+    }
+    for key in host_keys {
+        command.args(["-r", key]);
+    }
+    let output = command
+        .guarded_output()
+        .with_context(|| "unable to run dropbear command")?;
+    if !output.status.success() {
+        bail!(
+            "dropbear command failed:\n{:?}",
+            String::from_utf8(output.stderr)
+        )
+    }
+
+    Ok(())
+}
+
+/// Stop accepting new connections before switching to the real root
+pub fn stop() -> Result<()> {
+    let pid =
+        fs::read_to_string(PID_FILE).with_context(|| format!("unable to read {}", PID_FILE))?;
+    let pid: i32 = pid
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid pid in {}", PID_FILE))?;
+    kill(Pid::from_raw(pid), Signal::SIGTERM).with_context(|| "unable to stop dropbear")?;
+    // Make the pending `/init unlock` sessions exit
+    fs::remove_file(PASSPHRASE_FIFO)
+        .with_context(|| format!("unable to remove {}", PASSPHRASE_FIFO))?;
+
+    Ok(())
+}
+
+/// Return true if the passphrases can be entered remotely
+pub fn is_running() -> bool {
+    Path::new(PASSPHRASE_FIFO).exists()
+}
+
+fn read_line(reader: impl Read) -> Result<String> {
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line)?;
+
+    Ok(line.trim_end_matches('\n').to_string())
+}
+
+/// Ask for a password on the console, while also accepting it from an SSH session
+pub fn ask_for_password(prompt: &str) -> Result<String> {
+    fs::write(PROMPT_FILE, prompt).with_context(|| format!("unable to write {}", PROMPT_FILE))?;
+    // Keep the fifo open for writing too, so that it does not report EOF between sessions
+    let fifo = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(PASSPHRASE_FIFO)
+        .with_context(|| format!("unable to open {}", PASSPHRASE_FIFO))?;
+
+    let stdin = io::stdin();
+    // The console could be missing on headless machines
+    let termios = tcgetattr(&stdin).ok();
+    if let Some(termios) = &termios {
+        let mut noecho = termios.clone();
+        noecho.local_flags.remove(LocalFlags::ECHO);
+        noecho.local_flags.insert(LocalFlags::ECHONL);
+        tcsetattr(&stdin, SetArg::TCSANOW, &noecho)?;
+    }
+    print!("{}", prompt);
+    io::stdout().flush()?;
+
+    let mut fds = [
+        PollFd::new(&stdin, PollFlags::POLLIN),
+        PollFd::new(&fifo, PollFlags::POLLIN),
+    ];
+    let res = poll(&mut fds, -1).with_context(|| "unable to wait for a password");
+    let from_console = fds[0]
+        .revents()
+        .is_some_and(|revents| revents.contains(PollFlags::POLLIN));
+    if let Some(termios) = &termios {
+        tcsetattr(&stdin, SetArg::TCSANOW, termios)?;
+    }
+    res?;
+    let _ = fs::remove_file(PROMPT_FILE);
+
+    if from_console {
+        read_line(stdin.lock())
+    } else {
+        println!();
+        read_line(&fifo)
+    }
+}
+
+/// Entry point of `/init unlock`, run from an SSH session. Forward the passphrases to
+/// initrz until it switches to the real root
+pub fn unlock() -> Result<()> {
+    loop {
+        // Opening the fifo only succeeds while initrz is waiting for a passphrase
+        let mut fifo = match OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(PASSPHRASE_FIFO)
+        {
+            Ok(fifo) => fifo,
+            Err(err) if err.raw_os_error() == Some(libc::ENXIO) => {
+                sleep(POLL_INTERVAL);
+                continue;
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                println!("no more passphrases are needed");
+                return Ok(());
+            }
+            Err(err) => {
+                return Err(err).with_context(|| format!("unable to open {}", PASSPHRASE_FIFO))
+            }
+        };
+
+        let prompt = fs::read_to_string(PROMPT_FILE).unwrap_or_default();
+        let passphrase =
+            rpassword::prompt_password(prompt).context("unable to read password from stdin")?;
+        // The passphrase could have been entered on the console in the meantime
+        if let Err(err) = writeln!(fifo, "{}", passphrase) {
+            eprintln!("unable to send the passphrase: {}", err);
+        }
+        // Let initrz try the passphrase before asking for the next one
+        sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_cmdline_test() {
+        assert_eq!(
+            get_port_from_cmdline(&Cmdline::default()).unwrap(),
+            DEFAULT_PORT
+        );
+        assert_eq!(
+            get_port_from_cmdline(&Cmdline::parse("rd.ssh.port=2222")).unwrap(),
+            2222
+        );
+        assert!(get_port_from_cmdline(&Cmdline::parse("rd.ssh.port=ssh")).is_err());
+    }
+}
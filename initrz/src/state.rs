@@ -0,0 +1,160 @@
+// Describe what initrz knows to the breakpoint and emergency shells, so that the boot
+// can be debugged from the shell alone
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::console;
+use crate::probe::Superblock;
+
+const STATE_DIR: &str = "/run/initrz";
+const STATE_FILE: &str = "/run/initrz/state.json";
+const FUNCTIONS_FILE: &str = "/run/initrz/functions.sh";
+const LOG_FILE: &str = "/run/initrz/log";
+const BREAK_PARAM: &str = "rd.break";
+// Sourced by the interactive busybox shells through $ENV
+const FUNCTIONS: &str = r#"# Helpers of the initrz shells, the boot state is in $INITRZ_STATE
+
+# Start initrz again, e.g. after loading a missing driver
+retry_root() {
+    exec /init
+}
+alias retry-root=retry_root
+
+# Unlock a LUKS device: unlock <device> [name]
+unlock() {
+    /init unlock-device "$@"
+}
+
+# Print the log of initrz, including the records hidden by quiet
+initrz_log() {
+    cat "$INITRZ_LOG"
+}
+alias initrz-log=initrz_log
+
+echo "root: ${INITRZ_ROOT:-unknown} (found: ${INITRZ_ROOT_DEVICE:-no})"
+echo "boot state: $INITRZ_STATE, helpers: retry-root, unlock <device> [name], initrz-log"
+"#;
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct DeviceState {
+    pub path: String,
+    pub fs_type: Option<String>,
+    pub uuid: Option<String>,
+    pub label: Option<String>,
+}
+
+impl DeviceState {
+    pub fn new(path: &str, superblock: Option<&Superblock>) -> DeviceState {
+        DeviceState {
+            path: path.to_string(),
+            fs_type: superblock.map(|superblock| superblock.fs_type.to_string()),
+            uuid: superblock.and_then(|superblock| superblock.uuid.clone()),
+            label: superblock.and_then(|superblock| superblock.label.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct EncryptedDeviceState {
+    pub name: String,
+    pub identifier: String,
+    pub unlocked: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct State {
+    pub cmdline: Vec<String>,
+    /// The root identifier, e.g. UUID=...
+    pub root: String,
+    /// The device matching the root identifier, if found
+    pub root_device: Option<String>,
+    pub encrypted_devices: Vec<EncryptedDeviceState>,
+    pub devices: Vec<DeviceState>,
+}
+
+impl State {
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(STATE_DIR).with_context(|| format!("unable to create {}", STATE_DIR))?;
+        fs::write(STATE_FILE, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("unable to write {}", STATE_FILE))
+    }
+
+    fn load() -> Option<State> {
+        serde_json::from_str(&fs::read_to_string(STATE_FILE).ok()?).ok()
+    }
+}
+
+/// rd.break stops the boot right before mounting the root
+pub fn is_break_requested(cmdline: &Cmdline) -> bool {
+    cmdline.has_flag(BREAK_PARAM)
+}
+
+/// Build the busybox shell command with the helpers and the state in its environment
+pub fn shell() -> Command {
+    let mut command = Command::new("busybox");
+    command.arg("sh");
+    let _ = fs::create_dir_all(STATE_DIR);
+    match console::save_log(Path::new(LOG_FILE)) {
+        Ok(()) => {
+            command.env("INITRZ_LOG", LOG_FILE);
+        }
+        Err(err) => warn!("{:?}", err),
+    }
+    if fs::write(FUNCTIONS_FILE, FUNCTIONS).is_ok() {
+        command.env("ENV", FUNCTIONS_FILE);
+    }
+    if let Some(state) = State::load() {
+        command
+            .env("INITRZ_STATE", STATE_FILE)
+            .env("INITRZ_ROOT", state.root)
+            .env("INITRZ_CMDLINE", state.cmdline.join(" "));
+        if let Some(root_device) = state.root_device {
+            command.env("INITRZ_ROOT_DEVICE", root_device);
+        }
+    }
+
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_state_test() {
+        let superblock = Superblock {
+            fs_type: "ext4",
+            uuid: Some("f3b8e1a2-0000-4000-8000-000000000000".to_string()),
+            label: None,
+        };
+        assert_eq!(
+            DeviceState::new("/dev/sda1", Some(&superblock)),
+            DeviceState {
+                path: "/dev/sda1".to_string(),
+                fs_type: Some("ext4".to_string()),
+                uuid: superblock.uuid.clone(),
+                label: None,
+            }
+        );
+        assert_eq!(
+            DeviceState::new("/dev/sda", None),
+            DeviceState {
+                path: "/dev/sda".to_string(),
+                ..DeviceState::default()
+            }
+        );
+    }
+
+    #[test]
+    fn is_break_requested_test() {
+        assert!(is_break_requested(&Cmdline::parse("rd.break")));
+        assert!(!is_break_requested(&Cmdline::parse("rd.breakpoint")));
+    }
+}
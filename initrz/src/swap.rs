@@ -0,0 +1,152 @@
+// Enable swap before mounting the root, so that memory constrained systems do not run out
+// of memory, e.g. when unpacking a large image or checking a huge filesystem. The swap
+// devices are listed in /etc/fstab.initramfs or marked with x-initrd.swap in
+// /etc/crypttab.initramfs
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use tracing::{info, warn};
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::device_registry::DeviceRegistry;
+use crate::fstab::parse_fstab;
+use crate::identifier::Identifier;
+
+const FSTAB: &str = "/etc/fstab.initramfs";
+const SWAP_TYPE: &str = "swap";
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+const SWAP_VERSION: u32 = 1;
+// The header follows the space reserved for the boot loader
+const SWAP_HEADER_OFFSET: usize = 1024;
+// mkswap(8) refuses smaller swap spaces
+const MIN_SWAP_PAGES: u64 = 10;
+
+/// Get the identifiers of the swap entries
+fn get_fstab_swaps(fstab: &str) -> Vec<Identifier> {
+    parse_fstab(fstab)
+        .into_iter()
+        .filter(|entry| entry.fs_type == SWAP_TYPE)
+        .map(|entry| Identifier::from(entry.spec.as_str()))
+        .collect()
+}
+
+/// Build the first page of a swap space of the given size, like mkswap(8) does
+fn get_swap_header(size: u64, page_size: usize, uuid: [u8; 16]) -> Result<Vec<u8>> {
+    let pages = size / page_size as u64;
+    if pages < MIN_SWAP_PAGES {
+        bail!("swap space of {} bytes is too small", size);
+    }
+    let mut header = vec![0; page_size];
+    let mut fields = Vec::new();
+    fields.extend(SWAP_VERSION.to_ne_bytes());
+    // last_page, then nr_badpages
+    fields.extend(u32::try_from(pages - 1).unwrap_or(u32::MAX).to_ne_bytes());
+    fields.extend(0u32.to_ne_bytes());
+    fields.extend(uuid);
+    header[SWAP_HEADER_OFFSET..SWAP_HEADER_OFFSET + fields.len()].copy_from_slice(&fields);
+    header[page_size - SWAP_MAGIC.len()..].copy_from_slice(SWAP_MAGIC);
+
+    Ok(header)
+}
+
+/// Write a swap signature with a random UUID on the device
+pub fn mkswap(path: &str) -> Result<()> {
+    let mut uuid = [0; 16];
+    File::open("/dev/urandom")
+        .and_then(|mut urandom| urandom.read_exact(&mut uuid))
+        .context("unable to read from /dev/urandom")?;
+    // Version 4, variant 1
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+    let page_size = usize::try_from(unsafe { libc::sysconf(libc::_SC_PAGESIZE) })
+        .context("unable to get the page size")?;
+
+    let mut device = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format!("unable to open {}", path))?;
+    let size = device.seek(SeekFrom::End(0))?;
+    let header = get_swap_header(size, page_size, uuid)?;
+    device.seek(SeekFrom::Start(0))?;
+    device
+        .write_all(&header)
+        .and_then(|_| device.sync_all())
+        .with_context(|| format!("unable to write the swap header on {}", path))
+}
+
+fn swapon(path: &str) -> Result<()> {
+    let c_path = CString::new(path)?;
+    Errno::result(unsafe { libc::swapon(c_path.as_ptr(), 0) })
+        .with_context(|| format!("unable to enable swap on {}", path))?;
+
+    Ok(())
+}
+
+/// Enable the swaps of fstab.initramfs and the given unlocked devices. Swap is never
+/// required to boot, the failures are only logged
+pub fn activate(encrypted_swaps: &[String], device_registry: &DeviceRegistry) {
+    let mut swaps: Vec<Identifier> = fs::read_to_string(FSTAB)
+        .map(|fstab| get_fstab_swaps(&fstab))
+        .unwrap_or_default();
+    swaps.extend(
+        encrypted_swaps
+            .iter()
+            .map(|path| Identifier::Path(path.clone())),
+    );
+    for identifier in swaps {
+        let result = identifier.get_path(device_registry).and_then(|path| {
+            info!("enabling swap on {}", path);
+            swapon(&path)
+        });
+        if let Err(err) = result {
+            warn!("{:?}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::probe;
+
+    #[test]
+    fn get_swap_header_test() {
+        let uuid = [0x12; 16];
+        let header = get_swap_header(1 << 20, 4096, uuid).unwrap();
+        assert_eq!(header.len(), 4096);
+        assert_eq!(&header[1028..1032], &255u32.to_ne_bytes());
+        assert!(get_swap_header(8192, 4096, uuid).is_err());
+
+        let path = std::env::temp_dir().join("initrz-swap.img");
+        fs::write(&path, &header).unwrap();
+        let superblock = probe::probe_device(path.to_str().unwrap())
+            .unwrap()
+            .unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(superblock.fs_type, "swap");
+        assert_eq!(
+            superblock.uuid.as_deref(),
+            Some("12121212-1212-1212-1212-121212121212")
+        );
+    }
+
+    #[test]
+    fn get_fstab_swaps_test() {
+        assert_eq!(
+            get_fstab_swaps(
+                "UUID=1234 none swap defaults,x-initrd.swap 0 0
+/dev/mapper/swap none swap x-initrd.swap
+/dev/sda1 /boot vfat defaults 0 2
+"
+            ),
+            vec![
+                Identifier::Uuid("1234".to_string()),
+                Identifier::Path("/dev/mapper/swap".to_string())
+            ]
+        );
+    }
+}
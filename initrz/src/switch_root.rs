@@ -0,0 +1,248 @@
+// Move into the new root like busybox switch_root: check that the switch can be done,
+// delete the initramfs contents to free its memory, move the new root mount onto /, chroot
+// into it and reopen the console. pivot_root(2) cannot be used, the rootfs is never
+// unmounted
+// https://github.com/mirror/busybox/blob/9ec836c033fc6e55e80f3309b3e05acdf09bb297/util-linux/switch_root.c#L297
+
+use anyhow::{bail, Context, Result};
+use nix::mount::{mount, MsFlags};
+use nix::sys::statfs::{statfs, FsType, TMPFS_MAGIC};
+use nix::unistd::{chroot, dup2};
+use tracing::warn;
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Component, Path, PathBuf};
+
+pub const NEW_ROOT: &str = "/new_root";
+const CONSOLE: &str = "/dev/console";
+// Same limit of the kernel when following symlinks
+const MAX_SYMLINKS: usize = 40;
+// Missing from nix, https://github.com/torvalds/linux/blob/master/include/uapi/linux/magic.h
+const RAMFS_MAGIC: FsType = FsType(0x858458f6_u32 as _);
+
+/// Only PID 1 can switch root, the kernel panics when it exits
+fn check_pid(pid: u32) -> Result<()> {
+    if pid != 1 {
+        bail!("switch_root must be run as PID 1, running as {}", pid);
+    }
+
+    Ok(())
+}
+
+/// The initramfs must be the rootfs, a real filesystem would be deleted
+fn check_rootfs(filesystem: FsType) -> Result<()> {
+    if filesystem != RAMFS_MAGIC && filesystem != TMPFS_MAGIC {
+        bail!("/ is not an initramfs, refusing to delete its contents");
+    }
+
+    Ok(())
+}
+
+/// The new root must be another filesystem mounted on top of the initramfs
+fn check_mountpoint(new_root: &Path, rootfs_dev: u64) -> Result<()> {
+    let metadata =
+        fs::metadata(new_root).with_context(|| format!("unable to access {:?}", new_root))?;
+    if !metadata.is_dir() {
+        bail!("{:?} is not a directory", new_root);
+    }
+    if metadata.dev() == rootfs_dev {
+        bail!("{:?} is not a mountpoint", new_root);
+    }
+
+    Ok(())
+}
+
+/// Resolve a path inside root, following the absolute symlinks from root instead of /,
+/// like it happens once chrooted
+fn resolve_in(root: &Path, path: &Path) -> Result<PathBuf> {
+    let mut resolved = PathBuf::from("/");
+    let mut pending: Vec<PathBuf> = vec![path.to_path_buf()];
+    let mut symlinks = 0;
+    while let Some(path) = pending.pop() {
+        let mut components = path.components();
+        while let Some(component) = components.next() {
+            match component {
+                Component::RootDir => resolved = PathBuf::from("/"),
+                Component::CurDir | Component::Prefix(_) => {}
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => {
+                    resolved.push(name);
+                    let full = root.join(resolved.strip_prefix("/")?);
+                    if let Ok(target) = fs::read_link(&full) {
+                        symlinks += 1;
+                        if symlinks > MAX_SYMLINKS {
+                            bail!("too many symlinks when resolving {:?}", path);
+                        }
+                        resolved.pop();
+                        // Resolve the target, then the rest of the path
+                        pending.push(components.as_path().to_path_buf());
+                        pending.push(target);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(root.join(resolved.strip_prefix("/")?))
+}
+
+/// The init must be executable in the new root, nothing can be done once the initramfs
+/// has been deleted
+fn check_init(new_root: &Path, init: &Path) -> Result<()> {
+    let path = resolve_in(new_root, init)?;
+    let metadata = fs::metadata(&path)
+        .with_context(|| format!("unable to find {:?} in the new root", init))?;
+    if !metadata.is_file() || metadata.permissions().mode() & 0o111 == 0 {
+        bail!("{:?} in the new root is not an executable file", init);
+    }
+
+    Ok(())
+}
+
+/// Free the memory used by the initramfs before switching root
+fn delete_rootfs_contents(rootfs_dev: u64) {
+    delete_contents(Path::new("/"), rootfs_dev);
+}
+
+/// Recursively delete the contents of dir, without descending into other filesystems
+fn delete_contents(dir: &Path, rootfs_dev: u64) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            warn!("unable to read {:?}: {}", dir, err);
+            return;
+        }
+    };
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        // Mountpoints like /new_root are on another device
+        if metadata.dev() != rootfs_dev {
+            continue;
+        }
+        let res = if metadata.is_dir() {
+            delete_contents(&path, rootfs_dev);
+            fs::remove_dir(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        if let Err(err) = res {
+            warn!("unable to remove {:?}: {}", path, err);
+        }
+    }
+}
+
+/// Point stdin, stdout and stderr to the console of the new root, the old one has been
+/// deleted with the initramfs
+fn reopen_console() -> Result<()> {
+    let console = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(CONSOLE)
+        .with_context(|| format!("unable to open {}", CONSOLE))?;
+    for fd in 0..=2 {
+        dup2(console.as_raw_fd(), fd).with_context(|| "unable to duplicate the console")?;
+    }
+
+    Ok(())
+}
+
+/// Make the root mounted on new_root the new /. The checks are done before deleting
+/// anything, so that a failure still leaves a working initramfs for the emergency shell
+pub fn switch_root(new_root: &Path, init: &Path) -> Result<()> {
+    check_pid(std::process::id())?;
+    check_rootfs(
+        statfs("/")
+            .with_context(|| "unable to get filesystem of /")?
+            .filesystem_type(),
+    )?;
+    let rootfs_dev = fs::symlink_metadata("/")
+        .with_context(|| "unable to get metadata of /")?
+        .dev();
+    check_mountpoint(new_root, rootfs_dev)?;
+    check_init(new_root, init)?;
+
+    env::set_current_dir(new_root)
+        .with_context(|| format!("unable to change directory to {:?}", new_root))?;
+    delete_rootfs_contents(rootfs_dev);
+    mount(Some("."), "/", None::<&str>, MsFlags::MS_MOVE, None::<&str>)
+        .with_context(|| "unable to move the new root into /")?;
+    chroot(".").with_context(|| "unable to chroot")?;
+    env::set_current_dir("/")?;
+    if let Err(err) = reopen_console() {
+        warn!("{:?}", err);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn check_pid_test() {
+        assert!(check_pid(1).is_ok());
+        assert!(check_pid(42).is_err());
+    }
+
+    #[test]
+    fn check_rootfs_test() {
+        assert!(check_rootfs(RAMFS_MAGIC).is_ok());
+        assert!(check_rootfs(TMPFS_MAGIC).is_ok());
+        // ext4
+        assert!(check_rootfs(FsType(0xef53)).is_err());
+    }
+
+    #[test]
+    fn check_mountpoint_test() {
+        let dir = std::env::temp_dir().join("initrz-switch-root-mountpoint");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let dev = fs::metadata(&dir).unwrap().dev();
+        assert!(check_mountpoint(&dir, dev + 1).is_ok());
+        assert!(check_mountpoint(&dir, dev).is_err());
+        assert!(check_mountpoint(&dir.join("missing"), dev + 1).is_err());
+        fs::write(dir.join("file"), "").unwrap();
+        assert!(check_mountpoint(&dir.join("file"), dev + 1).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn check_init_test() {
+        let root = std::env::temp_dir().join("initrz-switch-root-init");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sbin")).unwrap();
+        fs::create_dir_all(root.join("lib/systemd")).unwrap();
+        let systemd = root.join("lib/systemd/systemd");
+        fs::write(&systemd, "").unwrap();
+        fs::set_permissions(&systemd, fs::Permissions::from_mode(0o755)).unwrap();
+        // Absolute symlinks are resolved inside the new root
+        symlink("/lib/systemd/systemd", root.join("sbin/init")).unwrap();
+        symlink("../lib/systemd/systemd", root.join("sbin/relative")).unwrap();
+        symlink("/missing", root.join("sbin/dangling")).unwrap();
+        symlink("/sbin/loop", root.join("sbin/loop")).unwrap();
+        fs::write(root.join("sbin/noexec"), "").unwrap();
+        fs::set_permissions(root.join("sbin/noexec"), fs::Permissions::from_mode(0o644)).unwrap();
+
+        assert_eq!(resolve_in(&root, Path::new("/sbin/init")).unwrap(), systemd);
+        assert!(check_init(&root, Path::new("/sbin/init")).is_ok());
+        assert!(check_init(&root, Path::new("/sbin/relative")).is_ok());
+        assert!(check_init(&root, Path::new("/sbin/dangling")).is_err());
+        assert!(check_init(&root, Path::new("/sbin/loop")).is_err());
+        assert!(check_init(&root, Path::new("/sbin/noexec")).is_err());
+        assert!(check_init(&root, Path::new("/lib/systemd")).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
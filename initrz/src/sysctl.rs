@@ -0,0 +1,166 @@
+// Apply the sysctl settings before the storage and network stacks start
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use tracing::{debug, warn};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const PROC_SYS: &str = "/proc/sys";
+const SYSCTL_PARAM: &str = "sysctl.";
+// Embedded by mkinitrz in host-only initramfs
+const SYSCTL_CONF: &str = "/etc/sysctl.conf";
+const SYSCTL_DIR: &str = "/etc/sysctl.d";
+
+#[derive(Debug, PartialEq)]
+struct Setting {
+    key: String,
+    value: String,
+    // Keys prefixed with '-' do not fail when they cannot be set
+    ignore_failure: bool,
+}
+
+impl Setting {
+    fn path(&self) -> PathBuf {
+        // Keys can use either '.' or '/' as separator, the first one decides
+        let key = if self.key.find(['.', '/']).map(|i| &self.key[i..i + 1]) == Some("/") {
+            self.key.clone()
+        } else {
+            self.key.replace('.', "/")
+        };
+        Path::new(PROC_SYS).join(key)
+    }
+
+    fn apply(&self) -> Result<()> {
+        let path = self.path();
+        fs::write(&path, &self.value)
+            .with_context(|| format!("unable to write {} to {:?}", self.value, path))
+    }
+}
+
+fn parse_setting(line: &str) -> Option<Setting> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(['#', ';']) {
+        return None;
+    }
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    let (key, ignore_failure) = match key.strip_prefix('-') {
+        Some(key) => (key, true),
+        None => (key, false),
+    };
+    if key.is_empty() {
+        return None;
+    }
+
+    Some(Setting {
+        key: key.to_string(),
+        value: value.trim().to_string(),
+        ignore_failure,
+    })
+}
+
+fn get_settings_from_cmdline(cmdline: &Cmdline) -> Vec<Setting> {
+    cmdline
+        .iter()
+        .filter_map(|arg| arg.strip_prefix(SYSCTL_PARAM))
+        .filter_map(parse_setting)
+        .collect()
+}
+
+/// Read /etc/sysctl.d/*.conf in lexicographic order, then /etc/sysctl.conf
+fn get_settings_from_files() -> Result<Vec<Setting>> {
+    let mut files = Vec::new();
+    if Path::new(SYSCTL_DIR).exists() {
+        for entry in fs::read_dir(SYSCTL_DIR)
+            .with_context(|| format!("unable to read directory {}", SYSCTL_DIR))?
+        {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "conf") {
+                files.push(path);
+            }
+        }
+        files.sort();
+    }
+    files.push(PathBuf::from(SYSCTL_CONF));
+
+    let mut settings = Vec::new();
+    for file in files.iter().filter(|file| file.exists()) {
+        let content =
+            fs::read_to_string(file).with_context(|| format!("unable to read {:?}", file))?;
+        settings.extend(content.lines().filter_map(parse_setting));
+    }
+
+    Ok(settings)
+}
+
+/// Apply the settings of the embedded configuration, then the ones on the cmdline
+pub fn apply(cmdline: &Cmdline) -> Result<()> {
+    let mut settings = get_settings_from_files()?;
+    settings.extend(get_settings_from_cmdline(cmdline));
+
+    for setting in settings {
+        debug!("setting {} to {}", setting.key, setting.value);
+        if let Err(err) = setting.apply() {
+            if setting.ignore_failure {
+                debug!("{:?}", err);
+            } else {
+                warn!("{:?}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_setting_test() {
+        assert_eq!(
+            parse_setting("kernel.panic = 10"),
+            Some(Setting {
+                key: "kernel.panic".to_string(),
+                value: "10".to_string(),
+                ignore_failure: false,
+            })
+        );
+        assert_eq!(
+            parse_setting("-net.ipv4.conf.eth0/1.rp_filter=0").map(|s| s.ignore_failure),
+            Some(true)
+        );
+        assert_eq!(parse_setting("# vm.swappiness = 10"), None);
+        assert_eq!(parse_setting("; comment"), None);
+        assert_eq!(parse_setting("vm.swappiness"), None);
+    }
+
+    #[test]
+    fn setting_path_test() {
+        let setting = parse_setting("net.ipv4.conf.eth0/1.rp_filter=0").unwrap();
+        assert_eq!(
+            setting.path(),
+            PathBuf::from("/proc/sys/net/ipv4/conf/eth0/1/rp_filter")
+        );
+        let setting = parse_setting("net/ipv4/conf/eth0.1/rp_filter=0").unwrap();
+        assert_eq!(
+            setting.path(),
+            PathBuf::from("/proc/sys/net/ipv4/conf/eth0.1/rp_filter")
+        );
+    }
+
+    #[test]
+    fn get_settings_from_cmdline_test() {
+        let cmdline = Cmdline::from(vec![
+            "root=/dev/sda1".to_string(),
+            "sysctl.kernel.panic=10".to_string(),
+            "sysctl.vm.dirty_ratio=5".to_string(),
+        ]);
+        let settings = get_settings_from_cmdline(&cmdline);
+        assert_eq!(settings.len(), 2);
+        assert_eq!(settings[0].key, "kernel.panic");
+        assert_eq!(settings[1].value, "5");
+    }
+}
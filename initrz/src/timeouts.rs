@@ -0,0 +1,89 @@
+// Timeouts of the boot stages, given in seconds with rd.timeout.<stage>=
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use tracing::warn;
+
+use std::time::Duration;
+
+const TIMEOUT_PARAM: &str = "rd.timeout.";
+const DEFAULT_ROOT_TIMEOUT: Duration = Duration::from_secs(180);
+const DEFAULT_SETTLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_NETWORK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timeouts {
+    /// Waiting for the root device to appear
+    pub root: Duration,
+    /// Waiting for the disks of iSCSI and NVMe over Fabrics targets after connecting, and
+    /// for the root device before loading every storage driver
+    pub settle: Duration,
+    /// Waiting for a network interface to appear
+    pub network: Duration,
+    /// Limit for the passphrase prompt of the devices without timeout= in crypttab, None
+    /// waits forever
+    pub passphrase: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            root: DEFAULT_ROOT_TIMEOUT,
+            settle: DEFAULT_SETTLE_TIMEOUT,
+            network: DEFAULT_NETWORK_TIMEOUT,
+            passphrase: None,
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn from_cmdline(cmdline: &Cmdline) -> Result<Timeouts> {
+        let mut timeouts = Timeouts::default();
+        for (stage, value) in cmdline.get_prefixed(TIMEOUT_PARAM) {
+            let timeout = Duration::from_secs(value.parse().with_context(|| {
+                format!("invalid value for {}{}={}", TIMEOUT_PARAM, stage, value)
+            })?);
+            match stage {
+                "root" => timeouts.root = timeout,
+                "settle" => timeouts.settle = timeout,
+                "network" => timeouts.network = timeout,
+                // 0 waits forever
+                "passphrase" => timeouts.passphrase = (!timeout.is_zero()).then_some(timeout),
+                _ => warn!("unknown timeout {}{}, ignoring", TIMEOUT_PARAM, stage),
+            }
+        }
+
+        Ok(timeouts)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_cmdline_test() {
+        assert_eq!(
+            Timeouts::from_cmdline(&Cmdline::parse("quiet")).unwrap(),
+            Timeouts::default()
+        );
+        assert_eq!(
+            Timeouts::from_cmdline(&Cmdline::parse(
+                "rd.timeout.root=60 rd.timeout.passphrase=120 rd.timeout.root=30"
+            ))
+            .unwrap(),
+            Timeouts {
+                root: Duration::from_secs(30),
+                passphrase: Some(Duration::from_secs(120)),
+                ..Timeouts::default()
+            }
+        );
+        assert_eq!(
+            Timeouts::from_cmdline(&Cmdline::parse("rd.timeout.passphrase=0"))
+                .unwrap()
+                .passphrase,
+            None
+        );
+        assert!(Timeouts::from_cmdline(&Cmdline::parse("rd.timeout.network=ten")).is_err());
+    }
+}
@@ -1,35 +1,48 @@
 use anyhow::{bail, Context, Result};
-use bstr::ByteSlice;
-use log::warn;
 use netlink_sys::{protocols::NETLINK_KOBJECT_UEVENT, Socket, SocketAddr};
+use nix::sys::socket::{setsockopt, sockopt::RcvBufForce};
+use nix::sys::stat::makedev;
+use tracing::{debug, warn};
 
-use std::collections::HashMap;
-use std::ffi::CString;
 use std::fs;
-use std::path::Path;
-use std::sync::mpsc::Sender;
+use std::io;
+use std::os::unix::io::{AsRawFd, BorrowedFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
+use common::uevent::{Uevent, UeventSource};
+
+use crate::device_mapper;
 use crate::module_loader::ModuleLoader;
 
-#[derive(Debug)]
-pub struct Uevent {
-    name: String,
-    vars: HashMap<String, String>,
-}
+const UEVENT_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+// Devices created empty, they announce their contents with a change event once the
+// mapping is loaded, the array is assembled or the backing file is attached
+const CHANGE_READY_PREFIXES: [&str; 3] = ["dm-", "md", "loop"];
 
 pub struct UeventListener {
     socket: Socket,
     module_loader: Arc<ModuleLoader>,
 }
 
+impl AsRawFd for UeventListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
 impl UeventListener {
     pub fn init(module_loader: Arc<ModuleLoader>) -> Result<UeventListener> {
         let mut socket = Socket::new(NETLINK_KOBJECT_UEVENT)
             .with_context(|| "unable to create socket".to_string())?;
-        // socket
-        //     .set_non_blocking(true)
-        //     .with_context(|| format!("unable to set O_NONBLOCK to socket"))?;
+        socket
+            .set_non_blocking(true)
+            .with_context(|| "unable to set O_NONBLOCK to socket".to_string())?;
+        // The uevents of the coldplug are queued until the event loop runs
+        let fd = unsafe { BorrowedFd::borrow_raw(socket.as_raw_fd()) };
+        if let Err(err) = setsockopt(&fd, RcvBufForce, &UEVENT_BUFFER_SIZE) {
+            warn!("unable to set the uevent socket buffer size: {}", err);
+        }
         let kernel_addr = SocketAddr::new(0, 1);
         // socket
         //     .connect(&kernel_addr)
@@ -44,48 +57,70 @@ impl UeventListener {
         })
     }
 
-    pub fn listen(&self, device_tx: Sender<String>) {
-        loop {
-            let mut buf = vec![0; 4096];
-            let msg = self.socket.recv(&mut buf, 0);
-            if msg.is_err() {
-                // warn!("unable to receive from socket");
-                continue;
+    /// Receive a pending uevent, returning the path of the device to probe if any
+    pub fn receive(&self) -> Result<Option<String>> {
+        let mut buf = vec![0; 4096];
+        let msglen = match self.socket.recv(&mut buf, 0) {
+            Ok(msglen) => msglen,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+            Err(err) => {
+                warn!("unable to receive from socket: {}", err);
+                return Ok(None);
             }
-            let msglen = msg.unwrap();
-            if msglen == 0 {
-                // received empty message
-                continue;
+        };
+        if msglen == 0 {
+            // received empty message
+            return Ok(None);
+        }
+        let uevent = match Uevent::parse(&buf[0..msglen]) {
+            Ok(uevent) => uevent,
+            Err(err) => {
+                warn!("uevent: {:?}", err);
+                return Ok(None);
             }
-            let uevent = parse_uevent(&buf[0..msglen - 1]);
-            if uevent.is_err() {
-                warn!("uevent: {:?}", uevent);
-                continue;
+        };
+        // The events broadcast by a running udevd repeat the kernel ones
+        if uevent.source == UeventSource::Udev {
+            debug!(
+                "skipping libudev uevent {}",
+                uevent.get("DEVPATH").unwrap_or("")
+            );
+            return Ok(None);
+        }
+
+        match self.get_device_path(uevent) {
+            Ok(path) => Ok(path),
+            Err(err) => {
+                warn!("uevent: {:?}", err);
+                Ok(None)
             }
+        }
+    }
 
-            let uevent = uevent.unwrap();
-            let res = self.get_device_path(uevent);
-            if let Ok(path) = res {
-                if let Some(path) = path {
-                    if let Err(err) = device_tx.send(path) {
-                        warn!("send error: {:?}", err);
-                        break;
-                    }
-                }
-            } else {
-                warn!("uevent: {:?}", res);
+    /// Log the uevents left in the socket and close it
+    pub fn shutdown(self) {
+        let mut buf = vec![0; 4096];
+        while let Ok(msglen) = self.socket.recv(&mut buf, 0) {
+            if msglen == 0 {
+                continue;
+            }
+            match Uevent::parse(&buf[0..msglen]) {
+                Ok(uevent) => debug!(
+                    "discarding uevent {} {}",
+                    uevent.get("ACTION").unwrap_or(""),
+                    uevent.get("DEVPATH").unwrap_or("")
+                ),
+                Err(err) => warn!("uevent: {:?}", err),
             }
         }
     }
 
     fn get_device_path(&self, uevent: Uevent) -> Result<Option<String>> {
-        let modalias = uevent.vars.get("MODALIAS");
-        if modalias.is_some() {
-            self.module_loader.load_modalias(modalias.unwrap())?;
+        if let Some(modalias) = uevent.get("MODALIAS") {
+            self.module_loader.load_modalias(modalias);
         }
 
         let devpath = uevent
-            .vars
             .get("DEVPATH")
             .with_context(|| "unable to find DEVPATH in uevent")?;
         let devname = Path::new(devpath)
@@ -94,43 +129,25 @@ impl UeventListener {
             .to_str()
             .with_context(|| "unable to convert OsString to String")?;
         let action = uevent
-            .vars
             .get("ACTION")
             .with_context(|| "unable to find ACTION in uevent")?;
 
         let subsystem = uevent
-            .vars
             .get("SUBSYSTEM")
             .with_context(|| "unable to find SUBSYSTEM in uevent")?;
 
-        // (
-        //     devname is not dm-* and action is add
-        // ||
-        //     is devname is dm-* and action is change
-        // )
-        // and
-        // subsytem is block
-
         if subsystem != "block" {
             return Ok(None);
         }
 
-        if devname.starts_with("dm-") && action != "change" {
-            return Ok(None);
-        }
-
-        if !devname.starts_with("dm-") && action != "add" {
+        let devtype = uevent.get("DEVTYPE");
+        if action != get_ready_action(devname, devtype) {
             return Ok(None);
         }
 
         Ok(Some(
             if devname.starts_with("dm-") {
-                let dm_name = Path::new("/sys").join(devname).join("dm/name");
-                if !dm_name.exists() {
-                    bail!("unable to find file {:?}", dm_name);
-                }
-                let dm_name = String::from_utf8(fs::read(dm_name)?)?;
-                Path::new("/dev/mapper").join(dm_name)
+                get_dm_node(&uevent, devpath)?
             } else {
                 Path::new("/dev").join(devname)
             }
@@ -140,40 +157,54 @@ impl UeventListener {
     }
 }
 
-pub fn parse_uevent(buf: &[u8]) -> Result<Uevent> {
-    let mut lines = buf.split(|c| c == &0);
-
-    let name = lines
-        .next()
-        .with_context(|| "uncorrect uevent received".to_string())?;
-    name.find_char('@')
-        .with_context(|| "uncorrect uevent received".to_string())?;
-
-    let vars: HashMap<_, _> = lines
-        .map(parse_line)
-        .filter_map(|res| {
-            if res.is_err() {
-                warn!("unable to process line\n{:?}", res);
-            }
-            res.ok()
-        })
-        .collect();
+/// Get the /dev/mapper node of the device-mapper device, creating it if needed so that
+/// the device can be found by name
+fn get_dm_node(uevent: &Uevent, devpath: &str) -> Result<PathBuf> {
+    let dm_name = match uevent.get("DM_NAME") {
+        Some(dm_name) => dm_name.to_string(),
+        None => {
+            let dm_name = Path::new("/sys")
+                .join(devpath.trim_start_matches('/'))
+                .join("dm/name");
+            fs::read_to_string(&dm_name)
+                .with_context(|| format!("unable to read {:?}", dm_name))?
+                .trim()
+                .to_string()
+        }
+    };
+    let major = uevent.get("MAJOR").and_then(|major| major.parse().ok());
+    let minor = uevent.get("MINOR").and_then(|minor| minor.parse().ok());
+    match (major, minor) {
+        (Some(major), Some(minor)) => device_mapper::create_node(&dm_name, makedev(major, minor)),
+        _ => bail!("unable to find MAJOR and MINOR in uevent of {}", dm_name),
+    }
+}
 
-    Ok(Uevent {
-        name: CString::new(name)?.into_string()?,
-        vars,
-    })
+/// Get the action announcing that the device can be probed
+fn get_ready_action(devname: &str, devtype: Option<&str>) -> &'static str {
+    // Partitions are only created once their parent device has its contents
+    if devtype != Some("partition")
+        && CHANGE_READY_PREFIXES
+            .iter()
+            .any(|prefix| devname.starts_with(prefix))
+    {
+        "change"
+    } else {
+        "add"
+    }
 }
 
-pub fn parse_line(line: &[u8]) -> Result<(String, String)> {
-    let token_index = line.find_char('=').with_context(|| {
-        format!(
-            "unable to locate '=' in line '{}'",
-            String::from_utf8_lossy(line)
-        )
-    })?;
-    Ok((
-        CString::new(&line[0..token_index])?.into_string()?,
-        CString::new(&line[token_index + 1..])?.into_string()?,
-    ))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_ready_action_test() {
+        assert_eq!(get_ready_action("sda", Some("disk")), "add");
+        assert_eq!(get_ready_action("sda1", Some("partition")), "add");
+        assert_eq!(get_ready_action("dm-0", Some("disk")), "change");
+        assert_eq!(get_ready_action("md127", Some("disk")), "change");
+        assert_eq!(get_ready_action("loop0", Some("disk")), "change");
+        assert_eq!(get_ready_action("loop0p1", Some("partition")), "add");
+    }
 }
@@ -0,0 +1,223 @@
+// Load the console keymap and font bundled by mkinitrz from the host vconsole.conf
+// https://github.com/mirror/busybox/blob/master/console-tools/loadkmap.c
+
+use anyhow::{bail, Context, Result};
+use nix::{ioctl_write_ptr_bad, request_code_none};
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+const KEYMAP: &str = "/etc/initrz/keymap.bmap";
+const FONT: &str = "/etc/initrz/font.psf";
+const CONSOLES: [&str; 2] = ["/dev/tty0", "/dev/console"];
+
+const BINARY_KEYMAP_MAGIC: &[u8] = b"bkeymap";
+const MAX_NR_KEYMAPS: usize = 256;
+const NR_KEYS: usize = 128;
+
+const PSF1_MAGIC: &[u8] = &[0x36, 0x04];
+const PSF1_MODE512: u8 = 0x01;
+const PSF2_MAGIC: &[u8] = &[0x72, 0xb5, 0x4a, 0x86];
+// The kernel expects every glyph to be padded to 32 rows
+const FONT_ROWS: usize = 32;
+const KD_FONT_OP_SET: u32 = 0;
+
+#[repr(C)]
+struct KbEntry {
+    kb_table: u8,
+    kb_index: u8,
+    kb_value: u16,
+}
+
+#[repr(C)]
+struct ConsoleFontOp {
+    op: u32,
+    flags: u32,
+    width: u32,
+    height: u32,
+    charcount: u32,
+    data: *mut u8,
+}
+
+ioctl_write_ptr_bad!(kdskbent, request_code_none!(b'K', 0x47), KbEntry);
+ioctl_write_ptr_bad!(kdfontop, request_code_none!(b'K', 0x72), ConsoleFontOp);
+
+#[derive(Debug, PartialEq, Eq)]
+struct Font {
+    width: u32,
+    height: u32,
+    charcount: u32,
+    /// Glyphs padded to FONT_ROWS rows
+    data: Vec<u8>,
+}
+
+fn open_console() -> Result<File> {
+    CONSOLES
+        .iter()
+        .find_map(|console| OpenOptions::new().write(true).open(console).ok())
+        .with_context(|| "unable to open the console")
+}
+
+/// Parse a binary keymap as a list of (table, index, value) entries
+fn parse_keymap(keymap: &[u8]) -> Result<Vec<KbEntry>> {
+    let flags_end = BINARY_KEYMAP_MAGIC.len() + MAX_NR_KEYMAPS;
+    if !keymap.starts_with(BINARY_KEYMAP_MAGIC) || keymap.len() < flags_end {
+        bail!("invalid binary keymap");
+    }
+    let flags = &keymap[BINARY_KEYMAP_MAGIC.len()..flags_end];
+    let mut tables = keymap[flags_end..].chunks_exact(NR_KEYS * 2);
+
+    let mut entries = Vec::new();
+    for (table, _) in flags.iter().enumerate().filter(|(_, flag)| **flag != 0) {
+        let keys = tables.next().with_context(|| "truncated binary keymap")?;
+        entries.extend(
+            keys.chunks_exact(2)
+                .enumerate()
+                .map(|(index, value)| KbEntry {
+                    kb_table: table as u8,
+                    kb_index: index as u8,
+                    kb_value: u16::from_ne_bytes([value[0], value[1]]),
+                }),
+        );
+    }
+
+    Ok(entries)
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        data.get(offset..offset + 4)
+            .with_context(|| "truncated PSF header")?
+            .try_into()?,
+    ))
+}
+
+/// Parse a PSF1 or PSF2 font, the unicode table is ignored
+fn parse_font(font: &[u8]) -> Result<Font> {
+    let (width, height, charcount, charsize, header_size) = if font.starts_with(PSF2_MAGIC) {
+        (
+            read_u32(font, 28)?,
+            read_u32(font, 24)?,
+            read_u32(font, 16)?,
+            read_u32(font, 20)? as usize,
+            read_u32(font, 8)? as usize,
+        )
+    } else if font.starts_with(PSF1_MAGIC) && font.len() >= 4 {
+        let charcount = if font[2] & PSF1_MODE512 != 0 {
+            512
+        } else {
+            256
+        };
+        (8, font[3] as u32, charcount, font[3] as usize, 4)
+    } else {
+        bail!("unsupported font format");
+    };
+    if height as usize > FONT_ROWS || width == 0 || width > 32 {
+        bail!("unsupported font size {}x{}", width, height);
+    }
+    let glyphs = font
+        .get(header_size..header_size + charcount as usize * charsize)
+        .with_context(|| "truncated font")?;
+
+    let row_size = (width as usize).div_ceil(8);
+    let mut data = vec![0u8; charcount as usize * FONT_ROWS * row_size];
+    for (glyph, padded) in glyphs
+        .chunks_exact(charsize)
+        .zip(data.chunks_exact_mut(FONT_ROWS * row_size))
+    {
+        let len = (height as usize * row_size).min(charsize);
+        padded[..len].copy_from_slice(&glyph[..len]);
+    }
+
+    Ok(Font {
+        width,
+        height,
+        charcount,
+        data,
+    })
+}
+
+fn load_keymap(console: &File) -> Result<()> {
+    let keymap = fs::read(KEYMAP).with_context(|| format!("unable to read {}", KEYMAP))?;
+    for entry in parse_keymap(&keymap)? {
+        unsafe { kdskbent(console.as_raw_fd(), &entry) }
+            .with_context(|| "unable to set keymap entry")?;
+    }
+
+    Ok(())
+}
+
+fn load_font(console: &File) -> Result<()> {
+    let mut font =
+        parse_font(&fs::read(FONT).with_context(|| format!("unable to read {}", FONT))?)?;
+    let op = ConsoleFontOp {
+        op: KD_FONT_OP_SET,
+        flags: 0,
+        width: font.width,
+        height: font.height,
+        charcount: font.charcount,
+        data: font.data.as_mut_ptr(),
+    };
+    unsafe { kdfontop(console.as_raw_fd(), &op) }.with_context(|| "unable to set font")?;
+
+    Ok(())
+}
+
+/// Apply the keymap and the font, if they have been bundled
+pub fn setup() -> Result<()> {
+    let has_keymap = Path::new(KEYMAP).exists();
+    let has_font = Path::new(FONT).exists();
+    if !has_keymap && !has_font {
+        return Ok(());
+    }
+
+    let console = open_console()?;
+    if has_keymap {
+        load_keymap(&console)?;
+    }
+    if has_font {
+        load_font(&console)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_keymap_test() {
+        let mut keymap = BINARY_KEYMAP_MAGIC.to_vec();
+        let mut flags = vec![0u8; MAX_NR_KEYMAPS];
+        flags[1] = 1;
+        keymap.extend(flags);
+        keymap.extend((0..NR_KEYS as u16).flat_map(|key| key.to_ne_bytes()));
+
+        let entries = parse_keymap(&keymap).unwrap();
+        assert_eq!(entries.len(), NR_KEYS);
+        assert_eq!(entries[16].kb_table, 1);
+        assert_eq!(entries[16].kb_index, 16);
+        assert_eq!(entries[16].kb_value, 16);
+
+        assert!(parse_keymap(&keymap[..keymap.len() - 1]).is_err());
+        assert!(parse_keymap(b"keymap").is_err());
+    }
+
+    #[test]
+    fn parse_font_test() {
+        // PSF1 with 256 glyphs of 8x2
+        let mut font = vec![0x36, 0x04, 0x00, 0x02];
+        font.extend((0..256).flat_map(|glyph| [glyph as u8, 0xff]));
+
+        let parsed = parse_font(&font).unwrap();
+        assert_eq!((parsed.width, parsed.height, parsed.charcount), (8, 2, 256));
+        assert_eq!(parsed.data.len(), 256 * FONT_ROWS);
+        assert_eq!(&parsed.data[FONT_ROWS..FONT_ROWS + 3], &[1, 0xff, 0]);
+
+        assert!(parse_font(&font[..100]).is_err());
+        assert!(parse_font(b"font").is_err());
+    }
+}
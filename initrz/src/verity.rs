@@ -0,0 +1,259 @@
+use anyhow::{bail, Context, Result};
+use common::cmdline::Cmdline;
+use common::verity::VERITY_CERTIFICATE;
+use libcryptsetup_rs::consts::flags::CryptActivate;
+use libcryptsetup_rs::consts::vals::EncryptionFormat;
+use libcryptsetup_rs::{CryptInit, Either};
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use crate::identifier::Identifier;
+use crate::image::get_image_from_cmdline;
+use crate::signal_handler::GuardedCommand;
+
+pub const VERITY_NAME: &str = "root";
+pub const ROOTHASH_PARAM: &str = "roothash";
+const VERITY_DATA_PARAM: &str = "systemd.verity_root_data";
+const VERITY_HASH_PARAM: &str = "systemd.verity_root_hash";
+const ROOTHASHSIG_PARAM: &str = "roothashsig";
+const OPENSSL: &str = "/usr/bin/openssl";
+const VERIFY_DIR: &str = "/run/initrz";
+
+/// A dm-verity mapping described by the systemd-veritysetup cmdline parameters
+pub struct VerityDevice {
+    pub name: String,
+    pub data: Identifier,
+    pub hash: Identifier,
+    pub roothash: Vec<u8>,
+    /// PKCS#7 signature of the root hash
+    pub signature: Option<Vec<u8>>,
+}
+
+impl VerityDevice {
+    pub fn get_mapper_path(&self) -> String {
+        format!("/dev/mapper/{}", self.name)
+    }
+
+    /// Verify the root hash signature against the certificate embedded in the initramfs.
+    /// When a certificate is present, unsigned root hashes are rejected
+    fn verify_signature(&self) -> Result<()> {
+        let signature = match (&self.signature, Path::new(VERITY_CERTIFICATE).exists()) {
+            (Some(signature), true) => signature,
+            (None, false) => return Ok(()),
+            (None, true) => bail!(
+                "the root hash is not signed but {} requires it",
+                VERITY_CERTIFICATE
+            ),
+            (Some(_), false) => bail!(
+                "unable to verify the root hash signature, {} not found",
+                VERITY_CERTIFICATE
+            ),
+        };
+
+        fs::create_dir_all(VERIFY_DIR)
+            .with_context(|| format!("unable to create {}", VERIFY_DIR))?;
+        let content = Path::new(VERIFY_DIR).join("roothash");
+        let signature_file = Path::new(VERIFY_DIR).join("roothash.p7s");
+        // The signature covers the hex representation of the root hash, as in systemd
+        fs::write(&content, encode_hex(&self.roothash))
+            .with_context(|| format!("unable to write {:?}", content))?;
+        fs::write(&signature_file, signature)
+            .with_context(|| format!("unable to write {:?}", signature_file))?;
+
+        let output = Command::new(OPENSSL)
+            .args(["smime", "-verify", "-binary", "-inform", "DER"])
+            .arg("-in")
+            .arg(&signature_file)
+            .arg("-content")
+            .arg(&content)
+            .args(["-CAfile", VERITY_CERTIFICATE])
+            .args(["-partial_chain", "-purpose", "any", "-out", "/dev/null"])
+            .guarded_output()
+            .with_context(|| "unable to run openssl command");
+        let _ = fs::remove_file(&content);
+        let _ = fs::remove_file(&signature_file);
+        let output = output?;
+        if !output.status.success() {
+            bail!(
+                "root hash signature verification failed:\n{:?}",
+                String::from_utf8(output.stderr)
+            )
+        }
+
+        Ok(())
+    }
+
+    /// Create the read-only verity mapping on top of the data and hash devices
+    pub fn activate(&self, data_path: &str, hash_path: &str) -> Result<()> {
+        self.verify_signature()?;
+
+        let mut device = CryptInit::init_with_data_device(Either::Right((
+            Path::new(hash_path),
+            Path::new(data_path),
+        )))
+        .with_context(|| format!("unable to initialize verity device {}", hash_path))?;
+        device
+            .context_handle()
+            .load::<()>(Some(EncryptionFormat::Verity), None)
+            .with_context(|| format!("unable to load verity header from {}", hash_path))?;
+        device
+            .activate_handle()
+            .activate_by_volume_key(
+                Some(&self.name),
+                Some(&self.roothash),
+                CryptActivate::READONLY,
+            )
+            .with_context(|| {
+                format!(
+                    "unable to verify {} against the root hash",
+                    self.get_mapper_path()
+                )
+            })?;
+
+        Ok(())
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        bail!("{} is not a valid hex string", hex);
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+                .with_context(|| format!("{} is not a valid hex string", hex))
+        })
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_base64(base64: &str) -> Result<Vec<u8>> {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut decoded = Vec::with_capacity(base64.len() * 3 / 4);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in base64.bytes().filter(|c| *c != b'=') {
+        let value = ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .with_context(|| format!("invalid character {:?} in base64 string", c as char))?;
+        buffer = (buffer << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// roothashsig= takes either a path to the signature or its base64 encoding prefixed
+/// by "base64:"
+fn parse_signature(signature: &str) -> Result<Vec<u8>> {
+    match signature.strip_prefix("base64:") {
+        Some(base64) => decode_base64(base64),
+        None => fs::read(signature).with_context(|| format!("unable to read {}", signature)),
+    }
+}
+
+pub fn get_verity_from_cmdline(cmdline: &Cmdline) -> Result<Option<VerityDevice>> {
+    let roothash = match cmdline.get(ROOTHASH_PARAM) {
+        Some(roothash) => roothash,
+        None => return Ok(None),
+    };
+
+    // rd.image provides the devices when the systemd parameters are missing
+    let image = get_image_from_cmdline(cmdline)?;
+    let data = match cmdline.get(VERITY_DATA_PARAM) {
+        Some(data) => data.into(),
+        None => image
+            .as_ref()
+            .map(|image| image.data.clone())
+            .with_context(|| format!("{} is required by {}", VERITY_DATA_PARAM, ROOTHASH_PARAM))?,
+    };
+    let hash = match cmdline.get(VERITY_HASH_PARAM) {
+        Some(hash) => hash.into(),
+        None => image
+            .and_then(|image| image.hash)
+            .with_context(|| format!("{} is required by {}", VERITY_HASH_PARAM, ROOTHASH_PARAM))?,
+    };
+
+    Ok(Some(VerityDevice {
+        name: VERITY_NAME.to_string(),
+        data,
+        hash,
+        roothash: decode_hex(roothash).with_context(|| "invalid roothash")?,
+        signature: cmdline
+            .get(ROOTHASHSIG_PARAM)
+            .map(parse_signature)
+            .transpose()
+            .with_context(|| "invalid roothashsig")?,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_hex_test() {
+        assert_eq!(
+            decode_hex("00ff1aB2").unwrap(),
+            vec![0x00, 0xff, 0x1a, 0xb2]
+        );
+        assert!(decode_hex("").is_err());
+        assert!(decode_hex("abc").is_err());
+        assert!(decode_hex("zz").is_err());
+    }
+
+    #[test]
+    fn encode_hex_test() {
+        assert_eq!(encode_hex(&[0x00, 0xff, 0x1a, 0xb2]), "00ff1ab2");
+        assert_eq!(encode_hex(&decode_hex("abcd").unwrap()), "abcd");
+    }
+
+    #[test]
+    fn decode_base64_test() {
+        assert_eq!(decode_base64("aW5pdHJ6").unwrap(), b"initrz");
+        assert_eq!(decode_base64("aW5pdA==").unwrap(), b"init");
+        assert_eq!(decode_base64("aW5pdHI=").unwrap(), b"initr");
+        assert!(decode_base64("aW5p*A==").is_err());
+        assert_eq!(
+            parse_signature("base64:aW5pdHJ6").unwrap(),
+            b"initrz".to_vec()
+        );
+    }
+
+    #[test]
+    fn verity_cmdline_test() {
+        let cmdline: Cmdline = [
+            "roothash=abcd",
+            "systemd.verity_root_data=/dev/vda2",
+            "systemd.verity_root_hash=UUID=1234",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        let verity = get_verity_from_cmdline(&cmdline).unwrap().unwrap();
+        assert_eq!(verity.roothash, vec![0xab, 0xcd]);
+        assert!(verity.data == Identifier::Path("/dev/vda2".to_string()));
+        assert!(verity.hash == Identifier::Uuid("1234".to_string()));
+        assert_eq!(verity.get_mapper_path(), "/dev/mapper/root");
+
+        assert!(get_verity_from_cmdline(&Cmdline::from(cmdline[..2].to_vec())).is_err());
+        assert!(get_verity_from_cmdline(&Cmdline::default())
+            .unwrap()
+            .is_none());
+    }
+}
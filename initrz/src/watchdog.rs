@@ -0,0 +1,119 @@
+// Keep the hardware watchdog alive during the boot, so that a hang in the initramfs
+// resets unattended machines
+// https://www.kernel.org/doc/html/latest/watchdog/watchdog-api.html
+
+use anyhow::{Context, Result};
+use common::cmdline::Cmdline;
+use nix::ioctl_read;
+use tracing::{info, warn};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+const WATCHDOG: &str = "/dev/watchdog";
+const NOWAYOUT: &str = "/sys/class/watchdog/watchdog0/nowayout";
+const WATCHDOG_PARAM: &str = "rd.watchdog";
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+// Writing it before closing the device disarms the watchdog
+const MAGIC_CLOSE: &[u8] = b"V";
+
+ioctl_read!(wdioc_gettimeout, b'W', 7, libc::c_int);
+
+pub struct Watchdog {
+    stop_tx: Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+/// rd.watchdog=1 requires the watchdog, rd.watchdog=0 disables it
+fn get_watchdog_param(cmdline: &Cmdline) -> Option<bool> {
+    cmdline.get_bool(WATCHDOG_PARAM)
+}
+
+/// With nowayout the watchdog cannot be disarmed anymore once opened
+fn is_nowayout() -> bool {
+    fs::read_to_string(NOWAYOUT)
+        .map(|nowayout| nowayout.trim() == "1")
+        .unwrap_or(false)
+}
+
+fn get_interval(watchdog: &File) -> Duration {
+    let mut timeout: libc::c_int = 0;
+    match unsafe { wdioc_gettimeout(watchdog.as_raw_fd(), &mut timeout) } {
+        // Pet it twice per timeout period
+        Ok(_) if timeout > 1 => Duration::from_secs(timeout as u64 / 2),
+        _ => DEFAULT_INTERVAL,
+    }
+}
+
+impl Watchdog {
+    /// Start petting the watchdog if its device exists and it is not disabled on the
+    /// cmdline. When rd.watchdog=1 is not given, watchdogs that cannot be disarmed are
+    /// left alone
+    pub fn start_from_cmdline(cmdline: &Cmdline) -> Result<Option<Watchdog>> {
+        let param = get_watchdog_param(cmdline);
+        if param == Some(false)
+            || !Path::new(WATCHDOG).exists()
+            || (param.is_none() && is_nowayout())
+        {
+            return Ok(None);
+        }
+
+        let mut watchdog = OpenOptions::new()
+            .write(true)
+            .open(WATCHDOG)
+            .with_context(|| format!("unable to open {}", WATCHDOG))?;
+        let interval = get_interval(&watchdog);
+        info!("petting {} every {:?}", WATCHDOG, interval);
+        let (stop_tx, stop_rx) = channel::<()>();
+        let thread = thread::spawn(move || loop {
+            if let Err(err) = watchdog.write_all(b"\0") {
+                warn!("unable to pet the watchdog: {}", err);
+            }
+            match stop_rx.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => continue,
+                _ => {
+                    if let Err(err) = watchdog.write_all(MAGIC_CLOSE) {
+                        warn!("unable to disarm the watchdog: {}", err);
+                    }
+                    break;
+                }
+            }
+        });
+
+        Ok(Some(Watchdog { stop_tx, thread }))
+    }
+
+    /// Return true if the watchdog has been required with rd.watchdog=1
+    pub fn is_required(cmdline: &Cmdline) -> bool {
+        get_watchdog_param(cmdline) == Some(true)
+    }
+
+    /// Disarm and close the watchdog, so that the real init can open it again
+    pub fn stop(self) {
+        let _ = self.stop_tx.send(());
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog_param_test() {
+        assert_eq!(get_watchdog_param(&Cmdline::default()), None);
+        assert_eq!(
+            get_watchdog_param(&Cmdline::parse("rd.watchdog=1")),
+            Some(true)
+        );
+        assert_eq!(
+            get_watchdog_param(&Cmdline::parse("rd.watchdog=1 rd.watchdog=0")),
+            Some(false)
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::{bail, Context, Result};
+
+use std::process::Command;
+
+use crate::signal_handler::GuardedCommand;
+
+pub const ZFS_ROOT_PREFIX: &str = "ZFS=";
+// Installed by mkinitrz when zfs is enabled in its configuration
+const ZPOOL: &str = "/usr/bin/zpool";
+
+/// Get the pool containing the dataset, i.e. "rpool" for "rpool/ROOT/default"
+pub fn get_pool(dataset: &str) -> &str {
+    dataset.split('/').next().unwrap_or(dataset)
+}
+
+fn is_pool_imported(pool: &str) -> bool {
+    Command::new(ZPOOL)
+        .args(["list", "-H", "-o", "name", pool])
+        .guarded_output()
+        .map(|output| output.status.success())
+        .unwrap_or(false)
+}
+
+/// Import the pool containing the dataset without mounting any of its datasets
+pub fn import_pool(dataset: &str, readonly: bool) -> Result<()> {
+    let pool = get_pool(dataset);
+    if is_pool_imported(pool) {
+        return Ok(());
+    }
+
+    let mut command = Command::new(ZPOOL);
+    command.args(["import", "-N"]);
+    if readonly {
+        command.args(["-o", "readonly=on"]);
+    }
+    let output = command
+        .arg(pool)
+        .guarded_output()
+        .with_context(|| "unable to run zpool command")?;
+    if !output.status.success() {
+        bail!(
+            "unable to import zfs pool {}:\n{:?}",
+            pool,
+            String::from_utf8(output.stderr)
+        )
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_pool_test() {
+        assert_eq!(get_pool("rpool/ROOT/default"), "rpool");
+        assert_eq!(get_pool("rpool"), "rpool");
+    }
+}
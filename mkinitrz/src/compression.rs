@@ -0,0 +1,50 @@
+use std::ops::RangeInclusive;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    pub fn get_default_level(&self) -> i32 {
+        match self {
+            Compression::None => 0,
+            Compression::Gzip | Compression::Xz => 6,
+            Compression::Zstd => 3,
+        }
+    }
+
+    pub fn get_level_range(&self) -> RangeInclusive<i32> {
+        match self {
+            Compression::None => 0..=0,
+            Compression::Gzip | Compression::Xz => 0..=9,
+            Compression::Zstd => zstd::compression_level_range(),
+        }
+    }
+}
+
+impl clap::ValueEnum for Compression {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[
+            Compression::None,
+            Compression::Gzip,
+            Compression::Xz,
+            Compression::Zstd,
+        ]
+    }
+
+    fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
+        match self {
+            Compression::None => Some(clap::builder::PossibleValue::new("none")),
+            Compression::Gzip => Some(clap::builder::PossibleValue::new("gzip")),
+            Compression::Xz => Some(clap::builder::PossibleValue::new("xz")),
+            Compression::Zstd => Some(clap::builder::PossibleValue::new("zstd")),
+        }
+    }
+}
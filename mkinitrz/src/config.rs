@@ -1,22 +1,281 @@
 use std::fs;
 
-use anyhow::Result;
-use camino::Utf8Path;
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
+use serde_yaml::Value;
 
-#[derive(Serialize, Deserialize)]
+use crate::compression::Compression;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub modules: Vec<String>,
+    /// Modules included and loaded by initrz before probing any device, for the drivers
+    /// that are not matched by a modalias, e.g. hv_storvsc or xen-blkfront
+    #[serde(default)]
+    pub preload_modules: Vec<String>,
+    /// Certificate used by initrz to verify the signature of the dm-verity root hash
+    #[serde(default)]
+    pub verity_certificate: Option<Utf8PathBuf>,
+    /// IMA policy loaded by initrz when the new root does not ship /etc/ima/ima-policy
+    #[serde(default)]
+    pub ima_policy: Option<Utf8PathBuf>,
+    /// Include the zfs modules and tools needed to boot from a root=ZFS= dataset
+    #[serde(default)]
+    pub zfs: bool,
+    /// Include the network drivers and the NFS client needed to boot from a network root
+    #[serde(default)]
+    pub network: bool,
+    /// Include iscsistart to log in to the iSCSI targets given by rd.iscsi.*, implies network
+    #[serde(default)]
+    pub iscsi: bool,
+    /// Include the NVMe host identity used to connect to the targets given by
+    /// rd.nvmf.discover, implies network
+    #[serde(default)]
+    pub nvmf: bool,
+    /// Keys allowed to log in to the SSH rescue server, enables it and implies network
+    #[serde(default)]
+    pub ssh_authorized_keys: Option<Utf8PathBuf>,
+    /// Leave busybox out and use the rescue shell built into initrz, busybox is still
+    /// included for the SSH rescue server
+    #[serde(default)]
+    pub rescue_shell: bool,
+    /// Runtime configuration of initrz (timeouts, log level, emergency policy and
+    /// consoles), the kernel parameters still take precedence over it
+    #[serde(default)]
+    pub initrz_conf: Option<Utf8PathBuf>,
+    /// Existing images (cpio archives, optionally compressed) layered over the generated
+    /// one, in order; their files replace the generated ones with the same path
+    #[serde(default)]
+    pub overlays: Vec<Utf8PathBuf>,
+    /// Archives written as they are before the generated one, e.g. the early microcode,
+    /// followed by the ones given with --prepend-cpio
+    #[serde(default)]
+    pub prepend_cpio: Vec<Utf8PathBuf>,
+    /// Archives written as they are after the generated one, e.g. firmware bundles,
+    /// followed by the ones given with --append-cpio
+    #[serde(default)]
+    pub append_cpio: Vec<Utf8PathBuf>,
+    /// Kernel command line embedded in the image, read by initrz when the boot loader does
+    /// not pass root=, and written in the boot loader entries by mkinitrz install, where
+    /// it defaults to /etc/kernel/cmdline; --cmdline takes precedence over it
+    #[serde(default)]
+    pub cmdline: Option<String>,
+    /// Compression of the image, --compression takes precedence over it
+    #[serde(default)]
+    pub compression: Option<Compression>,
+    /// Level of the compression, --compression-level takes precedence over it
+    #[serde(default)]
+    pub compression_level: Option<i32>,
+    /// Threads used by the xz and zstd encoders, --compression-threads takes precedence
+    /// over it
+    #[serde(default)]
+    pub compression_threads: Option<u32>,
 }
 
 impl Config {
     pub fn new(file: &Utf8Path) -> Result<Config> {
-        if file.exists() {
-            Ok(serde_yaml::from_slice(&fs::read(file)?)?)
-        } else {
-            Ok(Config {
-                modules: Vec::new(),
-            })
+        if !file.exists() {
+            return Ok(Config::default());
+        }
+        let contents = fs::read(file).with_context(|| format!("unable to read {}", file))?;
+        let config =
+            Config::parse(&contents).with_context(|| format!("invalid configuration {}", file))?;
+        let problems = config.validate();
+        if !problems.is_empty() {
+            bail!(
+                "invalid configuration {}:\n  {}",
+                file,
+                problems.join("\n  ")
+            );
+        }
+
+        Ok(config)
+    }
+
+    /// Parse the configuration, refusing the unknown keys with a suggestion for the
+    /// misspelled ones
+    fn parse(contents: &[u8]) -> Result<Config> {
+        let value: Value = serde_yaml::from_slice(contents)?;
+        if let Value::Mapping(mapping) = &value {
+            let known_keys = get_known_keys();
+            for key in mapping.keys().filter_map(Value::as_str) {
+                if known_keys.contains(&key.to_string()) {
+                    continue;
+                }
+                match get_closest_key(key, &known_keys) {
+                    Some(closest) => bail!("unknown key `{}`, did you mean `{}`?", key, closest),
+                    None => bail!(
+                        "unknown key `{}`, expected one of: {}",
+                        key,
+                        known_keys.join(", ")
+                    ),
+                }
+            }
+        }
+
+        Ok(serde_yaml::from_value(value)?)
+    }
+
+    /// Check the values that the parser accepts but mkinitrz cannot use, returning a
+    /// message for each one
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (key, module) in self.modules.iter().map(|module| ("modules", module)).chain(
+            self.preload_modules
+                .iter()
+                .map(|module| ("preload_modules", module)),
+        ) {
+            if module.is_empty() || module.contains(|c: char| c == '/' || c.is_whitespace()) {
+                problems.push(format!("{}: `{}` is not a valid module name", key, module));
+            }
+        }
+        if let Some(level) = self.compression_level {
+            let compression = self.compression.unwrap_or(Compression::None);
+            if !compression.get_level_range().contains(&level) {
+                problems.push(format!(
+                    "compression_level: {} is out of range {:?} for {:?}",
+                    level,
+                    compression.get_level_range(),
+                    compression
+                ));
+            }
+        }
+        if self
+            .cmdline
+            .as_deref()
+            .is_some_and(|cmdline| cmdline.contains('\n'))
+        {
+            problems.push("cmdline: must be a single line".to_string());
+        }
+        if self.compression_threads == Some(0) {
+            problems.push("compression_threads: must be at least 1".to_string());
+        }
+        let files = [
+            ("verity_certificate", self.verity_certificate.as_ref()),
+            ("ima_policy", self.ima_policy.as_ref()),
+            ("ssh_authorized_keys", self.ssh_authorized_keys.as_ref()),
+            ("initrz_conf", self.initrz_conf.as_ref()),
+        ];
+        for (key, file) in files
+            .iter()
+            .filter_map(|(key, file)| file.map(|file| (key, file)))
+            .chain(self.overlays.iter().map(|overlay| (&"overlays", overlay)))
+            .chain(self.prepend_cpio.iter().map(|cpio| (&"prepend_cpio", cpio)))
+            .chain(self.append_cpio.iter().map(|cpio| (&"append_cpio", cpio)))
+        {
+            if !file.is_file() {
+                problems.push(format!("{}: {} does not exist", key, file));
+            }
+        }
+
+        problems
+    }
+}
+
+/// Get the keys of the configuration, as serialized by serde
+fn get_known_keys() -> Vec<String> {
+    match serde_yaml::to_value(Config::default()) {
+        Ok(Value::Mapping(mapping)) => mapping
+            .keys()
+            .filter_map(Value::as_str)
+            .map(String::from)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Get the known key closest to the unknown one, if it is close enough to be a typo
+fn get_closest_key<'a>(key: &str, known_keys: &'a [String]) -> Option<&'a str> {
+    known_keys
+        .iter()
+        .map(|known| (get_edit_distance(key, known), known))
+        .filter(|(distance, known)| *distance <= (known.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, known)| known.as_str())
+}
+
+/// Levenshtein distance between the two strings
+fn get_edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(current)
+            };
+            previous = current;
         }
     }
+
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let config = Config::parse(b"modules: [nvme]\nzfs: true\n").unwrap();
+        assert_eq!(config.modules, vec!["nvme"]);
+        assert!(config.zfs);
+
+        let err = Config::parse(b"modules: []\nmodulse: [nvme]\n").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unknown key `modulse`, did you mean `modules`?"
+        );
+        let config =
+            Config::parse(b"modules: []\ncompression: xz\ncompression_level: 9\n").unwrap();
+        assert_eq!(config.compression, Some(Compression::Xz));
+        assert_eq!(config.compression_level, Some(9));
+        assert!(Config::parse(b"modules: []\ncompression: lz4\n").is_err());
+        let err = Config::parse(b"modules: []\nkernel: 6.6.1\n").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unknown key `kernel`, expected one of: modules,"));
+        assert!(Config::parse(b"modules: []\nzfs: maybe\n").is_err());
+    }
+
+    #[test]
+    fn validate_test() {
+        let config = Config {
+            modules: vec!["nvme".to_string(), "kernel/fs/ext4".to_string()],
+            preload_modules: vec!["hv storvsc".to_string()],
+            ima_policy: Some(Utf8PathBuf::from("/nonexistent/ima-policy")),
+            compression: Some(Compression::Gzip),
+            compression_level: Some(19),
+            cmdline: Some("root=/dev/sda1\nquiet".to_string()),
+            append_cpio: vec![Utf8PathBuf::from("/nonexistent/firmware.cpio")],
+            ..Config::default()
+        };
+        assert_eq!(
+            config.validate(),
+            vec![
+                "modules: `kernel/fs/ext4` is not a valid module name",
+                "preload_modules: `hv storvsc` is not a valid module name",
+                "compression_level: 19 is out of range 0..=9 for Gzip",
+                "cmdline: must be a single line",
+                "ima_policy: /nonexistent/ima-policy does not exist",
+                "append_cpio: /nonexistent/firmware.cpio does not exist",
+            ]
+        );
+        assert!(Config::default().validate().is_empty());
+    }
+
+    #[test]
+    fn get_closest_key_test() {
+        let keys = get_known_keys();
+        assert_eq!(get_closest_key("netwrok", &keys), Some("network"));
+        assert_eq!(get_closest_key("rescue-shell", &keys), Some("rescue_shell"));
+        assert_eq!(get_closest_key("kernel", &keys), None);
+        assert_eq!(get_edit_distance("kitten", "sitting"), 3);
+    }
 }
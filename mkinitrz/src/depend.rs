@@ -2,7 +2,6 @@
 
 use anyhow::{bail, Result};
 use camino::{Utf8Path, Utf8PathBuf};
-use log::error;
 use object::{
     elf::{FileHeader32, FileHeader64, DT_NEEDED, DT_STRSZ, DT_STRTAB, PT_DYNAMIC},
     read::{
@@ -16,6 +15,7 @@ use std::fs;
 use std::mem::MaybeUninit;
 use std::os::unix::ffi::OsStrExt;
 use std::{convert::TryInto, path::PathBuf};
+use tracing::error;
 
 pub fn resolve(path: &Utf8Path) -> Result<Vec<String>> {
     let data = fs::read(path)?;
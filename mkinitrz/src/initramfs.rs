@@ -1,17 +1,26 @@
+use std::error::Error as StdError;
+use std::io::{self, Write};
 use std::path::Path;
 use std::{collections::HashSet, env, fs};
 
-use anyhow::{ensure, Context, Result};
 use camino::Utf8Path;
 use camino::Utf8PathBuf;
 use colored::Colorize;
-use log::debug;
+use common::crypttab::{self, CrypttabEntry, CrypttabFormat, UnlockType};
+use common::modules;
+use common::verity::VERITY_CERTIFICATE;
+use thiserror::Error;
+use tracing::{debug, warn};
 
 use crate::config::Config;
 use crate::depend;
 use crate::initramfs_modules;
 use crate::initramfs_type::InitramfsType;
-use crate::newc::{Archive, Entry, EntryBuilder};
+use crate::module_dictionary;
+use crate::module_manifest;
+use crate::newc::{Archive, Entry, EntryBuilder, NewcError};
+use crate::release;
+use crate::vconsole::{self, VconsoleConf};
 
 const ROOT_DIRECTORIES: [&str; 9] = [
     "/dev",
@@ -34,77 +43,264 @@ const ROOT_SYMLINKS: [(&str, &str); 6] = [
     ("/usr/sbin", "bin"),
 ];
 
+const IMA_POLICY: &str = "/etc/initrz/ima-policy";
+const INITRZ_CONF: &str = "/etc/initrz.conf";
+// Loaded by initrz before probing any device, see initrz/src/module_loader.rs
+const PRELOAD_MODULES: &str = "/etc/initrz/preload-modules";
+const CMDLINE: &str = "/etc/initrz/cmdline";
+
+const ZPOOL_PATHS: [&str; 3] = ["/usr/bin/zpool", "/usr/sbin/zpool", "/sbin/zpool"];
+const ZPOOL: &str = "/usr/bin/zpool";
+const ZFS_MODULES: [&str; 2] = ["zfs", "spl"];
+// Files needed by zpool to import the pools of this host
+const ZFS_HOST_FILES: [&str; 2] = ["/etc/hostid", "/etc/zfs/zpool.cache"];
+
+const ISCSISTART_PATHS: [&str; 3] = [
+    "/usr/bin/iscsistart",
+    "/usr/sbin/iscsistart",
+    "/sbin/iscsistart",
+];
+const ISCSISTART: &str = "/usr/bin/iscsistart";
+const ISCSI_INITIATOR_NAME: &str = "/etc/iscsi/initiatorname.iscsi";
+const NVME_HOST_FILES: [&str; 2] = ["/etc/nvme/hostnqn", "/etc/nvme/hostid"];
+
+const DROPBEAR_PATHS: [&str; 3] = ["/usr/sbin/dropbear", "/usr/bin/dropbear", "/sbin/dropbear"];
+const DROPBEAR: &str = "/usr/bin/dropbear";
+// Reuse the host keys so that the fingerprint does not change at every boot
+const DROPBEAR_HOST_KEYS: [&str; 3] = [
+    "/etc/dropbear/dropbear_ed25519_host_key",
+    "/etc/dropbear/dropbear_ecdsa_host_key",
+    "/etc/dropbear/dropbear_rsa_host_key",
+];
+const SSH_AUTHORIZED_KEYS: &str = "/root/.ssh/authorized_keys";
+const PASSWD: &str = "/etc/passwd";
+const ROOT_PASSWD_ENTRY: &str = "root:x:0:0:root:/root:/bin/sh\n";
+
+// Loaded by initrz before asking for any passphrase
+const KEYMAP: &str = "/etc/initrz/keymap.bmap";
+const FONT: &str = "/etc/initrz/font.psf";
+
+// Executables run by initrz at the boot stages, see initrz/src/hooks.rs
+const HOOKS_DIRS: [&str; 3] = [
+    "/etc/initrz/hooks/pre-trigger.d",
+    "/etc/initrz/hooks/pre-mount.d",
+    "/etc/initrz/hooks/pre-pivot.d",
+];
+
+const BIN_DIRS: [&str; 3] = ["/usr/bin", "/usr/sbin", "/sbin"];
+const FSTAB_INITRAMFS: &str = "/etc/fstab.initramfs";
+
 const DEFAULT_DIR_MODE: u32 = 0o040_000 + 0o755;
 const DEFAULT_SYMLINK_MODE: u32 = 0o120_000;
+const DEFAULT_FILE_MODE: u32 = 0o100_000 + 0o644;
+
+type BoxError = Box<dyn StdError + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum InitramfsError {
+    #[error("unable to find initrz executable {0}. Please set INITRZ environment variable")]
+    MissingInitrz(Utf8PathBuf),
+    #[error("file {0} does not exist")]
+    MissingFile(Utf8PathBuf),
+    #[error("unable to find {0} executable")]
+    MissingExecutable(&'static str),
+    #[error("invalid crypttab {path}")]
+    InvalidCrypttab {
+        path: Utf8PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("unable to parse {path}")]
+    InvalidArchive {
+        path: Utf8PathBuf,
+        #[source]
+        source: NewcError,
+    },
+    #[error("unable to find library {0}")]
+    MissingLibrary(String),
+    #[error("unable to read {path}")]
+    Io {
+        path: Utf8PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("unable to get libraries linked to {path}")]
+    Dependencies {
+        path: Utf8PathBuf,
+        #[source]
+        source: BoxError,
+    },
+    #[error("unable to get the kernel modules")]
+    Modules(#[source] BoxError),
+    #[error("unable to generate the archive")]
+    Archive(#[from] NewcError),
+}
+
+impl InitramfsError {
+    /// Whether the error comes from the configuration or the files given by the user,
+    /// instead of the system the initramfs is being built on
+    pub fn is_user_error(&self) -> bool {
+        match self {
+            InitramfsError::Archive(err) => err.is_user_error(),
+            _ => matches!(
+                self,
+                InitramfsError::MissingInitrz(_)
+                    | InitramfsError::MissingFile(_)
+                    | InitramfsError::MissingExecutable(_)
+                    | InitramfsError::InvalidCrypttab { .. }
+                    | InitramfsError::InvalidArchive { .. }
+            ),
+        }
+    }
+}
+
+type Result<T> = std::result::Result<T, InitramfsError>;
+
+fn io_error(path: &Utf8Path) -> impl FnOnce(io::Error) -> InitramfsError + '_ {
+    move |source| InitramfsError::Io {
+        path: path.to_path_buf(),
+        source,
+    }
+}
 
 pub struct Initramfs {
     entries: Vec<Entry>,
     files: HashSet<Utf8PathBuf>,
+    /// Regular files copied from the host, as (path in the initramfs, path on the host)
+    sources: Vec<(Utf8PathBuf, Utf8PathBuf)>,
+    overlays: Vec<Archive>,
 }
 
 impl Initramfs {
     pub fn new(
         initramfs_type: InitramfsType,
         kroot: Utf8PathBuf,
-        config: Config,
+        config: &Config,
+        module_dictionary: bool,
     ) -> Result<Initramfs> {
         let mut initramfs = Initramfs::new_basic_structure()?;
         let initrz =
             Utf8PathBuf::from(&env::var("INITRZ").unwrap_or("target/release/initrz".to_string()));
-        ensure!(
-            initrz.exists(),
-            "unable to find initrz executable. Please set INITRZ environment variable"
-        );
+        if !initrz.exists() {
+            return Err(InitramfsError::MissingInitrz(initrz));
+        }
         initramfs.add_elf_with_path(&initrz, Utf8Path::new("/init"))?;
 
-        initramfs.add_elf(Utf8Path::new("/sbin/vgchange"))?;
-        initramfs.add_elf(Utf8Path::new("/sbin/vgmknodes"))?;
-
-        initramfs.add_elf(Utf8Path::new("/bin/busybox"))?;
+        if !config.rescue_shell || config.ssh_authorized_keys.is_some() {
+            initramfs.add_elf(Utf8Path::new("/bin/busybox"))?;
+        }
 
         let ld_conf = Utf8Path::new("/etc/ld.so.conf");
         initramfs.add_entry(
             ld_conf,
             EntryBuilder::file(ld_conf, Vec::new())
-                .with_metadata(&fs::metadata(ld_conf)?)
+                .with_metadata(&fs::metadata(ld_conf).map_err(io_error(ld_conf))?)
                 .build(),
         );
 
-        let modules_dep = &kroot.join("modules.dep");
-        initramfs.add_file_with_path(
-            modules_dep,
-            &Utf8Path::new("/lib/modules")
-                .join(modules_dep.strip_prefix(kroot.parent().unwrap()).unwrap()),
-        )?;
-        let modules_alias = &kroot.join("modules.alias");
-        initramfs.add_file_with_path(
-            modules_alias,
-            &Utf8Path::new("/lib/modules")
-                .join(modules_alias.strip_prefix(kroot.parent().unwrap()).unwrap()),
-        )?;
-
-        initramfs.apply_config(&config);
-
-        initramfs_modules::get_modules(initramfs_type.clone(), &kroot, config.modules)?
-            .iter()
-            .try_for_each(|module| -> Result<()> {
-                initramfs.add_file_with_path(
-                    module,
-                    &Utf8Path::new("/lib/modules").join(
-                        Utf8Path::new(module)
-                            .strip_prefix(kroot.parent().unwrap())
-                            .unwrap(),
-                    ),
-                )?;
-                Ok(())
-            })?;
+        // Only modules.dep and modules.alias are required by initrz
+        for (index, required) in [
+            (modules::MODULES_DEP, true),
+            (modules::MODULES_ALIAS, true),
+            (modules::MODULES_SOFTDEP, false),
+            (modules::MODULES_BUILTIN, false),
+        ] {
+            let index = &kroot.join(index);
+            if !required && !index.exists() {
+                continue;
+            }
+            initramfs.add_file_with_path(
+                index,
+                &Utf8Path::new("/lib/modules")
+                    .join(index.strip_prefix(kroot.parent().unwrap()).unwrap()),
+            )?;
+        }
+
+        initramfs.apply_config(config)?;
+        initramfs.add_release(&kroot, config)?;
+
+        let mut modules = config.modules.clone();
+        modules.extend(config.preload_modules.iter().cloned());
+        if config.zfs {
+            modules.extend(ZFS_MODULES.iter().map(|module| module.to_string()));
+        }
+        let modules: Vec<(Utf8PathBuf, Utf8PathBuf)> = initramfs_modules::get_modules(
+            initramfs_type.clone(),
+            &kroot,
+            modules,
+            config.network || config.iscsi || config.nvmf || config.ssh_authorized_keys.is_some(),
+        )
+        .map_err(|err| InitramfsError::Modules(err.into()))?
+        .iter()
+        .map(|module| {
+            (
+                Utf8PathBuf::from(module),
+                Utf8Path::new("/lib/modules").join(
+                    Utf8Path::new(module)
+                        .strip_prefix(kroot.parent().unwrap())
+                        .unwrap(),
+                ),
+            )
+        })
+        .collect();
+        let mut recompressed = match module_dictionary {
+            true => module_dictionary::recompress(
+                &modules
+                    .iter()
+                    .map(|(module, _)| module.clone())
+                    .collect::<Vec<_>>(),
+            )
+            .map_err(|err| InitramfsError::Modules(err.into()))?,
+            false => None,
+        };
+        if let Some(recompressed) = &recompressed {
+            initramfs.add_data(
+                Utf8Path::new(module_dictionary::MODULE_DICTIONARY),
+                recompressed.dictionary.clone(),
+            );
+        }
+        // The modules copied as they are from the host, hashed from their files
+        let mut host_modules = Vec::new();
+        let mut manifest = String::new();
+        for (module, path) in modules {
+            match recompressed
+                .as_mut()
+                .and_then(|recompressed| recompressed.modules.remove(&module))
+            {
+                Some(data) => {
+                    match module_manifest::get_data_manifest(&path, &data) {
+                        Ok(line) => manifest.push_str(&line),
+                        Err(err) => warn!("unable to hash {}: {:?}", path, err),
+                    }
+                    initramfs.add_file_data(&module, &path, data)?;
+                }
+                None => {
+                    initramfs.add_file_with_path(&module, &path)?;
+                    host_modules.push((module, path));
+                }
+            }
+        }
+        // Checked by initrz before loading the modules when rd.verify=1 is given
+        match module_manifest::get_manifest(&host_modules) {
+            Ok(host_manifest) => {
+                manifest.push_str(&host_manifest);
+                initramfs.add_data(
+                    Utf8Path::new(module_manifest::MODULE_MANIFEST),
+                    manifest.into_bytes(),
+                )
+            }
+            Err(err) => warn!("unable to generate the module manifest: {:?}", err),
+        }
 
         match initramfs_type {
             InitramfsType::Host => {
-                let crypttab = Utf8Path::new("/etc/crypttab.initramfs");
-                if crypttab.exists() {
-                    initramfs.add_file(crypttab)?;
+                initramfs.add_crypttab()?;
+                initramfs.add_swaps()?;
+                let hostname = Utf8Path::new("/etc/hostname");
+                if hostname.exists() {
+                    initramfs.add_file(hostname)?;
                 }
+                initramfs.add_sysctl_files()?;
             }
             InitramfsType::General => {}
         }
@@ -130,10 +326,285 @@ impl Initramfs {
             )
         });
 
-        Ok(Initramfs { entries, files })
+        Ok(Initramfs {
+            entries,
+            files,
+            sources: Vec::new(),
+            overlays: Vec::new(),
+        })
+    }
+
+    fn apply_config(&mut self, config: &Config) -> Result<()> {
+        if let Some(certificate) = &config.verity_certificate {
+            self.add_file_with_path(certificate, Utf8Path::new(VERITY_CERTIFICATE))?;
+            // initrz verifies the root hash signature using openssl
+            self.add_elf(Utf8Path::new("/usr/bin/openssl"))?;
+        }
+        if let Some(policy) = &config.ima_policy {
+            self.add_file_with_path(policy, Utf8Path::new(IMA_POLICY))?;
+        }
+        if let Some(conf) = &config.initrz_conf {
+            self.add_file_with_path(conf, Utf8Path::new(INITRZ_CONF))?;
+        }
+        if let Some(cmdline) = &config.cmdline {
+            self.add_data(
+                Utf8Path::new(CMDLINE),
+                format!("{}\n", cmdline).into_bytes(),
+            );
+        }
+        if !config.preload_modules.is_empty() {
+            let preload: String = config
+                .preload_modules
+                .iter()
+                .map(|module| format!("{}\n", module))
+                .collect();
+            self.add_data(Utf8Path::new(PRELOAD_MODULES), preload.into_bytes());
+        }
+
+        if config.zfs {
+            let zpool = ZPOOL_PATHS
+                .iter()
+                .map(Utf8Path::new)
+                .find(|path| path.exists())
+                .ok_or(InitramfsError::MissingExecutable("zpool"))?;
+            self.add_elf_with_path(zpool, Utf8Path::new(ZPOOL))?;
+            ZFS_HOST_FILES
+                .iter()
+                .map(Utf8Path::new)
+                .filter(|file| file.exists())
+                .try_for_each(|file| self.add_file(file).map(|_| ()))?;
+        }
+
+        if config.iscsi {
+            let iscsistart = ISCSISTART_PATHS
+                .iter()
+                .map(Utf8Path::new)
+                .find(|path| path.exists())
+                .ok_or(InitramfsError::MissingExecutable("iscsistart"))?;
+            self.add_elf_with_path(iscsistart, Utf8Path::new(ISCSISTART))?;
+            // Used as default initiator name when rd.iscsi.initiator is not given
+            let initiator_name = Utf8Path::new(ISCSI_INITIATOR_NAME);
+            if initiator_name.exists() {
+                self.add_file(initiator_name)?;
+            }
+        }
+
+        if config.nvmf {
+            // Used as default host identity when rd.nvmf.hostnqn and rd.nvmf.hostid are not given
+            for file in NVME_HOST_FILES.iter().map(Utf8Path::new) {
+                if file.exists() {
+                    self.add_file(file)?;
+                }
+            }
+        }
+
+        if let Some(authorized_keys) = &config.ssh_authorized_keys {
+            let dropbear = DROPBEAR_PATHS
+                .iter()
+                .map(Utf8Path::new)
+                .find(|path| path.exists())
+                .ok_or(InitramfsError::MissingExecutable("dropbear"))?;
+            self.add_elf_with_path(dropbear, Utf8Path::new(DROPBEAR))?;
+            self.add_file_with_path(authorized_keys, Utf8Path::new(SSH_AUTHORIZED_KEYS))?;
+            DROPBEAR_HOST_KEYS
+                .iter()
+                .map(Utf8Path::new)
+                .filter(|key| key.exists())
+                .try_for_each(|key| self.add_file(key).map(|_| ()))?;
+            // dropbear looks up the home and the shell of root in /etc/passwd
+            self.add_data(Utf8Path::new(PASSWD), ROOT_PASSWD_ENTRY.as_bytes().to_vec());
+            let sh = Utf8Path::new("/bin/sh");
+            self.add_entry(
+                sh,
+                EntryBuilder::symlink(sh, Path::new("busybox"))
+                    .mode(DEFAULT_SYMLINK_MODE)
+                    .build(),
+            );
+        }
+
+        if Utf8Path::new(vconsole::VCONSOLE_CONF).exists() {
+            self.add_vconsole()?;
+        }
+
+        self.add_hooks()?;
+
+        for overlay in &config.overlays {
+            let data = fs::read(overlay).map_err(io_error(overlay))?;
+            self.overlays.push(Archive::parse(&data).map_err(|source| {
+                InitramfsError::InvalidArchive {
+                    path: overlay.clone(),
+                    source,
+                }
+            })?);
+        }
+
+        Ok(())
+    }
+
+    /// Add the os-release of the host and the initrz-release describing the image
+    fn add_release(&mut self, kroot: &Utf8Path, config: &Config) -> Result<()> {
+        if let Some(os_release) = release::OS_RELEASE
+            .iter()
+            .map(Utf8Path::new)
+            .find(|os_release| os_release.exists())
+        {
+            // Copy the file pointed by the /etc/os-release symlink
+            let os_release = os_release
+                .canonicalize_utf8()
+                .map_err(io_error(os_release))?;
+            self.add_file_with_path(&os_release, Utf8Path::new(release::OS_RELEASE_INITRAMFS))?;
+        }
+
+        // The configuration in effect, after the options overriding it
+        let config_hash = serde_yaml::to_string(config)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| module_manifest::get_data_hash(config.as_bytes()));
+        if let Err(err) = &config_hash {
+            warn!("unable to hash the configuration: {:?}", err);
+        }
+        self.add_data(
+            Utf8Path::new(release::INITRZ_RELEASE),
+            release::get_release(
+                kroot.file_name().unwrap_or_default(),
+                release::get_build_time(),
+                config_hash.ok().as_deref(),
+            )
+            .into_bytes(),
+        );
+
+        Ok(())
+    }
+
+    /// Ship the hooks of the host, the files keep their mode so that the executable ones
+    /// are run
+    fn add_hooks(&mut self) -> Result<()> {
+        for dir in HOOKS_DIRS
+            .iter()
+            .map(Utf8Path::new)
+            .filter(|dir| dir.is_dir())
+        {
+            for entry in dir.read_dir_utf8().map_err(io_error(dir))? {
+                let hook = entry.map_err(io_error(dir))?.into_path();
+                if hook.is_file() {
+                    self.add_file(&hook)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ship the keymap and the font of the host console, so that the passphrases are
+    /// typed using the expected layout
+    fn add_vconsole(&mut self) -> Result<()> {
+        let conf = VconsoleConf::parse(
+            &fs::read_to_string(vconsole::VCONSOLE_CONF)
+                .map_err(io_error(Utf8Path::new(vconsole::VCONSOLE_CONF)))?,
+        );
+        // The console settings are not essential, keep building without them
+        if let Some(keymap) = &conf.keymap {
+            match vconsole::get_binary_keymap(keymap) {
+                Ok(keymap) => self.add_data(Utf8Path::new(KEYMAP), keymap),
+                Err(err) => warn!("{:?}", err),
+            }
+        }
+        if let Some(font) = &conf.font {
+            match vconsole::get_font(font) {
+                Ok(font) => self.add_data(Utf8Path::new(FONT), font),
+                Err(err) => warn!("{:?}", err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ship the crypttab of the initramfs together with the keyfiles it references, initrz
+    /// removes them once the devices have been unlocked. An invalid crypttab is refused,
+    /// initrz would skip its broken entries at boot
+    fn add_crypttab(&mut self) -> Result<()> {
+        let crypttab = Utf8Path::new(crypttab::CRYPTTAB_INITRAMFS);
+        if !crypttab.exists() {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(crypttab).map_err(io_error(crypttab))?;
+        let entries = crypttab::parse_crypttab(&contents, CrypttabFormat::Initramfs)
+            .into_iter()
+            .collect::<anyhow::Result<Vec<CrypttabEntry>>>()
+            .map_err(|err| InitramfsError::InvalidCrypttab {
+                path: crypttab.to_path_buf(),
+                source: err.into(),
+            })?;
+        self.add_file(crypttab)?;
+        // Random keys are generated by initrz on every boot
+        for keyfile in entries.iter().filter_map(|entry| match &entry.unlock {
+            UnlockType::Key(keyfile) => Some(Utf8Path::new(keyfile)),
+            _ => None,
+        }) {
+            if keyfile.exists() {
+                self.add_file(keyfile)?;
+            } else {
+                warn!("keyfile {} not found", keyfile.as_str().purple().bold());
+            }
+        }
+        // The devices with the tmp option are formatted by initrz on every boot
+        for fs_type in entries.iter().filter_map(|entry| {
+            entry
+                .get_option("tmp")
+                .map(|fs_type| fs_type.unwrap_or(crypttab::DEFAULT_TMP_FILESYSTEM))
+        }) {
+            let mkfs = format!("mkfs.{}", fs_type);
+            match BIN_DIRS
+                .iter()
+                .map(|dir| Utf8Path::new(dir).join(&mkfs))
+                .find(|path| path.exists())
+            {
+                Some(path) => {
+                    self.add_elf(&path)?;
+                }
+                None => warn!("{} not found", mkfs.purple().bold()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ship the swap entries of the host fstab marked with x-initrd.swap, initrz enables
+    /// them before mounting root
+    fn add_swaps(&mut self) -> Result<()> {
+        let fstab = Utf8Path::new("/etc/fstab");
+        if !fstab.exists() {
+            return Ok(());
+        }
+        let contents = fs::read_to_string(fstab).map_err(io_error(fstab))?;
+        let swaps = get_initrd_swaps(&contents);
+        if !swaps.is_empty() {
+            self.add_data(Utf8Path::new(FSTAB_INITRAMFS), swaps.into_bytes());
+        }
+
+        Ok(())
     }
 
-    fn apply_config(&mut self, _config: &Config) {}
+    /// Ship the sysctl settings of the host, initrz applies them before mounting root
+    fn add_sysctl_files(&mut self) -> Result<()> {
+        let sysctl_conf = Utf8Path::new("/etc/sysctl.conf");
+        if sysctl_conf.exists() {
+            self.add_file(sysctl_conf)?;
+        }
+        let sysctl_dir = Utf8Path::new("/etc/sysctl.d");
+        if !sysctl_dir.exists() {
+            return Ok(());
+        }
+        for entry in sysctl_dir.read_dir_utf8().map_err(io_error(sysctl_dir))? {
+            let path = entry.map_err(io_error(sysctl_dir))?.into_path();
+            // Skip the files masked by linking them to /dev/null
+            if path.extension() == Some("conf") && path.is_file() {
+                let file = path.canonicalize_utf8().map_err(io_error(&path))?;
+                self.add_file_with_path(&file, &path)?;
+            }
+        }
+
+        Ok(())
+    }
 
     fn add_elf(&mut self, exe: &Utf8Path) -> Result<()> {
         self.add_elf_with_path(exe, exe)
@@ -144,7 +615,10 @@ impl Initramfs {
             return Ok(());
         }
         depend::resolve(Utf8Path::new(exe))
-            .with_context(|| format!("unable to get libraries linked to {exe}"))?
+            .map_err(|err| InitramfsError::Dependencies {
+                path: exe.to_path_buf(),
+                source: err.into(),
+            })?
             .iter()
             .try_for_each(|lib| self.add_library(lib))?;
 
@@ -164,13 +638,16 @@ impl Initramfs {
                     None
                 }
             })
-            .with_context(|| format!("unable to find library {}", lib))?;
+            .ok_or_else(|| InitramfsError::MissingLibrary(lib.to_string()))?;
         if !self.add_file(&full_path)? {
             return Ok(());
         }
 
         depend::resolve(Utf8Path::new(&full_path))
-            .with_context(|| format!("unable to get libraries linked to {full_path}"))?
+            .map_err(|err| InitramfsError::Dependencies {
+                path: full_path.clone(),
+                source: err.into(),
+            })?
             .iter()
             .try_for_each(|lib| self.add_library(lib))?;
 
@@ -182,18 +659,16 @@ impl Initramfs {
     }
 
     fn add_file_with_path(&mut self, file: &Utf8Path, path: &Utf8Path) -> Result<bool> {
-        ensure!(
-            file.exists(),
-            "file {} does not exist",
-            file.as_str().red().bold()
-        );
+        if !file.exists() {
+            return Err(InitramfsError::MissingFile(file.to_path_buf()));
+        }
 
         if self.files.contains(path) {
             return Ok(false);
         }
 
         if file.is_symlink() {
-            let pointed_file = path.read_link_utf8()?;
+            let pointed_file = path.read_link_utf8().map_err(io_error(path))?;
             // if pointed file is not absolute, join the directory of the symlink with the pointed
             // file
             let pointed_file = if pointed_file.is_absolute() {
@@ -216,27 +691,42 @@ impl Initramfs {
 
             self.add_file(&pointed_file)?;
         } else {
-            self.add_directory(
-                path.parent()
-                    .expect("Files path shall contain a parent directory"),
-            );
-            self.add_entry(
+            self.add_host_file(
+                file,
                 path,
-                EntryBuilder::file(
-                    path,
-                    fs::read(file)
-                        .with_context(|| format!("unable to read from file {:?}", file))?,
-                )
-                .with_metadata(
-                    &fs::metadata(file)
-                        .with_context(|| format!("unable to read metadata of file {:?}", file))?,
-                )
-                .build(),
-            );
+                EntryBuilder::host_file(path, file.as_std_path()),
+            )?;
         }
         Ok(true)
     }
 
+    /// Add a regular file from the host with a different content, e.g. recompressed
+    fn add_file_data(&mut self, file: &Utf8Path, path: &Utf8Path, data: Vec<u8>) -> Result<()> {
+        self.add_host_file(file, path, EntryBuilder::file(path, data))
+    }
+
+    /// Add the entry of a regular file from the host with its metadata
+    fn add_host_file(
+        &mut self,
+        file: &Utf8Path,
+        path: &Utf8Path,
+        entry: EntryBuilder,
+    ) -> Result<()> {
+        self.add_directory(
+            path.parent()
+                .expect("Files path shall contain a parent directory"),
+        );
+        self.add_entry(
+            path,
+            entry
+                .with_metadata(&fs::metadata(file).map_err(io_error(file))?)
+                .build(),
+        );
+        self.sources.push((path.to_path_buf(), file.to_path_buf()));
+
+        Ok(())
+    }
+
     fn add_directory(&mut self, dir: &Utf8Path) {
         if self.files.contains(dir) {
             return;
@@ -251,13 +741,83 @@ impl Initramfs {
         );
     }
 
+    /// Add a regular file with the given content
+    fn add_data(&mut self, path: &Utf8Path, data: Vec<u8>) {
+        if let Some(parent) = path.parent() {
+            self.add_directory(parent);
+        }
+        self.add_entry(
+            path,
+            EntryBuilder::file(path, data)
+                .mode(DEFAULT_FILE_MODE)
+                .build(),
+        );
+    }
+
     fn add_entry(&mut self, path: &Utf8Path, entry: Entry) {
         debug!("Added entry {:?}", path);
         self.files.insert(path.into());
         self.entries.push(entry);
     }
 
-    pub fn into_bytes(self) -> Result<Vec<u8>> {
-        Archive::new(self.entries).into_bytes()
+    pub fn get_sources(&self) -> &[(Utf8PathBuf, Utf8PathBuf)] {
+        &self.sources
+    }
+
+    /// Write the archive, the files of the host are read one at a time
+    pub fn write<W: Write>(self, writer: &mut W) -> Result<()> {
+        Ok(
+            Archive::merge(std::iter::once(Archive::new(self.entries)).chain(self.overlays))
+                .write(writer)?,
+        )
+    }
+}
+
+/// Keep the swap entries of an fstab marked with x-initrd.swap
+fn get_initrd_swaps(fstab: &str) -> String {
+    fstab
+        .lines()
+        .filter(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            !line.trim_start().starts_with('#')
+                && fields.get(2) == Some(&"swap")
+                && fields
+                    .get(3)
+                    .is_some_and(|options| options.split(',').any(|o| o == "x-initrd.swap"))
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_error_test() {
+        let mut initramfs = Initramfs::new_basic_structure().unwrap();
+        let err = initramfs
+            .add_file(Utf8Path::new("/nonexistent/mkinitrz.conf"))
+            .unwrap_err();
+        assert!(matches!(err, InitramfsError::MissingFile(_)));
+        assert!(err.is_user_error());
+
+        let err = io_error(Utf8Path::new("/etc"))(io::Error::from(io::ErrorKind::PermissionDenied));
+        assert!(!err.is_user_error());
+        assert!(InitramfsError::Archive(NewcError::UnknownFormat).is_user_error());
+    }
+
+    #[test]
+    fn get_initrd_swaps_test() {
+        assert_eq!(
+            get_initrd_swaps(
+                "# /etc/fstab
+UUID=1234 / ext4 defaults 0 1
+UUID=5678 none swap defaults,x-initrd.swap 0 0
+/dev/sdb2 none swap defaults 0 0
+"
+            ),
+            "UUID=5678 none swap defaults,x-initrd.swap 0 0\n"
+        );
     }
 }
@@ -1,21 +1,41 @@
 use std::{
-    collections::HashSet,
-    fs::File,
-    io::{BufRead, BufReader},
-    path::Path,
+    collections::{HashMap, HashSet},
+    fs,
 };
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
-use log::warn;
+use common::modules::{self, Module, SoftDep};
 use rayon::prelude::*;
+use tracing::{info, instrument, warn};
 
 use crate::initramfs_type::InitramfsType;
 
+// Directories of the modules built outside of the kernel tree, e.g. by DKMS
+const OUT_OF_TREE_DIRS: [&str; 3] = ["updates/", "extra/", "weak-updates/"];
+
+/// A module loaded in the running kernel, as listed in /proc/modules
+#[derive(Debug, PartialEq, Eq)]
+struct HostModule {
+    name: String,
+    /// Tainted the kernel with O, it has been built outside of the kernel tree
+    out_of_tree: bool,
+    /// Tainted the kernel with P, it has a proprietary license
+    proprietary: bool,
+}
+
+fn is_out_of_tree(path: &Utf8Path) -> bool {
+    OUT_OF_TREE_DIRS
+        .iter()
+        .any(|dir| path.as_str().starts_with(dir))
+}
+
 fn is_module_needed(name: &str, path: &Utf8Path) -> bool {
     let path = match path.strip_prefix("kernel/") {
         Ok(path) => path.as_str(),
+        // Only included when loaded on the host or explicitly requested
+        Err(_) if is_out_of_tree(path) => return false,
         Err(_) => {
             warn!("module {} is not supported", path.as_str().purple().bold());
             return false;
@@ -26,7 +46,7 @@ fn is_module_needed(name: &str, path: &Utf8Path) -> bool {
     if path.starts_with("fs") && !path.starts_with("fs/nls") {
         return true; // file systems
     }
-    if path.starts_with("crypto") || name == "dm-crypt" || name == "dm-integrity" {
+    if path.starts_with("crypto") || name == "dm_crypt" || name == "dm_integrity" {
         return true; // disk encryption
     }
     if path.starts_with("drivers/md/") || path.starts_with("lib/") {
@@ -61,78 +81,259 @@ fn is_module_needed(name: &str, path: &Utf8Path) -> bool {
     false
 }
 
+fn is_network_driver(path: &Utf8Path) -> bool {
+    path.starts_with("kernel/drivers/net/")
+}
+
+fn is_network_filesystem(path: &Utf8Path) -> bool {
+    [
+        "kernel/fs/nfs",
+        "kernel/fs/lockd",
+        "kernel/net/sunrpc",
+        "kernel/net/dns_resolver",
+    ]
+    .iter()
+    .any(|prefix| path.as_str().starts_with(prefix))
+}
+
+/// Add the hard and soft dependencies of the modules, the ones missing from modules.dep
+/// are skipped as they could be builtin
+fn add_dependencies(
+    selected: HashSet<String>,
+    modules: &HashMap<String, Module>,
+    softdeps: &HashMap<String, SoftDep>,
+) -> HashSet<String> {
+    let mut resolved = HashSet::new();
+    let mut pending: Vec<String> = selected.into_iter().collect();
+    while let Some(name) = pending.pop() {
+        if !modules.contains_key(&name) || !resolved.insert(name.clone()) {
+            continue;
+        }
+        pending.extend(modules[&name].deps.iter().cloned());
+        if let Some(softdep) = softdeps.get(&name) {
+            pending.extend(softdep.pre.iter().chain(softdep.post.iter()).cloned());
+        }
+    }
+
+    resolved
+}
+
+#[instrument(name = "modules", skip_all)]
 pub fn get_modules(
     initramfs_type: InitramfsType,
     kroot: &Utf8Path,
     additional_modules: Vec<String>,
+    network: bool,
 ) -> Result<Vec<Utf8PathBuf>> {
-    let additional_modules = additional_modules.into_iter().collect::<HashSet<String>>();
-    let modules = get_all_modules(kroot)?;
+    let additional_modules = additional_modules
+        .iter()
+        .map(|module| modules::normalize_name(module))
+        .collect::<HashSet<String>>();
+    let mut modules = modules::parse_modules_dep(
+        &fs::read_to_string(kroot.join(modules::MODULES_DEP))
+            .with_context(|| "unable to open modules.dep")?,
+    );
+    for problem in modules::check_modules_dep(&mut modules) {
+        warn!("{}", problem);
+    }
+    // Not every kernel has soft dependencies
+    let softdeps = fs::read_to_string(kroot.join(modules::MODULES_SOFTDEP))
+        .map(|softdep| modules::parse_modules_softdep(&softdep))
+        .unwrap_or_default();
 
-    Ok(match initramfs_type {
+    let selected = match initramfs_type {
         InitramfsType::General => modules
             .par_iter()
-            .filter(|(name, path)| {
-                is_module_needed(name, path) || additional_modules.contains(name)
+            .filter(|(name, module)| {
+                let path = Utf8Path::new(&module.filename);
+                is_module_needed(name, path)
+                    || (network && (is_network_driver(path) || is_network_filesystem(path)))
+                    || additional_modules.contains(*name)
             })
-            .map(|(_, path)| kroot.join(path))
-            .collect::<Vec<Utf8PathBuf>>(),
+            .map(|(name, _)| name.clone())
+            .collect::<HashSet<String>>(),
         InitramfsType::Host => {
-            let host_modules = get_host_modules()?.into_iter().collect::<HashSet<String>>();
+            let host_modules = get_host_modules()?;
+            let out_of_tree = get_out_of_tree_modules(&host_modules, &modules, kroot);
+            let host_modules = host_modules
+                .into_iter()
+                .map(|module| module.name)
+                .collect::<HashSet<String>>();
             modules
                 .par_iter()
-                .filter(|(name, path)| {
-                    (host_modules.contains(name) && is_module_needed(name, path))
-                        || additional_modules.contains(name)
+                .filter(|(name, module)| {
+                    let path = Utf8Path::new(&module.filename);
+                    (host_modules.contains(*name)
+                        && (is_module_needed(name, path) || (network && is_network_driver(path))))
+                        || (network && is_network_filesystem(path))
+                        || additional_modules.contains(*name)
                 })
-                .map(|(_, path)| kroot.join(path))
-                .collect::<Vec<Utf8PathBuf>>()
+                .map(|(name, _)| name.clone())
+                .chain(out_of_tree.into_par_iter())
+                .collect::<HashSet<String>>()
         }
-    })
+    };
+
+    Ok(add_dependencies(selected, &modules, &softdeps)
+        .iter()
+        .map(|name| kroot.join(&modules[name].filename))
+        .collect())
+}
+
+fn get_host_modules() -> Result<Vec<HostModule>> {
+    Ok(parse_proc_modules(
+        &fs::read_to_string("/proc/modules")
+            .with_context(|| "unable to open file /proc/modules")?,
+    ))
 }
 
-fn get_module_name(filename: &Utf8Path) -> Result<String> {
-    Ok(filename
-        .file_stem()
-        .and_then(|module| Path::new(module).file_stem())
-        .with_context(|| format!("failed to get module name of file {:?}", filename))?
-        .to_str()
-        .with_context(|| {
-            format!(
-                "failed to convert the module name in file {:?} from OsStr to Str",
-                filename
-            )
-        })?
-        .to_string())
+/// Parse /proc/modules, made of lines like "nvidia 56823808 19 nvidia_uvm, Live 0x0 (POE)"
+fn parse_proc_modules(contents: &str) -> Vec<HostModule> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let name = fields.next()?;
+            // The taint flags are only shown for the modules tainting the kernel
+            let taints = fields
+                .last()
+                .and_then(|field| field.strip_prefix('('))
+                .and_then(|field| field.strip_suffix(')'))
+                .unwrap_or("");
+            Some(HostModule {
+                name: modules::normalize_name(name),
+                out_of_tree: taints.contains('O'),
+                proprietary: taints.contains('P'),
+            })
+        })
+        .collect()
 }
 
-fn get_all_modules(kroot: &Utf8Path) -> Result<Vec<(String, Utf8PathBuf)>> {
-    BufReader::new(
-        File::open(kroot.join("modules.dep")).with_context(|| "unable to open modules.dep")?,
-    )
-    .lines()
-    .map_while(Result::ok)
-    .map(|line| -> Result<(String, Utf8PathBuf)> {
-        let module_path = Utf8Path::new(
-            line.split(':')
-                .next()
-                .with_context(|| "unable to get module from modules.dep")?,
+/// Get the out-of-tree modules loaded on the host whose dependencies can all be included,
+/// warning about the ones that cannot
+fn get_out_of_tree_modules(
+    host_modules: &[HostModule],
+    modules: &HashMap<String, Module>,
+    kroot: &Utf8Path,
+) -> Vec<String> {
+    host_modules
+        .iter()
+        .filter(|module| module.out_of_tree)
+        .filter_map(
+            |module| match check_out_of_tree_module(&module.name, modules, kroot) {
+                Ok(()) => {
+                    if module.proprietary {
+                        info!("including proprietary module {}", module.name);
+                    }
+                    Some(module.name.clone())
+                }
+                Err(err) => {
+                    warn!(
+                        "skipping out-of-tree module {}: {}",
+                        module.name.purple().bold(),
+                        err
+                    );
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// Check that the out-of-tree module and its dependency closure have been installed for the
+/// kernel of the initramfs, DKMS could have built it for the running kernel only
+fn check_out_of_tree_module(
+    name: &str,
+    modules: &HashMap<String, Module>,
+    kroot: &Utf8Path,
+) -> Result<()> {
+    let module = match modules.get(name) {
+        Some(module) => module,
+        None => bail!(
+            "not found in modules.dep of {}, it has not been built for this kernel",
+            kroot.file_name().unwrap_or_default()
+        ),
+    };
+    if !is_out_of_tree(Utf8Path::new(&module.filename)) {
+        bail!(
+            "{} is not in {}",
+            module.filename,
+            OUT_OF_TREE_DIRS.join(", ")
         );
-        Ok((get_module_name(module_path)?, module_path.to_path_buf()))
-    })
-    .collect()
+    }
+    for name in std::iter::once(name).chain(module.deps.iter().map(String::as_str)) {
+        let filename = match modules.get(name) {
+            Some(dep) => &dep.filename,
+            None => bail!("dependency {} not found in modules.dep", name),
+        };
+        if !kroot.join(filename).exists() {
+            bail!("{} does not exist, run depmod", kroot.join(filename));
+        }
+    }
+
+    Ok(())
 }
 
-fn get_host_modules() -> Result<Vec<String>> {
-    Ok(BufReader::new(
-        File::open("/proc/modules").with_context(|| "unable to open file /proc/modules")?,
-    )
-    .lines()
-    .map_while(Result::ok)
-    .filter_map(|line| {
-        line.split_whitespace()
-            .next()
-            .map(|module| module.to_string())
-    })
-    .collect())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_dependencies_test() {
+        let modules = modules::parse_modules_dep(
+            "kernel/fs/ext4/ext4.ko.zst: kernel/fs/mbcache.ko.zst kernel/fs/jbd2/jbd2.ko.zst
+kernel/fs/mbcache.ko.zst:
+kernel/fs/jbd2/jbd2.ko.zst:
+kernel/crypto/crc32c_generic.ko.zst:
+kernel/drivers/net/e1000e/e1000e.ko.zst:
+",
+        );
+        let softdeps = modules::parse_modules_softdep("softdep ext4 pre: crc32c crc32c_generic\n");
+        let mut resolved: Vec<String> =
+            add_dependencies(HashSet::from(["ext4".to_string()]), &modules, &softdeps)
+                .into_iter()
+                .collect();
+        resolved.sort();
+        assert_eq!(resolved, vec!["crc32c_generic", "ext4", "jbd2", "mbcache"]);
+    }
+
+    #[test]
+    fn out_of_tree_test() {
+        let host_modules = parse_proc_modules(
+            "nvidia_uvm 1425408 0 - Live 0x0000000000000000 (POE)
+nvidia 56823808 19 nvidia_uvm, Live 0x0000000000000000 (POE)
+zfs 6021120 8 - Live 0x0000000000000000 (OE)
+ext4 1007616 1 - Live 0x0000000000000000
+",
+        );
+        assert_eq!(
+            host_modules[2],
+            HostModule {
+                name: "zfs".to_string(),
+                out_of_tree: true,
+                proprietary: false,
+            }
+        );
+        assert!(!host_modules[3].out_of_tree);
+
+        let modules = modules::parse_modules_dep(
+            "updates/dkms/nvidia-uvm.ko: updates/dkms/nvidia.ko
+updates/dkms/nvidia.ko:
+kernel/fs/ext4/ext4.ko:
+",
+        );
+        let kroot = Utf8Path::new("/nonexistent/6.1.0");
+        let err = check_out_of_tree_module("zfs", &modules, kroot).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "not found in modules.dep of 6.1.0, it has not been built for this kernel"
+        );
+        let err = check_out_of_tree_module("ext4", &modules, kroot).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("kernel/fs/ext4/ext4.ko is not in"));
+        let err = check_out_of_tree_module("nvidia_uvm", &modules, kroot).unwrap_err();
+        assert!(err.to_string().ends_with("does not exist, run depmod"));
+        assert!(get_out_of_tree_modules(&host_modules, &modules, kroot).is_empty());
+    }
 }
@@ -0,0 +1,167 @@
+// Install the kernel and the image where the boot loader finds them, described by a Boot
+// Loader Specification entry, with the layout used by kernel-install:
+// https://uapi-group.org/specifications/specs/boot_loader_specification/
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::output::AtomicFile;
+
+const MACHINE_ID: &str = "/etc/machine-id";
+const OS_RELEASE: &str = "/etc/os-release";
+/// Also read by kernel-install
+pub const KERNEL_CMDLINE: &str = "/etc/kernel/cmdline";
+const ENTRIES_DIR: &str = "loader/entries";
+
+/// Copy the kernel and the image under boot and write the entry booting them, returning
+/// the path of the entry
+pub fn install(
+    boot: &Utf8Path,
+    kernel_version: &str,
+    kernel: &Utf8Path,
+    image: &Utf8Path,
+    cmdline: Option<&str>,
+) -> Result<Utf8PathBuf> {
+    let machine_id = fs::read_to_string(MACHINE_ID)
+        .ok()
+        .map(|id| id.trim().to_string())
+        .filter(|id| !id.is_empty());
+    let os_release = fs::read_to_string(OS_RELEASE).unwrap_or_default();
+    // Keep the files of each installation apart, like kernel-install
+    let entry_token = machine_id
+        .clone()
+        .or_else(|| get_os_release_value(&os_release, "ID"))
+        .unwrap_or_else(|| "linux".to_string());
+    let title = get_os_release_value(&os_release, "PRETTY_NAME")
+        .or_else(|| get_os_release_value(&os_release, "NAME"))
+        .unwrap_or_else(|| "Linux".to_string());
+
+    // The paths of the entry are relative to the root of the boot partition
+    let dir = Utf8PathBuf::from(format!("/{}/{}", entry_token, kernel_version));
+    let linux = dir.join("linux");
+    let initrd = dir.join("initrd");
+    copy_file(kernel, &boot.join(linux.strip_prefix("/")?))?;
+    copy_file(image, &boot.join(initrd.strip_prefix("/")?))?;
+
+    let entries = boot.join(ENTRIES_DIR);
+    fs::create_dir_all(&entries).with_context(|| format!("unable to create {}", entries))?;
+    let entry = entries.join(format!("{}-{}.conf", entry_token, kernel_version));
+    let contents = get_entry(
+        &title,
+        kernel_version,
+        machine_id.as_deref(),
+        &linux,
+        &initrd,
+        cmdline,
+    );
+    let mut file = AtomicFile::create(&entry)?;
+    file.write_all(contents.as_bytes())
+        .with_context(|| format!("unable to write {}", entry))?;
+    file.commit()?;
+
+    Ok(entry)
+}
+
+/// Get the kernel command line written in the file, joining its lines, if any
+pub fn read_cmdline(file: &Utf8Path) -> Result<Option<String>> {
+    match fs::read_to_string(file) {
+        Ok(cmdline) => {
+            let cmdline = cmdline.split_whitespace().collect::<Vec<_>>().join(" ");
+            Ok((!cmdline.is_empty()).then_some(cmdline))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| format!("unable to read {}", file)),
+    }
+}
+
+fn copy_file(src: &Utf8Path, dest: &Utf8Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent).with_context(|| format!("unable to create {}", parent))?;
+    }
+    let mut src_file = File::open(src).with_context(|| format!("unable to open {}", src))?;
+    let mut dest_file = AtomicFile::create(dest)?;
+    io::copy(&mut src_file, &mut dest_file)
+        .with_context(|| format!("unable to copy {} to {}", src, dest))?;
+    dest_file.commit()
+}
+
+fn get_entry(
+    title: &str,
+    kernel_version: &str,
+    machine_id: Option<&str>,
+    linux: &Utf8Path,
+    initrd: &Utf8Path,
+    cmdline: Option<&str>,
+) -> String {
+    let mut entry = format!("title {}\nversion {}\n", title, kernel_version);
+    if let Some(machine_id) = machine_id {
+        entry.push_str(&format!("machine-id {}\n", machine_id));
+    }
+    entry.push_str(&format!("linux {}\ninitrd {}\n", linux, initrd));
+    if let Some(cmdline) = cmdline {
+        entry.push_str(&format!("options {}\n", cmdline));
+    }
+
+    entry
+}
+
+/// Get the value of a key of os-release, without its quotes
+fn get_os_release_value(os_release: &str, key: &str) -> Option<String> {
+    os_release
+        .lines()
+        .filter_map(|line| line.trim().split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value.trim_matches(|c| c == '"' || c == '\'').to_string())
+        .filter(|value| !value.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_entry_test() {
+        let os_release = "NAME=\"Arch Linux\"\nPRETTY_NAME=\"Arch Linux\"\nID=arch\nBUILD_ID=\n";
+        assert_eq!(
+            get_os_release_value(os_release, "PRETTY_NAME").as_deref(),
+            Some("Arch Linux")
+        );
+        assert_eq!(
+            get_os_release_value(os_release, "ID").as_deref(),
+            Some("arch")
+        );
+        assert_eq!(get_os_release_value(os_release, "BUILD_ID"), None);
+        assert_eq!(get_os_release_value(os_release, "VERSION_ID"), None);
+
+        assert_eq!(
+            get_entry(
+                "Arch Linux",
+                "6.6.1-arch1-1",
+                Some("4b1ac5b0a0f54c03a1bb4c8a2e4a13f6"),
+                Utf8Path::new("/4b1ac5b0a0f54c03a1bb4c8a2e4a13f6/6.6.1-arch1-1/linux"),
+                Utf8Path::new("/4b1ac5b0a0f54c03a1bb4c8a2e4a13f6/6.6.1-arch1-1/initrd"),
+                Some("root=UUID=1234 rw"),
+            ),
+            "title Arch Linux\n\
+             version 6.6.1-arch1-1\n\
+             machine-id 4b1ac5b0a0f54c03a1bb4c8a2e4a13f6\n\
+             linux /4b1ac5b0a0f54c03a1bb4c8a2e4a13f6/6.6.1-arch1-1/linux\n\
+             initrd /4b1ac5b0a0f54c03a1bb4c8a2e4a13f6/6.6.1-arch1-1/initrd\n\
+             options root=UUID=1234 rw\n"
+        );
+        assert_eq!(
+            get_entry(
+                "Linux",
+                "6.6.1",
+                None,
+                Utf8Path::new("/linux/6.6.1/linux"),
+                Utf8Path::new("/linux/6.6.1/initrd"),
+                None,
+            ),
+            "title Linux\nversion 6.6.1\nlinux /linux/6.6.1/linux\ninitrd /linux/6.6.1/initrd\n"
+        );
+    }
+}
@@ -1,95 +1,615 @@
+mod compression;
 mod config;
 mod depend;
 mod initramfs;
 mod initramfs_modules;
 mod initramfs_type;
+mod install;
+mod module_dictionary;
+mod module_manifest;
 mod newc;
+mod output;
+mod provenance;
+mod release;
+mod seekable;
+mod sign;
+mod vconsole;
 
 use std::{
-    fs::{self, File},
-    io::{BufWriter, Write},
-    path::Path,
+    fs,
+    io::{self, BufWriter, IsTerminal, Write},
+    process, thread,
 };
 
-use anyhow::{ensure, Context, Result};
-use camino::Utf8PathBuf;
-use clap::Parser;
+use anyhow::{anyhow, ensure, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, Subcommand};
 use colored::Colorize;
-use simplelog::{ColorChoice, LevelFilter, TermLogger, TerminalMode};
+use common::modules;
+use flate2::write::GzEncoder;
+use tracing::{error, info, info_span, level_filters::LevelFilter, warn};
+use tracing_subscriber::fmt::format::FmtSpan;
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::write::XzEncoder;
 use zstd::stream::write::Encoder;
 
+use compression::Compression;
 use config::Config;
-use initramfs::Initramfs;
+use initramfs::{Initramfs, InitramfsError};
 use initramfs_type::InitramfsType;
+use output::{AtomicFile, SegmentWriter};
+use seekable::SeekableWriter;
+use sign::Signer;
+
+const USER_ERROR_EXIT_CODE: i32 = 2;
+// Given as output to write the image to the standard output
+const STDOUT: &str = "-";
 
 #[derive(Clone, Copy, Debug)]
-enum Compression {
-    None,
-    Zstd,
+enum LogFormat {
+    Pretty,
+    Json,
 }
 
-impl clap::ValueEnum for Compression {
+impl clap::ValueEnum for LogFormat {
     fn value_variants<'a>() -> &'a [Self] {
-        &[Compression::None, Compression::Zstd]
+        &[LogFormat::Pretty, LogFormat::Json]
     }
 
     fn to_possible_value(&self) -> Option<clap::builder::PossibleValue> {
         match self {
-            Compression::None => Some(clap::builder::PossibleValue::new("none")),
-            Compression::Zstd => Some(clap::builder::PossibleValue::new("zstd")),
+            LogFormat::Pretty => Some(clap::builder::PossibleValue::new("pretty")),
+            LogFormat::Json => Some(clap::builder::PossibleValue::new("json")),
         }
     }
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Validate the configuration without generating the initramfs
+    CheckConfig,
+    /// Copy the kernel and its image to the boot partition, with a Boot Loader
+    /// Specification entry booting them
+    Install {
+        #[clap(short = 'k', long = "kver")]
+        kernel_version: String,
+        /// Defaults to initramfs-<kver>.img
+        #[clap(short = 'i', long)]
+        image: Option<Utf8PathBuf>,
+        /// Defaults to the vmlinuz installed with the kernel modules, or to
+        /// /boot/vmlinuz-<kver>
+        #[clap(long)]
+        kernel: Option<Utf8PathBuf>,
+        /// Mount point of the partition read by the boot loader, e.g. the ESP
+        #[clap(long, default_value = "/boot")]
+        boot: Utf8PathBuf,
+        /// Defaults to the cmdline of the configuration, or to /etc/kernel/cmdline
+        #[clap(long)]
+        cmdline: Option<String>,
+    },
+}
+
 #[derive(Parser)]
-#[clap(version = "0.1", author = "danyspin97")]
+#[clap(version = "0.1", author = "danyspin97", subcommand_negates_reqs = true)]
 struct Opts {
+    #[clap(subcommand)]
+    command: Option<Command>,
     #[clap(
         long = "config",
-        default_value = "/etc/initrz/mkinitrz.conf"
+        default_value = "/etc/initrz/mkinitrz.conf",
+        global = true
     )]
     config: Utf8PathBuf,
     #[clap(long = "host-only")]
     host: bool,
-    #[clap(short = 'k', long = "kver")]
-    kernel_version: String,
+    #[clap(
+        short = 'k',
+        long = "kver",
+        required_unless_present_any = ["all", "kver_from_stdin"]
+    )]
+    kernel_version: Option<String>,
+    /// Build initramfs-<kver>.img for every kernel under the kernel modules path
+    #[clap(long, conflicts_with_all = ["kernel_version", "output", "provenance"])]
+    all: bool,
+    /// Build initramfs-<kver>.img for the kernels read from the standard input, one per
+    /// line, given as versions or as paths under the modules directory like pacman hooks
+    /// pass them, e.g. usr/lib/modules/6.6.1-arch1-1/vmlinuz
+    #[clap(
+        long,
+        conflicts_with_all = ["kernel_version", "all", "output", "provenance"]
+    )]
+    kver_from_stdin: bool,
+    /// Defaults to initramfs-<kver>.img, - writes the image to the standard output
     #[clap(short = 'o', long = "output")]
     output: Option<String>,
     #[clap(short = 'q', long = "quiet")]
     quiet: bool,
     #[clap(short = 'v', long = "verbose", action = clap::ArgAction::Count)]
     verbose: u8,
-    #[clap(long, default_value = "/lib/modules")]
+    #[clap(long, default_value = "/lib/modules", global = true)]
     kernel_modules_path: Utf8PathBuf,
-    #[clap(value_enum, short, long, default_value_t = Compression::None)]
+    /// Defaults to the compression of the configuration, or to none
+    #[clap(value_enum, short, long)]
+    compression: Option<Compression>,
+    /// Defaults to the level of the configuration, or to 6 for gzip and xz and 3 for zstd
+    #[clap(long, allow_negative_numbers = true)]
+    compression_level: Option<i32>,
+    /// Threads used by the xz and zstd encoders, defaults to the threads of the
+    /// configuration, or to the number of CPUs
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    compression_threads: Option<u32>,
+    /// Split the zstd compressed image in independent frames followed by a seek table, so
+    /// that it can be read at random offsets
+    #[clap(long)]
+    seekable: bool,
+    /// Decompressed size in bytes of each frame of the seekable image
+    #[clap(long, requires = "seekable", default_value_t = seekable::DEFAULT_FRAME_SIZE)]
+    seekable_frame_size: usize,
+    /// Enable the zstd long-distance matching with a window of 2^WINDOW_LOG bytes, at most
+    /// 2^27 so that the image can be decompressed within the default memory limit
+    #[clap(
+        long,
+        value_name = "WINDOW_LOG",
+        num_args = 0..=1,
+        default_missing_value = "27",
+        value_parser = clap::value_parser!(u32).range(10..=27)
+    )]
+    zstd_long: Option<u32>,
+    /// Recompress the zstd modules with a dictionary trained over them, shipped in the image
+    #[clap(long)]
+    zstd_dictionary: bool,
+    #[clap(value_enum, long, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+    /// Write the package and the license of every file included from the host
+    #[clap(long)]
+    provenance: Option<Utf8PathBuf>,
+    /// Kernel command line embedded in the image, read by initrz when the boot loader does
+    /// not pass root=
+    #[clap(long, conflicts_with = "cmdline_file")]
+    cmdline: Option<String>,
+    /// Read the kernel command line to embed from a file, e.g. /etc/kernel/cmdline
+    #[clap(long, value_name = "FILE")]
+    cmdline_file: Option<Utf8PathBuf>,
+    /// Write a detached signature of the image to <output>.sig, made by gpg with the key
+    #[clap(long, value_name = "gpg:KEYID")]
+    sign_with: Option<Signer>,
+    /// Archive written as it is before the generated one, e.g. the early microcode, after
+    /// the ones of the configuration
+    #[clap(long, value_name = "FILE")]
+    prepend_cpio: Vec<Utf8PathBuf>,
+    /// Archive written as it is after the generated one, e.g. a firmware bundle, after the
+    /// ones of the configuration
+    #[clap(long, value_name = "FILE")]
+    append_cpio: Vec<Utf8PathBuf>,
+}
+
+fn init_logger(opts: &Opts) -> Result<()> {
+    let level = if opts.quiet {
+        LevelFilter::ERROR
+    } else {
+        match opts.verbose {
+            0 => LevelFilter::WARN,
+            1 => LevelFilter::INFO,
+            2 => LevelFilter::DEBUG,
+            _ => LevelFilter::TRACE,
+        }
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_max_level(level)
+        .with_writer(io::stderr)
+        // Show how long each phase took with -vv
+        .with_span_events(if level >= LevelFilter::DEBUG {
+            FmtSpan::CLOSE
+        } else {
+            FmtSpan::NONE
+        });
+    match opts.log_format {
+        LogFormat::Pretty => builder.without_time().with_target(false).try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    }
+    .map_err(|err| anyhow!(err))
+}
+
+/// Scripts calling mkinitrz can tell a wrong configuration from a failure of the host
+fn handle_build_error(err: InitramfsError) -> anyhow::Error {
+    if err.is_user_error() {
+        error!("{:?}", anyhow::Error::new(err));
+        process::exit(USER_ERROR_EXIT_CODE);
+    }
+
+    err.into()
+}
+
+fn check_config(file: &Utf8Path) -> Result<()> {
+    ensure!(file.exists(), "{} does not exist", file.as_str().red());
+    Config::new(file)?;
+    println!("{} is valid", file);
+
+    Ok(())
+}
+
+fn install_kernel(
+    opts: &Opts,
+    kernel_version: &str,
+    image: Option<&Utf8Path>,
+    kernel: Option<&Utf8Path>,
+    boot: &Utf8Path,
+    cmdline: Option<&str>,
+) -> Result<()> {
+    let config = Config::new(&opts.config)?;
+    let image = image
+        .map(Utf8Path::to_path_buf)
+        .unwrap_or_else(|| Utf8PathBuf::from(get_default_output(kernel_version)));
+    ensure!(image.is_file(), "{} does not exist", image.as_str().red());
+    let kernel = match kernel {
+        Some(kernel) => kernel.to_path_buf(),
+        None => vec![
+            opts.kernel_modules_path
+                .join(kernel_version)
+                .join("vmlinuz"),
+            Utf8PathBuf::from(format!("/boot/vmlinuz-{}", kernel_version)),
+        ]
+        .into_iter()
+        .find(|kernel| kernel.is_file())
+        .ok_or_else(|| anyhow!("unable to find the kernel {}", kernel_version.red()))?,
+    };
+    ensure!(kernel.is_file(), "{} does not exist", kernel.as_str().red());
+    // The command line takes precedence over the configuration
+    let cmdline = match cmdline.map(String::from).or(config.cmdline) {
+        Some(cmdline) => Some(cmdline),
+        None => install::read_cmdline(Utf8Path::new(install::KERNEL_CMDLINE))?,
+    };
+    if cmdline.is_none() {
+        warn!("no kernel command line given, the boot loader entry has no options");
+    }
+
+    let entry = install::install(boot, kernel_version, &kernel, &image, cmdline.as_deref())?;
+    println!("{} installed", entry);
+
+    Ok(())
+}
+
+/// Stream the image through the compression, returning the writer once the compressed
+/// stream has been finished
+fn compress_image<W: Write>(
+    mut writer: W,
+    initramfs: Initramfs,
+    opts: &Opts,
+    compression: Compression,
+    level: i32,
+    threads: u32,
+) -> Result<W> {
+    Ok(match compression {
+        Compression::None => {
+            initramfs.write(&mut writer)?;
+            writer
+        }
+        // For the kernels only built with CONFIG_RD_GZIP
+        Compression::Gzip => {
+            let mut gzip_encoder = GzEncoder::new(writer, flate2::Compression::new(level as u32));
+            initramfs.write(&mut gzip_encoder)?;
+            gzip_encoder.finish()?
+        }
+        Compression::Xz => {
+            // The kernel decompressor only supports the CRC32 check
+            let stream = MtStreamBuilder::new()
+                .preset(level as u32)
+                .threads(threads)
+                .check(Check::Crc32)
+                .encoder()?;
+            let mut xz_encoder = XzEncoder::new_stream(writer, stream);
+            initramfs.write(&mut xz_encoder)?;
+            xz_encoder.finish()?
+        }
+        Compression::Zstd if opts.seekable => {
+            let mut seekable_writer =
+                SeekableWriter::new(writer, opts.seekable_frame_size, level, opts.zstd_long);
+            initramfs.write(&mut seekable_writer)?;
+            seekable_writer.finish()?
+        }
+        Compression::Zstd => {
+            let mut zstd_encoder = Encoder::new(writer, level)?;
+            // A single thread compresses in the calling thread
+            if threads > 1 {
+                zstd_encoder.multithread(threads)?;
+            }
+            if let Some(window_log) = opts.zstd_long {
+                zstd_encoder.long_distance_matching(true)?;
+                zstd_encoder.window_log(window_log)?;
+            }
+            initramfs.write(&mut zstd_encoder)?;
+            zstd_encoder.finish()?
+        }
+    })
+}
+
+/// Write the generated image between the archives to prepend and append, each one aligned
+fn write_image<W: Write>(
+    writer: W,
+    initramfs: Initramfs,
+    opts: &Opts,
+    config: &Config,
+    compression: Compression,
+    level: i32,
+    threads: u32,
+) -> Result<W> {
+    let mut writer = SegmentWriter::new(writer);
+    for segment in config.prepend_cpio.iter().chain(&opts.prepend_cpio) {
+        writer.write_segment(segment)?;
+    }
+    writer.pad()?;
+    let mut writer = compress_image(writer, initramfs, opts, compression, level, threads)?;
+    for segment in config.append_cpio.iter().chain(&opts.append_cpio) {
+        writer.write_segment(segment)?;
+    }
+
+    Ok(writer.into_inner())
+}
+
+fn get_default_output(kernel_version: &str) -> String {
+    format!("initramfs-{}.img", kernel_version)
+}
+
+/// Get the kernels with modules installed, leaving out the other directories like the
+/// extramodules of Arch Linux
+fn get_kernel_versions(kernel_modules_path: &Utf8Path) -> Result<Vec<String>> {
+    let mut kernel_versions = Vec::new();
+    for entry in kernel_modules_path.read_dir_utf8()? {
+        let entry = entry?;
+        if entry.path().join(modules::MODULES_DEP).exists() {
+            kernel_versions.push(entry.file_name().to_string());
+        }
+    }
+    kernel_versions.sort();
+
+    Ok(kernel_versions)
+}
+
+fn build_image(
+    opts: &Opts,
+    config: &Config,
+    kernel_version: &str,
+    output: &str,
+    compression: Compression,
+    level: i32,
+    threads: u32,
+) -> Result<()> {
+    // Canonicalize path to avoid problems with dowser and filter
+    let kernel_modules = opts.kernel_modules_path.join(kernel_version);
+    let kroot = Utf8PathBuf::from_path_buf(fs::canonicalize(kernel_modules)?).map_err(|path| {
+        anyhow::anyhow!("unable to convert path {} to utf8", path.to_string_lossy())
+    })?;
+    let initramfs_type = if opts.host {
+        InitramfsType::Host
+    } else {
+        InitramfsType::General
+    };
+    let initramfs = info_span!("build")
+        .in_scope(|| Initramfs::new(initramfs_type, kroot, config, opts.zstd_dictionary))
+        .map_err(handle_build_error)?;
+    if let Some(report) = &opts.provenance {
+        provenance::write_report(report, initramfs.get_sources())?;
+    }
+
+    let _span = info_span!("write").entered();
+    // Stream the image, e.g. into cpio -t or ssh
+    if output == STDOUT {
+        let stdout = io::stdout().lock();
+        write_image(
+            BufWriter::new(stdout),
+            initramfs,
+            opts,
+            config,
+            compression,
+            level,
+            threads,
+        )?
+        .flush()?;
+        return Ok(());
+    }
+    let file = AtomicFile::create(Utf8Path::new(output))?;
+    let writer = write_image(
+        BufWriter::new(file),
+        initramfs,
+        opts,
+        config,
+        compression,
+        level,
+        threads,
+    )?;
+    let file = writer.into_inner().map_err(|err| err.into_error())?;
+    // Sign the complete image, the previous one is kept when signing fails
+    let signature = opts
+        .sign_with
+        .as_ref()
+        .map(|signer| signer.sign(file.get_tmp_path()))
+        .transpose()
+        .with_context(|| format!("unable to sign {}", output))?;
+    file.commit()?;
+    if let Some(signature) = signature {
+        let path = sign::get_signature_path(Utf8Path::new(output));
+        let mut file = AtomicFile::create(&path)?;
+        file.write_all(&signature)
+            .with_context(|| format!("unable to write {}", path))?;
+        file.commit()?;
+    }
+
+    Ok(())
+}
+
+/// Get the kernel versions given one per line, either as they are or in the path of a
+/// file installed with the kernel modules
+fn parse_kernel_versions(input: &str) -> Vec<String> {
+    let mut kernel_versions = Vec::new();
+    for line in input.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let kernel_version = match line.split('/').skip_while(|c| *c != "modules").nth(1) {
+            Some(kernel_version) => kernel_version,
+            None if !line.contains('/') => line,
+            None => {
+                warn!("unable to find the kernel version in {}", line);
+                continue;
+            }
+        };
+        if !kernel_versions
+            .iter()
+            .any(|version| version == kernel_version)
+        {
+            kernel_versions.push(kernel_version.to_string());
+        }
+    }
+
+    kernel_versions
+}
+
+/// Build the images of the kernels read from the standard input, skipping the ones that
+/// have been removed
+fn build_from_stdin(
+    opts: &Opts,
+    config: &Config,
     compression: Compression,
+    level: i32,
+    threads: u32,
+) -> Result<()> {
+    let input = io::read_to_string(io::stdin()).context("unable to read the standard input")?;
+    let kernel_versions: Vec<String> = parse_kernel_versions(&input)
+        .into_iter()
+        .filter(|kernel_version| {
+            let installed = opts
+                .kernel_modules_path
+                .join(kernel_version)
+                .join(modules::MODULES_DEP)
+                .exists();
+            if !installed {
+                info!("Skipping kernel {}, it is not installed", kernel_version);
+            }
+            installed
+        })
+        .collect();
+
+    build_all(opts, config, &kernel_versions, compression, level, threads)
+}
+
+/// Build an image for every kernel, the configuration is read once and a failing kernel
+/// does not stop the others from being built
+fn build_all(
+    opts: &Opts,
+    config: &Config,
+    kernel_versions: &[String],
+    compression: Compression,
+    level: i32,
+    threads: u32,
+) -> Result<()> {
+    let mut failed = Vec::new();
+    for kernel_version in kernel_versions {
+        info!("Building the image for kernel {}", kernel_version);
+        if let Err(err) = build_image(
+            opts,
+            config,
+            kernel_version,
+            &get_default_output(kernel_version),
+            compression,
+            level,
+            threads,
+        ) {
+            error!(
+                "unable to build the image for kernel {}: {:?}",
+                kernel_version, err
+            );
+            failed.push(kernel_version.red().to_string());
+        }
+    }
+    ensure!(
+        failed.is_empty(),
+        "unable to build the images for the kernels {}",
+        failed.join(", ")
+    );
+
+    Ok(())
 }
 
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
-    TermLogger::init(
-        if opts.quiet {
-            LevelFilter::Error
-        } else {
-            match opts.verbose {
-                0 => LevelFilter::Warn,
-                1 => LevelFilter::Info,
-                2 => LevelFilter::Debug,
-                _ => LevelFilter::Trace,
-            }
-        },
-        simplelog::Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )?;
+    init_logger(&opts)?;
 
-    let file = File::create(
-        opts.output
-            .clone()
-            .unwrap_or_else(|| format!("initramfs-{}.img", opts.kernel_version)),
-    )
-    .with_context(|| format!("unable to create file {:?}", opts.output))?;
+    match &opts.command {
+        Some(Command::CheckConfig) => return check_config(&opts.config),
+        Some(Command::Install {
+            kernel_version,
+            image,
+            kernel,
+            boot,
+            cmdline,
+        }) => {
+            return install_kernel(
+                &opts,
+                kernel_version,
+                image.as_deref(),
+                kernel.as_deref(),
+                boot,
+                cmdline.as_deref(),
+            )
+        }
+        None => {}
+    }
+    let mut config = Config::new(&opts.config)?;
+    if let Some(cmdline) = &opts.cmdline {
+        ensure!(
+            !cmdline.contains('\n'),
+            "the kernel command line must be a single line"
+        );
+        config.cmdline = Some(cmdline.clone());
+    }
+    if let Some(file) = &opts.cmdline_file {
+        ensure!(file.is_file(), "{} does not exist", file.as_str().red());
+        config.cmdline = install::read_cmdline(file)?;
+    }
+    // The command line takes precedence over the configuration
+    let compression = opts
+        .compression
+        .or(config.compression)
+        .unwrap_or(Compression::None);
+    ensure!(
+        !opts.seekable || matches!(compression, Compression::Zstd),
+        "--seekable requires the zstd compression"
+    );
+    ensure!(
+        opts.zstd_long.is_none() || matches!(compression, Compression::Zstd),
+        "--zstd-long requires the zstd compression"
+    );
+    let level = opts
+        .compression_level
+        .or(config.compression_level)
+        .unwrap_or_else(|| compression.get_default_level());
+    ensure!(
+        compression.get_level_range().contains(&level),
+        "compression level {} out of range {:?}",
+        level,
+        compression.get_level_range()
+    );
+    let threads = opts
+        .compression_threads
+        .or(config.compression_threads)
+        .unwrap_or_else(|| {
+            thread::available_parallelism()
+                .map(|threads| threads.get() as u32)
+                .unwrap_or(1)
+        });
+    ensure!(
+        opts.output.as_deref() != Some(STDOUT) || !io::stdout().is_terminal(),
+        "refusing to write the image to a terminal"
+    );
+    for cpio in opts.prepend_cpio.iter().chain(&opts.append_cpio) {
+        ensure!(cpio.is_file(), "{} does not exist", cpio.as_str().red());
+    }
+    ensure!(
+        opts.output.as_deref() != Some(STDOUT) || opts.sign_with.is_none(),
+        "--sign-with requires the image to be written to a file"
+    );
+    ensure!(
+        opts.seekable_frame_size > 0,
+        "the seekable frame size must be greater than zero"
+    );
 
     ensure!(
         opts.kernel_modules_path.exists(),
@@ -101,13 +621,37 @@ fn main() -> Result<()> {
         "{} is not a directory",
         opts.kernel_modules_path.as_str().red()
     );
-    let kernel_modules = opts.kernel_modules_path.join(&opts.kernel_version);
+    if opts.all {
+        let kernel_versions = get_kernel_versions(&opts.kernel_modules_path)?;
+        ensure!(
+            !kernel_versions.is_empty(),
+            "no kernel found in {}",
+            opts.kernel_modules_path.as_str().red()
+        );
+        return build_all(
+            &opts,
+            &config,
+            &kernel_versions,
+            compression,
+            level,
+            threads,
+        );
+    }
+    if opts.kver_from_stdin {
+        return build_from_stdin(&opts, &config, compression, level, threads);
+    }
+
+    let kernel_version = opts
+        .kernel_version
+        .as_deref()
+        .expect("--kver is required without a subcommand, --all or --kver-from-stdin");
+    let kernel_modules = opts.kernel_modules_path.join(kernel_version);
     // ensure that the path kernel_modules exists. If not, show the user all available kernel
     // versions
     ensure!(
         kernel_modules.exists(),
         "kernel version {} not found. Available versions: {}",
-        opts.kernel_version.red(),
+        kernel_version.red(),
         opts.kernel_modules_path
             .read_dir()?
             .filter_map(|entry| {
@@ -122,31 +666,38 @@ fn main() -> Result<()> {
             .collect::<Vec<String>>()
             .join(", ")
     );
+    let output = opts
+        .output
+        .clone()
+        .unwrap_or_else(|| get_default_output(kernel_version));
 
-    let initramfs = &Initramfs::new(
-        if opts.host {
-            InitramfsType::Host
-        } else {
-            InitramfsType::General
-        },
-        // Canonicalize path to avoid problems with dowser and filter
-        Utf8PathBuf::from_path_buf(fs::canonicalize(kernel_modules)?).map_err(|path| {
-            anyhow::anyhow!("unable to convert path {} to utf8", path.to_string_lossy())
-        })?,
-        Config::new(&opts.config)?,
-    )?
-    .into_bytes()?;
-
-    let mut writer = BufWriter::new(file);
-
-    match opts.compression {
-        Compression::None => writer.write_all(initramfs)?,
-        Compression::Zstd => {
-            let mut zstd_encoder = Encoder::new(writer, 3)?;
-            zstd_encoder.write_all(initramfs)?;
-            zstd_encoder.finish()?;
-        }
-    }
+    build_image(
+        &opts,
+        &config,
+        kernel_version,
+        &output,
+        compression,
+        level,
+        threads,
+    )
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_kernel_versions_test() {
+        assert_eq!(
+            parse_kernel_versions(
+                "usr/lib/modules/6.6.1-arch1-1/vmlinuz\n\
+                 usr/lib/modules/6.6.1-arch1-1/pkgbase\n\
+                 6.1.62-1-lts\n\
+                 \n\
+                 boot/vmlinuz-linux\n"
+            ),
+            vec!["6.6.1-arch1-1", "6.1.62-1-lts"]
+        );
+        assert!(parse_kernel_versions("").is_empty());
+    }
 }
@@ -0,0 +1,120 @@
+// Train a zstd dictionary over the modules and recompress them with it. The modules are
+// small and share most of their structure, so each frame compresses much better when the
+// common parts come from the dictionary. initrz decompresses the modules itself before
+// loading them, reading the dictionary from MODULE_DICTIONARY
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use rayon::prelude::*;
+use tracing::{info, instrument, warn};
+use zstd::bulk::Compressor;
+use zstd::dict::EncoderDictionary;
+
+pub const MODULE_DICTIONARY: &str = "/etc/initrz/modules.zdict";
+// Same as zstd --train
+const DICTIONARY_SIZE: usize = 112_640;
+const COMPRESSION_LEVEL: i32 = 19;
+// The trainer needs enough samples to find the common parts
+const MIN_MODULES: usize = 8;
+
+pub struct Recompressed {
+    pub dictionary: Vec<u8>,
+    /// The modules compressed with the dictionary, by their path on the host
+    pub modules: HashMap<Utf8PathBuf, Vec<u8>>,
+}
+
+fn is_zstd_module(module: &Utf8Path) -> bool {
+    module.as_str().ends_with(".ko.zst")
+}
+
+/// Recompress the zstd modules with a dictionary trained over them. The other ones are left
+/// untouched, since changing their extension would break modules.dep. None when there are
+/// too few modules or the dictionary does not make them smaller
+#[instrument(name = "dictionary", skip_all)]
+pub fn recompress(modules: &[Utf8PathBuf]) -> Result<Option<Recompressed>> {
+    let modules: Vec<&Utf8PathBuf> = modules
+        .iter()
+        .filter(|module| is_zstd_module(module))
+        .collect();
+    if modules.len() < MIN_MODULES {
+        warn!(
+            "{} zstd compressed modules found, at least {} are needed to train the dictionary",
+            modules.len(),
+            MIN_MODULES
+        );
+        return Ok(None);
+    }
+
+    let compressed_len = modules
+        .iter()
+        .map(|module| {
+            fs::metadata(module)
+                .map(|metadata| metadata.len() as usize)
+                .with_context(|| format!("unable to read {}", module))
+        })
+        .sum::<Result<usize>>()?;
+    let samples = modules
+        .par_iter()
+        .map(|module| {
+            fs::read(module)
+                .and_then(|contents| zstd::stream::decode_all(&contents[..]))
+                .with_context(|| format!("unable to decompress {}", module))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let dictionary = match zstd::dict::from_samples(&samples, DICTIONARY_SIZE) {
+        Ok(dictionary) => dictionary,
+        Err(err) => {
+            warn!("unable to train the module dictionary: {}", err);
+            return Ok(None);
+        }
+    };
+
+    let prepared = EncoderDictionary::copy(&dictionary, COMPRESSION_LEVEL);
+    let recompressed = samples
+        .par_iter()
+        .map(|sample| {
+            Compressor::with_prepared_dictionary(&prepared)
+                .and_then(|mut compressor| compressor.compress(sample))
+        })
+        .collect::<io::Result<Vec<_>>>()
+        .context("unable to compress the modules with the dictionary")?;
+    let recompressed_len = dictionary.len() + recompressed.iter().map(Vec::len).sum::<usize>();
+    if recompressed_len >= compressed_len {
+        warn!(
+            "the module dictionary does not make the modules smaller ({} bytes instead of {}), skipping it",
+            recompressed_len, compressed_len
+        );
+        return Ok(None);
+    }
+    info!(
+        "modules recompressed with the dictionary: {} bytes instead of {}",
+        recompressed_len, compressed_len
+    );
+
+    Ok(Some(Recompressed {
+        dictionary,
+        modules: modules.into_iter().cloned().zip(recompressed).collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_zstd_module_test() {
+        assert!(is_zstd_module(Utf8Path::new(
+            "/lib/modules/6.6.1/kernel/fs/ext4/ext4.ko.zst"
+        )));
+        assert!(!is_zstd_module(Utf8Path::new(
+            "/lib/modules/6.6.1/extra/zfs.ko.xz"
+        )));
+        assert!(!is_zstd_module(Utf8Path::new(
+            "/lib/modules/6.6.1/extra/zfs.ko"
+        )));
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+pub const MODULE_MANIFEST: &str = "/etc/initrz/modules.sha256";
+const SHA256SUM: &str = "sha256sum";
+
+/// Replace the host paths in the sha256sum output with the ones in the initramfs
+fn to_initramfs_paths(output: &str, paths: &HashMap<&Utf8Path, &Utf8Path>) -> Result<String> {
+    output
+        .lines()
+        .map(|line| {
+            let (hash, file) = line
+                .split_once("  ")
+                .with_context(|| format!("unexpected {} output: {}", SHA256SUM, line))?;
+            let path = paths
+                .get(Utf8Path::new(file))
+                .with_context(|| format!("unexpected file {} hashed", file))?;
+            Ok(format!("{}  {}\n", hash, path))
+        })
+        .collect()
+}
+
+/// Hash the modules, given as (host path, initramfs path), in the format read by initrz
+pub fn get_manifest(modules: &[(Utf8PathBuf, Utf8PathBuf)]) -> Result<String> {
+    if modules.is_empty() {
+        return Ok(String::new());
+    }
+    let output = Command::new(SHA256SUM)
+        .args(modules.iter().map(|(module, _)| module))
+        .output()
+        .with_context(|| format!("unable to run {} command", SHA256SUM))?;
+
+    to_initramfs_paths(
+        &get_stdout(output)?,
+        &modules
+            .iter()
+            .map(|(module, path)| (module.as_path(), path.as_path()))
+            .collect(),
+    )
+}
+
+/// Hash a module whose content differs from the host file, e.g. recompressed
+pub fn get_data_manifest(path: &Utf8Path, data: &[u8]) -> Result<String> {
+    Ok(format!("{}  {}\n", get_data_hash(data)?, path))
+}
+
+/// Hash the data with sha256
+pub fn get_data_hash(data: &[u8]) -> Result<String> {
+    let mut child = Command::new(SHA256SUM)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("unable to run {} command", SHA256SUM))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(data)
+        .with_context(|| format!("unable to write to {} command", SHA256SUM))?;
+    let output = child
+        .wait_with_output()
+        .with_context(|| format!("unable to run {} command", SHA256SUM))?;
+
+    // sha256sum names the standard input "-"
+    let stdout = get_stdout(output)?;
+    let (hash, _) = stdout
+        .split_once("  ")
+        .with_context(|| format!("unexpected {} output: {}", SHA256SUM, stdout))?;
+    Ok(hash.to_string())
+}
+
+fn get_stdout(output: Output) -> Result<String> {
+    if !output.status.success() {
+        bail!(
+            "{} command failed:\n{:?}",
+            SHA256SUM,
+            String::from_utf8(output.stderr)
+        )
+    }
+
+    Ok(String::from_utf8(output.stdout)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_initramfs_paths_test() {
+        let host = Utf8Path::new("/usr/lib/modules/6.6.1/kernel/fs/ext4/ext4.ko.zst");
+        let initramfs = Utf8Path::new("/lib/modules/6.6.1/kernel/fs/ext4/ext4.ko.zst");
+        let paths = HashMap::from([(host, initramfs)]);
+        assert_eq!(
+            to_initramfs_paths(&format!("abcd  {}\n", host), &paths).unwrap(),
+            format!("abcd  {}\n", initramfs)
+        );
+        assert!(to_initramfs_paths("abcd  /usr/lib/modules/other.ko\n", &paths).is_err());
+    }
+
+    #[test]
+    fn get_data_manifest_test() {
+        assert_eq!(
+            get_data_manifest(Utf8Path::new("/lib/modules/6.6.1/empty.ko.zst"), b"").unwrap(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855  /lib/modules/6.6.1/empty.ko.zst\n"
+        );
+    }
+}
@@ -5,16 +5,18 @@
 //! that can be used with the Linux kernel to
 //! load an initramfs.
 
-use anyhow::Result;
+use std::collections::HashMap;
 use std::convert::TryInto;
-use std::ffi::CString;
+use std::ffi::{CString, NulError};
 use std::fmt;
-use std::fs::Metadata;
-use std::io::Write;
-use std::ops::Deref;
+use std::fs::{self, Metadata};
+use std::io::{self, Read, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+use crate::seekable::SeekTable;
 
 /// Magic number for newc cpio files
 const MAGIC: &[u8] = b"070701";
@@ -23,6 +25,45 @@ const TRAILER: &str = "TRAILER!!!";
 
 /// Offset for inode number to avoid reserved inodes (arbitrary)
 const INO_OFFSET: u64 = 1337;
+/// Size of the header, magic included
+const HEADER_LEN: usize = 6 + 13 * 8;
+/// Magic bytes of the compressed archives that can be parsed
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const XZ_MAGIC: &[u8] = &[0xfd, b'7', b'z', b'X', b'Z', 0x00];
+
+#[derive(Debug, Error)]
+pub enum NewcError {
+    #[error("entry name contains a NUL byte")]
+    InvalidName(#[from] NulError),
+    #[error("truncated {what} at offset {offset}")]
+    Truncated { what: &'static str, offset: usize },
+    #[error("invalid {what} at offset {offset}")]
+    InvalidHeader { what: &'static str, offset: usize },
+    #[error("unknown archive format")]
+    UnknownFormat,
+    #[error("unable to decompress {format} archive")]
+    Decompress {
+        format: &'static str,
+        #[source]
+        source: io::Error,
+    },
+    #[error("unable to read {path}")]
+    Read {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    #[error("unable to write the archive")]
+    Io(#[from] io::Error),
+}
+
+impl NewcError {
+    /// Whether the error comes from the archive given, e.g. a corrupted image, instead of
+    /// the system it is being built on
+    pub fn is_user_error(&self) -> bool {
+        !matches!(self, NewcError::Read { .. } | NewcError::Io(_))
+    }
+}
 
 /// Represents a cpio archive
 #[derive(PartialEq, Debug)]
@@ -36,20 +77,100 @@ impl Archive {
         Archive { entries }
     }
 
-    /// Serialize this entry into cpio newc format
-    pub fn into_bytes(self) -> Result<Vec<u8>> {
-        let mut buf = Vec::new();
+    /// Parse an existing image, made of one or more archives concatenated, each one
+    /// optionally compressed with zstd or xz like the kernel accepts
+    pub fn parse(mut data: &[u8]) -> Result<Self, NewcError> {
+        let mut archive = Archive::new(Vec::new());
+        loop {
+            // archives are padded with zeroes when concatenated
+            while let Some((0, rest)) = data.split_first() {
+                data = rest;
+            }
+            if data.is_empty() {
+                break;
+            }
+
+            if data.starts_with(MAGIC) {
+                let (entries, rest) = parse_entries(data)?;
+                archive.extend(Archive::new(entries));
+                data = rest;
+            } else if data.starts_with(ZSTD_MAGIC) || data.starts_with(XZ_MAGIC) {
+                // the compressed archive spans until the end of the image
+                let mut decompressed = Vec::new();
+                let (format, res) = if let Some(table) = SeekTable::parse(data) {
+                    (
+                        "zstd",
+                        table.decompress(data).map(|frames| decompressed = frames),
+                    )
+                } else if data.starts_with(ZSTD_MAGIC) {
+                    (
+                        "zstd",
+                        zstd::stream::read::Decoder::new(data)
+                            .and_then(|mut decoder| decoder.read_to_end(&mut decompressed))
+                            .map(|_| ()),
+                    )
+                } else {
+                    (
+                        "xz",
+                        xz2::read::XzDecoder::new(data)
+                            .read_to_end(&mut decompressed)
+                            .map(|_| ()),
+                    )
+                };
+                res.map_err(|source| NewcError::Decompress { format, source })?;
+                archive.extend(Archive::parse(&decompressed)?);
+                break;
+            } else {
+                return Err(NewcError::UnknownFormat);
+            }
+        }
+
+        Ok(archive)
+    }
+
+    /// Add the entries of other, replacing the entries with the same name
+    pub fn extend(&mut self, other: Archive) {
+        let mut positions: HashMap<Vec<u8>, usize> = self
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (entry.name.name.clone(), index))
+            .collect();
+        for entry in other.entries {
+            match positions.get(&entry.name.name) {
+                // keep the position so that directories still come before their contents
+                Some(index) => self.entries[*index] = entry,
+                None => {
+                    positions.insert(entry.name.name.clone(), self.entries.len());
+                    self.entries.push(entry);
+                }
+            }
+        }
+    }
+
+    /// Merge the archives into one, the later entries override the earlier ones
+    pub fn merge<I>(archives: I) -> Self
+    where
+        I: IntoIterator<Item = Archive>,
+    {
+        let mut merged = Archive::new(Vec::new());
+        for archive in archives {
+            merged.extend(archive);
+        }
 
+        merged
+    }
+
+    /// Serialize this archive into cpio newc format, one entry at a time
+    pub fn write<W: Write>(self, writer: &mut W) -> Result<(), NewcError> {
         // iterate and lazily assign new inode number
         for (index, mut entry) in self.entries.into_iter().enumerate() {
             entry.ino = INO_OFFSET + index as u64;
-            entry.write(&mut buf)?;
+            entry.write(writer)?;
         }
 
         let trailer = EntryBuilder::trailer().build();
-        trailer.write(&mut buf)?;
-
-        Ok(buf)
+        trailer.write(writer)
     }
 }
 
@@ -61,7 +182,7 @@ pub struct EntryName {
 
 impl EntryName {
     /// Get a null byte terminated vector for this entry name
-    pub fn into_bytes_with_nul(self) -> Result<Vec<u8>> {
+    pub fn into_bytes_with_nul(self) -> Result<Vec<u8>, NulError> {
         let cstr = CString::new(self.name)?;
         Ok(cstr.into_bytes_with_nul())
     }
@@ -96,27 +217,31 @@ impl fmt::Debug for EntryName {
 
 /// Wrapper type for data
 #[derive(PartialEq)]
-pub struct EntryData {
-    data: Vec<u8>,
+pub enum EntryData {
+    /// Data held in memory
+    Bytes(Vec<u8>),
+    /// File on the host, only read when the entry is written
+    File(PathBuf),
 }
 
 impl EntryData {
-    fn new(data: Vec<u8>) -> Self {
-        EntryData { data }
-    }
-}
-
-impl Deref for EntryData {
-    type Target = [u8];
-
-    fn deref(&self) -> &Self::Target {
-        &self.data
+    /// Get the data, reading it from the host if needed
+    fn into_bytes(self) -> Result<Vec<u8>, NewcError> {
+        match self {
+            EntryData::Bytes(data) => Ok(data),
+            EntryData::File(path) => {
+                fs::read(&path).map_err(|source| NewcError::Read { path, source })
+            }
+        }
     }
 }
 
 impl fmt::Debug for EntryData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.write_str("EntryData(<data>)")
+        match self {
+            EntryData::Bytes(_) => f.write_str("EntryData(<data>)"),
+            EntryData::File(path) => f.debug_tuple("EntryData").field(path).finish(),
+        }
     }
 }
 
@@ -168,25 +293,23 @@ impl Entry {
     {
         Entry {
             name: name.into(),
-            data: Some(EntryData::new(data)),
+            data: Some(EntryData::Bytes(data)),
             ..Entry::default()
         }
     }
 }
 
 impl Entry {
-    /// Serialize the entry to the passed buffer
-    pub fn write(self, buf: &mut Vec<u8>) -> Result<()> {
-        let file_size = match &self.data {
-            Some(data) => data.len(),
-            None => 0,
-        };
+    /// Serialize the entry to the passed writer, entries are aligned as long as the archive
+    /// is written from the start
+    pub fn write<W: Write>(self, buf: &mut W) -> Result<(), NewcError> {
+        // only the data of this entry is kept in memory
+        let data = self.data.map(EntryData::into_bytes).transpose()?;
+        let file_size = data.as_ref().map_or(0, Vec::len);
 
         // serialize the header for this entry
         let filename = self.name.into_bytes_with_nul()?;
 
-        // magic + 8 * fields + filename + file
-        buf.reserve(6 + (13 * 8) + filename.len() + file_size);
         buf.write_all(MAGIC)?;
         write!(buf, "{:08x}", self.ino)?;
         write!(buf, "{:08x}", self.mode)?;
@@ -202,11 +325,11 @@ impl Entry {
         write!(buf, "{:08x}", filename.len())?;
         write!(buf, "{:08x}", 0)?; // CRC, null bytes with our MAGIC
         buf.write_all(&filename)?;
-        pad_buf(buf);
+        pad(buf, HEADER_LEN + filename.len())?;
 
-        if let Some(data) = &self.data {
+        if let Some(data) = &data {
             buf.write_all(data)?;
-            pad_buf(buf);
+            pad(buf, data.len())?;
         }
 
         Ok(())
@@ -240,6 +363,20 @@ impl EntryBuilder {
         }
     }
 
+    /// Create an entry representing a regular file of the host, read when it is written
+    pub fn host_file<T>(name: T, path: &Path) -> Self
+    where
+        T: Into<EntryName>,
+    {
+        EntryBuilder {
+            entry: Entry {
+                name: name.into(),
+                data: Some(EntryData::File(path.to_path_buf())),
+                ..Entry::default()
+            },
+        }
+    }
+
     /// Create an entry representing a special file
     #[allow(dead_code)]
     pub fn special_file<T>(name: T) -> Self
@@ -314,15 +451,77 @@ impl EntryBuilder {
     }
 }
 
-/// Pad the buffer so entries align according to cpio requirements
-pub fn pad_buf(buf: &mut Vec<u8>) {
-    let rem = buf.len() % 4;
+/// Parse the entries of an uncompressed archive until its trailer, returning the data
+/// following it
+fn parse_entries(mut data: &[u8]) -> Result<(Vec<Entry>, &[u8]), NewcError> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    loop {
+        let truncated = |what| NewcError::Truncated { what, offset };
+        let header = data.get(..HEADER_LEN).ok_or_else(|| truncated("header"))?;
+        if !header.starts_with(MAGIC) {
+            return Err(NewcError::InvalidHeader {
+                what: "magic",
+                offset,
+            });
+        }
+        let field = |index: usize| -> Result<u64, NewcError> {
+            let start = MAGIC.len() + index * 8;
+            std::str::from_utf8(&header[start..start + 8])
+                .ok()
+                .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+                .ok_or(NewcError::InvalidHeader {
+                    what: "header field",
+                    offset,
+                })
+        };
+        let file_size = field(6)? as usize;
+        let name_size = field(11)? as usize;
+        let name_end = HEADER_LEN + name_size;
+        let data_start = align(name_end);
+        let data_end = data_start + file_size;
+        let name = data
+            .get(HEADER_LEN..name_end)
+            .ok_or_else(|| truncated("name"))?;
+        // the name is terminated by a NUL byte
+        let name = name.strip_suffix(&[0]).unwrap_or(name).to_vec();
+        let contents = data
+            .get(data_start..data_end)
+            .ok_or_else(|| truncated("data"))?;
+        let next = align(data_end).min(data.len());
+        if name == TRAILER.as_bytes() {
+            return Ok((entries, &data[next..]));
+        }
 
-    if rem != 0 {
-        buf.resize(buf.len() + (4 - rem), 0);
+        entries.push(Entry {
+            name: EntryName { name },
+            ino: field(0)?,
+            mode: field(1)? as u32,
+            uid: field(2)?,
+            gid: field(3)?,
+            nlink: field(4)?,
+            mtime: field(5)?,
+            dev_major: field(7)?,
+            dev_minor: field(8)?,
+            rdev_major: field(9)?,
+            rdev_minor: field(10)?,
+            data: (file_size > 0).then(|| EntryData::Bytes(contents.to_vec())),
+        });
+        data = &data[next..];
+        offset += next;
     }
 }
 
+/// Round up to the cpio alignment
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Pad the len bytes written so entries align according to cpio requirements
+fn pad<W: Write>(buf: &mut W, len: usize) -> io::Result<()> {
+    buf.write_all(&[0; 3][..align(len) - len])
+}
+
 /// Shamelessly taken from the `nix` crate, thanks !
 pub const fn major(dev: u64) -> u64 {
     ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0x0000_0fff)
@@ -336,6 +535,13 @@ pub const fn minor(dev: u64) -> u64 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use anyhow::Result;
+
+    fn to_bytes(archive: Archive) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        archive.write(&mut buf)?;
+        Ok(buf)
+    }
 
     #[test]
     fn test_builder() -> Result<()> {
@@ -346,6 +552,22 @@ mod tests {
 
         assert!(!buf.is_empty());
 
+        // files of the host are only read when written
+        let path = std::env::temp_dir().join(format!("mkinitrz-newc-{}", std::process::id()));
+        let host_file = EntryBuilder::host_file("/testfile", &path).build();
+        fs::write(&path, b"datadatadata")?;
+        let mut host_buf = Vec::new();
+        host_file.write(&mut host_buf)?;
+        assert_eq!(host_buf, buf);
+
+        fs::remove_file(&path)?;
+        let err = EntryBuilder::host_file("/testfile", &path)
+            .build()
+            .write(&mut Vec::new())
+            .unwrap_err();
+        assert!(matches!(err, NewcError::Read { .. }));
+        assert!(!err.is_user_error());
+
         Ok(())
     }
 
@@ -358,7 +580,50 @@ mod tests {
         trailer.write(&mut buf)?;
 
         // an empty archive is just a trailer entry
-        assert_eq!(empty.into_bytes()?, buf);
+        assert_eq!(to_bytes(empty)?, buf);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_merge() -> Result<()> {
+        let base = Archive::new(vec![
+            EntryBuilder::directory("/etc").build(),
+            EntryBuilder::file("/etc/hostname", b"base".to_vec()).build(),
+            EntryBuilder::file("/etc/empty", Vec::new()).build(),
+        ]);
+        let overlay = Archive::new(vec![
+            EntryBuilder::file("/etc/hostname", b"site".to_vec()).build(),
+            EntryBuilder::file("/etc/motd", b"hello".to_vec()).build(),
+        ]);
+
+        let mut image = to_bytes(base)?;
+        // the kernel accepts archives concatenated and padded with zeroes
+        image.extend([0; 4]);
+        image.extend(zstd::stream::encode_all(&to_bytes(overlay)?[..], 0)?);
+        let merged = Archive::parse(&image)?;
+        let names: Vec<_> = merged.entries.iter().map(|e| &e.name.name[..]).collect();
+        assert_eq!(
+            names,
+            vec![&b"etc"[..], b"etc/hostname", b"etc/empty", b"etc/motd"]
+        );
+        assert_eq!(
+            merged.entries[1].data,
+            Some(EntryData::Bytes(b"site".to_vec()))
+        );
+        assert!(merged.entries[2].data.is_none());
+
+        // parsing the serialized archive gives back the same entries
+        let reparsed = Archive::parse(&to_bytes(Archive::merge(vec![merged]))?)?;
+        assert_eq!(reparsed.entries.len(), 4);
+
+        assert!(matches!(
+            Archive::parse(b"garbage"),
+            Err(NewcError::UnknownFormat)
+        ));
+        let err = Archive::parse(&image[..image.len() / 4]).unwrap_err();
+        assert!(matches!(err, NewcError::Truncated { .. }));
+        assert!(err.is_user_error());
 
         Ok(())
     }
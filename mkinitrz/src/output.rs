@@ -0,0 +1,177 @@
+// Write the image to a temporary file next to the output and rename it over the output
+// once complete, so that a failure never leaves a truncated image for the bootloader, and
+// concatenate the external archives to it
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+pub struct AtomicFile {
+    file: File,
+    path: Utf8PathBuf,
+    tmp_path: Utf8PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Utf8Path) -> Result<AtomicFile> {
+        let tmp_path = path.with_file_name(format!(
+            ".{}.{}.tmp",
+            path.file_name().unwrap_or("initramfs.img"),
+            process::id()
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .with_context(|| format!("unable to create file {}", tmp_path))?;
+
+        Ok(AtomicFile {
+            file,
+            path: path.to_path_buf(),
+            tmp_path,
+            committed: false,
+        })
+    }
+
+    /// Get the path of the image being written, e.g. to sign it before it replaces the
+    /// output
+    pub fn get_tmp_path(&self) -> &Utf8Path {
+        &self.tmp_path
+    }
+
+    /// Replace the output with the complete image
+    pub fn commit(mut self) -> Result<()> {
+        self.file
+            .sync_all()
+            .with_context(|| format!("unable to write file {}", self.tmp_path))?;
+        fs::rename(&self.tmp_path, &self.path)
+            .with_context(|| format!("unable to rename {} to {}", self.tmp_path, self.path))?;
+        self.committed = true;
+
+        Ok(())
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Writer keeping track of the bytes written, so that the archives concatenated in the
+/// image start aligned
+pub struct SegmentWriter<W> {
+    writer: W,
+    len: u64,
+}
+
+impl<W: Write> SegmentWriter<W> {
+    pub fn new(writer: W) -> SegmentWriter<W> {
+        SegmentWriter { writer, len: 0 }
+    }
+
+    /// Pad with zeroes to the cpio alignment, the kernel skips them between the archives
+    pub fn pad(&mut self) -> io::Result<()> {
+        let rem = (self.len % 4) as usize;
+        if rem != 0 {
+            self.write_all(&[0; 4][rem..])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write an existing archive as it is
+    pub fn write_segment(&mut self, path: &Utf8Path) -> Result<()> {
+        self.pad()?;
+        let mut file = File::open(path).with_context(|| format!("unable to open {}", path))?;
+        io::copy(&mut file, self).with_context(|| format!("unable to write {}", path))?;
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write> Write for SegmentWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    #[test]
+    fn atomic_file_test() {
+        let dir = Utf8PathBuf::from_path_buf(env::temp_dir())
+            .unwrap()
+            .join(format!("mkinitrz-output-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("initramfs.img");
+        fs::write(&path, b"previous").unwrap();
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"partial").unwrap();
+        drop(file);
+        assert_eq!(fs::read(&path).unwrap(), b"previous");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        let mut file = AtomicFile::create(&path).unwrap();
+        file.write_all(b"complete").unwrap();
+        file.commit().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"complete");
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segment_writer_test() {
+        let dir = Utf8PathBuf::from_path_buf(env::temp_dir())
+            .unwrap()
+            .join(format!("mkinitrz-segment-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let segment = dir.join("ucode.cpio");
+        fs::write(&segment, b"07070").unwrap();
+
+        let mut writer = SegmentWriter::new(Vec::new());
+        writer.write_segment(&segment).unwrap();
+        writer.pad().unwrap();
+        writer.write_all(b"abc").unwrap();
+        writer.write_segment(&segment).unwrap();
+        writer.pad().unwrap();
+        assert_eq!(writer.into_inner(), b"07070\0\0\0abc\x0007070\0\0\0");
+        assert!(SegmentWriter::new(Vec::new())
+            .write_segment(&dir.join("missing.cpio"))
+            .is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+// Map the host files included in the initramfs to the package owning them and its license,
+// so that the images can be vetted before being redistributed
+
+use std::{collections::HashMap, fs, process::Command};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::{instrument, warn};
+
+const DPKG_DB: &str = "/var/lib/dpkg/status";
+const RPM_DB: &str = "/var/lib/rpm";
+const PACMAN_DB: &str = "/var/lib/pacman/local";
+const DPKG_DOC_DIR: &str = "/usr/share/doc";
+const RPM_QUERYFORMAT: &str = "%{NAME}\t%{LICENSE}\n";
+const UNKNOWN: &str = "-";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum PackageManager {
+    Dpkg,
+    Rpm,
+    Pacman,
+}
+
+impl PackageManager {
+    fn detect() -> Option<PackageManager> {
+        [
+            (DPKG_DB, PackageManager::Dpkg),
+            (RPM_DB, PackageManager::Rpm),
+            (PACMAN_DB, PackageManager::Pacman),
+        ]
+        .iter()
+        .find(|(db, _)| Utf8Path::new(db).exists())
+        .map(|(_, manager)| *manager)
+    }
+
+    /// Get the package owning the file and its license
+    fn query(&self, file: &Utf8Path) -> Option<(String, Option<String>)> {
+        match self {
+            PackageManager::Dpkg => {
+                let package = run("dpkg", &["-S", file.as_str()])
+                    .or_else(|| {
+                        // The packages not converted to the merged /usr register /bin
+                        file.strip_prefix("/usr")
+                            .ok()
+                            .and_then(|file| run("dpkg", &["-S", &format!("/{}", file)]))
+                    })
+                    .and_then(|output| parse_dpkg_search(&output))?;
+                let copyright = Utf8Path::new(DPKG_DOC_DIR).join(&package).join("copyright");
+                let license = fs::read_to_string(copyright)
+                    .ok()
+                    .and_then(|copyright| parse_copyright_license(&copyright));
+                Some((package, license))
+            }
+            PackageManager::Rpm => run(
+                "rpm",
+                &["-qf", "--queryformat", RPM_QUERYFORMAT, file.as_str()],
+            )
+            .and_then(|output| {
+                let (package, license) = output.lines().next()?.split_once('\t')?;
+                Some((package.to_string(), Some(license.to_string())))
+            }),
+            PackageManager::Pacman => {
+                let package = run("pacman", &["-Qoq", file.as_str()])?
+                    .lines()
+                    .next()?
+                    .to_string();
+                let license =
+                    run("pacman", &["-Qi", &package]).and_then(|info| parse_pacman_license(&info));
+                Some((package, license))
+            }
+        }
+    }
+}
+
+/// Run the query, a failure means that the file is not owned by any package
+fn run(command: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(command).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+/// Parse the output of dpkg -S, made of lines like "coreutils: /usr/bin/ls"
+fn parse_dpkg_search(output: &str) -> Option<String> {
+    output
+        .lines()
+        // dpkg-divert(1) adds lines like "diversion by <package> from: <file>"
+        .filter(|line| !line.starts_with("diversion by "))
+        .find_map(|line| line.split_once(": "))
+        // A file shared by multiple packages is listed as "pkg1, pkg2: <file>"
+        .and_then(|(packages, _)| packages.split(", ").next())
+        // Drop the architecture qualifier, e.g. libc6:amd64
+        .map(|package| package.split(':').next().unwrap_or(package).to_string())
+}
+
+/// Get the licenses of a machine-readable debian/copyright, in the order they appear
+fn parse_copyright_license(copyright: &str) -> Option<String> {
+    let mut licenses: Vec<&str> = Vec::new();
+    for license in copyright
+        .lines()
+        .filter_map(|line| line.strip_prefix("License:"))
+        .map(str::trim)
+        .filter(|license| !license.is_empty())
+    {
+        if !licenses.contains(&license) {
+            licenses.push(license);
+        }
+    }
+    if licenses.is_empty() {
+        None
+    } else {
+        Some(licenses.join(", "))
+    }
+}
+
+/// Get the license from the output of pacman -Qi
+fn parse_pacman_license(info: &str) -> Option<String> {
+    info.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(key, _)| key.trim() == "Licenses")
+        .map(|(_, license)| license.split_whitespace().collect::<Vec<_>>().join(", "))
+}
+
+/// Write a tab separated report with the path in the initramfs, the path on the host, the
+/// package and its license of every file included from the host
+#[instrument(name = "provenance", skip_all)]
+pub fn write_report(report: &Utf8Path, sources: &[(Utf8PathBuf, Utf8PathBuf)]) -> Result<()> {
+    let manager = PackageManager::detect();
+    if manager.is_none() {
+        warn!("no supported package manager found, the packages will be unknown");
+    }
+    let mut licenses: HashMap<String, Option<String>> = HashMap::new();
+    let mut sources = sources.to_vec();
+    sources.sort();
+    let mut contents = String::from("# path\tsource\tpackage\tlicense\n");
+    for (path, source) in &sources {
+        let owner = manager.and_then(|manager| manager.query(source));
+        let (package, license) = match owner {
+            Some((package, license)) => {
+                let license = licenses.entry(package.clone()).or_insert(license).clone();
+                (package, license)
+            }
+            None => (UNKNOWN.to_string(), None),
+        };
+        contents.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            path,
+            source,
+            package,
+            license.as_deref().unwrap_or(UNKNOWN)
+        ));
+    }
+
+    fs::write(report, contents).with_context(|| format!("unable to write {}", report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            parse_dpkg_search(
+                "diversion by dash from: /bin/sh\nlibc6:amd64: /lib/x86_64-linux-gnu/libc.so.6\n"
+            ),
+            Some("libc6".to_string())
+        );
+        assert_eq!(
+            parse_dpkg_search("busybox, busybox-static: /bin/busybox\n"),
+            Some("busybox".to_string())
+        );
+        assert_eq!(parse_dpkg_search(""), None);
+
+        assert_eq!(
+            parse_copyright_license(
+                "Format: https://www.debian.org/doc/packaging-manuals/copyright-format/1.0/
+Files: *
+License: GPL-3+
+
+Files: lib/*
+License: LGPL-2.1+
+
+Files: debian/*
+License: GPL-3+
+"
+            ),
+            Some("GPL-3+, LGPL-2.1+".to_string())
+        );
+        assert_eq!(
+            parse_copyright_license("Copyright (C) 2000 Someone\n"),
+            None
+        );
+
+        assert_eq!(
+            parse_pacman_license(
+                "Name            : cryptsetup
+Version         : 2.6.1-3
+Licenses        : GPL-2.0-or-later  LGPL-2.1-or-later
+"
+            ),
+            Some("GPL-2.0-or-later, LGPL-2.1-or-later".to_string())
+        );
+    }
+}
@@ -0,0 +1,56 @@
+// Describe which mkinitrz built the image, when, and for which kernel and configuration,
+// so that the generator of an image can be found when debugging a boot
+
+use std::env;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const INITRZ_RELEASE: &str = "/etc/initrz-release";
+/// Where the host os-release is looked for, /etc/os-release is usually a symlink
+pub const OS_RELEASE: [&str; 2] = ["/etc/os-release", "/usr/lib/os-release"];
+pub const OS_RELEASE_INITRAMFS: &str = "/etc/os-release";
+
+/// Get the build time in seconds since the epoch, SOURCE_DATE_EPOCH overrides it for
+/// reproducible images
+pub fn get_build_time() -> u64 {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default()
+        })
+}
+
+/// Get the contents of initrz-release, in the os-release format
+pub fn get_release(kernel_version: &str, build_time: u64, config_hash: Option<&str>) -> String {
+    let mut release = format!(
+        "MKINITRZ_VERSION={}\nBUILD_TIME={}\nKERNEL_VERSION={}\n",
+        env!("CARGO_PKG_VERSION"),
+        build_time,
+        kernel_version
+    );
+    if let Some(config_hash) = config_hash {
+        release.push_str(&format!("CONFIG_SHA256={}\n", config_hash));
+    }
+
+    release
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn get_release_test() {
+        assert_eq!(
+            get_release("6.6.1-arch1-1", 1700000000, Some("e3b0c442")),
+            format!(
+                "MKINITRZ_VERSION={}\nBUILD_TIME=1700000000\nKERNEL_VERSION=6.6.1-arch1-1\nCONFIG_SHA256=e3b0c442\n",
+                env!("CARGO_PKG_VERSION")
+            )
+        );
+        assert!(!get_release("6.6.1", 0, None).contains("CONFIG_SHA256"));
+    }
+}
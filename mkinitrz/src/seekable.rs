@@ -0,0 +1,268 @@
+// Write and read the zstd seekable format: the data is split in independent frames of a
+// fixed decompressed size, followed by a skippable frame holding the seek table, see
+// https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+// Decoders unaware of it, like the kernel one, decompress the frames one after the other and
+// skip the table
+
+use std::convert::TryInto;
+use std::io::{self, Write};
+
+use rayon::prelude::*;
+use zstd::bulk::Compressor;
+use zstd::zstd_safe::CParameter;
+
+const SKIPPABLE_MAGIC: u32 = 0x184d_2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+// number of frames, descriptor and seekable magic
+const FOOTER_LEN: usize = 4 + 1 + 4;
+// skippable magic and frame size
+const SKIPPABLE_HEADER_LEN: usize = 4 + 4;
+// compressed and decompressed size, without the optional checksum
+const ENTRY_LEN: usize = 4 + 4;
+const CHECKSUM_FLAG: u8 = 1 << 7;
+// The reserved bits must be zero
+const RESERVED_BITS: u8 = 0x7c;
+
+pub const DEFAULT_FRAME_SIZE: usize = 1024 * 1024;
+
+/// Compress the data written in frames of frame_size decompressed bytes each, a batch of
+/// frames is compressed in parallel once full. finish appends the seek table
+pub struct SeekableWriter<W: Write> {
+    writer: W,
+    frame_size: usize,
+    level: i32,
+    // enables the long-distance matching
+    long_window_log: Option<u32>,
+    // decompressed frames waiting to be compressed
+    pending: Vec<Vec<u8>>,
+    // compressed and decompressed size of the frames written
+    entries: Vec<(u32, u32)>,
+}
+
+impl<W: Write> SeekableWriter<W> {
+    pub fn new(
+        writer: W,
+        frame_size: usize,
+        level: i32,
+        long_window_log: Option<u32>,
+    ) -> SeekableWriter<W> {
+        assert!(frame_size > 0, "the frame size must not be zero");
+        SeekableWriter {
+            writer,
+            frame_size,
+            level,
+            long_window_log,
+            pending: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    fn write_frames(&mut self) -> io::Result<()> {
+        let (level, long_window_log) = (self.level, self.long_window_log);
+        let frames = self
+            .pending
+            .par_iter()
+            .map(|chunk| {
+                let mut compressor = Compressor::new(level)?;
+                if let Some(window_log) = long_window_log {
+                    compressor.set_parameter(CParameter::EnableLongDistanceMatching(true))?;
+                    compressor.set_parameter(CParameter::WindowLog(window_log))?;
+                }
+                compressor.compress(chunk)
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        for (frame, chunk) in frames.iter().zip(&self.pending) {
+            self.writer.write_all(frame)?;
+            self.entries
+                .push((to_u32(frame.len())?, to_u32(chunk.len())?));
+        }
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// Compress the last frames and append the seek table, returning the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.write_frames()?;
+
+        let table_len = self.entries.len() * ENTRY_LEN + FOOTER_LEN;
+        let mut table = Vec::with_capacity(SKIPPABLE_HEADER_LEN + table_len);
+        table.extend(SKIPPABLE_MAGIC.to_le_bytes());
+        table.extend(to_u32(table_len)?.to_le_bytes());
+        for (compressed_len, decompressed_len) in &self.entries {
+            table.extend(compressed_len.to_le_bytes());
+            table.extend(decompressed_len.to_le_bytes());
+        }
+        table.extend(to_u32(self.entries.len())?.to_le_bytes());
+        table.push(0);
+        table.extend(SEEKABLE_MAGIC.to_le_bytes());
+        self.writer.write_all(&table)?;
+
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for SeekableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let frame_size = self.frame_size;
+        if !matches!(self.pending.last(), Some(frame) if frame.len() < frame_size) {
+            if self.pending.len() == rayon::current_num_threads() {
+                self.write_frames()?;
+            }
+            self.pending.push(Vec::with_capacity(frame_size));
+        }
+        let frame = self.pending.last_mut().unwrap();
+        let len = buf.len().min(frame_size - frame.len());
+        frame.extend_from_slice(&buf[..len]);
+
+        Ok(len)
+    }
+
+    /// Only the frames already compressed are flushed, a frame is never cut short
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn to_u32(len: usize) -> io::Result<u32> {
+    len.try_into().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the frame is too big for the seek table",
+        )
+    })
+}
+
+/// A frame of the seekable archive, the offsets are relative to the start of the
+/// compressed and the decompressed data
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub compressed_offset: usize,
+    pub compressed_len: usize,
+    pub decompressed_offset: usize,
+    pub decompressed_len: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SeekTable {
+    frames: Vec<Frame>,
+}
+
+impl SeekTable {
+    /// Read the seek table at the end of data, None when data is not a seekable archive
+    pub fn parse(data: &[u8]) -> Option<SeekTable> {
+        let footer = data.get(data.len().checked_sub(FOOTER_LEN)?..)?;
+        let read_u32 = |buf: &[u8]| u32::from_le_bytes(buf[..4].try_into().unwrap()) as usize;
+        if read_u32(&footer[5..]) != SEEKABLE_MAGIC as usize || footer[4] & RESERVED_BITS != 0 {
+            return None;
+        }
+        let entry_len = if footer[4] & CHECKSUM_FLAG != 0 {
+            ENTRY_LEN + 4
+        } else {
+            ENTRY_LEN
+        };
+        let table_len = read_u32(footer)
+            .checked_mul(entry_len)?
+            .checked_add(FOOTER_LEN)?;
+        let table_start = data
+            .len()
+            .checked_sub(table_len)?
+            .checked_sub(SKIPPABLE_HEADER_LEN)?;
+        let header = &data[table_start..table_start + SKIPPABLE_HEADER_LEN];
+        if read_u32(header) != SKIPPABLE_MAGIC as usize || read_u32(&header[4..]) != table_len {
+            return None;
+        }
+
+        let mut frames = Vec::new();
+        let (mut compressed_offset, mut decompressed_offset) = (0, 0);
+        for entry in data[table_start + SKIPPABLE_HEADER_LEN..data.len() - FOOTER_LEN]
+            .chunks_exact(entry_len)
+        {
+            let frame = Frame {
+                compressed_offset,
+                compressed_len: read_u32(entry),
+                decompressed_offset,
+                decompressed_len: read_u32(&entry[4..]),
+            };
+            compressed_offset = compressed_offset.checked_add(frame.compressed_len)?;
+            decompressed_offset += frame.decompressed_len;
+            frames.push(frame);
+        }
+        // The frames must fill the data before the seek table
+        if compressed_offset != table_start {
+            return None;
+        }
+
+        Some(SeekTable { frames })
+    }
+
+    /// Decompress a single frame of data
+    pub fn decompress_frame(data: &[u8], frame: &Frame) -> io::Result<Vec<u8>> {
+        let compressed = data
+            .get(frame.compressed_offset..frame.compressed_offset + frame.compressed_len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame"))?;
+        let decompressed = zstd::bulk::decompress(compressed, frame.decompressed_len)?;
+        if decompressed.len() != frame.decompressed_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the frame size does not match the seek table",
+            ));
+        }
+
+        Ok(decompressed)
+    }
+
+    /// Decompress every frame of data in parallel
+    pub fn decompress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let frames = self
+            .frames
+            .par_iter()
+            .map(|frame| SeekTable::decompress_frame(data, frame))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        Ok(frames.concat())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seekable_test() -> io::Result<()> {
+        let data: Vec<u8> = (0..10_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+        let mut writer = SeekableWriter::new(Vec::new(), 4096, 3, Some(27));
+        // The frames do not depend on the size of the writes
+        for chunk in data.chunks(1000) {
+            writer.write_all(chunk)?;
+        }
+        let compressed = writer.finish()?;
+
+        let table = SeekTable::parse(&compressed).unwrap();
+        assert_eq!(table.frames.len(), 10);
+        assert_eq!(table.decompress(&compressed)?, data);
+        let frame = &table.frames[2];
+        assert_eq!(frame.decompressed_offset, 8192);
+        assert_eq!(
+            SeekTable::decompress_frame(&compressed, frame)?,
+            &data[8192..12288]
+        );
+
+        // Plain zstd decoders read the frames and skip the seek table
+        assert_eq!(zstd::stream::decode_all(&compressed[..])?, data);
+
+        assert!(SeekTable::parse(&zstd::stream::encode_all(&data[..], 3)?).is_none());
+        assert!(SeekTable::parse(&compressed[1..]).is_none());
+        assert!(SeekTable::parse(&[]).is_none());
+
+        let empty = SeekableWriter::new(Vec::new(), 4096, 3, None).finish()?;
+        assert!(SeekTable::parse(&empty).unwrap().frames.is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+// Detached signatures of the image, written next to it so that the boot loaders and the
+// verified boot scripts can check it
+
+use std::fs::File;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+const GPG: &str = "gpg";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Signer {
+    /// OpenPGP signature made by gpg with the given key
+    Gpg(String),
+}
+
+impl FromStr for Signer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("gpg", key_id)) if !key_id.is_empty() => Ok(Signer::Gpg(key_id.to_string())),
+            Some(("gpg", _)) => Err("missing the gpg key id".to_string()),
+            _ => Err(format!("unsupported signer {}, expected gpg:<keyid>", s)),
+        }
+    }
+}
+
+impl Signer {
+    /// Sign the complete image, returning the detached signature
+    pub fn sign(&self, image: &Utf8Path) -> Result<Vec<u8>> {
+        match self {
+            Signer::Gpg(key_id) => {
+                let file =
+                    File::open(image).with_context(|| format!("unable to open {}", image))?;
+                let output = Command::new(GPG)
+                    .args(["--batch", "--detach-sign", "--local-user", key_id])
+                    .args(["--output", "-"])
+                    .stdin(file)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::piped())
+                    .output()
+                    .with_context(|| format!("unable to run {} command", GPG))?;
+                if !output.status.success() {
+                    bail!(
+                        "{} command failed:\n{}",
+                        GPG,
+                        String::from_utf8_lossy(&output.stderr)
+                    );
+                }
+
+                Ok(output.stdout)
+            }
+        }
+    }
+}
+
+/// Get the path of the signature of the image
+pub fn get_signature_path(image: &Utf8Path) -> Utf8PathBuf {
+    Utf8PathBuf::from(format!("{}.sig", image))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_signer_test() {
+        assert_eq!(
+            "gpg:0xDEADBEEF".parse(),
+            Ok(Signer::Gpg("0xDEADBEEF".to_string()))
+        );
+        assert!("gpg:".parse::<Signer>().is_err());
+        assert!("minisign:key".parse::<Signer>().is_err());
+        assert!("0xDEADBEEF".parse::<Signer>().is_err());
+        assert_eq!(
+            get_signature_path(Utf8Path::new("/boot/initramfs-6.6.1.img")),
+            "/boot/initramfs-6.6.1.img.sig"
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::{fs, process::Command};
+
+use anyhow::{bail, Context, Result};
+use camino::Utf8Path;
+
+pub const VCONSOLE_CONF: &str = "/etc/vconsole.conf";
+const LOADKEYS: &str = "loadkeys";
+const GZIP: &str = "gzip";
+const CONSOLEFONTS_DIRS: [&str; 2] = ["/usr/share/kbd/consolefonts", "/usr/share/consolefonts"];
+const FONT_EXTENSIONS: [&str; 6] = ["", ".psfu.gz", ".psf.gz", ".psfu", ".psf", ".gz"];
+
+/// Console settings of the host, as described in vconsole.conf(5)
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct VconsoleConf {
+    pub keymap: Option<String>,
+    pub font: Option<String>,
+}
+
+impl VconsoleConf {
+    pub fn parse(content: &str) -> VconsoleConf {
+        let mut conf = VconsoleConf::default();
+        for (key, value) in content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| line.split_once('='))
+        {
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+            if value.is_empty() {
+                continue;
+            }
+            match key.trim() {
+                "KEYMAP" => conf.keymap = Some(value.to_string()),
+                "FONT" => conf.font = Some(value.to_string()),
+                _ => {}
+            }
+        }
+
+        conf
+    }
+}
+
+fn run(command: &mut Command, name: &str) -> Result<Vec<u8>> {
+    let output = command
+        .output()
+        .with_context(|| format!("unable to run {} command", name))?;
+    if !output.status.success() {
+        bail!(
+            "{} command failed:\n{:?}",
+            name,
+            String::from_utf8(output.stderr)
+        )
+    }
+
+    Ok(output.stdout)
+}
+
+/// Compile the keymap in the binary format read by initrz (and busybox loadkmap)
+pub fn get_binary_keymap(keymap: &str) -> Result<Vec<u8>> {
+    run(Command::new(LOADKEYS).args(["-q", "-b", keymap]), LOADKEYS)
+        .with_context(|| format!("unable to compile keymap {}", keymap))
+}
+
+/// Get the uncompressed PSF font
+pub fn get_font(font: &str) -> Result<Vec<u8>> {
+    let path = CONSOLEFONTS_DIRS
+        .iter()
+        .flat_map(|dir| {
+            FONT_EXTENSIONS
+                .iter()
+                .map(move |ext| Utf8Path::new(dir).join(format!("{}{}", font, ext)))
+        })
+        .find(|path| path.is_file())
+        .with_context(|| format!("unable to find console font {}", font))?;
+
+    if path.as_str().ends_with(".gz") {
+        run(Command::new(GZIP).arg("-dc").arg(&path), GZIP)
+    } else {
+        fs::read(&path).with_context(|| format!("unable to read {}", path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let conf = VconsoleConf::parse("# comment\nKEYMAP=\"it\"\nFONT=eurlatgr\nFONT_MAP=\n");
+        assert_eq!(
+            conf,
+            VconsoleConf {
+                keymap: Some("it".to_string()),
+                font: Some("eurlatgr".to_string()),
+            }
+        );
+        assert_eq!(VconsoleConf::parse(""), VconsoleConf::default());
+    }
+}